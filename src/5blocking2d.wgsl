@@ -17,33 +17,29 @@ var<storage, read> sizes: vec3<u32>;
 
 /*
 
-サイズを変えるときにすること
-1. BM, BN, BKを決める
-2. workgroup_size.xを決める(~256)
-3. TM, TNを計算する
-4. shared memoryの大きさを更新する
-5. 呼び出し側に行き、tile_sizeをBM==BNに更新する
+タイルの大きさはRust側(autotune::TileConfig)からテンプレート(wgsl_template.rs)として埋めて生成する
+BM, BN, BK, TM, TNを決めれば残りは計算で決まる
 
 */
 
 // BM * BK == workgroup_size.x, lhsのブロックの要素数
 // BN * BK == workgroup_size.x, rhsのブロックの要素数
-const BM: u32 = 64u; // tile_size = 64
-const BN: u32 = 64u;
-const BK: u32 = 8u;
+const BM: u32 = {{BM}}u;
+const BN: u32 = {{BN}}u;
+const BK: u32 = {{BK}}u;
 // TM * TN = BM * BN / workgroup_size.x
-const TM: u32 = 8u;
-const TN: u32 = 8u;
+const TM: u32 = {{TM}}u;
+const TN: u32 = {{TN}}u;
 // TM_TN = TM * TN
-const TM_TN: u32 = 64u;
+const TM_TN: u32 = {{TM_TN}}u;
 
 // array<f32, BM * BK> or BK * BN
-var<workgroup> lhs_shared: array<f32, 512>;
-var<workgroup> rhs_shared: array<f32, 512>;
+var<workgroup> lhs_shared: array<f32, {{BM_BK}}>;
+var<workgroup> rhs_shared: array<f32, {{BK_BN}}>;
 
 
 // BM * BN / TM_TN = workgroup_size.x, BM*BNはoutputのブロックの要素数。TMで割るとスレッド数
-@compute @workgroup_size(64, 1, 1)
+@compute @workgroup_size({{WG}}, 1, 1)
 fn main(
     @builtin(workgroup_id) workgroup_id: vec3<u32>,
     @builtin(local_invocation_id) local_id: vec3<u32>
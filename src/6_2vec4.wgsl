@@ -17,38 +17,34 @@ var<storage, read> sizes: vec3<u32>;
 
 /*
 
-サイズを変えるときにすること
-1. BM, BN, BKを決める
-2. workgroup_size.xを決める(~256)
-3. TM, TNを計算する
-4. shared memoryの大きさを更新する
-5. 呼び出し側に行き、tile_sizeをBM==BNに更新する
+タイルの大きさはRust側(autotune::TileConfig)からテンプレート(wgsl_template.rs)として埋めて生成する
+BM, BN, BK, TM, TNを決めれば残りは計算で決まる
 
 */
 
 // BM * BK == workgroup_size.x, lhsのブロックの要素数
 // BN * BK == workgroup_size.x, rhsのブロックの要素数
-const BM: u32 = 64u; // tile_size = 64
-const BN: u32 = 64u;
-const BK: u32 = 8u;
+const BM: u32 = {{BM}}u;
+const BN: u32 = {{BN}}u;
+const BK: u32 = {{BK}}u;
 // TM * TN = BM * BN / workgroup_size.x
-const TM: u32 = 8u; // 4の倍数（vec4のため）
-const TN: u32 = 8u; // 4の倍数（vec4のため）
+const TM: u32 = {{TM}}u; // 4の倍数（vec4のため）
+const TN: u32 = {{TN}}u; // 4の倍数（vec4のため）
 // TM_TN = TM * TN
-const TMTN: u32 = 64u;
+const TMTN: u32 = {{TM_TN}}u;
 
 // resister cache用
-const TM4: u32 = 2u; // TM / 4
-const TN4: u32 = 2u; // TN / 4
-const TMTN4: u32 = 16u; // TM * TN / 4
+const TM4: u32 = {{TM4}}u; // TM / 4
+const TN4: u32 = {{TN4}}u; // TN / 4
+const TMTN4: u32 = {{TMTN4}}u; // TM * TN / 4
 
 // array<f32, BM * BK / 4> or BK * BN / 4
-var<workgroup> lhs_shared: array<vec4<f32>, 128>;
-var<workgroup> rhs_shared: array<vec4<f32>, 128>;
+var<workgroup> lhs_shared: array<vec4<f32>, {{BM_BK4}}>;
+var<workgroup> rhs_shared: array<vec4<f32>, {{BK_BN4}}>;
 
 
 // BM * BN / TM = workgroup_size.x, BM*BNはoutputのブロックの要素数。TMで割るとスレッド数
-@compute @workgroup_size(64, 1, 1)
+@compute @workgroup_size({{WG}}, 1, 1)
 fn main(
     @builtin(workgroup_id) workgroup_id: vec3<u32>,
     @builtin(local_invocation_id) local_id: vec3<u32>
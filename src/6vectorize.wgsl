@@ -9,49 +9,71 @@ var<storage, read> rhs: array<f32>;
 // Matrix<f32, M, N>
 @group(0) @binding(2)
 var<storage, read_write> output: array<f32>;
-// メタデータ
-// vec![M, K, N]
-@group(0) @binding(3)
-var<storage, read> sizes: vec3<u32>;
+// メタデータ。push constantsが使えればvar<push_constant>，使えなければbinding(3)のuniform
+// output = alpha * lhs * rhs + beta * output
+struct MatmulParams {
+    m: u32,
+    k: u32,
+    n: u32,
+    // 行の間隔（要素数）
+    lhs_stride: u32,
+    rhs_stride: u32,
+    out_stride: u32,
+    alpha: f32,
+    beta: f32,
+}
+{{PARAMS}}
+// epilogue(epilogue.rs)のbias, residual。使わないときは空行
+{{BIAS}}
+{{RESIDUAL}}
+
+fn tanh_safe(x: f32) -> f32 {
+    // 大きい値でNaNになる実装があるのでclampする
+    return tanh(clamp(x, -15.0, 15.0));
+}
+fn gelu(x: f32) -> f32 {
+    return 0.5 * x * (1.0 + tanh_safe(0.7978845608 * (x + 0.044715 * x * x * x)));
+}
 
 
 /*
 
-サイズを変えるときにすること
-1. BM, BN, BKを決める
-2. workgroup_size.xを決める(~256)
-3. TM, TNを計算する
-4. shared memoryの大きさを更新する
-5. 呼び出し側に行き、tile_sizeをBM==BNに更新する
+タイルの大きさはRust側(autotune::TileConfig)からテンプレート(wgsl_template.rs)として埋めて生成する
+BM, BN, BK, TM, TNを決めれば残りは計算で決まる
 
 */
 
 // BM * BK == num_workgroup.x, ブロック数
 // BN * BK == num_workgroup.x, ブロック数
-const BM: u32 = 32u; // tile_size = 64
-const BN: u32 = 32u;
-const BK: u32 = 4u;
+const BM: u32 = {{BM}}u;
+const BN: u32 = {{BN}}u;
+const BK: u32 = {{BK}}u;
 // TM * TN = BM * BN / workgroup_size.x
-const TM: u32 = 4u;
-const TN: u32 = 4u;
+const TM: u32 = {{TM}}u;
+const TN: u32 = {{TN}}u;
 // TM_TN = TM * TN
-const TM_TN: u32 = 16u;
+const TM_TN: u32 = {{TM_TN}}u;
+// shared memoryの大きさ
+const BM_BK: u32 = {{BM_BK}}u;
+const BK_BN: u32 = {{BK_BN}}u;
 
-// array<f32, BM * BK> or 
-var<workgroup> lhs_shared: array<f32, 128>;
-// BK * BN
-var<workgroup> rhs_shared: array<f32, 128>;
+var<workgroup> lhs_shared: array<f32, BM_BK>;
+var<workgroup> rhs_shared: array<f32, BK_BN>;
 
 
 // BM * BN / (TM * TN) = workgroup_size.x, BM*BNはoutputのブロックの要素数。TM*TNで割るとスレッド数
-@compute @workgroup_size(64, 1, 1)
+@compute @workgroup_size({{WG}}, 1, 1)
 fn main(
     @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
     @builtin(local_invocation_id) local_id: vec3<u32>
 ) {
-    let M: u32 = sizes[0]; // Number of rows in lhs and output
-    let K: u32 = sizes[1]; // Number of columns in lhs and rows in rhs
-    let N: u32 = sizes[2]; // Number of columns in rhs and output
+    let M: u32 = params.m; // Number of rows in lhs and output
+    let K: u32 = params.k; // Number of columns in lhs and rows in rhs
+    let N: u32 = params.n; // Number of columns in rhs and output
+    let LDA: u32 = params.lhs_stride;
+    let LDB: u32 = params.rhs_stride;
+    let LDC: u32 = params.out_stride;
 
      /*
     yが下向き、xが右向き方向
@@ -62,8 +84,13 @@ fn main(
 
     
 // 〇　最終outputのindexが正しく計算できてるからこれは合ってる
-    let cRow = workgroup_id.y;
+    // 行のタイルが1次元の上限を超えたらzにも分ける(TileConfig::dispatch)
+    let cRow = workgroup_id.y + workgroup_id.z * num_workgroups.y;
     let cCol = workgroup_id.x;
+    // y * zは行のタイル数より多くなることがある。cRowはworkgroupで同じなのでbarrierの前に抜けてよい
+    if (cRow * BM >= M) {
+        return;
+    }
 
 // 〇　最終outputのindexが正しく計算できてるからこれは合ってる
     // BNをTNで割ると横に並べるスレッドの数
@@ -72,9 +99,9 @@ fn main(
 
 // 〇　最終outputのindexが正しく計算できてるからこれは合ってる
     // ブロックのシフト
-    var lhs_shift = cRow * BM * K;
+    var lhs_shift = cRow * BM * LDA;
     var rhs_shift = cCol * BN;
-    var out_shift = cRow * BM * N + cCol * BN;
+    var out_shift = cRow * BM * LDC + cCol * BN;
 
     // lhsとrhsのアクセス用
     let lhs_innerRow = local_id.x / (BK ); // BK = 8のとき、local_id.x / 2 -> 0..8
@@ -112,10 +139,10 @@ fn main(
     for (var bkIdx = 0u; bkIdx < K; bkIdx += BK) {
         // populate the Shared Memory caches
         for (var loadOffset = 0u; loadOffset < BM; loadOffset += lhs_stride) {
-            lhs_shared[lhs_innerCol * BM + lhs_innerRow + loadOffset] = lhs[lhs_shift + (lhs_innerRow + loadOffset) * K + lhs_innerCol];
+            lhs_shared[lhs_innerCol * BM + lhs_innerRow + loadOffset] = lhs[lhs_shift + (lhs_innerRow + loadOffset) * LDA + lhs_innerCol];
         }
         for (var loadOffset = 0u; loadOffset < BK; loadOffset += rhs_stride) {
-            rhs_shared[(rhs_innerRow + loadOffset) * BN + rhs_innerCol] = rhs[rhs_shift + (rhs_innerRow + loadOffset) * N + rhs_innerCol];
+            rhs_shared[(rhs_innerRow + loadOffset) * BN + rhs_innerCol] = rhs[rhs_shift + (rhs_innerRow + loadOffset) * LDB + rhs_innerCol];
         }
         /*
         
@@ -134,7 +161,7 @@ fn main(
 
         // shift
         lhs_shift += BK;
-        rhs_shift += BK * N;
+        rhs_shift += BK * LDB;
 
         // calculate per-thread results
         for (var dotIdx = 0u; dotIdx < BK; dotIdx += 1u) {
@@ -167,7 +194,17 @@ fn main(
             // CUDAはC += shiftでずらしていたが、こっちではできないので。
             // cRow * BM, cCol * BNはworkgroupの位置
 
-            output[out_shift + (threadRow * TM + resIdxM) * N + threadCol * TN + resIdxN] = threadResults[resIdxM * TN + resIdxN];
+            let row = cRow * BM + threadRow * TM + resIdxM;
+            let col = cCol * BN + threadCol * TN + resIdxN;
+            let out_idx = out_shift + (threadRow * TM + resIdxM) * LDC + threadCol * TN + resIdxN;
+            var value = params.alpha * threadResults[resIdxM * TN + resIdxN];
+            // beta == 0ならoutputを読まない（未初期化のNaNを拾わない）
+            if (params.beta != 0.0) {
+                value += params.beta * output[out_idx];
+            }
+            // bias, activation, residual
+            {{EPILOGUE}}
+            output[out_idx] = value;
             
                 
         }
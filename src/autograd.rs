@@ -0,0 +1,497 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::elementwise::{self, Map};
+use crate::epilogue::{Activation, Epilogue};
+use crate::matmul_structured2::{RawGf32, Shape};
+use crate::softmax::Labels;
+
+
+/*
+リバースモードの自動微分
+    let tape = Tape::new();
+    let w = tape.var(w, true);   // requires_grad
+    let x = tape.var(x, false);
+    let loss = x.matmul(w).sum();
+    tape.backward(loss);
+    w.grad()   // d loss / d w
+演算はその場でGPUで計算して，入力と演算の種類をTapeに記録する
+backward()はTapeを逆順にたどって，各演算の勾配をGPUのカーネルで足し込む
+    matmul: dA += dC * B^T, dB += A^T * dC (gemm_transで転置を作らずに計算)
+    linear: matmul + bias (epilogueでまとめて計算)。dbias += 1^T * dC
+    add / sub / mul / scale / activation: elementwise
+    sum / mean: スカラーの勾配を全要素に足す
+    cross_entropy: softmax - onehot (cross_entropy_grad.wgsl)
+requires_gradの入力が1つもない演算の勾配は計算しない
+パラメータは学習の間ずっと使うのでRc<RawGf32>で持ち，Tapeは1ステップごとに作り直す
+    同じRcをleaf()に2回渡すと同じVarになる（勾配が1か所にまとまる）
+*/
+
+#[derive(Clone)]
+enum Op {
+    Leaf,
+    Matmul(usize, usize),
+    // x * w + bias
+    Linear(usize, usize, usize),
+    Activation(usize, Activation),
+    CrossEntropy(usize, Rc<Labels>),
+    Add(usize, usize),
+    Sub(usize, usize),
+    Mul(usize, usize),
+    Scale(usize, f32),
+    Sum(usize),
+    Mean(usize),
+}
+impl Op {
+    fn inputs(&self) -> Vec<usize> {
+        match *self {
+            Op::Leaf => vec![],
+            Op::Matmul(a, b) | Op::Add(a, b) | Op::Sub(a, b) | Op::Mul(a, b) => vec![a, b],
+            Op::Linear(x, w, b) => vec![x, w, b],
+            Op::Scale(a, _) | Op::Sum(a) | Op::Mean(a) | Op::Activation(a, _) | Op::CrossEntropy(a, _) => vec![a],
+        }
+    }
+}
+
+struct Node {
+    value: Rc<RawGf32>,
+    op: Op,
+    requires_grad: bool,
+    grad: Option<Rc<RawGf32>>,
+}
+
+#[derive(Default)]
+pub struct Tape {
+    nodes: RefCell<Vec<Node>>,
+}
+
+// Tapeの中の1つのテンソル
+#[derive(Clone, Copy)]
+pub struct Var<'t> {
+    tape: &'t Tape,
+    id: usize,
+}
+
+impl Tape {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // パラメータのように他と共有するもの
+    pub fn leaf(&self, value: Rc<RawGf32>, requires_grad: bool) -> Var<'_> {
+        if let Some(id) = self.find_leaf(&value) {
+            if self.nodes.borrow()[id].requires_grad != requires_grad {
+                panic!("leaf is already recorded with requires_grad = {}", !requires_grad);
+            }
+            return Var { tape: self, id };
+        }
+        self.push(value, Op::Leaf, requires_grad)
+    }
+
+    fn find_leaf(&self, value: &Rc<RawGf32>) -> Option<usize> {
+        self.nodes.borrow().iter().position(|n| matches!(n.op, Op::Leaf) && Rc::ptr_eq(&n.value, value))
+    }
+
+    // leaf()に渡したパラメータの勾配（optimizer用）
+    pub fn grad(&self, value: &Rc<RawGf32>) -> Option<Rc<RawGf32>> {
+        self.find_leaf(value).and_then(|id| self.nodes.borrow()[id].grad.clone())
+    }
+
+    pub fn var(&self, value: RawGf32, requires_grad: bool) -> Var<'_> {
+        self.leaf(Rc::new(value), requires_grad)
+    }
+
+    fn push(&self, value: Rc<RawGf32>, op: Op, requires_grad: bool) -> Var<'_> {
+        let mut nodes = self.nodes.borrow_mut();
+        nodes.push(Node { value, op, requires_grad, grad: None });
+        Var { tape: self, id: nodes.len() - 1 }
+    }
+
+    // 入力のどれかがrequires_gradならrequires_grad
+    fn record(&self, value: RawGf32, op: Op) -> Var<'_> {
+        let requires_grad = {
+            let nodes = self.nodes.borrow();
+            op.inputs().iter().any(|&i| nodes[i].requires_grad)
+        };
+        self.push(Rc::new(value), op, requires_grad)
+    }
+
+    // rootの各要素の和を微分する（rootがスカラーならそのまま）
+    // 前のbackward()の勾配は捨てる
+    pub fn backward(&self, root: Var<'_>) {
+        if !std::ptr::eq(root.tape, self) {
+            panic!("backward: root is recorded on another tape");
+        }
+        let mut nodes = self.nodes.borrow_mut();
+        if !nodes[root.id].requires_grad {
+            panic!("backward: root does not require grad");
+        }
+
+        // 新しく作ったbufferは0で初期化されている(wgpuが保証する)ので，勾配は全部足し込みで書ける
+        let mut grads: Vec<Option<RawGf32>> = (0..=root.id).map(|_| None).collect();
+        let seed = RawGf32::_new_empty(nodes[root.id].value.shape().clone(), Some("grad seed"));
+        elementwise::fill(&seed, 1.0);
+        grads[root.id] = Some(seed);
+
+        for id in (0..=root.id).rev() {
+            let Some(g) = grads[id].take() else { continue };
+            let value = |i: usize| -> &RawGf32 { &nodes[i].value };
+            let mut acc = |i: usize, f: &dyn Fn(&mut RawGf32)| {
+                if nodes[i].requires_grad {
+                    let grad = grads[i].get_or_insert_with(|| RawGf32::_new_empty(nodes[i].value.shape().clone(), Some("grad")));
+                    f(grad);
+                }
+            };
+            match nodes[id].op.clone() {
+                Op::Leaf => {}
+                Op::Matmul(a, b) => {
+                    acc(a, &|ga| g.gemm_trans(false, value(b), true, 1.0, 1.0, ga));
+                    acc(b, &|gb| value(a).gemm_trans(true, &g, false, 1.0, 1.0, gb));
+                }
+                Op::Linear(x, w, b) => {
+                    acc(x, &|gx| g.gemm_trans(false, value(w), true, 1.0, 1.0, gx));
+                    acc(w, &|gw| value(x).gemm_trans(true, &g, false, 1.0, 1.0, gw));
+                    // 列ごとの和 = (1, M)の1 * dC
+                    acc(b, &|gb| {
+                        let (m, _) = g.shape().d2();
+                        let ones = RawGf32::_new_empty(Shape::D2(1, m), Some("ones"));
+                        elementwise::fill(&ones, 1.0);
+                        ones.gemm_trans(false, &g, false, 1.0, 1.0, gb);
+                    });
+                }
+                Op::Activation(a, activation) => {
+                    let map = match activation {
+                        Activation::None => Map::Axpy,
+                        Activation::Relu => Map::ReluGrad,
+                        Activation::Gelu => Map::GeluGrad,
+                        Activation::Tanh => Map::TanhGrad,
+                    };
+                    acc(a, &|ga| elementwise::map(map, &g, value(a), ga, 1.0));
+                }
+                Op::CrossEntropy(a, labels) => {
+                    acc(a, &|ga| value(a).cross_entropy_backward(&labels, &g, ga));
+                }
+                Op::Add(a, b) => {
+                    acc(a, &|ga| elementwise::map(Map::Axpy, &g, &g, ga, 1.0));
+                    acc(b, &|gb| elementwise::map(Map::Axpy, &g, &g, gb, 1.0));
+                }
+                Op::Sub(a, b) => {
+                    acc(a, &|ga| elementwise::map(Map::Axpy, &g, &g, ga, 1.0));
+                    acc(b, &|gb| elementwise::map(Map::Axpy, &g, &g, gb, -1.0));
+                }
+                Op::Mul(a, b) => {
+                    acc(a, &|ga| elementwise::map(Map::MulAcc, &g, value(b), ga, 1.0));
+                    acc(b, &|gb| elementwise::map(Map::MulAcc, &g, value(a), gb, 1.0));
+                }
+                Op::Scale(a, alpha) => {
+                    acc(a, &|ga| elementwise::map(Map::Axpy, &g, &g, ga, alpha));
+                }
+                Op::Sum(a) => {
+                    acc(a, &|ga| elementwise::map(Map::AxpyScalar, &g, &g, ga, 1.0));
+                }
+                Op::Mean(a) => {
+                    let alpha = 1.0 / value(a).shape().size() as f32;
+                    acc(a, &|ga| elementwise::map(Map::AxpyScalar, &g, &g, ga, alpha));
+                }
+            }
+            nodes[id].grad = Some(Rc::new(g));
+        }
+        for node in nodes.iter_mut().skip(root.id + 1) {
+            node.grad = None;
+        }
+    }
+}
+
+impl<'t> Var<'t> {
+    pub fn value(&self) -> Rc<RawGf32> {
+        self.tape.nodes.borrow()[self.id].value.clone()
+    }
+
+    // backward()の後。requires_gradでないか，rootに関係しなければNone
+    pub fn grad(&self) -> Option<Rc<RawGf32>> {
+        self.tape.nodes.borrow()[self.id].grad.clone()
+    }
+
+    pub fn requires_grad(&self) -> bool {
+        self.tape.nodes.borrow()[self.id].requires_grad
+    }
+
+    fn check_tape(&self, other: &Var<'t>) {
+        if !std::ptr::eq(self.tape, other.tape) {
+            panic!("vars are recorded on different tapes");
+        }
+    }
+
+    pub fn matmul(&self, other: Var<'t>) -> Var<'t> {
+        self.check_tape(&other);
+        let value = self.value().matmul(&other.value());
+        self.tape.record(value, Op::Matmul(self.id, other.id))
+    }
+
+    // self * weight + bias。biasはShape::D2(1, N)で各行に足す
+    pub fn linear(&self, weight: Var<'t>, bias: Var<'t>) -> Var<'t> {
+        self.check_tape(&weight);
+        self.check_tape(&bias);
+        let bias_value = bias.value();
+        let value = self.value().matmul_fused(&weight.value(), &Epilogue::new().bias(&bias_value));
+        self.tape.record(value, Op::Linear(self.id, weight.id, bias.id))
+    }
+
+    pub fn activation(&self, activation: Activation) -> Var<'t> {
+        let map = match activation {
+            Activation::None => return *self,
+            Activation::Relu => Map::Relu,
+            Activation::Gelu => Map::Gelu,
+            Activation::Tanh => Map::Tanh,
+        };
+        let a = self.value();
+        let out = RawGf32::_new_empty(a.shape().clone(), Some("activation"));
+        elementwise::map(map, &a, &a, &out, 1.0);
+        self.tape.record(out, Op::Activation(self.id, activation))
+    }
+
+    // selfはlogits。Shape::D2(1, 1)
+    pub fn cross_entropy(&self, labels: &Rc<Labels>) -> Var<'t> {
+        let value = self.value().cross_entropy(labels);
+        self.tape.record(value, Op::CrossEntropy(self.id, labels.clone()))
+    }
+
+    fn map2(&self, other: Var<'t>, map: Map, op: Op, label: &str) -> Var<'t> {
+        self.check_tape(&other);
+        let (a, b) = (self.value(), other.value());
+        if a.shape() != b.shape() {
+            panic!("{}: size unmatch, self.shape: {}, other.shape: {}", label, a.shape().to_string(), b.shape().to_string());
+        }
+        let out = RawGf32::_new_empty(a.shape().clone(), Some(label));
+        elementwise::map(map, &a, &b, &out, 1.0);
+        self.tape.record(out, op)
+    }
+
+    pub fn add(&self, other: Var<'t>) -> Var<'t> {
+        self.map2(other, Map::Add, Op::Add(self.id, other.id), "add")
+    }
+
+    pub fn sub(&self, other: Var<'t>) -> Var<'t> {
+        self.map2(other, Map::Sub, Op::Sub(self.id, other.id), "sub")
+    }
+
+    // 要素ごとの積
+    pub fn mul(&self, other: Var<'t>) -> Var<'t> {
+        self.map2(other, Map::Mul, Op::Mul(self.id, other.id), "mul")
+    }
+
+    pub fn scale(&self, alpha: f32) -> Var<'t> {
+        self.tape.record(self.value().scale(alpha), Op::Scale(self.id, alpha))
+    }
+
+    // Shape::D2(1, 1)
+    pub fn sum(&self) -> Var<'t> {
+        self.tape.record(self.value().sum(), Op::Sum(self.id))
+    }
+
+    // Shape::D2(1, 1)
+    pub fn mean(&self) -> Var<'t> {
+        self.tape.record(self.value().mean(), Op::Mean(self.id))
+    }
+}
+
+
+
+// 中心差分と比べる（f32なのでepsは大きめ）
+// fはleafのVarからスカラーを計算する
+fn check_grads(name: &str, shapes: &[Shape], values: &[Vec<f32>], f: &dyn for<'t> Fn(&[Var<'t>]) -> Var<'t>) {
+    let leaves = |values: &[Vec<f32>]| -> Vec<Rc<RawGf32>> {
+        shapes.iter().zip(values).map(|(shape, v)| Rc::new(RawGf32::new_init(shape.clone(), v, Some("x")))).collect()
+    };
+    let tape = Tape::new();
+    let params = leaves(values);
+    let vars: Vec<Var> = params.iter().map(|p| tape.leaf(p.clone(), true)).collect();
+    tape.backward(f(&vars));
+
+    let eps = 1e-2;
+    let eval = |values: &[Vec<f32>]| -> f32 {
+        let tape = Tape::new();
+        let vars: Vec<Var> = leaves(values).into_iter().map(|p| tape.leaf(p, false)).collect();
+        f(&vars).value().to_vec()[0]
+    };
+    for (i, param) in params.iter().enumerate() {
+        let grad = tape.grad(param).expect("leaf must have grad").to_vec();
+        for j in 0..values[i].len() {
+            let mut plus = values.to_vec();
+            plus[i][j] += eps;
+            let mut minus = values.to_vec();
+            minus[i][j] -= eps;
+            let numerical = (eval(&plus) - eval(&minus)) / (2.0 * eps);
+            if (numerical - grad[j]).abs() > 1e-2 * grad[j].abs().max(1.0) {
+                panic!("{}: grad of input {} at {} mismatch: autograd {} != finite difference {}", name, i, j, grad[j], numerical);
+            }
+        }
+    }
+}
+
+// -1 ~ 1くらいの適当な値。0.05ずらしているのでReLUの折れ目(0)は踏まない
+fn init(len: usize, seed: usize) -> Vec<f32> {
+    (0..len).map(|i| (((i * 7 + seed * 13) % 11) as f32 - 5.0) * 0.2 + 0.05).collect()
+}
+
+pub fn run() {
+    let (m, k, n) = (3, 4, 2);
+
+    // loss = mean((A B + C) * (A B - C)) + 0.1 * sum(C * C) - sum(A B)
+    let shapes = [Shape::D2(m, k), Shape::D2(k, n), Shape::D2(m, n)];
+    let values = [init(m * k, 1), init(k * n, 2), init(m * n, 3)];
+    check_grads("matmul", &shapes, &values, &|v| {
+        let y = v[0].matmul(v[1]);
+        let z = y.add(v[2]).mul(y.sub(v[2])).mean();
+        z.add(v[2].mul(v[2]).sum().scale(0.1)).sub(y.sum())
+    });
+
+    // loss = cross_entropy(act(X W + b) V)
+    let labels = Rc::new(Labels::new(&[1, 0, 1], Some("labels")));
+    let shapes = [Shape::D2(m, k), Shape::D2(k, n), Shape::D2(1, n), Shape::D2(n, 2)];
+    let values = [init(m * k, 1), init(k * n, 2), init(n, 3), init(n * 2, 4)];
+    for activation in [Activation::None, Activation::Relu, Activation::Gelu, Activation::Tanh] {
+        check_grads(&format!("linear ({:?})", activation), &shapes, &values, &|v| {
+            v[0].linear(v[1], v[2]).activation(activation).matmul(v[3]).cross_entropy(&labels)
+        });
+    }
+
+    // 同じVarを2回使うと勾配は足される: d sum(x * x) = 2x
+    // requires_gradでないものには勾配がない
+    let tape = Tape::new();
+    let x = tape.var(RawGf32::new_init(Shape::D2(2, 2), &vec![1.0, -2.0, 3.0, 0.5], Some("x")), true);
+    let w = tape.var(RawGf32::new_init(Shape::D2(2, 2), &vec![1.0; 4], Some("w")), false);
+    tape.backward(x.mul(x).add(w).sum());
+    if x.grad().unwrap().to_vec() != vec![2.0, -4.0, 6.0, 1.0] {
+        panic!("grad of x * x mismatch");
+    }
+    if w.grad().is_some() {
+        panic!("w does not require grad");
+    }
+    println!("autograd ok ({} nodes)", tape.len());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matmul_structured2::adapter_available;
+
+    // 1つのOpだけを通して，最後にsumでスカラーにする
+    // sumの勾配は全部1なので，重みの違う勾配にするためにmulで別の入力を掛ける
+    fn check_op(name: &str, shapes: &[Shape], f: &dyn for<'t> Fn(&[Var<'t>]) -> Var<'t>) {
+        let values: Vec<Vec<f32>> = shapes.iter().enumerate().map(|(i, shape)| init(shape.size(), i + 1)).collect();
+        check_grads(name, shapes, &values, f);
+    }
+
+    #[test]
+    fn matmul() {
+        if !adapter_available() {
+            return;
+        }
+        // 割り切れない大きさも
+        for (m, k, n) in [(3, 4, 2), (5, 7, 3)] {
+            let shapes = [Shape::D2(m, k), Shape::D2(k, n), Shape::D2(m, n)];
+            check_op("matmul", &shapes, &|v| v[0].matmul(v[1]).mul(v[2]).sum());
+        }
+    }
+
+    #[test]
+    fn linear() {
+        if !adapter_available() {
+            return;
+        }
+        let (m, k, n) = (3, 4, 2);
+        let shapes = [Shape::D2(m, k), Shape::D2(k, n), Shape::D2(1, n), Shape::D2(m, n)];
+        check_op("linear", &shapes, &|v| v[0].linear(v[1], v[2]).mul(v[3]).sum());
+    }
+
+    #[test]
+    fn add() {
+        if !adapter_available() {
+            return;
+        }
+        let shapes = [Shape::D2(3, 4), Shape::D2(3, 4), Shape::D2(3, 4)];
+        check_op("add", &shapes, &|v| v[0].add(v[1]).mul(v[2]).sum());
+    }
+
+    #[test]
+    fn sub() {
+        if !adapter_available() {
+            return;
+        }
+        let shapes = [Shape::D2(3, 4), Shape::D2(3, 4), Shape::D2(3, 4)];
+        check_op("sub", &shapes, &|v| v[0].sub(v[1]).mul(v[2]).sum());
+    }
+
+    #[test]
+    fn mul() {
+        if !adapter_available() {
+            return;
+        }
+        let shapes = [Shape::D2(3, 4), Shape::D2(3, 4)];
+        check_op("mul", &shapes, &|v| v[0].mul(v[1]).sum());
+        // 同じVarどうし: d sum(x * x) = 2x
+        check_op("mul (same var)", &shapes[..1], &|v| v[0].mul(v[0]).sum());
+    }
+
+    #[test]
+    fn scale() {
+        if !adapter_available() {
+            return;
+        }
+        let shapes = [Shape::D2(3, 4), Shape::D2(3, 4)];
+        check_op("scale", &shapes, &|v| v[0].scale(-2.5).mul(v[1]).sum());
+    }
+
+    #[test]
+    fn activations() {
+        if !adapter_available() {
+            return;
+        }
+        let shapes = [Shape::D2(3, 4), Shape::D2(3, 4)];
+        for activation in [Activation::None, Activation::Relu, Activation::Gelu, Activation::Tanh] {
+            check_op(&format!("{:?}", activation), &shapes, &|v| v[0].activation(activation).mul(v[1]).sum());
+        }
+    }
+
+    #[test]
+    fn sum() {
+        if !adapter_available() {
+            return;
+        }
+        // 後ろにスカラーの演算をつないで，seed以外の勾配が流れてくるようにする
+        let shapes = [Shape::D2(3, 4), Shape::D2(1, 1)];
+        check_op("sum", &shapes, &|v| v[0].sum().mul(v[1]).sum());
+    }
+
+    #[test]
+    fn mean() {
+        if !adapter_available() {
+            return;
+        }
+        let shapes = [Shape::D2(3, 4), Shape::D2(1, 1)];
+        check_op("mean", &shapes, &|v| v[0].mean().mul(v[1]).sum());
+    }
+
+    #[test]
+    fn cross_entropy() {
+        if !adapter_available() {
+            return;
+        }
+        // クラス数がworkgroup_sizeより大きいものも
+        for (rows, cols) in [(3, 4), (2, 70)] {
+            let labels: Vec<u32> = (0..rows).map(|i| (i * 5 % cols) as u32).collect();
+            let labels = Rc::new(Labels::new(&labels, Some("labels")));
+            let shapes = [Shape::D2(rows, cols), Shape::D2(1, 1)];
+            check_op("cross_entropy", &shapes, &|v| v[0].cross_entropy(&labels).mul(v[1]).sum());
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use std::{fs, io::Write, path::PathBuf, time::{Duration, Instant}};
+use crate::matmul_structured2::{RawGf32, Shape, WgpuServer};
+use crate::wgsl_template::{TemplateParams, WgslTemplate};
+
+
+/*
+タイル化カーネル(6vectorize.wgsl)のBM, BN, BK, TM, TNをRustから決める
+
+以前は
+1. BM, BN, BKを決める
+2. workgroup_size.xを決める(~256)
+3. TM, TNを計算する
+4. shared memoryの大きさを更新する
+5. 呼び出し側に行き、tile_sizeをBM==BNに更新する
+を手でやっていたが，TileConfigから全部計算してシェーダを生成する
+
+autotuneは(M, K, N)とadapterごとに候補を全部ベンチマークして一番速いものをキャッシュファイルに残す
+キャッシュファイルは WGPU_MATMUL_AUTOTUNE_CACHE か ユーザーごとのキャッシュディレクトリ
+    $XDG_CACHE_HOME/wgpu_matmul/autotune_cache.txt (なければ ~/.cache/wgpu_matmul/autotune_cache.txt)
+    どれもなければキャッシュしない（実行したディレクトリには書かない）
+    adapter名 \t M \t K \t N \t BM BN BK TM TN
+*/
+
+pub const VECTORIZE: WgslTemplate = WgslTemplate::new("6vectorize.wgsl", include_str!("./6vectorize.wgsl"));
+pub const BLOCKING1D: WgslTemplate = WgslTemplate::new("4blocking1d.wgsl", include_str!("./4blocking1d.wgsl"));
+pub const BLOCKING2D: WgslTemplate = WgslTemplate::new("5blocking2d.wgsl", include_str!("./5blocking2d.wgsl"));
+pub const VEC4: WgslTemplate = WgslTemplate::new("6_2vec4.wgsl", include_str!("./6_2vec4.wgsl"));
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TileConfig {
+    pub bm: u32,
+    pub bn: u32,
+    pub bk: u32,
+    pub tm: u32,
+    pub tn: u32,
+}
+impl Default for TileConfig {
+    // 以前6vectorize.wgslに手で書いていた値
+    fn default() -> Self {
+        Self { bm: 32, bn: 32, bk: 4, tm: 4, tn: 4 }
+    }
+}
+impl TileConfig {
+    // BM * BN / (TM * TN) = workgroup_size.x
+    pub fn workgroup_size(&self) -> u32 {
+        (self.bm * self.bn) / (self.tm * self.tn)
+    }
+
+    // shared memoryのByte数
+    pub fn shared_memory_size(&self) -> u32 {
+        (self.bm * self.bk + self.bk * self.bn) * 4
+    }
+
+    // カーネルのload loopがはみ出さない組み合わせか
+    pub fn is_valid(&self, limits: &wgpu::Limits) -> bool {
+        let wg = self.workgroup_size();
+        if !self.bm.is_multiple_of(self.tm) || !self.bn.is_multiple_of(self.tn) || wg == 0 {
+            return false;
+        }
+        if !(self.bm * self.bn).is_multiple_of(self.tm * self.tn) {
+            return false;
+        }
+        // lhs: 1回で wg / BK 行，BM行を埋める
+        if !wg.is_multiple_of(self.bk) || !self.bm.is_multiple_of(wg / self.bk) {
+            return false;
+        }
+        // rhs: 1回で wg / BN 行，BK行を埋める
+        if wg < self.bn || !wg.is_multiple_of(self.bn) || !self.bk.is_multiple_of(wg / self.bn) {
+            return false;
+        }
+        wg <= limits.max_compute_invocations_per_workgroup
+            && wg <= limits.max_compute_workgroup_size_x
+            && self.shared_memory_size() <= limits.max_compute_workgroup_storage_size
+    }
+
+    // この問題サイズに使えるか（カーネルは端数を扱えない）
+    pub fn fits(&self, m: usize, k: usize, n: usize) -> bool {
+        m.is_multiple_of(self.bm as usize) && n.is_multiple_of(self.bn as usize) && k.is_multiple_of(self.bk as usize)
+    }
+
+    // 6vectorize.wgslだけでなく4blocking1d, 5blocking2d, 6_2vec4.wgslのパラメータも含む
+    // 6vectorize.wgslのPARAMSはexecute_matmulが埋める
+    pub fn params(&self) -> TemplateParams {
+        TemplateParams::new()
+            .set("BM", self.bm)
+            .set("BN", self.bn)
+            .set("BK", self.bk)
+            .set("TM", self.tm)
+            .set("TN", self.tn)
+            .set("TM_TN", self.tm * self.tn)
+            .set("BM_BK", self.bm * self.bk)
+            .set("BK_BN", self.bk * self.bn)
+            .set("WG", self.workgroup_size())
+            // vec4版
+            .set("TM4", self.tm / 4)
+            .set("TN4", self.tn / 4)
+            .set("TMTN4", self.tm * self.tn / 4)
+            .set("BM_BK4", self.bm * self.bk / 4)
+            .set("BK_BN4", self.bk * self.bn / 4)
+    }
+
+    // (x, y) = (N / BN, M / BM)。行のタイルがyの上限を超えたらzにも分ける
+    pub fn dispatch(&self, m: usize, n: usize) -> (u32, u32, u32) {
+        self.dispatch_with_max(m, n, WgpuServer::limits().max_compute_workgroups_per_dimension as usize)
+    }
+
+    pub(crate) fn dispatch_with_max(&self, m: usize, n: usize, max: usize) -> (u32, u32, u32) {
+        let (col_tiles, row_tiles) = (n / self.bn as usize, (m / self.bm as usize).max(1));
+        if col_tiles > max {
+            panic!("matmul: N = {} is too large for dispatch (max {} workgroups per dimension)", n, max);
+        }
+        // zを先に決めてyを揃える（範囲外のタイルはシェーダで飛ばす）
+        let z = row_tiles.div_ceil(max);
+        if z > max {
+            panic!("matmul: M = {} is too large for dispatch (max {} workgroups per dimension)", m, max);
+        }
+        let y = row_tiles.div_ceil(z);
+        let to_u32 = |x: usize| u32::try_from(x).unwrap_or_else(|_| panic!("matmul: {}x{} is too large for dispatch", m, n));
+        (to_u32(col_tiles), to_u32(y), to_u32(z))
+    }
+
+    fn to_line(self) -> String {
+        format!("{} {} {} {} {}", self.bm, self.bn, self.bk, self.tm, self.tn)
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let v: Vec<u32> = line.split_whitespace().map(|s| s.parse().ok()).collect::<Option<Vec<u32>>>()?;
+        if v.len() != 5 {
+            return None;
+        }
+        Some(Self { bm: v[0], bn: v[1], bk: v[2], tm: v[3], tn: v[4] })
+    }
+}
+
+// 探索空間。is_validなものだけ
+// 候補ごとにシェーダのコンパイルが入るので，TM, TN = 2とworkgroup_size < 64は最初から外す
+pub fn search_space(limits: &wgpu::Limits) -> Vec<TileConfig> {
+    let mut configs = vec![];
+    for &bm in &[32, 64, 128] {
+        for &bn in &[32, 64, 128] {
+            for &bk in &[4, 8, 16] {
+                for &tm in &[4, 8] {
+                    for &tn in &[4, 8] {
+                        let config = TileConfig { bm, bn, bk, tm, tn };
+                        if config.workgroup_size() >= 64 && config.is_valid(limits) {
+                            configs.push(config);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    configs
+}
+
+fn cache_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("WGPU_MATMUL_AUTOTUNE_CACHE") {
+        return Some(PathBuf::from(path));
+    }
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(cache_dir.join("wgpu_matmul").join("autotune_cache.txt"))
+}
+
+fn cache_key(adapter_name: &str, m: usize, k: usize, n: usize) -> String {
+    // adapter名にタブは入らない前提
+    format!("{}\t{}\t{}\t{}", adapter_name, m, k, n)
+}
+
+fn load_cached(key: &str) -> Option<TileConfig> {
+    let text = fs::read_to_string(cache_path()?).ok()?;
+    // 後に追記されたものを優先
+    text.lines().rev()
+        .filter_map(|line| line.rsplit_once('\t'))
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, config)| TileConfig::from_line(config))
+}
+
+fn save_cached(key: &str, config: &TileConfig) {
+    let Some(path) = cache_path() else { return };
+    if let Some(dir) = path.parent() {
+        if let Err(e) = fs::create_dir_all(dir) {
+            log::warn!("failed to create autotune cache directory: {}", e);
+            return;
+        }
+    }
+    let file = fs::OpenOptions::new().create(true).append(true).open(path);
+    match file {
+        Ok(mut file) => {
+            if let Err(e) = writeln!(file, "{}\t{}", key, config.to_line()) {
+                log::warn!("failed to write autotune cache: {}", e);
+            }
+        }
+        Err(e) => log::warn!("failed to open autotune cache: {}", e),
+    }
+}
+
+fn benchmark(config: &TileConfig, lhs: &RawGf32, rhs: &RawGf32, repeat: u32) -> Duration {
+    // 1回目はシェーダのコンパイルが入るので捨てる
+    lhs.matmul_with(rhs, config);
+    WgpuServer::wait();
+
+    let s = Instant::now();
+    for _ in 0..repeat {
+        lhs.matmul_with(rhs, config);
+    }
+    WgpuServer::wait();
+    s.elapsed() / repeat
+}
+
+// (M, K, N)に一番速いTileConfigを返す。キャッシュにあればベンチマークしない
+pub fn autotune(m: usize, k: usize, n: usize) -> TileConfig {
+    let adapter_name = WgpuServer::adapter_name();
+    let key = cache_key(&adapter_name, m, k, n);
+    if let Some(config) = load_cached(&key) {
+        if config.is_valid(&WgpuServer::limits()) && config.fits(m, k, n) {
+            return config;
+        }
+    }
+
+    let candidates: Vec<TileConfig> = search_space(&WgpuServer::limits())
+        .into_iter()
+        .filter(|c| c.fits(m, k, n))
+        .collect();
+    if candidates.is_empty() {
+        panic!("no tile config fits M = {}, K = {}, N = {}", m, k, n);
+    }
+
+    let lhs = RawGf32::new_init(Shape::D2(m, k), &vec![1.0; m * k], Some("autotune lhs"));
+    let rhs = RawGf32::new_init(Shape::D2(k, n), &vec![1.0; k * n], Some("autotune rhs"));
+
+    let mut best = (candidates[0], Duration::MAX);
+    for config in candidates {
+        let time = benchmark(&config, &lhs, &rhs, 3);
+        log::info!("autotune {:?}: {:?}", config, time);
+        if time < best.1 {
+            best = (config, time);
+        }
+    }
+
+    save_cached(&key, &best.0);
+    best.0
+}
+
+
+
+pub fn run() {
+    // llvmpipeなどではコンパイルが遅いので小さめの問題で確認する
+    let size = 64;
+    let s = Instant::now();
+    let config = autotune(size, size, size);
+    println!("autotune: {:?}, {:?}", config, s.elapsed());
+
+    // 2回目はキャッシュから
+    let s = Instant::now();
+    let cached = autotune(size, size, size);
+    println!("cached: {:?}, {:?}", cached, s.elapsed());
+    if cached != config {
+        panic!("autotune cache returned a different config");
+    }
+
+    // 非正方でも同じ結果になるか
+    let (m, k, n) = (128, 64, 256);
+    let lhs_values: Vec<f32> = (0..m * k).map(|i| (i % 5) as f32).collect();
+    let rhs_values: Vec<f32> = (0..k * n).map(|i| (i % 3) as f32 - 1.0).collect();
+    let lhs = RawGf32::new_init(Shape::D2(m, k), &lhs_values, Some("lhs"));
+    let rhs = RawGf32::new_init(Shape::D2(k, n), &rhs_values, Some("rhs"));
+    let mut cpu = vec![0.0; m * n];
+    for i in 0..m {
+        for l in 0..k {
+            for j in 0..n {
+                cpu[i * n + j] += lhs_values[i * k + l] * rhs_values[l * n + j];
+            }
+        }
+    }
+    for config in [TileConfig::default(), config, TileConfig { bm: 64, bn: 128, bk: 8, tm: 8, tn: 8 }] {
+        if lhs.matmul_with(&rhs, &config).to_vec() != cpu {
+            panic!("matmul mismatch with {:?}", config);
+        }
+    }
+
+    // matmul_tunedはキャッシュを使う
+    let a = RawGf32::new_init(Shape::D2(size, size), &vec![1.0; size * size], Some("a"));
+    if a.matmul_tuned(&a).to_vec() != vec![size as f32; size * size] {
+        panic!("matmul_tuned mismatch");
+    }
+    println!("autotune ok");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epilogue::Epilogue;
+    use crate::matmul_structured2::{adapter_available, MatmulKernel, MatmulParams};
+
+    #[test]
+    fn dispatch_split() {
+        let config = TileConfig::default();
+        assert_eq!(config.dispatch_with_max(64, 96, 65535), (3, 2, 1));
+        // 7タイルを上限3で: z = 3, y = 3 (2タイルは範囲外)
+        assert_eq!(config.dispatch_with_max(32 * 7, 64, 3), (2, 3, 3));
+        assert_eq!(config.dispatch_with_max(32 * 6, 64, 3), (2, 3, 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "too large for dispatch")]
+    fn dispatch_too_many_columns() {
+        TileConfig::default().dispatch_with_max(32, 32 * 4, 3);
+    }
+
+    // 上限を小さくしてzに分けても，範囲外のタイルを書かずに同じ結果になる
+    #[test]
+    fn split_dispatch_matmul() {
+        if !adapter_available() {
+            return;
+        }
+        let config = TileConfig::default();
+        let (m, k, n) = (32 * 7, 8, 64);
+        let lhs_values: Vec<f32> = (0..m * k).map(|i| (i % 5) as f32).collect();
+        let rhs_values: Vec<f32> = (0..k * n).map(|i| (i % 3) as f32 - 1.0).collect();
+        let lhs = RawGf32::new_init(Shape::D2(m, k), &lhs_values, Some("lhs"));
+        let rhs = RawGf32::new_init(Shape::D2(k, n), &rhs_values, Some("rhs"));
+        // 範囲外に書けばわかるように，outの後ろに余分な要素を置く
+        let out = RawGf32::new_init(Shape::D2(m + 32, n), &vec![-7.0; (m + 32) * n], Some("out"));
+        WgpuServer::execute_matmul(
+            lhs.buffer(), rhs.buffer(), out.buffer(), &MatmulParams::new(m, k, n),
+            MatmulKernel::new(&VECTORIZE, config.params(), config.dispatch_with_max(m, n, 3)), &Epilogue::default(),
+        );
+        let result = out.to_vec();
+        for i in 0..m {
+            for j in 0..n {
+                let expected: f32 = (0..k).map(|l| lhs_values[i * k + l] * rhs_values[l * n + j]).sum();
+                assert_eq!(result[i * n + j], expected, "({}, {})", i, j);
+            }
+        }
+        assert!(result[m * n..].iter().all(|&x| x == -7.0));
+    }
+}
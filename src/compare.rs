@@ -0,0 +1,387 @@
+use std::fmt;
+
+use crate::autotune;
+use crate::epilogue::Epilogue;
+use crate::matmul_structured2::{RawGf32, Shape, WgpuServer};
+use crate::random::{self, Distribution};
+use crate::sparse::SparseCsrGf32;
+
+
+/*
+計算結果を期待値と比べる(numpy.allcloseと同じ判定)
+    |actual - expected| <= atol + rtol * |expected|
+NaNはどちらもNaNなら一致，infは符号まで同じなら一致とする
+print_1でbody[0]を目で見る代わりに，どれだけずれているかを返す
+    最大絶対誤差，最大相対誤差，ULP距離，一番ずれている要素の位置
+
+run()は行列積の全部の経路(GEMV, タイル化, ナイーブ, 転置, epilogue, CSR, タイルの候補全部)を
+いろいろな大きさと値の分布でCPUの行列積(f64)と比べて，経路と分布ごとに一番悪かったものを出す
+*/
+
+#[derive(Clone, Copy, Debug)]
+pub struct Tolerance {
+    pub rtol: f64,
+    pub atol: f64,
+}
+impl Tolerance {
+    pub fn new(rtol: f64, atol: f64) -> Self {
+        Self { rtol, atol }
+    }
+
+    // ビット単位で一致
+    pub fn exact() -> Self {
+        Self::new(0.0, 0.0)
+    }
+}
+impl Default for Tolerance {
+    // numpy.allcloseと同じ
+    fn default() -> Self {
+        Self::new(1e-5, 1e-8)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub len: usize,
+    // tolに収まらなかった要素の数
+    pub mismatches: usize,
+    // 両方有限の要素についての最大値
+    pub max_abs_err: f64,
+    pub max_rel_err: f64,
+    pub max_ulp: u64,
+    // |actual - expected| / (atol + rtol * |expected|)が最大の要素。NaNやinfが合わないものが優先
+    pub worst: Option<(usize, f32, f32)>,
+}
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.mismatches == 0
+    }
+
+    // 合わなければ行列のどこかを出してpanicする
+    pub fn check(&self, label: &str, shape: &Shape) {
+        if self.passed() {
+            return;
+        }
+        let (i, actual, expected) = self.worst.unwrap();
+        let position = match *shape {
+            Shape::D2(_, cols) => format!("({}, {})", i / cols, i % cols),
+            Shape::D3(_, rows, cols) => format!("({}, {}, {})", i / (rows * cols), i / cols % rows, i % cols),
+        };
+        panic!("{}: {} mismatch at {}: {} != {}\n{}", label, shape.to_string(), position, actual, expected, self);
+    }
+}
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{} mismatched, max abs {:.3e}, max rel {:.3e}, max ulp {}",
+            self.mismatches, self.len, self.max_abs_err, self.max_rel_err, self.max_ulp)?;
+        if let Some((i, actual, expected)) = self.worst {
+            write!(f, ", worst [{}] {:e} vs {:e}", i, actual, expected)?;
+        }
+        Ok(())
+    }
+}
+
+// 負の数も大小の順に並ぶ整数にする。+0と-0は同じ
+fn ordered(x: f32) -> i64 {
+    let bits = x.to_bits() as i32 as i64;
+    if bits < 0 { i32::MIN as i64 - bits } else { bits }
+}
+
+pub fn ulp_distance(a: f32, b: f32) -> u64 {
+    (ordered(a) - ordered(b)).unsigned_abs()
+}
+
+pub fn compare(actual: &[f32], expected: &[f32], tolerance: &Tolerance) -> Report {
+    compare_by(actual, expected, |_, e| tolerance.atol + tolerance.rtol * (e as f64).abs())
+}
+
+// 要素ごとに許す絶対誤差を決めて比べる（行列積の丸め誤差のように要素ごとに大きさが違うとき）
+pub fn compare_bounds(actual: &[f32], expected: &[f32], bounds: &[f64]) -> Report {
+    if bounds.len() != expected.len() {
+        panic!("compare_bounds: length unmatch, bounds: {}, expected: {}", bounds.len(), expected.len());
+    }
+    compare_by(actual, expected, |i, _| bounds[i])
+}
+
+// bound(i, expected[i])は両方有限のときの|actual - expected|の上限
+fn compare_by(actual: &[f32], expected: &[f32], bound: impl Fn(usize, f32) -> f64) -> Report {
+    if actual.len() != expected.len() {
+        panic!("compare: length unmatch, actual: {}, expected: {}", actual.len(), expected.len());
+    }
+    let mut report = Report { len: actual.len(), mismatches: 0, max_abs_err: 0.0, max_rel_err: 0.0, max_ulp: 0, worst: None };
+    let mut worst_ratio = -1.0;
+    for (i, (&a, &e)) in actual.iter().zip(expected).enumerate() {
+        let ratio = if a.is_finite() && e.is_finite() {
+            let abs_err = (a as f64 - e as f64).abs();
+            let rel_err = if e == 0.0 { if a == 0.0 { 0.0 } else { f64::INFINITY } } else { abs_err / (e as f64).abs() };
+            report.max_abs_err = report.max_abs_err.max(abs_err);
+            report.max_rel_err = report.max_rel_err.max(rel_err);
+            report.max_ulp = report.max_ulp.max(ulp_distance(a, e));
+            let bound = bound(i, e);
+            if abs_err <= bound {
+                abs_err / bound.max(f64::MIN_POSITIVE)
+            } else {
+                report.mismatches += 1;
+                // bound == 0で不一致のときもinfにして優先する
+                if bound == 0.0 { f64::INFINITY } else { abs_err / bound }
+            }
+        } else if (a.is_nan() && e.is_nan()) || a == e {
+            0.0
+        } else {
+            report.mismatches += 1;
+            f64::INFINITY
+        };
+        if ratio > worst_ratio {
+            worst_ratio = ratio;
+            report.worst = Some((i, a, e));
+        }
+    }
+    report
+}
+
+impl RawGf32 {
+    pub fn compare(&self, expected: &[f32], tolerance: &Tolerance) -> Report {
+        compare(&self.to_vec(), expected, tolerance)
+    }
+
+    pub fn compare_bounds(&self, expected: &[f32], bounds: &[f64]) -> Report {
+        compare_bounds(&self.to_vec(), expected, bounds)
+    }
+
+    pub fn allclose(&self, expected: &[f32], rtol: f64, atol: f64) -> bool {
+        self.compare(expected, &Tolerance::new(rtol, atol)).passed()
+    }
+}
+
+
+
+// run()で使う値の分布
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Values {
+    Uniform,
+    Normal,
+    // 1e15程度。積が1e30程度になる
+    Large,
+    // lhs(stream 0)は非正規化数を含む小さい値，rhsは[-1, 1)。積も非正規化数になる
+    // FTZ(0に丸める)のGPUもあるのでatolで許す
+    Denormal,
+    // NaN, inf, -infを混ぜる
+    Special,
+}
+impl Values {
+    const ALL: &'static [Values] = &[Values::Uniform, Values::Normal, Values::Large, Values::Denormal, Values::Special];
+
+    fn generate(&self, seed: u64, stream: u32, len: usize) -> Vec<f32> {
+        let mut values = match self {
+            Values::Normal => random::generate_cpu(Distribution::Normal, seed, stream, len, 0.0, 1.0),
+            Values::Large => random::generate_cpu(Distribution::Uniform, seed, stream, len, -1e15, 1e15),
+            Values::Denormal if stream == 0 => random::generate_cpu(Distribution::Uniform, seed, stream, len, -1e-38, 1e-38),
+            _ => random::generate_cpu(Distribution::Uniform, seed, stream, len, -1.0, 1.0),
+        };
+        if *self == Values::Special {
+            let specials = [f32::NAN, f32::INFINITY, f32::NEG_INFINITY];
+            for (j, &x) in specials.iter().enumerate() {
+                values[(j * 7919 + stream as usize * 31) % len] = x;
+            }
+        }
+        values
+    }
+}
+
+// CPUの行列積(f64で足してf32に丸める)と，要素ごとの丸め誤差の目安 sum_l |a_il| |b_lj|
+fn matmul_cpu(a: &[f32], b: &[f32], m: usize, k: usize, n: usize) -> (Vec<f32>, Vec<f64>) {
+    let mut c = vec![0.0; m * n];
+    let mut scales = vec![0.0; m * n];
+    for i in 0..m {
+        for j in 0..n {
+            let (mut sum, mut abs_sum) = (0.0f64, 0.0f64);
+            for l in 0..k {
+                let p = a[i * k + l] as f64 * b[l * n + j] as f64;
+                sum += p;
+                if p.is_finite() {
+                    abs_sum += p.abs();
+                }
+            }
+            c[i * n + j] = sum as f32;
+            scales[i * n + j] = abs_sum;
+        }
+    }
+    (c, scales)
+}
+
+fn transpose(values: &[f32], rows: usize, cols: usize) -> Vec<f32> {
+    (0..rows * cols).map(|i| values[(i % rows) * cols + i / rows]).collect()
+}
+
+// 足す順番がCPUと違うので要素ごとに k * eps * sum|a||b|まで。FTZで非正規化数が0になる分も許す
+// 行列全体の最大値を使うと，大きい要素のせいで小さい要素の誤差を見逃す
+fn matmul_bounds(k: usize, scales: &[f64]) -> Vec<f64> {
+    scales.iter().map(|&scale| 2.0 * k as f64 * f32::EPSILON as f64 * scale + k as f64 * f32::MIN_POSITIVE as f64).collect()
+}
+
+// 分布ごとに一番悪かったもの
+struct Summary {
+    worst: Vec<(String, Report)>,
+}
+impl Summary {
+    fn add(&mut self, label: String, report: Report) {
+        match self.worst.iter_mut().find(|(l, _)| *l == label) {
+            Some((_, r)) => {
+                if report.max_ulp > r.max_ulp {
+                    *r = report;
+                }
+            }
+            None => self.worst.push((label, report)),
+        }
+    }
+}
+
+fn check_matmul(m: usize, k: usize, n: usize, values: Values, seed: u64, summary: &mut Summary) {
+    let a_values = values.generate(seed, 0, m * k);
+    let b_values = values.generate(seed, 1, k * n);
+    let (expected, scales) = matmul_cpu(&a_values, &b_values, m, k, n);
+    let bounds = matmul_bounds(k, &scales);
+    let a = RawGf32::new_init(Shape::D2(m, k), &a_values, Some("a"));
+    let b = RawGf32::new_init(Shape::D2(k, n), &b_values, Some("b"));
+    let a_t = RawGf32::new_init(Shape::D2(k, m), &transpose(&a_values, m, k), Some("a_t"));
+    let b_t = RawGf32::new_init(Shape::D2(n, k), &transpose(&b_values, k, n), Some("b_t"));
+    let shape = Shape::D2(m, n);
+
+    let mut results: Vec<(&str, RawGf32)> = vec![("matmul", a.matmul(&b))];
+    // beta == 0ならoutのNaNは読まない
+    let mut out = RawGf32::full(shape.clone(), f32::NAN, Some("out"));
+    a.gemm(&b, 1.0, 0.0, &mut out);
+    results.push(("gemm", out));
+    for (name, lhs, trans_lhs, rhs, trans_rhs) in [
+        ("gemm_trans(a, b)", &a, false, &b, false),
+        ("gemm_trans(a^T, b)", &a_t, true, &b, false),
+        ("gemm_trans(a, b^T)", &a, false, &b_t, true),
+        ("gemm_trans(a^T, b^T)", &a_t, true, &b_t, true),
+    ] {
+        let mut out = RawGf32::zeros(shape.clone(), Some("out"));
+        lhs.gemm_trans(trans_lhs, rhs, trans_rhs, 1.0, 0.0, &mut out);
+        results.push((name, out));
+    }
+    // biasが0のepilogue。GEMVを使わずタイル化/ナイーブのepilogue付きを通る
+    let zero_bias = RawGf32::zeros(Shape::D2(1, n), Some("bias"));
+    results.push(("matmul_fused", a.matmul_fused(&b, &Epilogue::new().bias(&zero_bias))));
+    if n == 1 {
+        results.push(("gemv", a.gemv(&b)));
+    }
+    if m == 1 {
+        // c = (b^T a^T)^T
+        results.push(("gemv_t", b.gemv_t(&a)));
+    }
+    results.push(("csr", SparseCsrGf32::from_dense(&a).matmul(&b)));
+
+    for (name, result) in results {
+        let report = result.compare_bounds(&expected, &bounds);
+        report.check(&format!("{} {:?} ({}, {}, {})", name, values, m, k, n), &shape);
+        summary.add(format!("{:<22} {:?}", name, values), report);
+    }
+}
+
+// 大きさはPhiloxで決める。GEMV(1列, 1行)，タイル化(32の倍数)，ナイーブ(端数)を含める
+fn matmul_shapes() -> Vec<(usize, usize, usize)> {
+    let mut shapes = vec![(1, 37, 1), (1, 64, 50), (70, 33, 1), (64, 32, 96), (128, 64, 32)];
+    for i in 0..6 {
+        let [m, k, n, _] = random::philox([i, 0, 0, 0], [45, 0]);
+        shapes.push((m as usize % 100 + 1, k as usize % 100 + 1, n as usize % 100 + 1));
+    }
+    shapes
+}
+
+fn check_all_matmuls(summary: &mut Summary) {
+    for (seed, (m, k, n)) in matmul_shapes().into_iter().enumerate() {
+        for &values in Values::ALL {
+            check_matmul(m, k, n, values, seed as u64, summary);
+        }
+    }
+}
+
+// autotuneの候補全部。端数を扱えないのでタイルの倍数にする
+// 候補ごとにシェーダのコンパイルが入るので，1タイル分の大きさでNaN/infを混ぜたものだけにする
+// 128x128, BK = 16のタイルはllvmpipeでコンパイルに数分かかるので，WGPU_MATMUL_CHECK_ALL_TILESがあるときだけ
+fn check_tiles(summary: &mut Summary) {
+    let all_tiles = std::env::var("WGPU_MATMUL_CHECK_ALL_TILES").is_ok();
+    let limits = WgpuServer::limits();
+    for config in autotune::search_space(&limits) {
+        if !all_tiles && config.bm * config.bn * config.bk > 128 * 128 * 8 {
+            continue;
+        }
+        let (m, k, n) = (config.bm as usize, config.bk as usize * 2, config.bn as usize);
+        let values = Values::Special;
+        let a_values = values.generate(7, 0, m * k);
+        let b_values = values.generate(7, 1, k * n);
+        let (expected, scales) = matmul_cpu(&a_values, &b_values, m, k, n);
+        let a = RawGf32::new_init(Shape::D2(m, k), &a_values, Some("a"));
+        let b = RawGf32::new_init(Shape::D2(k, n), &b_values, Some("b"));
+        let report = a.matmul_with(&b, &config).compare_bounds(&expected, &matmul_bounds(k, &scales));
+        report.check(&format!("matmul_with {:?}", config), &Shape::D2(m, n));
+        summary.add(format!("{:<22} {:?}", "matmul_with", values), report);
+    }
+}
+
+pub fn run() {
+    let mut summary = Summary { worst: vec![] };
+    check_all_matmuls(&mut summary);
+    check_tiles(&mut summary);
+    for (label, report) in &summary.worst {
+        println!("{}: {}", label, report);
+    }
+    println!("compare ok");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matmul_structured2::adapter_available;
+
+    #[test]
+    fn compare_special_values() {
+        let report = compare(&[1.0, f32::NAN, f32::INFINITY, -0.0], &[1.0, f32::NAN, f32::INFINITY, 0.0], &Tolerance::exact());
+        assert!(report.passed() && report.max_ulp == 0, "{}", report);
+        let next = f32::from_bits(1.0f32.to_bits() + 3);
+        let report = compare(&[0.0, next, 2.0], &[0.0, 1.0, 2.0], &Tolerance::exact());
+        assert!(report.mismatches == 1 && report.max_ulp == 3 && report.worst.unwrap().0 == 1, "{}", report);
+        let report = compare(&[f32::NAN, f32::NEG_INFINITY], &[1.0, f32::INFINITY], &Tolerance::default());
+        assert!(report.mismatches == 2 && report.worst.unwrap().0 == 0, "{}", report);
+    }
+
+    #[test]
+    fn ulp() {
+        assert_eq!(ulp_distance(-f32::from_bits(1), f32::from_bits(1)), 2);
+        assert_eq!(ulp_distance(f32::MAX, f32::INFINITY), 1);
+    }
+
+    #[test]
+    fn bounds_are_per_element() {
+        // 1行目は値が大きく，2行目は小さい。行列全体の最大値で許すと2行目のずれを見逃す
+        let (a, b) = ([1e6, 1e6, 1e-3, 1e-3], [1.0, 1.0]);
+        let (expected, scales) = matmul_cpu(&a, &b, 2, 2, 1);
+        assert_eq!(expected, vec![2e6, 2e-3]);
+        let bounds = matmul_bounds(2, &scales);
+        assert!(compare_bounds(&expected, &expected, &bounds).passed());
+        let report = compare_bounds(&[2e6, 2.1e-3], &expected, &bounds);
+        assert!(report.mismatches == 1 && report.worst.unwrap().0 == 1, "{}", report);
+        // 1行目は丸め誤差程度なら通る
+        assert!(compare_bounds(&[2e6 + 0.25, 2e-3], &expected, &bounds).passed());
+    }
+
+    #[test]
+    fn matmul_paths() {
+        if !adapter_available() {
+            return;
+        }
+        check_all_matmuls(&mut Summary { worst: vec![] });
+    }
+
+    #[test]
+    fn matmul_tiles() {
+        if !adapter_available() {
+            return;
+        }
+        check_tiles(&mut Summary { worst: vec![] });
+    }
+}
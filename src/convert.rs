@@ -0,0 +1,112 @@
+/*
+外部の行列ライブラリとの変換
+RawGf32はrow-major(C order)で持っているので，それに合わせて並べ替える
+
+ndarray: Array2は任意のstrideを持てる。標準レイアウトならそのままコピー，それ以外は論理順で読む
+nalgebra: DMatrixはcolumn-majorなので必ず並べ替えが要る
+
+cargo run --features ndarray,nalgebra
+*/
+
+#[cfg(feature = "ndarray")]
+mod ndarray_convert {
+    use ndarray::{Array2, ArrayView2};
+    use crate::matmul_structured2::{RawGf32, Shape};
+
+    impl RawGf32 {
+        pub fn from_ndarray(array: ArrayView2<f32>) -> Self {
+            let (m, n) = array.dim();
+            let values: Vec<f32> = match array.as_slice() {
+                // row-majorで連続ならそのまま
+                Some(slice) => slice.to_vec(),
+                // 転置ビューやFortran orderなど。iter()は論理的なrow-major順で回る
+                None => array.iter().copied().collect(),
+            };
+            RawGf32::new_init(Shape::D2(m, n), &values, None)
+        }
+
+        pub fn to_ndarray(&self) -> Array2<f32> {
+            let (m, n) = self.shape().d2();
+            Array2::from_shape_vec((m, n), self.to_vec()).unwrap()
+        }
+    }
+
+    impl From<Array2<f32>> for RawGf32 {
+        fn from(array: Array2<f32>) -> Self {
+            RawGf32::from_ndarray(array.view())
+        }
+    }
+    impl From<&Array2<f32>> for RawGf32 {
+        fn from(array: &Array2<f32>) -> Self {
+            RawGf32::from_ndarray(array.view())
+        }
+    }
+}
+
+#[cfg(feature = "nalgebra")]
+mod nalgebra_convert {
+    use nalgebra::DMatrix;
+    use crate::matmul_structured2::{RawGf32, Shape};
+
+    impl RawGf32 {
+        pub fn from_nalgebra(matrix: &DMatrix<f32>) -> Self {
+            let (m, n) = matrix.shape();
+            // column-majorの転置を取るとrow-majorの並びになる
+            let values = matrix.transpose().as_slice().to_vec();
+            RawGf32::new_init(Shape::D2(m, n), &values, None)
+        }
+
+        pub fn to_nalgebra(&self) -> DMatrix<f32> {
+            let (m, n) = self.shape().d2();
+            DMatrix::from_row_slice(m, n, &self.to_vec())
+        }
+    }
+
+    impl From<DMatrix<f32>> for RawGf32 {
+        fn from(matrix: DMatrix<f32>) -> Self {
+            RawGf32::from_nalgebra(&matrix)
+        }
+    }
+    impl From<&DMatrix<f32>> for RawGf32 {
+        fn from(matrix: &DMatrix<f32>) -> Self {
+            RawGf32::from_nalgebra(matrix)
+        }
+    }
+}
+
+
+
+pub fn run() {
+    #[cfg(feature = "ndarray")]
+    {
+        use crate::matmul_structured2::RawGf32;
+
+        let a = ndarray::Array2::from_shape_fn((3, 5), |(i, j)| (i * 10 + j) as f32);
+        let g = RawGf32::from(&a);
+        if g.to_ndarray() != a {
+            panic!("ndarray roundtrip failed");
+        }
+        // 転置ビュー(非連続)
+        let t = a.t();
+        let g = RawGf32::from_ndarray(t);
+        if g.to_ndarray() != t {
+            panic!("ndarray transposed view roundtrip failed");
+        }
+        println!("ndarray conversion ok");
+    }
+    #[cfg(feature = "nalgebra")]
+    {
+        use crate::matmul_structured2::RawGf32;
+
+        let a = nalgebra::DMatrix::from_fn(3, 5, |i, j| (i * 10 + j) as f32);
+        let g = RawGf32::from(&a);
+        // row-majorで(0, 1)は1番目
+        if g.to_vec()[1] != 1.0 {
+            panic!("nalgebra layout is not row-major: {:?}", g.to_vec());
+        }
+        if g.to_nalgebra() != a {
+            panic!("nalgebra roundtrip failed");
+        }
+        println!("nalgebra conversion ok");
+    }
+}
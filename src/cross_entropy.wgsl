@@ -0,0 +1,80 @@
+// 交差エントロピー（log-softmaxとnegative log likelihoodをまとめたもの）の行ごとの値
+// Matrix<f32, ROWS, COLS>。softmaxする前の値
+@group(0) @binding(0)
+var<storage, read> logits: array<f32>;
+// array<u32, ROWS>。正解のクラス
+@group(0) @binding(1)
+var<storage, read> labels: array<u32>;
+// Matrix<f32, ROWS, 1>。行ごとのloss
+@group(0) @binding(2)
+var<storage, read_write> losses: array<f32>;
+// メタデータ
+struct SoftmaxParams {
+    rows: u32,
+    cols: u32,
+}
+@group(0) @binding(3)
+var<uniform> params: SoftmaxParams;
+
+
+/*
+
+loss_i = logsumexp(x_i) - x_i[label_i]
+       = -log_softmax(x_i)[label_i]
+softmax.wgslと同じく1つのworkgroupで1行を計算する（行数がいくつでもworkgroupが並列に動く）
+(最大値, 最大値を引いたexpの和)をworkgroup内で合わせるのでオーバーフローしない
+平均はRust側(softmax.rs)でもう1回reduce(mean)する
+
+*/
+
+// 2の累乗（reductionのため）
+const WG: u32 = 64u;
+const LOWEST: f32 = -3.402823e38;
+
+var<workgroup> shared_max: array<f32, WG>;
+var<workgroup> shared_sum: array<f32, WG>;
+
+
+@compute @workgroup_size(64, 1, 1)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>
+) {
+    // 行数がdispatchの上限を超えるときはyにも分ける
+    let row = workgroup_id.x + workgroup_id.y * num_workgroups.x;
+    // workgroupBarrierの前にreturnしないように，範囲外の行もbarrierまでは進めて読み書きだけしない
+    let in_range = row < params.rows;
+    let cols = params.cols;
+    let shift = row * cols;
+    let tid = local_id.x;
+
+    var m = LOWEST;
+    var s = 0.0;
+    if (in_range) {
+        for (var col = tid; col < cols; col += WG) {
+            let x = logits[shift + col];
+            let new_m = max(m, x);
+            s = s * exp(m - new_m) + exp(x - new_m);
+            m = new_m;
+        }
+    }
+    shared_max[tid] = m;
+    shared_sum[tid] = s;
+    workgroupBarrier();
+
+    for (var stride = WG / 2u; stride > 0u; stride /= 2u) {
+        if (tid < stride) {
+            let m1 = shared_max[tid];
+            let m2 = shared_max[tid + stride];
+            let new_m = max(m1, m2);
+            shared_sum[tid] = shared_sum[tid] * exp(m1 - new_m) + shared_sum[tid + stride] * exp(m2 - new_m);
+            shared_max[tid] = new_m;
+        }
+        workgroupBarrier();
+    }
+
+    if (in_range && tid == 0u) {
+        losses[row] = shared_max[0] + log(shared_sum[0]) - logits[shift + labels[row]];
+    }
+}
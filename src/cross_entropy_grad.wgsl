@@ -0,0 +1,62 @@
+// 交差エントロピーの逆伝播
+// grad += dloss * (softmax(logits) - onehot(labels)) / ROWS
+// Matrix<f32, ROWS, COLS>
+@group(0) @binding(0)
+var<storage, read> logits: array<f32>;
+// array<u32, ROWS>
+@group(0) @binding(1)
+var<storage, read> labels: array<u32>;
+// Matrix<f32, 1, 1>。lossの勾配
+@group(0) @binding(2)
+var<storage, read> dloss: array<f32>;
+// Matrix<f32, ROWS, COLS>
+@group(0) @binding(3)
+var<storage, read_write> grad: array<f32>;
+// メタデータ
+struct SoftmaxParams {
+    rows: u32,
+    cols: u32,
+}
+@group(0) @binding(4)
+var<uniform> params: SoftmaxParams;
+
+
+/*
+
+1スレッドが1行を受け持つ（クラス数は小さい想定）
+softmaxは保存せずにここで計算し直す
+
+*/
+
+// 行数が多いときはyにも分けてdispatchする(elementwise::dispatch)
+@compute @workgroup_size(64, 1, 1)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>
+) {
+    let row = global_id.x + global_id.y * num_workgroups.x * 64u;
+    if (row >= params.rows) {
+        return;
+    }
+    let cols = params.cols;
+    let shift = row * cols;
+
+    var m = logits[shift];
+    for (var col = 1u; col < cols; col += 1u) {
+        m = max(m, logits[shift + col]);
+    }
+    var s = 0.0;
+    for (var col = 0u; col < cols; col += 1u) {
+        s += exp(logits[shift + col] - m);
+    }
+
+    let scale = dloss[0] / f32(params.rows);
+    let label = labels[row];
+    for (var col = 0u; col < cols; col += 1u) {
+        var p = exp(logits[shift + col] - m) / s;
+        if (col == label) {
+            p -= 1.0;
+        }
+        grad[shift + col] += scale * p;
+    }
+}
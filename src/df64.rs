@@ -0,0 +1,287 @@
+use std::cell::Cell;
+
+use crate::matmul_structured2::{Binding, RawGf32, Shape, WgpuServer};
+use crate::random::Rng;
+use crate::wgsl_template::{TemplateParams, WgslTemplate};
+
+
+/*
+double-float(df64)のテンソルと行列積。WGSLにはどこでも使えるf64がないのでf32を2個使う
+    let a = RawGdf64::new_init(Shape::D2(m, k), &values, Some("a"));   // &[f64]から
+    let c = a.matmul(&b);
+    let c: Vec<f64> = c.to_vec();
+値はhi + lo(hi = f32に丸めたもの，lo = 残り)。仮数は約48bit(f64は53bit)で，指数の範囲はf32と同じ
+f32の範囲を超える値(|x| > 3.4e38)や非正規化数の近くは精度が落ちる
+積和はTwoSum / TwoProdで丸め誤差をloに集める(matmul_df64.wgsl)
+TwoProdはfmaが1回の丸めになるデバイスではfma，そうでなければDekkerの分割を使う
+(naga 0.13のGLSLはバージョンによってfmaをa * b + cにするので，1x1の行列積で確かめる)
+*/
+
+pub(crate) const MATMUL_DF64: WgslTemplate = WgslTemplate::new("matmul_df64.wgsl", include_str!("./matmul_df64.wgsl"));
+
+// matmul_df64.wgslのTILE
+const TILE: usize = 16;
+
+thread_local! {
+    // fmaが使えるか。DEVICEと同じくスレッドごと
+    static FMA: Cell<Option<bool>> = const { Cell::new(None) };
+}
+
+// shader_checkで全部検証する
+pub(crate) fn template_params() -> Vec<TemplateParams> {
+    vec![TemplateParams::new().set("FMA", true), TemplateParams::new().set("FMA", false)]
+}
+
+fn split(x: f64) -> [f32; 2] {
+    let hi = x as f32;
+    [hi, (x - hi as f64) as f32]
+}
+
+pub struct RawGdf64 {
+    label: Option<String>,
+    shape: Shape,
+    // 要素ごとに(hi, lo)
+    buffer: wgpu::Buffer,
+}
+impl RawGdf64 {
+    pub fn new_init(shape: Shape, values: &[f64], label: Option<&str>) -> Self {
+        if values.len() != shape.size() {
+            panic!("RawGdf64::new_init: {} values for shape {}", values.len(), shape.to_string());
+        }
+        let pairs: Vec<f32> = values.iter().flat_map(|&x| split(x)).collect();
+        let buffer = WgpuServer::create_buffer_init(&pairs, label);
+        Self {
+            label: label.map(|str| str.to_string()),
+            shape,
+            buffer,
+        }
+    }
+
+    fn _new_empty(shape: Shape, label: Option<&str>) -> Self {
+        let buffer = WgpuServer::create_buffer(shape.size() * 8, label);
+        Self {
+            label: label.map(|str| str.to_string()),
+            shape,
+            buffer,
+        }
+    }
+
+    pub fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    // bufferのバイト数。f32 2個
+    pub fn size(&self) -> usize {
+        self.shape.size() * 8
+    }
+
+    // CPU側に読み出す（row-major）
+    pub fn to_vec(&self) -> Vec<f64> {
+        let pairs: Vec<f32> = WgpuServer::get(&self.buffer);
+        pairs.chunks(2).map(|p| p[0] as f64 + p[1] as f64).collect()
+    }
+
+    // hiだけ(f32に丸めたもの)
+    pub fn to_f32(&self) -> RawGf32 {
+        let values: Vec<f32> = self.to_vec().iter().map(|&x| x as f32).collect();
+        RawGf32::new_init(self.shape.clone(), &values, self.label())
+    }
+
+    pub fn matmul(&self, other: &Self) -> Self {
+        self.matmul_with(other, supports_fma())
+    }
+
+    fn matmul_with(&self, other: &Self, fma: bool) -> Self {
+        let (m, k, n) = match (&self.shape, &other.shape) {
+            (Shape::D2(m, k), Shape::D2(k2, n)) if k == k2 => (*m, *k, *n),
+            _ => panic!("incompatible matrix size, self.shape: {}, other.shape: {}", self.shape.to_string(), other.shape.to_string()),
+        };
+        let result = Self::_new_empty(Shape::D2(m, n), Some("result"));
+
+        let dispatch = (n.div_ceil(TILE), m.div_ceil(TILE));
+        let max = WgpuServer::limits().max_compute_workgroups_per_dimension as usize;
+        if dispatch.0 > max || dispatch.1 > max {
+            panic!("matmul_df64: {}x{} is too large for dispatch (max {} workgroups per dimension)", m, n, max);
+        }
+        // uniformは16Byte単位。4つ目はmatmul_df64.wgslのzero
+        let params = WgpuServer::create_uniform_buffer(16, Some("matmul_df64 params"));
+        WgpuServer::write_buffer(&params, 0, bytemuck::cast_slice(&[m as u32, k as u32, n as u32, 0]));
+        let template_params = TemplateParams::new().set("FMA", fma);
+        let shader_str = MATMUL_DF64.instantiate(&template_params).unwrap_or_else(|e| panic!("{}", e));
+        let bindings = [Binding::read(&self.buffer), Binding::read(&other.buffer), Binding::read_write(&result.buffer), Binding::uniform(&params)];
+        WgpuServer::execute_n("matmul_df64", &bindings, &[], &MATMUL_DF64.instance_name(&template_params), &shader_str, (dispatch.0 as u32, dispatch.1 as u32, 1));
+        result
+    }
+}
+
+// fmaが1回の丸めになっているか
+// (1 + 2^-12)^2 = 1 + 2^-11 + 2^-24。f32の積では2^-24が落ちるので，TwoProdが正しければloに残る
+fn supports_fma() -> bool {
+    if let Some(fma) = FMA.with(|f| f.get()) {
+        return fma;
+    }
+    let x = 1.0 + 2f64.powi(-12);
+    let a = RawGdf64::new_init(Shape::D2(1, 1), &[x], Some("fma probe"));
+    let fma = a.matmul_with(&a, true).to_vec()[0] == x * x;
+    FMA.with(|f| f.set(Some(fma)));
+    fma
+}
+
+fn matmul_cpu(a: &[f64], b: &[f64], m: usize, k: usize, n: usize) -> Vec<f64> {
+    let mut c = vec![0.0; m * n];
+    for i in 0..m {
+        for j in 0..n {
+            c[i * n + j] = (0..k).map(|l| a[i * k + l] * b[l * n + j]).sum();
+        }
+    }
+    c
+}
+
+// 要素ごとの誤差を sum_k |a[i][l] * b[l][j]| で割ったもの(条件数によらない相対誤差)の最大値
+fn max_scaled_error(actual: &[f64], a: &[f64], b: &[f64], m: usize, k: usize, n: usize) -> f64 {
+    let expected = matmul_cpu(a, b, m, k, n);
+    let mut max: f64 = 0.0;
+    for i in 0..m {
+        for j in 0..n {
+            let scale: f64 = (0..k).map(|l| (a[i * k + l] * b[l * n + j]).abs()).sum();
+            if scale > 0.0 {
+                max = max.max((actual[i * n + j] - expected[i * n + j]).abs() / scale);
+            }
+        }
+    }
+    max
+}
+
+
+// タイルの端数も含む
+const MATMUL_SHAPES: [(usize, usize, usize); 5] = [(1, 1, 1), (16, 16, 16), (37, 50, 23), (64, 129, 65), (3, 1000, 2)];
+
+// f32に丸めるとわかるように，f32より細かい値にする
+fn matmul_values(rng: &mut Rng, m: usize, k: usize, n: usize) -> (Vec<f64>, Vec<f64>) {
+    let a_values = rng.uniform(Shape::D2(m, k), -1.0, 1.0, None).to_vec().iter().map(|&x| x as f64 / 3.0).collect();
+    let b_values = rng.normal(Shape::D2(k, n), 0.0, 1.0, None).to_vec().iter().map(|&x| x as f64 * std::f64::consts::E).collect();
+    (a_values, b_values)
+}
+
+// f64のCPUの行列積と比べて誤差を返す
+// 1要素の誤差は積和1回あたり2^-47程度なので k * 2^-46 を上限にする
+fn check_matmul(a_values: &[f64], b_values: &[f64], m: usize, k: usize, n: usize, fma: bool) -> f64 {
+    let a = RawGdf64::new_init(Shape::D2(m, k), a_values, Some("a"));
+    let b = RawGdf64::new_init(Shape::D2(k, n), b_values, Some("b"));
+    let c = a.matmul_with(&b, fma);
+    if *c.shape() != Shape::D2(m, n) {
+        panic!("matmul_df64 shape mismatch: {}", c.shape().to_string());
+    }
+    let err = max_scaled_error(&c.to_vec(), a_values, b_values, m, k, n);
+    let bound = k as f64 * 2f64.powi(-46);
+    if err > bound {
+        panic!("matmul_df64 {}x{}x{} (fma: {}): error {:.3e} > {:.3e}", m, k, n, fma, err, bound);
+    }
+    err
+}
+
+// 打ち消し合う和: 1 + 2^-40 - 1 はf32では0になる
+fn check_cancellation(fma: bool) {
+    let a = RawGdf64::new_init(Shape::D2(1, 3), &[1.0, 2f64.powi(-40), -1.0], Some("a"));
+    let b = RawGdf64::new_init(Shape::D2(3, 1), &[1.0, 1.0, 1.0], Some("b"));
+    let c = a.matmul_with(&b, fma).to_vec()[0];
+    if c != 2f64.powi(-40) {
+        panic!("matmul_df64 cancellation mismatch (fma: {}): {}", fma, c);
+    }
+}
+
+pub fn run() {
+    println!("fma: {}", supports_fma());
+
+    // f64からの変換はhi + loでf64の48bit分まで戻る
+    let values = [0.0, 1.0, -1.0 / 3.0, std::f64::consts::PI, 1e-20, 123456789.12345679, -2f64.powi(-30)];
+    let a = RawGdf64::new_init(Shape::D2(1, values.len()), &values, Some("a"));
+    for (x, y) in values.iter().zip(a.to_vec()) {
+        if (x - y).abs() > x.abs() * 2f64.powi(-48) {
+            panic!("df64 conversion mismatch: {} -> {}", x, y);
+        }
+    }
+    if a.size() != values.len() * 8 {
+        panic!("df64 size mismatch: {}", a.size());
+    }
+
+    // 行列積。タイルの端数も試す。FMAあり / なしのどちらも
+    let mut rng = Rng::new(64);
+    for (m, k, n) in MATMUL_SHAPES {
+        let (a_values, b_values) = matmul_values(&mut rng, m, k, n);
+        for fma in [true, false] {
+            if fma && !supports_fma() {
+                continue;
+            }
+            let err = check_matmul(&a_values, &b_values, m, k, n, fma);
+            println!("matmul_df64 {}x{}x{} (fma: {}): max error {:.3e}", m, k, n, fma, err);
+        }
+        // f32の行列積と比べる
+        let a32: Vec<f32> = a_values.iter().map(|&x| x as f32).collect();
+        let b32: Vec<f32> = b_values.iter().map(|&x| x as f32).collect();
+        let c32 = RawGf32::new_init(Shape::D2(m, k), &a32, None).matmul(&RawGf32::new_init(Shape::D2(k, n), &b32, None));
+        let err32 = max_scaled_error(&c32.to_vec().iter().map(|&x| x as f64).collect::<Vec<_>>(), &a_values, &b_values, m, k, n);
+        println!("matmul_f32  {}x{}x{}: max error {:.3e}", m, k, n, err32);
+    }
+
+    for fma in [true, false] {
+        if fma && !supports_fma() {
+            continue;
+        }
+        check_cancellation(fma);
+    }
+
+    // 速さ
+    let size = 256;
+    let values: Vec<f64> = (0..size * size).map(|i| (i % 7) as f64 / 7.0).collect();
+    let a = RawGdf64::new_init(Shape::D2(size, size), &values, Some("a"));
+    a.matmul(&a);
+    WgpuServer::wait();
+    let s = std::time::Instant::now();
+    let repeat = 3;
+    for _ in 0..repeat {
+        a.matmul(&a);
+    }
+    WgpuServer::wait();
+    let time = s.elapsed() / repeat;
+    let gflops = 2.0 * (size * size * size) as f64 / time.as_secs_f64() / 1e9;
+    println!("matmul_df64 {}x{}x{}: {:?}, {:.2} GFLOPS", size, size, size, time, gflops);
+    println!("df64 ok");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matmul_structured2::adapter_available;
+
+    fn check_all(fma: bool) {
+        let mut rng = Rng::new(64);
+        for (m, k, n) in MATMUL_SHAPES {
+            let (a_values, b_values) = matmul_values(&mut rng, m, k, n);
+            check_matmul(&a_values, &b_values, m, k, n, fma);
+        }
+        check_cancellation(fma);
+    }
+
+    #[test]
+    fn matmul_fma() {
+        // FMAが1回の丸めでないGPUではTwoProdが成り立たない
+        if !adapter_available() || !supports_fma() {
+            return;
+        }
+        check_all(true);
+    }
+
+    #[test]
+    fn matmul_without_fma() {
+        if !adapter_available() {
+            return;
+        }
+        check_all(false);
+    }
+}
@@ -0,0 +1,224 @@
+use std::cell::Cell;
+use std::fmt;
+
+use crate::matmul_structured2::{RawGf32, Shape};
+
+
+/*
+RawGf32のDisplay / Debug
+読み出して行列の形に並べる。大きいものはnumpyと同じように端だけ出して間を...にする
+    println!("{}", a);      小数点以下precision桁
+    println!("{:.2}", a);   桁数を指定
+    println!("{:?}", a);    f32を丸めずに(読み戻せる桁数で)出す
+    RawGf32 "a" (3, 4) f32
+    [[ 0.0000,  1.0000,  2.0000,  3.0000],
+     [ 4.0000,  5.0000,  6.0000,  7.0000],
+     [ 8.0000,  9.0000, 10.0000, 11.0000]]
+Shape::D3はnumpyと同じく行列を空行で区切って並べる
+絶対値が大きいもの(>= 1e8)や小さいもの(< 1e-4)を含むときは指数表記にする
+*/
+
+#[derive(Clone, Copy, Debug)]
+pub struct PrintOptions {
+    // 小数点以下の桁数
+    pub precision: usize,
+    // 要素数がこれより多いと省略する
+    pub threshold: usize,
+    // 省略するときに出す端の行数，列数
+    pub edge_items: usize,
+}
+impl Default for PrintOptions {
+    fn default() -> Self {
+        Self { precision: 4, threshold: 1000, edge_items: 3 }
+    }
+}
+
+thread_local! {
+    static PRINT_OPTIONS: Cell<PrintOptions> = Cell::new(PrintOptions::default());
+}
+
+// numpy.set_printoptionsと同じ。このスレッドのDisplay / Debugに効く
+pub fn set_print_options(options: PrintOptions) {
+    PRINT_OPTIONS.with(|o| o.set(options));
+}
+
+pub fn print_options() -> PrintOptions {
+    PRINT_OPTIONS.with(|o| o.get())
+}
+
+// 出す行(列)の番号。Noneは...
+fn indices(len: usize, summarize: bool, edge_items: usize) -> Vec<Option<usize>> {
+    if summarize && len > 2 * edge_items {
+        (0..edge_items).map(Some).chain([None]).chain((len - edge_items..len).map(Some)).collect()
+    } else {
+        (0..len).map(Some).collect()
+    }
+}
+
+// numpyと同じくnan, inf, -inf
+fn format_special(x: f32) -> Option<String> {
+    if x.is_nan() {
+        Some("nan".to_string())
+    } else if x.is_infinite() {
+        Some(if x > 0.0 { "inf" } else { "-inf" }.to_string())
+    } else {
+        None
+    }
+}
+
+// precisionがNoneならDebug(丸めない)
+// 3次元は行列をbatch個，空行で区切って並べる
+fn format_tensor(values: &[f32], batch: Option<usize>, rows: usize, cols: usize, precision: Option<usize>, options: &PrintOptions) -> String {
+    let summarize = values.len() > options.threshold;
+    let batch_indices = indices(batch.unwrap_or(1), summarize, options.edge_items);
+    let row_indices = indices(rows, summarize, options.edge_items);
+    let col_indices = indices(cols, summarize, options.edge_items);
+
+    // 出すものだけで表記を決める
+    let mut shown = vec![];
+    for &b in batch_indices.iter().flatten() {
+        for &i in row_indices.iter().flatten() {
+            shown.extend(col_indices.iter().flatten().map(|&j| values[(b * rows + i) * cols + j]));
+        }
+    }
+    let scientific = shown.iter().any(|x| x.is_finite() && *x != 0.0 && !(1e-4..1e8).contains(&x.abs()));
+    let format = |x: f32| -> String {
+        if let Some(s) = format_special(x) {
+            return s;
+        }
+        match (precision, scientific) {
+            (Some(p), false) => format!("{:.*}", p, x),
+            (Some(p), true) => format!("{:.*e}", p, x),
+            (None, false) => format!("{:?}", x),
+            (None, true) => format!("{:e}", x),
+        }
+    };
+    let width = shown.iter().map(|&x| format(x).len()).max().unwrap_or(0);
+
+    // b番目の行列。indentは2行目以降の字下げ
+    let matrix = |b: usize, indent: &str| -> String {
+        let mut out = String::from("[");
+        for (r, row) in row_indices.iter().enumerate() {
+            if r > 0 {
+                out.push_str(",\n");
+                out.push_str(indent);
+            }
+            let Some(i) = row else {
+                out.push_str("...");
+                continue;
+            };
+            let line: Vec<String> = col_indices.iter().map(|col| match col {
+                Some(j) => format!("{:>width$}", format(values[(b * rows + i) * cols + j]), width = width),
+                None => "...".to_string(),
+            }).collect();
+            out.push('[');
+            out.push_str(&line.join(", "));
+            out.push(']');
+        }
+        out.push(']');
+        out
+    };
+
+    if batch.is_none() {
+        return matrix(0, " ");
+    }
+    let blocks: Vec<String> = batch_indices.iter().map(|b| match b {
+        Some(b) => matrix(*b, "  "),
+        None => "...".to_string(),
+    }).collect();
+    format!("[{}]", blocks.join(",\n\n "))
+}
+
+impl RawGf32 {
+    fn header(&self) -> String {
+        let dims = match *self.shape() {
+            Shape::D2(rows, cols) => format!("({}, {})", rows, cols),
+            Shape::D3(batch, rows, cols) => format!("({}, {}, {})", batch, rows, cols),
+        };
+        match self.label() {
+            Some(label) => format!("RawGf32 {:?} {} f32", label, dims),
+            None => format!("RawGf32 {} f32", dims),
+        }
+    }
+
+    fn format_values(&self, precision: Option<usize>) -> String {
+        let (batch, rows, cols) = match *self.shape() {
+            Shape::D2(rows, cols) => (None, rows, cols),
+            Shape::D3(batch, rows, cols) => (Some(batch), rows, cols),
+        };
+        format_tensor(&self.to_vec(), batch, rows, cols, precision, &print_options())
+    }
+}
+
+impl fmt::Display for RawGf32 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f.precision().unwrap_or(print_options().precision);
+        write!(f, "{}\n{}", self.header(), self.format_values(Some(precision)))
+    }
+}
+
+impl fmt::Debug for RawGf32 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\n{}", self.header(), self.format_values(f.precision()))
+    }
+}
+
+
+
+pub fn run() {
+    let values: Vec<f32> = (0..12).map(|i| i as f32).collect();
+    let a = RawGf32::new_init(Shape::D2(3, 4), &values, Some("a"));
+    let expected = "RawGf32 \"a\" (3, 4) f32
+[[ 0.0000,  1.0000,  2.0000,  3.0000],
+ [ 4.0000,  5.0000,  6.0000,  7.0000],
+ [ 8.0000,  9.0000, 10.0000, 11.0000]]";
+    if format!("{}", a) != expected {
+        panic!("Display mismatch:\n{}", a);
+    }
+    let b = RawGf32::new_init(Shape::D2(1, 3), &vec![0.1, -2.5, 1.0 / 3.0], None);
+    if format!("{:.2}", b) != "RawGf32 (1, 3) f32\n[[ 0.10, -2.50,  0.33]]" {
+        panic!("Display with precision mismatch:\n{:.2}", b);
+    }
+    if format!("{:?}", b) != "RawGf32 (1, 3) f32\n[[       0.1,       -2.5, 0.33333334]]" {
+        panic!("Debug mismatch:\n{:?}", b);
+    }
+
+    // 指数表記とnan, inf
+    let c = RawGf32::new_init(Shape::D2(2, 2), &vec![1e10, f32::NAN, -1e-6, f32::NEG_INFINITY], Some("c"));
+    if format!("{:.1}", c) != "RawGf32 \"c\" (2, 2) f32\n[[ 1.0e10,     nan],\n [-1.0e-6,    -inf]]" {
+        panic!("scientific mismatch:\n{:.1}", c);
+    }
+
+    // 大きいものは端だけ
+    let (rows, cols) = (100, 50);
+    let big = RawGf32::new_init(Shape::D2(rows, cols), &(0..rows * cols).map(|i| i as f32).collect(), Some("big"));
+    let s = format!("{:.0}", big);
+    let lines: Vec<&str> = s.lines().collect();
+    // header + 3行 + ... + 3行
+    if lines.len() != 8 || lines[4] != " ...," || lines[1] != "[[   0,    1,    2, ...,   47,   48,   49]," || lines[7] != " [4950, 4951, 4952, ..., 4997, 4998, 4999]]" {
+        panic!("summarization mismatch:\n{}", s);
+    }
+    // 省略しない
+    set_print_options(PrintOptions { threshold: usize::MAX, ..PrintOptions::default() });
+    let full = format!("{:.0}", big);
+    set_print_options(PrintOptions::default());
+    if full.lines().count() != rows + 1 || full.contains("...") {
+        panic!("threshold mismatch");
+    }
+
+    // 3次元
+    let t = RawGf32::new_init(Shape::D3(2, 2, 3), &(0..12).map(|i| i as f32).collect(), Some("t"));
+    let expected = "RawGf32 \"t\" (2, 2, 3) f32
+[[[ 0,  1,  2],
+  [ 3,  4,  5]],
+
+ [[ 6,  7,  8],
+  [ 9, 10, 11]]]";
+    if format!("{:.0}", t) != expected {
+        panic!("3-D mismatch:\n{:.0}", t);
+    }
+    println!("{}", a);
+    println!("{}", big);
+    println!("{:.0}", t);
+    println!("display ok");
+}
@@ -0,0 +1,298 @@
+use crate::matmul_structured2::{Binding, RawGf32, Shape, WgpuServer};
+use crate::wgsl_template::{TemplateParams, WgslTemplate};
+
+
+/*
+要素ごとの演算と和
+elementwise.wgslの{{EXPR}}を演算ごとに埋めるので，演算ごとに別のpipelineになる
+autogradの逆伝播(勾配の足し込み)もこれを使う
+
+演算は3種類の形がある
+    a.add(&b) -> c            新しく作る
+    a.add_into(&b, &mut c)    cに書く（cを使い回す）
+    a.add_assign(&b)          aをその場で書き換える
+&mut selfや&mut outは他の引数と同じテンソルにならないので，同じbufferを読み書きすることはない
+要素ごとの演算はi番目を読んでi番目に書くだけなので，その場で書き換えるときはoutput[i]を読む式(Axpyなど)にする
+crate内でbufferを直接渡す場合はexecute_n(check_aliasing)で検出する
+*/
+
+pub(crate) const ELEMENTWISE: WgslTemplate = WgslTemplate::new("elementwise.wgsl", include_str!("./elementwise.wgsl"));
+pub(crate) const REDUCE: (&str, &str) = ("reduce.wgsl", include_str!("./reduce.wgsl"));
+
+const WG: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Map {
+    // a + b
+    Add,
+    // a - b
+    Sub,
+    // a * b
+    Mul,
+    // alpha * a
+    Scale,
+    // output + alpha * a
+    Axpy,
+    // output + alpha * a[0]。スカラーを全要素に足す
+    AxpyScalar,
+    // output + alpha * a * b
+    MulAcc,
+    // alpha
+    Fill,
+    // alpha * output。その場でscaleする
+    ScaleInPlace,
+    // alpha + beta * i。arange, linspace
+    Iota,
+    // 対角成分(行 == 列)がalpha，それ以外0。eye
+    Eye,
+    // 対角成分がa[行]，それ以外0。aはベクトル
+    Diag,
+    // 活性化関数 f(a)
+    Relu,
+    Gelu,
+    Tanh,
+    // 活性化関数の逆伝播 output + a * f'(b)。aは勾配，bは活性化前の値
+    ReluGrad,
+    GeluGrad,
+    TanhGrad,
+}
+impl Map {
+    // shader_checkで全部検証する
+    pub(crate) const ALL: &'static [Map] = &[
+        Map::Add, Map::Sub, Map::Mul, Map::Scale, Map::Axpy, Map::AxpyScalar, Map::MulAcc, Map::Fill, Map::ScaleInPlace,
+        Map::Iota, Map::Eye, Map::Diag,
+        Map::Relu, Map::Gelu, Map::Tanh, Map::ReluGrad, Map::GeluGrad, Map::TanhGrad,
+    ];
+
+    pub(crate) fn expr(&self) -> &'static str {
+        match self {
+            Map::Add => "a[i] + b[i]",
+            Map::Sub => "a[i] - b[i]",
+            Map::Mul => "a[i] * b[i]",
+            Map::Scale => "params.alpha * a[i]",
+            Map::Axpy => "output[i] + params.alpha * a[i]",
+            Map::AxpyScalar => "output[i] + params.alpha * a[0]",
+            Map::MulAcc => "output[i] + params.alpha * a[i] * b[i]",
+            Map::Fill => "params.alpha",
+            Map::ScaleInPlace => "params.alpha * output[i]",
+            Map::Iota => "params.alpha + params.beta * f32(i)",
+            Map::Eye => "select(0.0, params.alpha, i / params.cols == i % params.cols)",
+            Map::Diag => "select(0.0, a[i / params.cols], i / params.cols == i % params.cols)",
+            Map::Relu => "max(a[i], 0.0)",
+            Map::Gelu => "gelu(a[i])",
+            Map::Tanh => "tanh_safe(a[i])",
+            Map::ReluGrad => "output[i] + select(0.0, a[i], b[i] > 0.0)",
+            Map::GeluGrad => "output[i] + a[i] * gelu_grad(b[i])",
+            Map::TanhGrad => "output[i] + a[i] * tanh_grad(b[i])",
+        }
+    }
+
+    pub(crate) fn template_params(&self) -> TemplateParams {
+        TemplateParams::new().set("EXPR", self.expr())
+    }
+}
+
+thread_local! {
+    // (len, alpha, beta, cols)のuniform。softmax.rsのPARAMSと同じくスレッドごとに1つを使い回す
+    // uniformは16Byte単位
+    static PARAMS: wgpu::Buffer = WgpuServer::create_uniform_buffer(16, Some("elementwise params"));
+    // map_outでaとbの場所につなぐもの
+    // outをread_writeとreadの両方につなぐことはできない(wgpuの検証とcheck_aliasingで弾かれる)ので，小さいbufferを使い回す
+    static UNUSED_INPUT: RawGf32 = RawGf32::_new_empty(Shape::D2(1, 1), Some("unused input"));
+}
+
+// (len, alpha, beta, cols)を書き込んだuniformでfを呼ぶ
+fn with_params<R>(len: usize, alpha: f32, beta: f32, cols: usize, f: impl FnOnce(&wgpu::Buffer) -> R) -> R {
+    PARAMS.with(|params| {
+        let words = [len as u32, alpha.to_bits(), beta.to_bits(), cols as u32];
+        WgpuServer::write_buffer(params, 0, bytemuck::cast_slice(&words));
+        f(params)
+    })
+}
+
+// 1スレッド1要素。dispatchの上限を超える要素数はyにも分ける
+pub(crate) fn dispatch(len: usize) -> (u32, u32, u32) {
+    let max = WgpuServer::limits().max_compute_workgroups_per_dimension as usize;
+    let groups = len.div_ceil(WG).max(1);
+    let x = groups.min(max);
+    (x as u32, groups.div_ceil(x) as u32, 1)
+}
+
+// out[i] = map(a[i], b[i], out[i])
+// aとbは読むだけなので同じものでもよい。outはa, bと別のbufferであること(execute_nで確認する)
+pub(crate) fn map(map: Map, a: &RawGf32, b: &RawGf32, out: &RawGf32, alpha: f32) {
+    map_with(map, a, b, out, alpha, 0.0);
+}
+
+// betaとcolsも使う演算(Iota, Eye, Diag)用。colsはoutの列数
+pub(crate) fn map_with(map: Map, a: &RawGf32, b: &RawGf32, out: &RawGf32, alpha: f32, beta: f32) {
+    let len = out.shape().size();
+    let cols = out.shape().cols();
+    let template_params = map.template_params();
+    let shader_str = ELEMENTWISE.instantiate(&template_params).unwrap_or_else(|e| panic!("{}", e));
+    with_params(len, alpha, beta, cols, |params| {
+        let bindings = [
+            Binding::read(a.buffer()),
+            Binding::read(b.buffer()),
+            Binding::read_write(out.buffer()),
+            Binding::uniform(params),
+        ];
+        WgpuServer::execute_n(map.expr(), &bindings, &[], &ELEMENTWISE.instance_name(&template_params), &shader_str, dispatch(len));
+    });
+}
+
+// 入力を使わない演算(Fill, ScaleInPlace, Iota, Eye)用
+pub(crate) fn map_out(op: Map, out: &RawGf32, alpha: f32, beta: f32) {
+    UNUSED_INPUT.with(|unused| map_with(op, unused, unused, out, alpha, beta));
+}
+
+// out[i] = value
+pub(crate) fn fill(out: &RawGf32, value: f32) {
+    map_out(Map::Fill, out, value, 0.0);
+}
+
+impl RawGf32 {
+    fn check_same_shape(&self, other: &Self, op: &str) {
+        if self.shape() != other.shape() {
+            panic!("{}: size unmatch, self.shape: {}, other.shape: {}", op, self.shape().to_string(), other.shape().to_string());
+        }
+    }
+
+    // 要素ごとの積
+    pub fn mul(&self, other: &Self) -> Self {
+        let mut out = Self::_new_empty(self.shape().clone(), Some("mul out"));
+        self.mul_into(other, &mut out);
+        out
+    }
+
+    pub fn scale(&self, alpha: f32) -> Self {
+        let mut out = Self::_new_empty(self.shape().clone(), Some("scale out"));
+        self.scale_into(alpha, &mut out);
+        out
+    }
+
+    // out = self + other
+    pub fn add_into(&self, other: &Self, out: &mut Self) {
+        self.check_same_shape(other, "add");
+        self.check_same_shape(out, "add (out)");
+        map(Map::Add, self, other, out, 1.0);
+    }
+
+    // out = self - other
+    pub fn sub_into(&self, other: &Self, out: &mut Self) {
+        self.check_same_shape(other, "sub");
+        self.check_same_shape(out, "sub (out)");
+        map(Map::Sub, self, other, out, 1.0);
+    }
+
+    // out = self * other (要素ごと)
+    pub fn mul_into(&self, other: &Self, out: &mut Self) {
+        self.check_same_shape(other, "mul");
+        self.check_same_shape(out, "mul (out)");
+        map(Map::Mul, self, other, out, 1.0);
+    }
+
+    // out = alpha * self
+    pub fn scale_into(&self, alpha: f32, out: &mut Self) {
+        self.check_same_shape(out, "scale (out)");
+        map(Map::Scale, self, self, out, alpha);
+    }
+
+    // self += other
+    pub fn add_assign(&mut self, other: &Self) {
+        self.check_same_shape(other, "add_assign");
+        map(Map::Axpy, other, other, self, 1.0);
+    }
+
+    // self -= other
+    pub fn sub_assign(&mut self, other: &Self) {
+        self.check_same_shape(other, "sub_assign");
+        map(Map::Axpy, other, other, self, -1.0);
+    }
+
+    // self *= alpha
+    pub fn scale_(&mut self, alpha: f32) {
+        map_out(Map::ScaleInPlace, self, alpha, 0.0);
+    }
+
+    // 全要素をvalueにする
+    pub fn fill_(&mut self, value: f32) {
+        fill(self, value);
+    }
+
+    // Shape::D2(1, 1)
+    pub fn sum(&self) -> Self {
+        self.reduce(1.0, "sum")
+    }
+
+    // Shape::D2(1, 1)
+    pub fn mean(&self) -> Self {
+        self.reduce(1.0 / self.shape().size() as f32, "mean")
+    }
+
+    fn reduce(&self, alpha: f32, label: &str) -> Self {
+        let out = Self::_new_empty(Shape::D2(1, 1), Some(label));
+        with_params(self.shape().size(), alpha, 0.0, 0, |params| {
+            let bindings = [Binding::read(self.buffer()), Binding::read_write(out.buffer()), Binding::uniform(params)];
+            let (shader_name, shader_str) = REDUCE;
+            WgpuServer::execute_n(label, &bindings, &[], shader_name, shader_str, (1, 1, 1));
+        });
+        out
+    }
+}
+
+
+
+pub fn run() {
+    let (rows, cols) = (5, 7);
+    let a_values: Vec<f32> = (0..rows * cols).map(|i| i as f32 * 0.5 - 4.0).collect();
+    let b_values: Vec<f32> = (0..rows * cols).map(|i| (i % 4) as f32).collect();
+    let a = RawGf32::new_init(Shape::D2(rows, cols), &a_values, Some("a"));
+    let b = RawGf32::new_init(Shape::D2(rows, cols), &b_values, Some("b"));
+    let zip = |f: &dyn Fn(f32, f32) -> f32| -> Vec<f32> { a_values.iter().zip(&b_values).map(|(&x, &y)| f(x, y)).collect() };
+
+    // 新しく作る / outに書く / その場で書き換える
+    if a.add(&b).to_vec() != zip(&|x, y| x + y) || a.sub(&b).to_vec() != zip(&|x, y| x - y) {
+        panic!("add / sub mismatch");
+    }
+    let mut out = RawGf32::_new_empty(Shape::D2(rows, cols), Some("out"));
+    a.mul_into(&b, &mut out);
+    if out.to_vec() != zip(&|x, y| x * y) {
+        panic!("mul_into mismatch");
+    }
+    // 同じoutを使い回す
+    a.scale_into(3.0, &mut out);
+    if out.to_vec() != zip(&|x, _| 3.0 * x) {
+        panic!("scale_into mismatch");
+    }
+    let mut c = a.add(&b);
+    c.sub_assign(&b);
+    c.add_assign(&a);
+    c.scale_(0.5);
+    if c.to_vec() != a_values {
+        panic!("in-place ops mismatch: {:?}", c.to_vec());
+    }
+    c.fill_(-1.5);
+    if c.to_vec() != vec![-1.5; rows * cols] {
+        panic!("fill_ mismatch");
+    }
+    let mut m = RawGf32::_new_empty(Shape::D2(rows, rows), Some("m"));
+    a.matmul_into(&RawGf32::new_init(Shape::D2(cols, rows), &b_values, Some("b2")), &mut m);
+    let expected: Vec<f32> = (0..rows * rows)
+        .map(|i| (0..cols).map(|l| a_values[(i / rows) * cols + l] * b_values[l * rows + i % rows]).sum())
+        .collect();
+    if m.to_vec() != expected {
+        panic!("matmul_into mismatch");
+    }
+
+    // 同じbufferを読み書きするbindingはpanicする
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| map(Map::Add, &a, &b, &a, 1.0)));
+    std::panic::set_hook(hook);
+    match result {
+        Err(e) if e.downcast_ref::<String>().is_some_and(|m| m.contains("alias")) => {}
+        _ => panic!("aliasing must be detected"),
+    }
+    println!("elementwise ok");
+}
@@ -0,0 +1,60 @@
+// 要素ごとの演算 output[i] = EXPR
+// 使わない入力には同じbufferを渡す
+@group(0) @binding(0)
+var<storage, read> a: array<f32>;
+@group(0) @binding(1)
+var<storage, read> b: array<f32>;
+@group(0) @binding(2)
+var<storage, read_write> output: array<f32>;
+// メタデータ
+struct ElementwiseParams {
+    len: u32,
+    alpha: f32,
+    beta: f32,
+    // 行列として見たときの列数(Eye, Diagで使う)
+    cols: u32,
+}
+@group(0) @binding(3)
+var<uniform> params: ElementwiseParams;
+
+
+/*
+
+EXPRはRust側(elementwise::Map)からテンプレートとして埋める
+EXPRの中ではa[i], b[i], output[i], params.alpha, params.beta, params.colsと下の関数が使える
+    例: output[i] + params.alpha * a[i]
+添字iそのものを使う式(Iota, Eye)で初期化もする
+
+*/
+
+// 活性化関数(6vectorize.wgslのepilogueと同じ)とその微分
+fn tanh_safe(x: f32) -> f32 {
+    // 大きい値でNaNになる実装があるのでclampする
+    return tanh(clamp(x, -15.0, 15.0));
+}
+fn tanh_grad(x: f32) -> f32 {
+    let t = tanh_safe(x);
+    return 1.0 - t * t;
+}
+fn gelu(x: f32) -> f32 {
+    return 0.5 * x * (1.0 + tanh_safe(0.7978845608 * (x + 0.044715 * x * x * x)));
+}
+fn gelu_grad(x: f32) -> f32 {
+    let t = tanh_safe(0.7978845608 * (x + 0.044715 * x * x * x));
+    return 0.5 * (1.0 + t) + 0.5 * x * (1.0 - t * t) * 0.7978845608 * (1.0 + 3.0 * 0.044715 * x * x);
+}
+
+const WG: u32 = 64u;
+
+@compute @workgroup_size(64, 1, 1)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>
+) {
+    // 要素数がdispatchの上限を超えるときはyにも分ける
+    let i = global_id.x + global_id.y * num_workgroups.x * WG;
+    if (i >= params.len) {
+        return;
+    }
+    output[i] = {{EXPR}};
+}
@@ -0,0 +1,202 @@
+use crate::matmul_structured2::{RawGf32, Shape};
+use crate::wgsl_template::TemplateParams;
+
+
+/*
+行列積の後処理(epilogue)をカーネルに埋め込む
+    out = activation(scale * lhs * rhs + bias) + residual
+matmulのあとにadd, reluと別々のカーネルを呼ぶとoutputを何度も読み書きするので，書き込む直前にまとめて計算する
+
+どの処理をするかはテンプレート({{BIAS}}, {{RESIDUAL}}, {{EPILOGUE}})で決めるので，組み合わせごとに別のpipelineになる
+biasとresidualはMatmulParamsの次のbindingから順に割り当てる
+    uniformのとき: binding(3)がMatmulParams，binding(4)から
+    push constantsのとき: binding(3)から
+*/
+
+// biasとresidualの最初のbinding
+pub(crate) fn extra_binding(push_constants: bool) -> u32 {
+    if push_constants { 3 } else { 4 }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Activation {
+    #[default]
+    None,
+    Relu,
+    // tanh近似
+    Gelu,
+    Tanh,
+}
+impl Activation {
+    // 6vectorize.wgsl, matmul.wgslに定義してある関数を呼ぶ
+    fn wgsl(&self) -> &'static str {
+        match self {
+            Activation::None => "",
+            Activation::Relu => "value = max(value, 0.0);",
+            Activation::Gelu => "value = gelu(value);",
+            Activation::Tanh => "value = tanh_safe(value);",
+        }
+    }
+
+    // CPUでの確認用
+    pub fn apply(&self, x: f32) -> f32 {
+        match self {
+            Activation::None => x,
+            Activation::Relu => x.max(0.0),
+            Activation::Gelu => 0.5 * x * (1.0 + (0.797_884_6 * (x + 0.044715 * x * x * x)).clamp(-15.0, 15.0).tanh()),
+            Activation::Tanh => x.clamp(-15.0, 15.0).tanh(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Epilogue<'a> {
+    // Shape::D2(1, N)。列ごとに足す
+    pub bias: Option<&'a RawGf32>,
+    pub activation: Activation,
+    // Shape::D2(M, N)。activationの後に足す
+    pub residual: Option<&'a RawGf32>,
+    // MatmulParams::alphaになる
+    pub scale: f32,
+}
+impl Default for Epilogue<'_> {
+    fn default() -> Self {
+        Self { bias: None, activation: Activation::None, residual: None, scale: 1.0 }
+    }
+}
+impl<'a> Epilogue<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn bias(self, bias: &'a RawGf32) -> Self {
+        Self { bias: Some(bias), ..self }
+    }
+    pub fn activation(self, activation: Activation) -> Self {
+        Self { activation, ..self }
+    }
+    pub fn residual(self, residual: &'a RawGf32) -> Self {
+        Self { residual: Some(residual), ..self }
+    }
+    pub fn scale(self, scale: f32) -> Self {
+        Self { scale, ..self }
+    }
+
+    // 何もしない（GEMVのカーネルはepilogueに対応していない）
+    pub fn is_identity(&self) -> bool {
+        self.bias.is_none() && self.activation == Activation::None && self.residual.is_none()
+    }
+
+    // out.shapeが(M, N)のときに使えるか
+    pub(crate) fn check(&self, m: usize, n: usize) {
+        if let Some(bias) = self.bias {
+            if bias.shape().size() != n {
+                panic!("epilogue bias must have {} elements, but {}", n, bias.shape().to_string());
+            }
+        }
+        if let Some(residual) = self.residual {
+            if residual.shape() != &Shape::D2(m, n) {
+                panic!("epilogue residual must be {}, but {}", Shape::D2(m, n).to_string(), residual.shape().to_string());
+            }
+        }
+    }
+
+    // extra_binding()から順に渡すbuffer
+    pub(crate) fn buffers(&self) -> Vec<&'a wgpu::Buffer> {
+        self.bias.iter().chain(self.residual.iter()).map(|t| t.buffer()).collect()
+    }
+
+    // {{BIAS}}, {{RESIDUAL}}, {{EPILOGUE}}を埋める
+    pub(crate) fn template_params(&self, params: TemplateParams, push_constants: bool) -> TemplateParams {
+        template_params(params, push_constants, self.bias.is_some(), self.activation, self.residual.is_some())
+    }
+}
+
+// bufferなしで埋める（shader_checkはGPUなしで検証するため）
+// カーネル側ではvalue, row, col, params.nが使える
+pub(crate) fn template_params(params: TemplateParams, push_constants: bool, bias: bool, activation: Activation, residual: bool) -> TemplateParams {
+    let mut binding = extra_binding(push_constants);
+    let mut code = vec![];
+    let bias = if bias {
+        code.push("value += bias[col];");
+        binding += 1;
+        format!("@group(0) @binding({}) var<storage, read> bias: array<f32>;", binding - 1)
+    } else {
+        String::new()
+    };
+    code.push(activation.wgsl());
+    let residual = if residual {
+        code.push("value += residual[row * params.n + col];");
+        format!("@group(0) @binding({}) var<storage, read> residual: array<f32>;", binding)
+    } else {
+        String::new()
+    };
+    params
+        .set("BIAS", bias)
+        .set("RESIDUAL", residual)
+        .set("EPILOGUE", code.join(" ").trim())
+}
+
+impl RawGf32 {
+    // activation(scale * self * other + bias) + residual
+    pub fn matmul_fused(&self, other: &Self, epilogue: &Epilogue) -> Self {
+        let (m, _, n) = self.matmul_sizes(other);
+        let mut result = Self::_new_empty(Shape::D2(m, n), Some("fused result"));
+        self.gemm_fused(other, epilogue.scale, 0.0, epilogue, &mut result);
+        result
+    }
+}
+
+
+
+pub fn run() {
+    let (m, k, n) = (64, 32, 64);
+    let a_values: Vec<f32> = (0..m * k).map(|i| ((i % 7) as f32 - 3.0) * 0.25).collect();
+    let b_values: Vec<f32> = (0..k * n).map(|i| ((i % 5) as f32 - 2.0) * 0.5).collect();
+    let bias_values: Vec<f32> = (0..n).map(|i| i as f32 * 0.1 - 3.0).collect();
+    let residual_values: Vec<f32> = (0..m * n).map(|i| (i % 3) as f32).collect();
+    let a = RawGf32::new_init(Shape::D2(m, k), &a_values, Some("a"));
+    let b = RawGf32::new_init(Shape::D2(k, n), &b_values, Some("b"));
+    let bias = RawGf32::new_init(Shape::D2(1, n), &bias_values, Some("bias"));
+    let residual = RawGf32::new_init(Shape::D2(m, n), &residual_values, Some("residual"));
+
+    let mut ab = vec![0.0; m * n];
+    for i in 0..m {
+        for l in 0..k {
+            for j in 0..n {
+                ab[i * n + j] += a_values[i * k + l] * b_values[l * n + j];
+            }
+        }
+    }
+
+    for activation in [Activation::None, Activation::Relu, Activation::Gelu, Activation::Tanh] {
+        let scale = 0.5;
+        let epilogue = Epilogue::new().bias(&bias).activation(activation).residual(&residual).scale(scale);
+        let expected: Vec<f32> = (0..m * n)
+            .map(|i| activation.apply(scale * ab[i] + bias_values[i % n]) + residual_values[i])
+            .collect();
+
+        // タイル化カーネルとナイーブなカーネル(端数のある大きさ)
+        let tiled = a.matmul_fused(&b, &epilogue).to_vec();
+        let max_err = tiled.iter().zip(expected.iter()).map(|(x, y)| (x - y).abs()).fold(0.0, f32::max);
+        if max_err > 1e-4 {
+            panic!("fused matmul ({:?}) mismatch, max error = {}", activation, max_err);
+        }
+
+        let a2 = RawGf32::new_init(Shape::D2(3, k), &a_values[..3 * k].to_vec(), Some("a2"));
+        let residual2 = RawGf32::new_init(Shape::D2(3, n), &residual_values[..3 * n].to_vec(), Some("residual2"));
+        let epilogue2 = Epilogue { residual: Some(&residual2), ..epilogue };
+        let naive = a2.matmul_fused(&b, &epilogue2).to_vec();
+        let max_err = naive.iter().zip(expected.iter()).map(|(x, y)| (x - y).abs()).fold(0.0, f32::max);
+        if max_err > 1e-4 {
+            panic!("fused naive matmul ({:?}) mismatch, max error = {}", activation, max_err);
+        }
+    }
+
+    // biasだけ
+    let result = a.matmul_fused(&b, &Epilogue::new().bias(&bias)).to_vec();
+    let expected: Vec<f32> = (0..m * n).map(|i| ab[i] + bias_values[i % n]).collect();
+    if result != expected {
+        panic!("fused bias mismatch");
+    }
+    println!("epilogue ok");
+}
@@ -0,0 +1,242 @@
+use crate::compare::{self, Tolerance};
+use crate::elementwise;
+use crate::matmul_structured2::{Binding, RawGf32, Shape, WgpuServer};
+use crate::random::Rng;
+use crate::wgsl_template::{TemplateParams, WgslTemplate};
+
+
+/*
+f16で持つテンソルと，f16を読んでf32で積和する行列積
+    let a16 = a.to_f16();                 // f32 -> f16 (GPU)
+    let c = a16.matmul(&b.to_f16());      // 結果はRawGf32
+    let a32 = a16.to_f32();
+bufferはf16を2個ずつu32に詰める(pack2x16float)。要素iはi / 2番目のu32の(i % 2)番目で，array<f16>と同じ並び
+大きい行列では読むバイト数が半分になる。精度は入力をf16に丸めた分だけ落ちる(相対2^-11)
+f16の範囲(±65504)を超える値はto_f16(GPU)では不定(WGSLの仕様)。new_init(CPU)はinfにする
+DEVICEはadapterがSHADER_F16に対応していれば有効にする(supports_shader_f16)
+ただしwgpu 0.17のnaga(0.13)はWGSLのf16を読めないので，今はどちらでもpack2x16floatのカーネル(matmul_f16.wgsl)を使う
+並びが同じなのでnagaを上げたらarray<f16>で読むカーネルを足せばよい
+*/
+
+pub(crate) const CONVERT: WgslTemplate = WgslTemplate::new("f16.wgsl", include_str!("./f16.wgsl"));
+pub(crate) const MATMUL_F16: (&str, &str) = ("matmul_f16.wgsl", include_str!("./matmul_f16.wgsl"));
+
+// matmul_f16.wgslのTILE
+const TILE: usize = 16;
+
+// shader_checkで全部検証する
+pub(crate) fn convert_template_params() -> Vec<TemplateParams> {
+    vec![TemplateParams::new().set("PACK", true), TemplateParams::new().set("PACK", false)]
+}
+
+pub struct RawGf16 {
+    label: Option<String>,
+    shape: Shape,
+    // f16が2個ずつ。奇数個なら最後の上位16bitは0
+    buffer: wgpu::Buffer,
+}
+impl RawGf16 {
+    fn words(shape: &Shape) -> usize {
+        shape.size().div_ceil(2)
+    }
+
+    fn _new_empty(shape: Shape, label: Option<&str>) -> Self {
+        let buffer = WgpuServer::create_buffer(Self::words(&shape) * 4, label);
+        Self {
+            label: label.map(|str| str.to_string()),
+            shape,
+            buffer,
+        }
+    }
+
+    // CPUでf16に丸めて送る(round to nearest even)
+    pub fn new_init(shape: Shape, values: &[f32], label: Option<&str>) -> Self {
+        if values.len() != shape.size() {
+            panic!("RawGf16::new_init: {} values for shape {}", values.len(), shape.to_string());
+        }
+        let words = pack_cpu(values);
+        let buffer = WgpuServer::create_buffer_init(&words, label);
+        Self {
+            label: label.map(|str| str.to_string()),
+            shape,
+            buffer,
+        }
+    }
+
+    pub fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    // bufferのバイト数。同じshapeのRawGf32の約半分
+    pub fn size(&self) -> usize {
+        Self::words(&self.shape) * 4
+    }
+
+    pub(crate) fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    // CPU側に読み出してf32にする（row-major）
+    pub fn to_vec(&self) -> Vec<f32> {
+        let mut values = unpack_cpu(&WgpuServer::get_as::<u32>(&self.buffer));
+        values.truncate(self.shape.size());
+        values
+    }
+
+    pub fn to_f32(&self) -> RawGf32 {
+        let out = RawGf32::_new_empty(self.shape.clone(), self.label());
+        convert(false, &self.buffer, out.buffer(), self.shape.size());
+        out
+    }
+
+    // f16 x f16 -> f32。積和はf32
+    pub fn matmul(&self, other: &Self) -> RawGf32 {
+        let (m, k, n) = match (&self.shape, &other.shape) {
+            (Shape::D2(m, k), Shape::D2(k2, n)) if k == k2 => (*m, *k, *n),
+            _ => panic!("incompatible matrix size, self.shape: {}, other.shape: {}", self.shape.to_string(), other.shape.to_string()),
+        };
+        let result = RawGf32::_new_empty(Shape::D2(m, n), Some("result"));
+
+        let dispatch = (n.div_ceil(TILE), m.div_ceil(TILE));
+        let max = WgpuServer::limits().max_compute_workgroups_per_dimension as usize;
+        if dispatch.0 > max || dispatch.1 > max {
+            panic!("matmul_f16: {}x{} is too large for dispatch (max {} workgroups per dimension)", m, n, max);
+        }
+        // uniformは16Byte単位
+        let params = WgpuServer::create_uniform_buffer(16, Some("matmul_f16 params"));
+        WgpuServer::write_buffer(&params, 0, bytemuck::cast_slice(&[m as u32, k as u32, n as u32, 0]));
+        let bindings = [Binding::read(&self.buffer), Binding::read(&other.buffer), Binding::read_write(result.buffer()), Binding::uniform(&params)];
+        let (shader_name, shader_str) = MATMUL_F16;
+        WgpuServer::execute_n("matmul_f16", &bindings, &[], shader_name, shader_str, (dispatch.0 as u32, dispatch.1 as u32, 1));
+        result
+    }
+}
+
+impl RawGf32 {
+    // GPUでf16に丸める(pack2x16float)
+    pub fn to_f16(&self) -> RawGf16 {
+        let out = RawGf16::_new_empty(self.shape().clone(), self.label());
+        convert(true, self.buffer(), out.buffer(), self.shape().size());
+        out
+    }
+}
+
+// len: 要素数
+fn convert(pack: bool, input: &wgpu::Buffer, output: &wgpu::Buffer, len: usize) {
+    if len > u32::MAX as usize {
+        panic!("f16 convert: too many elements {}", len);
+    }
+    let params = WgpuServer::create_uniform_buffer(16, Some("f16 convert params"));
+    WgpuServer::write_buffer(&params, 0, bytemuck::cast_slice(&[len as u32, 0, 0, 0]));
+    let template_params = TemplateParams::new().set("PACK", pack);
+    let shader_str = CONVERT.instantiate(&template_params).unwrap_or_else(|e| panic!("{}", e));
+    let bindings = [Binding::read(input), Binding::read_write(output), Binding::uniform(&params)];
+    // packは1スレッドでu32を1個
+    let dispatch = elementwise::dispatch(if pack { len.div_ceil(2) } else { len });
+    WgpuServer::execute_n("f16 convert", &bindings, &[], &CONVERT.instance_name(&template_params), &shader_str, dispatch);
+}
+
+fn pack_cpu(values: &[f32]) -> Vec<u32> {
+    values.chunks(2).map(|pair| {
+        let lo = half::f16::from_f32(pair[0]).to_bits() as u32;
+        let hi = pair.get(1).map_or(0, |&x| half::f16::from_f32(x).to_bits() as u32);
+        lo | hi << 16
+    }).collect()
+}
+
+fn unpack_cpu(words: &[u32]) -> Vec<f32> {
+    words.iter().flat_map(|&w| [half::f16::from_bits(w as u16).to_f32(), half::f16::from_bits((w >> 16) as u16).to_f32()]).collect()
+}
+
+// f16に丸めてf32に戻す
+fn round_f16(values: &[f32]) -> Vec<f32> {
+    values.iter().map(|&x| half::f16::from_f32(x).to_f32()).collect()
+}
+
+fn matmul_cpu(a: &[f32], b: &[f32], m: usize, k: usize, n: usize) -> Vec<f32> {
+    let mut c = vec![0.0; m * n];
+    for i in 0..m {
+        for j in 0..n {
+            c[i * n + j] = (0..k).map(|l| a[i * k + l] as f64 * b[l * n + j] as f64).sum::<f64>() as f32;
+        }
+    }
+    c
+}
+
+
+
+pub fn run() {
+    println!("SHADER_F16: {}", WgpuServer::supports_shader_f16());
+
+    // f16で表せる値はそのまま，表せない値は最近接偶数丸め。奇数個も試す
+    // f16の範囲外とf16の非正規化数はpack2x16floatでは不定(WGSLの仕様)なのでCPU(new_init)だけ
+    let values: Vec<f32> = vec![0.0, -0.0, 1.0, -2.5, 0.1, 1.0 / 3.0, 65504.0, -6.1035156e-5, 2049.0, 2051.0, 1e-3, 12345.678, -0.7, 3.0, 1e4];
+    let a = RawGf32::new_init(Shape::D2(3, 5), &values, Some("a"));
+    let a16 = a.to_f16();
+    if a16.size() != 8 * 4 {
+        panic!("f16 size mismatch: {}", a16.size());
+    }
+    let expected = round_f16(&values);
+    let gpu = a16.to_vec();
+    for i in 0..values.len() {
+        if gpu[i].to_bits() != expected[i].to_bits() {
+            panic!("f16 rounding mismatch at {}: {} -> {}, expected {}", i, values[i], gpu[i], expected[i]);
+        }
+    }
+    let special = vec![1e5, -1e5, 6e-8, 1e-10, f32::INFINITY, f32::NEG_INFINITY, 65520.0];
+    let cpu = RawGf16::new_init(Shape::D2(1, 7), &special, None).to_vec();
+    if cpu != vec![f32::INFINITY, f32::NEG_INFINITY, 5.9604645e-8, 0.0, f32::INFINITY, f32::NEG_INFINITY, f32::INFINITY] {
+        panic!("f16 new_init mismatch: {:?}", cpu);
+    }
+    if RawGf16::new_init(Shape::D2(3, 5), &values, None).to_vec() != expected {
+        panic!("f16 new_init mismatch");
+    }
+    a16.to_f32().compare(&expected, &Tolerance::exact()).check("f16 to_f32", &Shape::D2(3, 5));
+    let nan = RawGf16::new_init(Shape::D2(1, 1), &[f32::NAN], None).to_vec();
+    if !nan[0].is_nan() {
+        panic!("f16 nan mismatch: {}", nan[0]);
+    }
+
+    // 行列積。タイルの端数も試す
+    let mut rng = Rng::new(16);
+    for (m, k, n) in [(1, 1, 1), (16, 16, 16), (37, 50, 23), (64, 129, 65), (3, 300, 2)] {
+        let a = rng.uniform(Shape::D2(m, k), -1.0, 1.0, Some("a"));
+        let b = rng.uniform(Shape::D2(k, n), -1.0, 1.0, Some("b"));
+        let (a_values, b_values) = (a.to_vec(), b.to_vec());
+        let c = a.to_f16().matmul(&b.to_f16());
+        // f16に丸めた入力に対してはf32の積和の誤差だけ
+        let rounded = matmul_cpu(&round_f16(&a_values), &round_f16(&b_values), m, k, n);
+        let tol = Tolerance::new(1e-5, 2.0 * k as f64 * f32::EPSILON as f64);
+        c.compare(&rounded, &tol).check("matmul_f16", c.shape());
+        // f32の入力に対しては丸めの分(積ごとに相対2^-10まで)
+        let exact = matmul_cpu(&a_values, &b_values, m, k, n);
+        let report = c.compare(&exact, &Tolerance::new(0.0, k as f64 * 2f64.powi(-10)));
+        report.check("matmul_f16 vs f32", c.shape());
+        println!("matmul_f16 {}x{}x{}: max_abs_err vs f32 = {:.3e}", m, k, n, report.max_abs_err);
+    }
+
+    // 読むバイト数と速さ
+    let size = 512;
+    let a = rng.uniform(Shape::D2(size, size), -1.0, 1.0, Some("a"));
+    let b = rng.uniform(Shape::D2(size, size), -1.0, 1.0, Some("b"));
+    let (a16, b16) = (a.to_f16(), b.to_f16());
+    println!("{}x{}: f32 {} bytes, f16 {} bytes", size, size, a.size(), a16.size());
+    let c = a16.matmul(&b16);
+    let report = compare::compare(&c.to_vec(), &a.matmul(&b).to_vec(), &Tolerance::new(0.0, size as f64 * 2f64.powi(-10)));
+    report.check("matmul_f16 512", c.shape());
+    WgpuServer::wait();
+    let s = std::time::Instant::now();
+    let repeat = 3;
+    for _ in 0..repeat {
+        a16.matmul(&b16);
+    }
+    WgpuServer::wait();
+    let time = s.elapsed() / repeat;
+    let gflops = 2.0 * (size * size * size) as f64 / time.as_secs_f64() / 1e9;
+    println!("matmul_f16 {}x{}x{}: {:?}, {:.2} GFLOPS", size, size, size, time, gflops);
+    println!("f16 ok");
+}
@@ -0,0 +1,51 @@
+// f32 <-> f16(2個ずつu32に詰めたもの)の変換
+// PACK: true  f32 -> f16  input: array<f32>(len個), output: array<u32>(ceil(len / 2)個)
+//       false f16 -> f32  input: array<u32>,       output: array<f32>
+// どちらもu32として宣言してbitcastする
+@group(0) @binding(0)
+var<storage, read> input: array<u32>;
+@group(0) @binding(1)
+var<storage, read_write> output: array<u32>;
+// メタデータ
+struct ConvertParams {
+    // 要素数(f16の数)
+    len: u32,
+}
+@group(0) @binding(2)
+var<uniform> params: ConvertParams;
+
+
+/*
+
+pack2x16floatは1つ目を下位16bitに置くので，array<f16>と同じ並びになる
+奇数個のときは最後のu32の上位16bitを0にする
+PACKは1スレッドでu32を1個，unpackは1スレッドでf32を1個書く
+
+*/
+
+const PACK: bool = {{PACK}};
+
+@compute @workgroup_size(64, 1, 1)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>
+) {
+    let i = global_id.x + global_id.y * num_workgroups.x * 64u;
+    if (PACK) {
+        if (2u * i >= params.len) {
+            return;
+        }
+        let lo = bitcast<f32>(input[2u * i]);
+        var hi = 0.0;
+        if (2u * i + 1u < params.len) {
+            hi = bitcast<f32>(input[2u * i + 1u]);
+        }
+        output[i] = pack2x16float(vec2<f32>(lo, hi));
+    } else {
+        if (i >= params.len) {
+            return;
+        }
+        let pair = unpack2x16float(input[i / 2u]);
+        output[i] = bitcast<u32>(pair[i % 2u]);
+    }
+}
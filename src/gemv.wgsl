@@ -0,0 +1,69 @@
+// 行列 × ベクトル y = A x
+// Matrix<f32, M, K>
+@group(0) @binding(0)
+var<storage, read> lhs: array<f32>;
+// Vector<f32, K>
+@group(0) @binding(1)
+var<storage, read> rhs: array<f32>;
+// Vector<f32, M>
+@group(0) @binding(2)
+var<storage, read_write> output: array<f32>;
+// メタデータ。push constantsが使えればvar<push_constant>，使えなければbinding(3)のuniform
+// output = alpha * lhs * rhs + beta * output
+struct MatmulParams {
+    m: u32,
+    k: u32,
+    n: u32,
+    // 行の間隔（要素数）
+    lhs_stride: u32,
+    rhs_stride: u32,
+    out_stride: u32,
+    alpha: f32,
+    beta: f32,
+}
+{{PARAMS}}
+
+const WG: u32 = 64u;
+
+var<workgroup> partial: array<f32, 64>;
+
+// 1ワークグループ = 1行。各スレッドがKをWG飛ばしで足して，最後にshared memoryで集める
+// Mが65535を超えるとdispatchのxが足りないのでyも使う
+@compute @workgroup_size(64, 1, 1)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>
+) {
+    let M: u32 = params.m;
+    let K: u32 = params.k;
+    let row = workgroup_id.y * num_workgroups.x + workgroup_id.x;
+    let tid = local_id.x;
+
+    // rowがはみ出したワークグループもbarrierまでは一緒に進む
+    var sum: f32 = 0.0;
+    if (row < M) {
+        // 隣のスレッドは隣の列を読むのでcoalescingが効く
+        for (var k = tid; k < K; k += WG) {
+            sum += lhs[row * params.lhs_stride + k] * rhs[k];
+        }
+    }
+    partial[tid] = sum;
+    workgroupBarrier();
+
+    // tree reduction
+    for (var stride = WG / 2u; stride > 0u; stride /= 2u) {
+        if (tid < stride) {
+            partial[tid] += partial[tid + stride];
+        }
+        workgroupBarrier();
+    }
+
+    if (tid == 0u && row < M) {
+        var value = params.alpha * partial[0];
+        if (params.beta != 0.0) {
+            value += params.beta * output[row];
+        }
+        output[row] = value;
+    }
+}
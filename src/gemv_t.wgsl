@@ -0,0 +1,64 @@
+// 転置行列 × ベクトル y = A^T x
+// (1, M)の行ベクトル × (M, K)の行列もこれで計算できる
+// Matrix<f32, M, K>
+@group(0) @binding(0)
+var<storage, read> lhs: array<f32>;
+// Vector<f32, M>
+@group(0) @binding(1)
+var<storage, read> rhs: array<f32>;
+// Vector<f32, K>
+@group(0) @binding(2)
+var<storage, read_write> output: array<f32>;
+// メタデータ。push constantsが使えればvar<push_constant>，使えなければbinding(3)のuniform
+// output = alpha * lhs * rhs + beta * output
+struct MatmulParams {
+    m: u32,
+    k: u32,
+    n: u32,
+    // 行の間隔（要素数）
+    lhs_stride: u32,
+    rhs_stride: u32,
+    out_stride: u32,
+    alpha: f32,
+    beta: f32,
+}
+{{PARAMS}}
+
+// x: 出力の列(64個)，y: Mを分担する(4本)
+const COLS: u32 = 64u;
+const SLICES: u32 = 4u;
+
+var<workgroup> partial: array<f32, 256>;
+
+// 1ワークグループ = 出力64要素。Aの行を横に読むのでcoalescingが効く
+// yの4本がMを分担して，最後にshared memoryで足し合わせる
+@compute @workgroup_size(64, 4, 1)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>
+) {
+    let M: u32 = params.m;
+    let K: u32 = params.k;
+    let col = workgroup_id.x * COLS + local_id.x;
+
+    var sum: f32 = 0.0;
+    if (col < K) {
+        for (var i = local_id.y; i < M; i += SLICES) {
+            sum += lhs[i * params.lhs_stride + col] * rhs[i];
+        }
+    }
+    partial[local_id.y * COLS + local_id.x] = sum;
+    workgroupBarrier();
+
+    if (local_id.y == 0u && col < K) {
+        var total: f32 = 0.0;
+        for (var s = 0u; s < SLICES; s += 1u) {
+            total += partial[s * COLS + local_id.x];
+        }
+        var value = params.alpha * total;
+        if (params.beta != 0.0) {
+            value += params.beta * output[col];
+        }
+        output[col] = value;
+    }
+}
@@ -0,0 +1,143 @@
+use crate::elementwise::{self, Map};
+use crate::matmul_structured2::{RawGf32, Shape, WgpuServer};
+
+
+/*
+GPU側で初期化するコンストラクタ
+new_initはCPUでvecを作って送るので，大きな行列だとホストのメモリと転送の時間がかかる
+ここではbufferを作ってからGPUで値を書く
+    zeros               clear_buffer
+    full, ones          elementwise(Map::Fill)
+    arange, linspace    elementwise(Map::Iota) alpha + beta * i
+    eye, diagonal       elementwise(Map::Eye, Map::Diag)
+ベクトルはShape::D2(1, n)で返す
+*/
+
+impl RawGf32 {
+    pub fn zeros(shape: Shape, label: Option<&str>) -> Self {
+        let out = Self::_new_empty(shape, label);
+        WgpuServer::clear_buffer(out.buffer());
+        out
+    }
+
+    pub fn ones(shape: Shape, label: Option<&str>) -> Self {
+        Self::full(shape, 1.0, label)
+    }
+
+    pub fn full(shape: Shape, value: f32, label: Option<&str>) -> Self {
+        let out = Self::_new_empty(shape, label);
+        elementwise::fill(&out, value);
+        out
+    }
+
+    // [start, end)をstep刻み
+    pub fn arange(start: f32, end: f32, step: f32, label: Option<&str>) -> Self {
+        if step == 0.0 || !step.is_finite() {
+            panic!("arange: invalid step {}", step);
+        }
+        let n = ((end as f64 - start as f64) / step as f64).ceil();
+        if n < 1.0 {
+            panic!("arange: empty range [{}, {}) with step {}", start, end, step);
+        }
+        Self::iota(n as usize, start, step, label)
+    }
+
+    // [start, end]をn等分。n == 1のときはstartだけ
+    pub fn linspace(start: f32, end: f32, n: usize, label: Option<&str>) -> Self {
+        if n == 0 {
+            panic!("linspace: n must be positive");
+        }
+        let step = if n == 1 { 0.0 } else { ((end as f64 - start as f64) / (n - 1) as f64) as f32 };
+        Self::iota(n, start, step, label)
+    }
+
+    fn iota(n: usize, start: f32, step: f32, label: Option<&str>) -> Self {
+        let out = Self::_new_empty(Shape::D2(1, n), label);
+        elementwise::map_out(Map::Iota, &out, start, step);
+        out
+    }
+
+    // 単位行列
+    pub fn eye(n: usize, label: Option<&str>) -> Self {
+        let out = Self::_new_empty(Shape::D2(n, n), label);
+        elementwise::map_out(Map::Eye, &out, 1.0, 0.0);
+        out
+    }
+
+    // selfの要素を対角に並べた正方行列。selfはShape::D2(1, n)かShape::D2(n, 1)
+    pub fn diagonal(&self, label: Option<&str>) -> Self {
+        let n = match *self.shape() {
+            Shape::D2(1, n) | Shape::D2(n, 1) => n,
+            _ => panic!("diagonal: self must be a vector, but {}", self.shape().to_string()),
+        };
+        let out = Self::_new_empty(Shape::D2(n, n), label);
+        elementwise::map_with(Map::Diag, self, self, &out, 1.0, 0.0);
+        out
+    }
+}
+
+
+
+pub fn run() {
+    let shape = Shape::D2(3, 70);
+    if RawGf32::zeros(shape.clone(), Some("zeros")).to_vec() != vec![0.0; 210] {
+        panic!("zeros mismatch");
+    }
+    // 使い終わったbufferに値を書いてからでも0になる
+    let mut x = RawGf32::ones(shape.clone(), Some("ones"));
+    if x.to_vec() != vec![1.0; 210] {
+        panic!("ones mismatch");
+    }
+    x.fill_(5.0);
+    WgpuServer::clear_buffer(x.buffer());
+    if x.to_vec() != vec![0.0; 210] {
+        panic!("clear_buffer mismatch");
+    }
+    if RawGf32::full(shape, -2.5, Some("full")).to_vec() != vec![-2.5; 210] {
+        panic!("full mismatch");
+    }
+
+    let a = RawGf32::arange(-3.0, 3.0, 0.5, Some("arange")).to_vec();
+    let expected: Vec<f32> = (0..12).map(|i| -3.0 + 0.5 * i as f32).collect();
+    if a != expected {
+        panic!("arange mismatch: {:?}", a);
+    }
+    let a = RawGf32::arange(0.0, 1000.0, 1.0, Some("arange")).to_vec();
+    if a != (0..1000).map(|i| i as f32).collect::<Vec<_>>() {
+        panic!("arange (1000) mismatch");
+    }
+    let l = RawGf32::linspace(0.0, 1.0, 11, Some("linspace")).to_vec();
+    for (i, &v) in l.iter().enumerate() {
+        if (v - i as f32 / 10.0).abs() > 1e-6 {
+            panic!("linspace mismatch at {}: {}", i, v);
+        }
+    }
+    if RawGf32::linspace(2.0, 5.0, 1, Some("linspace")).to_vec() != vec![2.0] {
+        panic!("linspace (n = 1) mismatch");
+    }
+
+    // 単位行列をかけても変わらない
+    let n = 37;
+    let values: Vec<f32> = (0..n * n).map(|i| ((i * 7) % 11) as f32 - 5.0).collect();
+    let m = RawGf32::new_init(Shape::D2(n, n), &values, Some("m"));
+    let eye = RawGf32::eye(n, Some("eye"));
+    if eye.to_vec() != (0..n * n).map(|i| if i / n == i % n { 1.0 } else { 0.0 }).collect::<Vec<_>>() {
+        panic!("eye mismatch");
+    }
+    if m.matmul(&eye).to_vec() != values || eye.matmul(&m).to_vec() != values {
+        panic!("matmul with eye mismatch");
+    }
+
+    // 対角行列を左からかけると行ごとにscaleされる
+    let d = RawGf32::arange(1.0, (n + 1) as f32, 1.0, Some("d"));
+    let diag = d.diagonal(Some("diag"));
+    let expected: Vec<f32> = values.iter().enumerate().map(|(i, &v)| v * (i / n + 1) as f32).collect();
+    if diag.matmul(&m).to_vec() != expected {
+        panic!("matmul with diagonal mismatch");
+    }
+    let column = RawGf32::new_init(Shape::D2(3, 1), &vec![1.0, 2.0, 3.0], Some("column"));
+    if column.diagonal(None).to_vec() != vec![1.0, 0.0, 0.0, 0.0, 2.0, 0.0, 0.0, 0.0, 3.0] {
+        panic!("diagonal (column) mismatch");
+    }
+    println!("init ok");
+}
@@ -1,7 +1,29 @@
 
+mod autograd;
+mod autotune;
 mod collatz;
+mod compare;
+mod convert;
+mod df64;
+mod display;
+mod elementwise;
+mod epilogue;
+mod f16;
+mod init;
 mod matmul;
 mod matmul_structured2;
+mod multi_gpu;
+mod mtx_csv;
+mod nn;
+mod npy;
+mod quantize;
+mod random;
+mod safetensors_io;
+mod shader_check;
+mod softmax;
+mod sparse;
+mod transpose;
+mod wgsl_template;
 
 mod strassen;
 
@@ -22,6 +44,32 @@ fn main() {
    matmul_structured2::run();
 
 
+   //multi_gpu::run();
+   //convert::run();
+   //npy::run();
+   //safetensors_io::run();
+   //mtx_csv::run();
+   //sparse::run();
+   //matmul_structured2::run_gemv();
+   //matmul_structured2::run_execute_n();
+   //matmul_structured2::run_gemm();
+   //autotune::run();
+   //wgsl_template::run();
+   //shader_check::run();
+   //epilogue::run();
+   //softmax::run();
+   //autograd::run();
+   //nn::run();
+   //elementwise::run();
+    //init::run();
+    //random::run();
+    //compare::run();
+    //display::run();
+    //transpose::run();
+    //f16::run();
+    //quantize::run();
+    //df64::run();
+
    // シュトラッセンはなんか結果おかしい。
    //strassen::run();
     
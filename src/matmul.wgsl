@@ -1,32 +1,63 @@
 
 
 // 行列積を計算する
-// Matrix<f32, N, M>
+// Matrix<f32, M, K>
 @group(0) @binding(0)
 var<storage, read> lhs: array<f32>;
-// Matrix<f32, M, K>
+// Matrix<f32, K, N>
 @group(0) @binding(1)
 var<storage, read> rhs: array<f32>;
-// Matrix<f32, N, K>
+// Matrix<f32, M, N>
 @group(0) @binding(2)
 var<storage, read_write> output: array<f32>;
-// メタデータ
-// vec![N, M, O]
-@group(0) @binding(3)
-var<storage, read> sizes: vec3<u32>;
+// メタデータ。push constantsが使えればvar<push_constant>，使えなければbinding(3)のuniform
+// output = alpha * lhs * rhs + beta * output
+struct MatmulParams {
+    m: u32,
+    k: u32,
+    n: u32,
+    // 行の間隔（要素数）
+    lhs_stride: u32,
+    rhs_stride: u32,
+    out_stride: u32,
+    alpha: f32,
+    beta: f32,
+}
+{{PARAMS}}
+// epilogue(epilogue.rs)のbias, residual。使わないときは空行
+{{BIAS}}
+{{RESIDUAL}}
 
-@compute @workgroup_size(1, 1, 1)
-fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    let N: u32 = sizes[0]; // Number of rows in lhs and output
-    let M: u32 = sizes[1]; // Number of columns in lhs and rows in rhs
-    let O: u32 = sizes[2]; // Number of columns in rhs and output
+fn tanh_safe(x: f32) -> f32 {
+    // 大きい値でNaNになる実装があるのでclampする
+    return tanh(clamp(x, -15.0, 15.0));
+}
+fn gelu(x: f32) -> f32 {
+    return 0.5 * x * (1.0 + tanh_safe(0.7978845608 * (x + 0.044715 * x * x * x)));
+}
 
-    var x = global_id.x; // Column index for output and rhs
-    var y = global_id.y; // Row index for output and lhs
+// タイルに収まらない大きさ用。1スレッド = outputの1要素で，はみ出したスレッドは何もしない
+// xが列，yが行。行のworkgroupが1次元の上限を超えたらzにも分ける(RawGf32::split_rows_dispatch)
+@compute @workgroup_size(16, 16, 1)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+) {
+    let col = global_id.x;
+    let row = global_id.y + global_id.z * num_workgroups.y * 16u;
+    if (row >= params.m || col >= params.n) {
+        return;
+    }
 
     var sum: f32 = 0.0;
-    for(var k: u32 = 0u; k < M; k = k + 1u) {
-        sum = sum + lhs[y * M + k] * rhs[k * O + x];
+    for (var k: u32 = 0u; k < params.k; k = k + 1u) {
+        sum = sum + lhs[row * params.lhs_stride + k] * rhs[k * params.rhs_stride + col];
+    }
+    let out_idx = row * params.out_stride + col;
+    var value = params.alpha * sum;
+    if (params.beta != 0.0) {
+        value += params.beta * output[out_idx];
     }
-    output[y * O + x] = sum;
-}
\ No newline at end of file
+    {{EPILOGUE}}
+    output[out_idx] = value;
+}
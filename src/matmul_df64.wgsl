@@ -0,0 +1,136 @@
+// C = A * B をdouble-float(f32 2個, hi + lo)で計算する
+// Matrix<vec2<f32>, M, K>
+@group(0) @binding(0)
+var<storage, read> lhs: array<vec2<f32>>;
+// Matrix<vec2<f32>, K, N>
+@group(0) @binding(1)
+var<storage, read> rhs: array<vec2<f32>>;
+// Matrix<vec2<f32>, M, N>
+@group(0) @binding(2)
+var<storage, read_write> output: array<vec2<f32>>;
+// メタデータ
+struct MatmulDf64Params {
+    m: u32,
+    k: u32,
+    n: u32,
+    // 0。下を参照
+    zero: u32,
+}
+@group(0) @binding(3)
+var<uniform> params: MatmulDf64Params;
+
+
+/*
+
+値はhi + lo (|lo| <= ulp(hi) / 2)。仮数は約48bit，指数の範囲はf32と同じ
+誤差なし変換(error-free transformation)で積と和の丸め誤差をloに集める
+    two_sum:  a + b = s + e
+    two_prod: a * b = p + e  FMAがtrueならfma(a, b, -p)，falseならDekkerの分割(12bitずつ)
+FMAはfmaが本当に1回の丸めになるデバイスでだけtrueにする(df64.rsで確かめる)
+シェーダーコンパイラは浮動小数点の式を実数として整理することがある(Mesaは s - (s - a) を a にしたり，結合の順序を変えたりする)
+そうするとeが0になってf32と同じ精度になるので，誤差なし変換の途中の値は全部opaqueに通して式の形を隠す
+(1.0を掛けるのでは x * one + y * one = (x + y) * one のようにまとめられてしまうので，ビット列で隠す)
+1 workgroup = 出力のTILE x TILE，1スレッド = 出力1要素(3shared.wgslと同じ形)
+M, K, Nは何でもよい。範囲外は0として読む
+
+*/
+
+const FMA: bool = {{FMA}};
+const TILE: u32 = 16u;
+// 2^12 + 1
+const SPLITTER: f32 = 4097.0;
+
+var<workgroup> tile_lhs: array<vec2<f32>, 256>;
+var<workgroup> tile_rhs: array<vec2<f32>, 256>;
+
+// xそのもの。ビット列にparams.zero(= 0)をxorする。コンパイラにはparams.zeroの値がわからないので，前後の式をまとめられない
+fn opaque(x: f32) -> f32 {
+    return bitcast<f32>(bitcast<u32>(x) ^ params.zero);
+}
+
+fn two_sum(a: f32, b: f32) -> vec2<f32> {
+    let s = opaque(a + b);
+    let bb = opaque(s - a);
+    let e = opaque(a - opaque(s - bb)) + opaque(b - bb);
+    return vec2<f32>(s, e);
+}
+
+// |a| >= |b|のとき
+fn quick_two_sum(a: f32, b: f32) -> vec2<f32> {
+    let s = opaque(a + b);
+    let e = b - opaque(s - a);
+    return vec2<f32>(s, e);
+}
+
+fn split(a: f32) -> vec2<f32> {
+    let t = opaque(SPLITTER * a);
+    let hi = opaque(t - opaque(t - a));
+    return vec2<f32>(hi, a - hi);
+}
+
+fn two_prod(a: f32, b: f32) -> vec2<f32> {
+    let p = a * b;
+    if (FMA) {
+        return vec2<f32>(p, fma(a, b, -p));
+    }
+    let x = split(a);
+    let y = split(b);
+    let e = opaque(opaque(opaque(x.x * y.x - p) + x.x * y.y) + x.y * y.x) + x.y * y.y;
+    return vec2<f32>(p, e);
+}
+
+fn df_add(a: vec2<f32>, b: vec2<f32>) -> vec2<f32> {
+    var s = two_sum(a.x, b.x);
+    let t = two_sum(a.y, b.y);
+    s.y += t.x;
+    s = quick_two_sum(s.x, s.y);
+    s.y += t.y;
+    return quick_two_sum(s.x, s.y);
+}
+
+fn df_mul(a: vec2<f32>, b: vec2<f32>) -> vec2<f32> {
+    var p = two_prod(a.x, b.x);
+    p.y += a.x * b.y + a.y * b.x;
+    return quick_two_sum(p.x, p.y);
+}
+
+@compute @workgroup_size(16, 16, 1)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>
+) {
+    let m = params.m;
+    let k = params.k;
+    let n = params.n;
+    let row = workgroup_id.y * TILE + local_id.y;
+    let col = workgroup_id.x * TILE + local_id.x;
+
+    var sum = vec2<f32>(0.0, 0.0);
+    for (var k0 = 0u; k0 < k; k0 += TILE) {
+        // A[row][k0 + local_id.x], B[k0 + local_id.y][col]
+        let a_col = k0 + local_id.x;
+        var a = vec2<f32>(0.0, 0.0);
+        if (row < m && a_col < k) {
+            a = lhs[row * k + a_col];
+        }
+        tile_lhs[local_id.y * TILE + local_id.x] = a;
+        let b_row = k0 + local_id.y;
+        var b = vec2<f32>(0.0, 0.0);
+        if (b_row < k && col < n) {
+            b = rhs[b_row * n + col];
+        }
+        tile_rhs[local_id.y * TILE + local_id.x] = b;
+
+        workgroupBarrier();
+
+        for (var l = 0u; l < TILE; l++) {
+            sum = df_add(sum, df_mul(tile_lhs[local_id.y * TILE + l], tile_rhs[l * TILE + local_id.x]));
+        }
+
+        workgroupBarrier();
+    }
+
+    if (row < m && col < n) {
+        output[row * n + col] = sum;
+    }
+}
@@ -0,0 +1,83 @@
+// C = A * B。A, Bはf16を2個ずつu32に詰めたもの(f16.wgslのpack)，Cはf32
+// Matrix<f16, M, K>
+@group(0) @binding(0)
+var<storage, read> lhs: array<u32>;
+// Matrix<f16, K, N>
+@group(0) @binding(1)
+var<storage, read> rhs: array<u32>;
+// Matrix<f32, M, N>
+@group(0) @binding(2)
+var<storage, read_write> output: array<f32>;
+// メタデータ
+struct MatmulF16Params {
+    m: u32,
+    k: u32,
+    n: u32,
+}
+@group(0) @binding(3)
+var<uniform> params: MatmulF16Params;
+
+
+/*
+
+読むのはf16(半分のバイト数)，shared memoryと積和はf32
+1 workgroup = 出力のTILE x TILE，1スレッド = 出力1要素(3shared.wgslと同じ形)
+タイルを読むときにunpack2x16floatでf32にしてshared memoryに置く
+要素iはi / 2番目のu32の(i % 2)番目(下位16bitが偶数番目)
+M, K, Nは何でもよい。範囲外は0として読む
+
+*/
+
+const TILE: u32 = 16u;
+
+var<workgroup> tile_lhs: array<f32, 256>;
+var<workgroup> tile_rhs: array<f32, 256>;
+
+fn load_lhs(i: u32) -> f32 {
+    return unpack2x16float(lhs[i / 2u])[i % 2u];
+}
+
+fn load_rhs(i: u32) -> f32 {
+    return unpack2x16float(rhs[i / 2u])[i % 2u];
+}
+
+@compute @workgroup_size(16, 16, 1)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>
+) {
+    let m = params.m;
+    let k = params.k;
+    let n = params.n;
+    let row = workgroup_id.y * TILE + local_id.y;
+    let col = workgroup_id.x * TILE + local_id.x;
+
+    var sum = 0.0;
+    for (var k0 = 0u; k0 < k; k0 += TILE) {
+        // A[row][k0 + local_id.x], B[k0 + local_id.y][col]
+        let a_col = k0 + local_id.x;
+        var a = 0.0;
+        if (row < m && a_col < k) {
+            a = load_lhs(row * k + a_col);
+        }
+        tile_lhs[local_id.y * TILE + local_id.x] = a;
+        let b_row = k0 + local_id.y;
+        var b = 0.0;
+        if (b_row < k && col < n) {
+            b = load_rhs(b_row * n + col);
+        }
+        tile_rhs[local_id.y * TILE + local_id.x] = b;
+
+        workgroupBarrier();
+
+        for (var l = 0u; l < TILE; l++) {
+            sum += tile_lhs[local_id.y * TILE + l] * tile_rhs[l * TILE + local_id.x];
+        }
+
+        workgroupBarrier();
+    }
+
+    if (row < m && col < n) {
+        output[row * n + col] = sum;
+    }
+}
@@ -0,0 +1,150 @@
+// C = A * B。A, Bはint8を4個ずつ詰めたもの(quantize.wgsl)，Cはf32
+// Matrix<i8, M, K>。行ごとのQParams
+@group(0) @binding(0)
+var<storage, read> lhs: array<u32>;
+@group(0) @binding(1)
+var<storage, read> lhs_qparams: array<QParams>;
+// Matrix<i8, K, N>。列ごとのQParams
+@group(0) @binding(2)
+var<storage, read> rhs: array<u32>;
+@group(0) @binding(3)
+var<storage, read> rhs_qparams: array<QParams>;
+// Matrix<f32, M, N>
+@group(0) @binding(4)
+var<storage, read_write> output: array<f32>;
+struct QParams {
+    scale: f32,
+    zero_point: i32,
+}
+// メタデータ
+struct MatmulI8Params {
+    m: u32,
+    k: u32,
+    n: u32,
+}
+@group(0) @binding(5)
+var<uniform> params: MatmulI8Params;
+
+
+/*
+
+5blocking2d.wgslと同じタイル分け(BM, BN, BK, TM, TNはautotune::TileConfig)
+shared memoryに置くときにzero pointを引いてi32にし，積和はi32でする
+    |q - zero_point| <= 255なので，i32であふれないのはK <= i32::MAX / (255 * 255) = 33025まで
+    K方向にFLUSH(BKの倍数)進むごとにi32の部分和をf32の和に足して0に戻す。K <= FLUSHなら結果はi32で足したものと同じ
+最後に行と列のscaleを掛けてf32で書く
+    C[i][j] = scale_a[i] * scale_b[j] * sum_k (A[i][k] - zp_a[i]) * (B[k][j] - zp_b[j])
+M, K, Nは何でもよい。範囲外は0として読む
+
+*/
+
+const BM: u32 = {{BM}}u;
+const BN: u32 = {{BN}}u;
+const BK: u32 = {{BK}}u;
+const TM: u32 = {{TM}}u;
+const TN: u32 = {{TN}}u;
+const TM_TN: u32 = {{TM_TN}}u;
+// 32768 * 255 * 255 < 2^31。BK(4, 8, 16)で割り切れる
+const FLUSH: u32 = 32768u;
+
+var<workgroup> lhs_shared: array<i32, {{BM_BK}}>;
+var<workgroup> rhs_shared: array<i32, {{BK_BN}}>;
+
+// 要素iはi / 4番目のu32の(i % 4)番目のbyte
+fn load_lhs(i: u32) -> i32 {
+    return i32(lhs[i / 4u] << (24u - 8u * (i % 4u))) >> 24u;
+}
+
+fn load_rhs(i: u32) -> i32 {
+    return i32(rhs[i / 4u] << (24u - 8u * (i % 4u))) >> 24u;
+}
+
+@compute @workgroup_size({{WG}}, 1, 1)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>
+) {
+    let M = params.m;
+    let K = params.k;
+    let N = params.n;
+
+    let cRow = workgroup_id.y;
+    let cCol = workgroup_id.x;
+
+    let threadCol = local_id.x % (BN / TN);
+    let threadRow = local_id.x / (BN / TN);
+
+    // lhsとrhsのアクセス用
+    let lhs_innerCol = local_id.x % BK;
+    let lhs_innerRow = local_id.x / BK;
+    let rhs_innerCol = local_id.x % BN;
+    let rhs_innerRow = local_id.x / BN;
+
+    let numThreadsBlocktile = BM * BN / (TM * TN);
+    let lhs_stride = numThreadsBlocktile / BK;
+    let rhs_stride = numThreadsBlocktile / BN;
+
+    // FLUSHごとのi32の部分和と，それを足したf32
+    var threadPartials = array<i32, TM_TN>();
+    var threadResults = array<f32, TM_TN>();
+    var regM = array<i32, TM>();
+    var regN = array<i32, TN>();
+
+    for (var bkIdx = 0u; bkIdx < K; bkIdx += BK) {
+        for (var loadOffset = 0u; loadOffset < BM; loadOffset += lhs_stride) {
+            let row = cRow * BM + lhs_innerRow + loadOffset;
+            let col = bkIdx + lhs_innerCol;
+            var a = 0;
+            if (row < M && col < K) {
+                a = load_lhs(row * K + col) - lhs_qparams[row].zero_point;
+            }
+            lhs_shared[(lhs_innerRow + loadOffset) * BK + lhs_innerCol] = a;
+        }
+        for (var loadOffset = 0u; loadOffset < BK; loadOffset += rhs_stride) {
+            let row = bkIdx + rhs_innerRow + loadOffset;
+            let col = cCol * BN + rhs_innerCol;
+            var b = 0;
+            if (row < K && col < N) {
+                b = load_rhs(row * N + col) - rhs_qparams[col].zero_point;
+            }
+            rhs_shared[(rhs_innerRow + loadOffset) * BN + rhs_innerCol] = b;
+        }
+        workgroupBarrier();
+
+        for (var dotIdx = 0u; dotIdx < BK; dotIdx += 1u) {
+            for (var i = 0u; i < TM; i += 1u) {
+                regM[i] = lhs_shared[(threadRow * TM + i) * BK + dotIdx];
+            }
+            for (var i = 0u; i < TN; i += 1u) {
+                regN[i] = rhs_shared[dotIdx * BN + threadCol * TN + i];
+            }
+            for (var resIdxM = 0u; resIdxM < TM; resIdxM += 1u) {
+                for (var resIdxN = 0u; resIdxN < TN; resIdxN += 1u) {
+                    threadPartials[resIdxM * TN + resIdxN] += regM[resIdxM] * regN[resIdxN];
+                }
+            }
+        }
+        workgroupBarrier();
+
+        if ((bkIdx + BK) % FLUSH == 0u) {
+            for (var i = 0u; i < TM_TN; i += 1u) {
+                threadResults[i] += f32(threadPartials[i]);
+                threadPartials[i] = 0;
+            }
+        }
+    }
+    for (var i = 0u; i < TM_TN; i += 1u) {
+        threadResults[i] += f32(threadPartials[i]);
+    }
+
+    for (var resIdxM = 0u; resIdxM < TM; resIdxM += 1u) {
+        let row = cRow * BM + threadRow * TM + resIdxM;
+        for (var resIdxN = 0u; resIdxN < TN; resIdxN += 1u) {
+            let col = cCol * BN + threadCol * TN + resIdxN;
+            if (row < M && col < N) {
+                let scale = lhs_qparams[row].scale * rhs_qparams[col].scale;
+                output[row * N + col] = scale * threadResults[resIdxM * TN + resIdxN];
+            }
+        }
+    }
+}
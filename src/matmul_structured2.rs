@@ -2,6 +2,10 @@ use std::{borrow::{BorrowMut, Cow}, collections::HashMap, ops::Deref, sync::{Arc
 use flume::r#async;
 use wgpu::{util::DeviceExt, Buffer, ShaderModule};
 use lazy_static::lazy_static;
+use crate::autotune::{self, TileConfig, VECTORIZE};
+use crate::display::{self, PrintOptions};
+use crate::epilogue::Epilogue;
+use crate::wgsl_template::{self, TemplateParams, WgslTemplate};
 
 
 
@@ -27,6 +31,155 @@ thread_local! {
         );
         let adapter = a.unwrap();
 
+        Wgpu::new(&adapter)
+    };
+}
+
+// テスト用。adapterがない環境ではDEVICEがpanicするので，GPUを使うテストはこれを見て飛ばす
+#[cfg(test)]
+pub(crate) fn adapter_available() -> bool {
+    static AVAILABLE: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        let instance = wgpu::Instance::default();
+        pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default())).is_some()
+    })
+}
+
+
+// thread_local!は
+lazy_static! {
+    static ref WGPU_SERVER: WgpuServer = WgpuServer::new();
+}
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum BindingKind {
+    // var<storage, read>
+    StorageRead,
+    // var<storage, read_write>
+    StorageReadWrite,
+    // var<uniform>。bufferにUNIFORMが必要
+    Uniform,
+}
+
+// execute_nに渡すbinding。offset, sizeでbufferの一部だけを渡せる
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Binding<'a> {
+    pub kind: BindingKind,
+    pub buffer: &'a wgpu::Buffer,
+    pub offset: u64,
+    // Noneならoffsetから最後まで
+    pub size: Option<std::num::NonZeroU64>,
+}
+impl<'a> Binding<'a> {
+    pub(crate) fn read(buffer: &'a wgpu::Buffer) -> Self {
+        Self { kind: BindingKind::StorageRead, buffer, offset: 0, size: None }
+    }
+    pub(crate) fn read_write(buffer: &'a wgpu::Buffer) -> Self {
+        Self { kind: BindingKind::StorageReadWrite, buffer, offset: 0, size: None }
+    }
+    pub(crate) fn uniform(buffer: &'a wgpu::Buffer) -> Self {
+        Self { kind: BindingKind::Uniform, buffer, offset: 0, size: None }
+    }
+    // offsetはlimits.min_{storage,uniform}_buffer_offset_alignmentの倍数であること
+    pub(crate) fn range(self, offset: u64, size: u64) -> Self {
+        Self { offset, size: std::num::NonZeroU64::new(size), ..self }
+    }
+}
+
+// 1回のdispatchでread_writeのbufferを他のbindingにも渡すとwgpuのエラー(conflicting usages)になる
+// 範囲が重ならなくてもbuffer単位でだめなので，同じbufferならpanicする
+// その場で書き換える演算は出力だけを渡して，カーネルの中でoutput[i]を読む
+pub(crate) fn check_aliasing(label: &str, bindings: &[Binding]) {
+    for (i, a) in bindings.iter().enumerate() {
+        for (j, b) in bindings.iter().enumerate().skip(i + 1) {
+            let writes = a.kind == BindingKind::StorageReadWrite || b.kind == BindingKind::StorageReadWrite;
+            if writes && std::ptr::eq(a.buffer, b.buffer) {
+                panic!("{}: binding({}) and binding({}) alias the same buffer, but one of them is read_write", label, i, j);
+            }
+        }
+    }
+}
+
+// 行列積カーネル(matmul, 6vectorize, gemv, gemv_t.wgsl)のメタデータ
+// output = alpha * lhs * rhs + beta * output
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct MatmulParams {
+    pub m: u32,
+    pub k: u32,
+    pub n: u32,
+    // 行の間隔（要素数）。連続した行列ならk, n, n
+    pub lhs_stride: u32,
+    pub rhs_stride: u32,
+    pub out_stride: u32,
+    pub alpha: f32,
+    pub beta: f32,
+}
+impl MatmulParams {
+    pub(crate) fn new(m: usize, k: usize, n: usize) -> Self {
+        Self {
+            m: m as u32,
+            k: k as u32,
+            n: n as u32,
+            lhs_stride: k as u32,
+            rhs_stride: n as u32,
+            out_stride: n as u32,
+            alpha: 1.0,
+            beta: 0.0,
+        }
+    }
+    // WGSLのstruct MatmulParamsと同じ並び(32Byte)
+    pub(crate) fn to_words(self) -> [u32; 8] {
+        [self.m, self.k, self.n, self.lhs_stride, self.rhs_stride, self.out_stride, self.alpha.to_bits(), self.beta.to_bits()]
+    }
+}
+
+// execute_matmulで使うカーネル。テンプレート(matmul, 6vectorize, gemv, gemv_t, matmul_trans.wgsl)と
+// そのパラメータ({{PARAMS}}とepilogue以外)，dispatchの大きさ
+pub(crate) struct MatmulKernel<'a> {
+    pub template: &'a WgslTemplate,
+    pub template_params: TemplateParams,
+    pub dispatch: (u32, u32, u32),
+}
+impl<'a> MatmulKernel<'a> {
+    pub(crate) fn new(template: &'a WgslTemplate, template_params: TemplateParams, dispatch: (u32, u32, u32)) -> Self {
+        Self { template, template_params, dispatch }
+    }
+}
+
+// {{PARAMS}}に入れる宣言
+pub(crate) fn matmul_params_decl(push_constants: bool) -> &'static str {
+    if push_constants {
+        "var<push_constant> params: MatmulParams;"
+    } else {
+        "@group(0) @binding(3) var<uniform> params: MatmulParams;"
+    }
+}
+
+// 行列積カーネルのテンプレートパラメータ({{PARAMS}}とepilogue)
+pub(crate) fn matmul_template_params(params: TemplateParams, push_constants: bool, epilogue: &Epilogue) -> TemplateParams {
+    epilogue.template_params(params, push_constants).set("PARAMS", matmul_params_decl(push_constants))
+}
+
+pub(crate) const MATMUL_NAIVE: WgslTemplate = WgslTemplate::new("matmul.wgsl", include_str!("./matmul.wgsl"));
+pub(crate) const GEMV: WgslTemplate = WgslTemplate::new("gemv.wgsl", include_str!("./gemv.wgsl"));
+pub(crate) const GEMV_T: WgslTemplate = WgslTemplate::new("gemv_t.wgsl", include_str!("./gemv_t.wgsl"));
+pub(crate) const MATMUL_TRANS: WgslTemplate = WgslTemplate::new("matmul_trans.wgsl", include_str!("./matmul_trans.wgsl"));
+
+pub(crate) struct Wgpu {
+    // type
+    // id
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    // autotuneのキャッシュのキー
+    adapter_name: String,
+
+    // MatmulParams用のuniform。push constantsが使えないときに毎回書き換えて使い回す
+    matmul_params: wgpu::Buffer,
+    // execute_nで作ったpipeline
+    pipeline_cache: RwLock<HashMap<String, Arc<(wgpu::BindGroupLayout, wgpu::ComputePipeline)>>>,
+}
+impl Wgpu {
+    // adapterからdeviceを作る。DEVICEの他にmulti_gpuからも使う
+    pub(crate) fn new(adapter: &wgpu::Adapter) -> Self {
         // これをしないと1024*8の正方行列が通らない
         let mut new_limit = wgpu::Limits::default();
         new_limit.max_storage_buffer_binding_size = adapter.limits().max_storage_buffer_binding_size;
@@ -35,12 +188,24 @@ thread_local! {
         // workgroup_size
         new_limit.max_compute_workgroup_size_x = adapter.limits().max_compute_workgroup_size_x;
         new_limit.max_compute_invocations_per_workgroup = adapter.limits().max_compute_invocations_per_workgroup;
+        // push constantsは使えるときだけ有効にする
+        // GLESはuniformで代用しているが，wgpu-hal 0.17ではデータを整列せずに読むのでdebugビルドでpanicする
+        let mut features = if adapter.get_info().backend == wgpu::Backend::Gl {
+            wgpu::Features::empty()
+        } else {
+            adapter.features() & wgpu::Features::PUSH_CONSTANTS
+        };
+        // f16もadapterが対応していれば有効にする(f16.rs)
+        features |= adapter.features() & wgpu::Features::SHADER_F16;
+        if features.contains(wgpu::Features::PUSH_CONSTANTS) {
+            new_limit.max_push_constant_size = adapter.limits().max_push_constant_size;
+        }
 
         let d = pollster::block_on(
             adapter.request_device(
                 &wgpu::DeviceDescriptor {
                     label: None,
-                    features: wgpu::Features::empty(),
+                    features,
                     limits: new_limit,
                 },
                 None,
@@ -48,317 +213,434 @@ thread_local! {
         );
         let (device, queue) = d.unwrap();
 
+        let matmul_params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("matmul params"),
+            size: std::mem::size_of::<[u32; 8]>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
         Wgpu {
             device,
             queue,
-            shader_cache: RwLock::new(HashMap::new()),
+            matmul_params,
+            adapter_name: adapter.get_info().name,
+            pipeline_cache: RwLock::new(HashMap::new()),
         }
-    };
-}
-
-
-// thread_local!は
-lazy_static! {
-    static ref WGPU_SERVER: WgpuServer = WgpuServer::new();
-}
-struct Wgpu {
-    // type
-    // id
-    device: wgpu::Device,
-    queue: wgpu::Queue,
-
-    shader_cache: RwLock<HashMap<String, wgpu::ShaderModule>>
-}
-
-// 操作を集約して，RwLockの中身を外部に送信しなくていいようにしたい
-struct WgpuServer {} // 中身ないのでmodでもいいが一応structの形をとらせる
-impl WgpuServer {
-    fn new() -> Self {
-        Self {}
     }
-    fn create_buffer(size: usize, label: Option<&str>) -> wgpu::Buffer {
-        DEVICE.with(|w| {
-            let b = w.device.create_buffer(&wgpu::BufferDescriptor {
-                label,
-                size: size as wgpu::BufferAddress,
-    
-                /*
-                emptyeは
-                １．途中の計算結果を書き込んだあと，他のシェーダで読む
-                ２．計算結果を書き込んだあと，ステージングバッファにコピーしてCPU側に読み出す
-                なので，COPY_SRCをつけとけばよいのでは。
-                STORAGEはシェーダ側，COPY_XXXはコマンドエンコーダから操作するための特性（なのでは）
-                 */
-                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
-                mapped_at_creation: false,
-            });
-            b
-        })
+    pub(crate) fn adapter_name(&self) -> &str {
+        &self.adapter_name
     }
-    fn create_buffer_init<T: bytemuck::Pod>(contents: &Vec<T>, label: Option<&str>) -> wgpu::Buffer {
-        DEVICE.with(|w| {
-            let b = w.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label,
-                contents: bytemuck::cast_slice(contents),
-                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-            });
-            b   
+    pub(crate) fn limits(&self) -> wgpu::Limits {
+        self.device.limits()
+    }
+    pub(crate) fn supports_push_constants(&self) -> bool {
+        self.device.features().contains(wgpu::Features::PUSH_CONSTANTS)
+    }
+    pub(crate) fn supports_shader_f16(&self) -> bool {
+        self.device.features().contains(wgpu::Features::SHADER_F16)
+    }
+    // submitした処理が全部終わるまで待つ。時間計測用
+    pub(crate) fn wait(&self) {
+        self.device.poll(wgpu::Maintain::Wait);
+    }
+    pub(crate) fn create_buffer(&self, size: usize, label: Option<&str>) -> wgpu::Buffer {
+        let b = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label,
+            size: size as wgpu::BufferAddress,
+
+            /*
+            emptyeは
+            １．途中の計算結果を書き込んだあと，他のシェーダで読む
+            ２．計算結果を書き込んだあと，ステージングバッファにコピーしてCPU側に読み出す
+            なので，COPY_SRCをつけとけばよいのでは。
+            STORAGEはシェーダ側，COPY_XXXはコマンドエンコーダから操作するための特性（なのでは）
+             */
+            // queue.write_bufferで後から書き込めるようにCOPY_DSTもつける
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        b
+    }
+    // var<uniform>用。queue.write_bufferで書き換えて使い回す
+    pub(crate) fn create_uniform_buffer(&self, size: usize, label: Option<&str>) -> wgpu::Buffer {
+        self.device.create_buffer(&wgpu::BufferDescriptor {
+            label,
+            size: size as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
         })
     }
+    // CPU側のbyte列をそのままbufferに書き込む。sizeは4の倍数であること
+    pub(crate) fn write_buffer(&self, buffer: &wgpu::Buffer, offset: u64, data: &[u8]) {
+        self.queue.write_buffer(buffer, offset, data);
+    }
+    // GPU側で0を書く。CPUからゼロのvecを送らなくてよい
+    pub(crate) fn clear_buffer(&self, buffer: &wgpu::Buffer) {
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("clear buffer"),
+        });
+        encoder.clear_buffer(buffer, 0, None);
+        self.queue.submit(Some(encoder.finish()));
+    }
+    pub(crate) fn create_buffer_init<T: bytemuck::Pod>(&self, contents: &[T], label: Option<&str>) -> wgpu::Buffer {
+        let b = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label,
+            contents: bytemuck::cast_slice(contents),
+            // to_vec()で読み出せるようにCOPY_SRCもつける
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+        });
+        b   
+    }
     
-    fn execute_3(
-        buf1: &wgpu::Buffer,
-        buf2: &wgpu::Buffer,
-        buf3: &wgpu::Buffer,
+    // bindings[i]をbinding(i)につないで実行する
+    // bind group layoutはシェーダから推論(get_bind_group_layout)せずにbindingsの種類から作る
+    // push_constantsが空でなければvar<push_constant>に渡す（対応していないadapterではpanic）
+    pub(crate) fn execute_n(
+        &self,
+        label: &str,
+        bindings: &[Binding],
+        push_constants: &[u8],
         shader_name: &str,
         shader_str: &str, // include_str!して実行ファイルを１つにするために必要
         dispatch: (u32, u32, u32),
     ) {
-        DEVICE.with(|w| {
-            // まずキャッシュを読み取り専用で確認
-            /*let shader_module = {
-                {
-                    let mut shader_cache = w.shader_cache.write().unwrap();
-                    if shader_cache.contains_key(shader_name) {
-                        // キャッシュに存在する場合、何もせずスコープを抜ける
-                    
-                    } else {
-                        // キャッシュに存在しない場合、新しいShaderModuleを作成
-                        
-                        // 作成したShaderModuleをキャッシュに挿入
-                        shader_cache.insert(shader_name.to_string(), shader_module);
-                    }
-                }
-                {
-                    let shader_cache: RwLockReadGuard<HashMap<String, ShaderModule>> = w.shader_cache.read().unwrap();
-                    shader_cache.get(shader_name).unwrap()
-                }
-            };*/
-            let shader_module = w.device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                label: None,
-                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(shader_str)),
-            });
+        if !push_constants.is_empty() && !self.supports_push_constants() {
+            panic!("{}: push constants are not supported on {}", label, self.adapter_name);
+        }
+        check_aliasing(label, bindings);
+        let pipeline = self.pipeline(bindings, push_constants.len() as u32, shader_name, shader_str);
+        let label = wgsl_template::short_label(label);
+        let label = label.as_str();
+
+        let entries: Vec<wgpu::BindGroupEntry> = bindings.iter().enumerate().map(|(i, b)| {
+            wgpu::BindGroupEntry {
+                // wgslからはbinding(i)で取れる
+                binding: i as u32,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: b.buffer,
+                    offset: b.offset,
+                    size: b.size,
+                }),
+            }
+        }).collect();
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(label),
+            layout: &pipeline.0,
+            entries: &entries,
+        });
 
-            let compute_pileline = w.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                label: None,
-                layout: None,
-                module: &shader_module,
-                entry_point: "main",
-                // このバージョンではないっぽい
-                // constantas: &Default::default(),
+        // comand encoderは一つか複数のパイプラインを実行する
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some(label),
+        });
+        {
+            let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(label),
+                // ない
+                // timestamp_writes: None,
             });
-    
-            let bind_group_layout = compute_pileline.get_bind_group_layout(0);
-            let bind_group = w.device.create_bind_group(&wgpu::BindGroupDescriptor {
-                label: None,
-                // 
-                layout: &bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        // wgslからはbinding(0)で取れる
-                        binding: 0,
-                        // binding(0)の中身をさっき作成したbufferに指定
-                        resource: buf1.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: buf2.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 2,
-                        resource: buf3.as_entire_binding(),
+            cpass.set_pipeline(&pipeline.1);
+            cpass.set_bind_group(0, &bind_group, &[]);
+            if !push_constants.is_empty() {
+                cpass.set_push_constants(0, push_constants);
+            }
+            cpass.dispatch_workgroups(dispatch.0, dispatch.1, dispatch.2);
+        }
+
+        // encoderの中身を送信
+        self.queue.submit(Some(encoder.finish()));
+    }
+
+    // (bind group layout, pipeline)をshader_nameとbindingの種類ごとにキャッシュする
+    // shader_nameが同じならshader_strも同じであること（テンプレートはinstance_nameを使う）
+    fn pipeline(&self, bindings: &[Binding], push_constant_size: u32, shader_name: &str, shader_str: &str) -> Arc<(wgpu::BindGroupLayout, wgpu::ComputePipeline)> {
+        let kinds: Vec<String> = bindings.iter().map(|b| format!("{:?}", b.kind)).collect();
+        let key = format!("{}|{}|{}", shader_name, kinds.join(","), push_constant_size);
+        if let Some(pipeline) = self.pipeline_cache.read().unwrap().get(&key) {
+            return pipeline.clone();
+        }
+
+        // キャッシュのキーはinstance_nameのまま，wgpuのラベルだけ短くする
+        let label = wgsl_template::short_label(shader_name);
+        let label = Some(label.as_str());
+        let shader_module = self.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label,
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(shader_str)),
+        });
+
+        let layout_entries: Vec<wgpu::BindGroupLayoutEntry> = bindings.iter().enumerate().map(|(i, b)| {
+            wgpu::BindGroupLayoutEntry {
+                binding: i as u32,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: match b.kind {
+                        BindingKind::StorageRead => wgpu::BufferBindingType::Storage { read_only: true },
+                        BindingKind::StorageReadWrite => wgpu::BufferBindingType::Storage { read_only: false },
+                        BindingKind::Uniform => wgpu::BufferBindingType::Uniform,
                     },
-                ],
-            });
-    
-            // comand encoderは一つか複数のパイプラインを実行する
-            let mut encoder = w.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: None,
-            });
-            {
-                let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-                    label: None,
-                    // ない
-                    // timestamp_writes: None,
-                });
-                cpass.set_pipeline(&compute_pileline);
-                cpass.set_bind_group(0, &bind_group, &[]);
-                cpass.insert_debug_marker("compute collatz iterations");
-                cpass.dispatch_workgroups(dispatch.0, dispatch.1, dispatch.2);
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
             }
-            
-            // encoderの中身を送信
-            w.queue.submit(Some(encoder.finish()));
-            
-            
-        })
+        }).collect();
+        let bind_group_layout = self.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label,
+            entries: &layout_entries,
+        });
+
+        let push_constant_ranges = if push_constant_size > 0 {
+            vec![wgpu::PushConstantRange { stages: wgpu::ShaderStages::COMPUTE, range: 0..push_constant_size }]
+        } else {
+            vec![]
+        };
+        let pipeline_layout = self.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label,
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &push_constant_ranges,
+        });
 
+        let compute_pipeline = self.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label,
+            layout: Some(&pipeline_layout),
+            module: &shader_module,
+            entry_point: "main",
+            // このバージョンではないっぽい
+            // constantas: &Default::default(),
+        });
+
+        let pipeline = Arc::new((bind_group_layout, compute_pipeline));
+        self.pipeline_cache.write().unwrap().insert(key, pipeline.clone());
+        pipeline
     }
-    
-    fn execute_4(
+    // 行列積 (lhs, rhs, out) + MatmulParams + epilogueのbias, residual
+    // templateの{{PARAMS}}とepilogueはここで埋める。push constantsが使えなければbinding(3)のuniformに書き込む
+    // queue.write_bufferは次のsubmitの前に実行されるので，前の呼び出しのsubmitが読み終わる前に上書きされることはない
+    // (なのでdynamic offsetで領域を分けなくても1つのbufferを使い回せる)
+    pub(crate) fn execute_matmul(
+        &self,
+        lhs: &wgpu::Buffer,
+        rhs: &wgpu::Buffer,
+        out: &wgpu::Buffer,
+        params: &MatmulParams,
+        kernel: MatmulKernel,
+        epilogue: &Epilogue,
+    ) {
+        let MatmulKernel { template, template_params, dispatch } = kernel;
+        let push_constants = self.supports_push_constants();
+        let template_params = matmul_template_params(template_params, push_constants, epilogue);
+        let shader_name = template.instance_name(&template_params);
+        let shader_str = template.instantiate(&template_params).unwrap_or_else(|e| panic!("{}", e));
+        let words = params.to_words();
+
+        // epilogueのbufferはMatmulParamsの後(epilogue::extra_binding)
+        let mut bindings = vec![Binding::read(lhs), Binding::read(rhs), Binding::read_write(out)];
+        let extra = epilogue.buffers();
+        if push_constants {
+            bindings.extend(extra.iter().map(|b| Binding::read(b)));
+            self.execute_n(template.name, &bindings, bytemuck::cast_slice(&words), &shader_name, &shader_str, dispatch)
+        } else {
+            self.write_buffer(&self.matmul_params, 0, bytemuck::cast_slice(&words));
+            bindings.push(Binding::uniform(&self.matmul_params));
+            bindings.extend(extra.iter().map(|b| Binding::read(b)));
+            self.execute_n(template.name, &bindings, &[], &shader_name, &shader_str, dispatch)
+        }
+    }
+    // 入力2つ，出力1つ
+    pub(crate) fn execute_3(
+        &self,
         buf1: &wgpu::Buffer,
         buf2: &wgpu::Buffer,
         buf3: &wgpu::Buffer,
-        buf4: &wgpu::Buffer,
         shader_name: &str,
-        shader_str: &str, // include_str!して実行ファイルを１つにするために必要
+        shader_str: &str,
         dispatch: (u32, u32, u32),
     ) {
-        DEVICE.with(|w| {
-            // まずキャッシュを読み取り専用で確認
-            /*let shader_module = {
-                {
-                    let mut shader_cache = w.shader_cache.write().unwrap();
-                    if shader_cache.contains_key(shader_name) {
-                        // キャッシュに存在する場合、何もせずスコープを抜ける
-                    
-                    } else {
-                        // キャッシュに存在しない場合、新しいShaderModuleを作成
-                        
-                        // 作成したShaderModuleをキャッシュに挿入
-                        shader_cache.insert(shader_name.to_string(), shader_module);
-                    }
-                }
-                {
-                    let shader_cache: RwLockReadGuard<HashMap<String, ShaderModule>> = w.shader_cache.read().unwrap();
-                    shader_cache.get(shader_name).unwrap()
-                }
-            };*/
-            let shader_module = w.device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                label: None,
-                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(shader_str)),
-            });
-
-            let compute_pileline = w.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                label: None,
-                layout: None,
-                module: &shader_module,
-                entry_point: "main",
-                // このバージョンではないっぽい
-                // constantas: &Default::default(),
-            });
-    
-            let bind_group_layout = compute_pileline.get_bind_group_layout(0);
-            let bind_group = w.device.create_bind_group(&wgpu::BindGroupDescriptor {
-                label: None,
-                // 
-                layout: &bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        // wgslからはbinding(0)で取れる
-                        binding: 0,
-                        // binding(0)の中身をさっき作成したbufferに指定
-                        resource: buf1.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: buf2.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 2,
-                        resource: buf3.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 3,
-                        resource: buf4.as_entire_binding(),
-                    },
-                ],
-            });
-    
-            // comand encoderは一つか複数のパイプラインを実行する
-            let mut encoder = w.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: None,
-            });
-            {
-                let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-                    label: None,
-                    // ない
-                    // timestamp_writes: None,
-                });
-                cpass.set_pipeline(&compute_pileline);
-                cpass.set_bind_group(0, &bind_group, &[]);
-                cpass.insert_debug_marker("compute collatz iterations");
-                cpass.dispatch_workgroups(dispatch.0, dispatch.1, dispatch.2);
-            }
-            
-            // encoderの中身を送信
-            w.queue.submit(Some(encoder.finish()));
-            
-            
-        });
+        let bindings = [Binding::read(buf1), Binding::read(buf2), Binding::read_write(buf3)];
+        self.execute_n(shader_name, &bindings, &[], shader_name, shader_str, dispatch)
     }
-    fn get(src: &wgpu::Buffer) -> Vec<f32> {
-        DEVICE.with(|w| {
-            // 
-            let staging_buffer = w.device.create_buffer(&wgpu::BufferDescriptor {
-                label: Some("staging buffer"),
-                size: src.size() as wgpu::BufferAddress,
-                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
-                mapped_at_creation: false,
-            });
-
-            let mut encoder = w.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: None,
-            });
-            // エンコーダにコピーを指示。たぶん前のbigin_compute_passが終わったら行われる。
-            // 処理結果が詰まったstorage_bufferはVRAM上にあり，それをCPUから見えるstaging_bufferに移す。
-            encoder.copy_buffer_to_buffer(&src, 0, &staging_buffer, 0, src.size() as u64);
+    // 行列積 (lhs, rhs, out, sizes)
+    pub(crate) fn execute_4(&self, buffers: [&wgpu::Buffer; 4], shader_name: &str, shader_str: &str, dispatch: (u32, u32, u32)) {
+        let [lhs, rhs, out, sizes] = buffers;
+        let bindings = [Binding::read(lhs), Binding::read(rhs), Binding::read_write(out), Binding::read(sizes)];
+        self.execute_n(shader_name, &bindings, &[], shader_name, shader_str, dispatch)
+    }
+    // 疎行列用 (row_ptr, col_idx, values, rhs, out, sizes)
+    pub(crate) fn execute_6(&self, buffers: [&wgpu::Buffer; 6], shader_name: &str, shader_str: &str, dispatch: (u32, u32, u32)) {
+        let [row_ptr, col_idx, values, rhs, out, sizes] = buffers;
+        let bindings = [
+            Binding::read(row_ptr), Binding::read(col_idx), Binding::read(values),
+            Binding::read(rhs), Binding::read_write(out), Binding::read(sizes),
+        ];
+        self.execute_n(shader_name, &bindings, &[], shader_name, shader_str, dispatch)
+    }
+    pub(crate) fn get(&self, src: &wgpu::Buffer) -> Vec<f32> {
+        self.get_as(src)
+    }
+    // u32のindexバッファなどf32以外を読み出す
+    pub(crate) fn get_as<T: bytemuck::Pod>(&self, src: &wgpu::Buffer) -> Vec<T> {
+        // 
+        let staging_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("staging buffer"),
+            size: src.size() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
 
-            // encoderの中身を送信
-            w.queue.submit(Some(encoder.finish()));
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: None,
+        });
+        // エンコーダにコピーを指示。たぶん前のbigin_compute_passが終わったら行われる。
+        // 処理結果が詰まったstorage_bufferはVRAM上にあり，それをCPUから見えるstaging_bufferに移す。
+        encoder.copy_buffer_to_buffer(&src, 0, &staging_buffer, 0, src.size() as u64);
 
+        // encoderの中身を送信
+        self.queue.submit(Some(encoder.finish()));
 
-            let buffer_slice = staging_buffer.slice(..);
-            let (sender, receiver) = flume::bounded(1);
-            buffer_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
 
-            // ブロッキング方式でデバイスポール
-            // ポールは実際にはイベントループか別スレッドでやるべきらしい
-            w.device.poll(wgpu::Maintain::Wait);
+        let buffer_slice = staging_buffer.slice(..);
+        let (sender, receiver) = flume::bounded(1);
+        buffer_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
 
-            // buffer_futureが読み出し可能になるまでawait
-            let result = if let Ok(Ok(())) = pollster::block_on(receiver.recv_async()) {
-                // get contents of buffer
-                let buffer_view = buffer_slice.get_mapped_range();
-                // bytes to u32
-                let result = bytemuck::cast_slice(&buffer_view).to_vec();
+        // ブロッキング方式でデバイスポール
+        // ポールは実際にはイベントループか別スレッドでやるべきらしい
+        self.device.poll(wgpu::Maintain::Wait);
 
-                // 現在のインタフェースでは，bufferをunmapする前に全てのviewがドロップしている必要がある。
-                drop(buffer_view); // delete pointer;
-                staging_buffer.unmap(); // pointer = NULL;
+        // buffer_futureが読み出し可能になるまでawait
+        let result = if let Ok(Ok(())) = pollster::block_on(receiver.recv_async()) {
+            // get contents of buffer
+            let buffer_view = buffer_slice.get_mapped_range();
+            // bytes to u32
+            let result = bytemuck::cast_slice(&buffer_view).to_vec();
 
-                result
-            } else {
-                panic!("failed to run compute on gpu!")
-            };
+            // 現在のインタフェースでは，bufferをunmapする前に全てのviewがドロップしている必要がある。
+            drop(buffer_view); // delete pointer;
+            staging_buffer.unmap(); // pointer = NULL;
 
             result
-        })
+        } else {
+            panic!("failed to run compute on gpu!")
+        };
+
+        result
         
     }
 }
 
+// 操作を集約して，RwLockの中身を外部に送信しなくていいようにしたい
+pub(crate) struct WgpuServer {} // 中身ないのでmodでもいいが一応structの形をとらせる
+impl WgpuServer {
+    fn new() -> Self {
+        Self {}
+    }
+    pub(crate) fn create_buffer(size: usize, label: Option<&str>) -> wgpu::Buffer {
+        DEVICE.with(|w| w.create_buffer(size, label))
+    }
+    pub(crate) fn create_buffer_init<T: bytemuck::Pod>(contents: &[T], label: Option<&str>) -> wgpu::Buffer {
+        DEVICE.with(|w| w.create_buffer_init(contents, label))
+    }
+    pub(crate) fn create_uniform_buffer(size: usize, label: Option<&str>) -> wgpu::Buffer {
+        DEVICE.with(|w| w.create_uniform_buffer(size, label))
+    }
+    pub(crate) fn execute_3(
+        buf1: &wgpu::Buffer,
+        buf2: &wgpu::Buffer,
+        buf3: &wgpu::Buffer,
+        shader_name: &str,
+        shader_str: &str,
+        dispatch: (u32, u32, u32),
+    ) {
+        DEVICE.with(|w| w.execute_3(buf1, buf2, buf3, shader_name, shader_str, dispatch))
+    }
+    pub(crate) fn execute_4(buffers: [&wgpu::Buffer; 4], shader_name: &str, shader_str: &str, dispatch: (u32, u32, u32)) {
+        DEVICE.with(|w| w.execute_4(buffers, shader_name, shader_str, dispatch))
+    }
+    pub(crate) fn write_buffer(buffer: &wgpu::Buffer, offset: u64, data: &[u8]) {
+        DEVICE.with(|w| w.write_buffer(buffer, offset, data))
+    }
+    pub(crate) fn clear_buffer(buffer: &wgpu::Buffer) {
+        DEVICE.with(|w| w.clear_buffer(buffer))
+    }
+    pub(crate) fn execute_n(
+        label: &str,
+        bindings: &[Binding],
+        push_constants: &[u8],
+        shader_name: &str,
+        shader_str: &str,
+        dispatch: (u32, u32, u32),
+    ) {
+        DEVICE.with(|w| w.execute_n(label, bindings, push_constants, shader_name, shader_str, dispatch))
+    }
+    pub(crate) fn supports_push_constants() -> bool {
+        DEVICE.with(|w| w.supports_push_constants())
+    }
+    pub(crate) fn supports_shader_f16() -> bool {
+        DEVICE.with(|w| w.supports_shader_f16())
+    }
+    pub(crate) fn execute_matmul(
+        lhs: &wgpu::Buffer,
+        rhs: &wgpu::Buffer,
+        out: &wgpu::Buffer,
+        params: &MatmulParams,
+        kernel: MatmulKernel,
+        epilogue: &Epilogue,
+    ) {
+        DEVICE.with(|w| w.execute_matmul(lhs, rhs, out, params, kernel, epilogue))
+    }
+    pub(crate) fn execute_6(buffers: [&wgpu::Buffer; 6], shader_name: &str, shader_str: &str, dispatch: (u32, u32, u32)) {
+        DEVICE.with(|w| w.execute_6(buffers, shader_name, shader_str, dispatch))
+    }
+    pub(crate) fn get(src: &wgpu::Buffer) -> Vec<f32> {
+        DEVICE.with(|w| w.get(src))
+    }
+    pub(crate) fn adapter_name() -> String {
+        DEVICE.with(|w| w.adapter_name().to_string())
+    }
+    pub(crate) fn limits() -> wgpu::Limits {
+        DEVICE.with(|w| w.limits())
+    }
+    pub(crate) fn wait() {
+        DEVICE.with(|w| w.wait())
+    }
+    pub(crate) fn get_as<T: bytemuck::Pod>(src: &wgpu::Buffer) -> Vec<T> {
+        DEVICE.with(|w| w.get_as(src))
+    }
+}
+
 
-#[derive(Eq, PartialEq, Clone)]
+#[derive(Eq, PartialEq, Clone, Debug)]
 pub enum Shape {
     //D1(usize),
     D2(usize, usize),
+    // (batch, rows, cols)。行列がbatch個並んでいる
+    D3(usize, usize, usize),
 }
 impl Shape {
-    fn to_string(&self) -> String {
-        if let Self::D2(i, j) = self {
-            let s = format!("Shape::D2({}, {})",i, j);
-            return s;
-        } else {
-            unimplemented!()
-        };
+    pub(crate) fn to_string(&self) -> String {
+        match self {
+            Self::D2(i, j) => format!("Shape::D2({}, {})", i, j),
+            Self::D3(b, i, j) => format!("Shape::D3({}, {}, {})", b, i, j),
+        }
     }
-    fn size(&self) -> usize {
-        if let Self::D2(i, j) = self {
-            return i*j;
-        } else {
-            unimplemented!()
-        };
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            Self::D2(i, j) => i * j,
+            Self::D3(b, i, j) => b * i * j,
+        }
+    }
+    // 行列としてしか使えないところ用。(rows, cols)
+    pub(crate) fn d2(&self) -> (usize, usize) {
+        match self {
+            Self::D2(i, j) => (*i, *j),
+            _ => panic!("expected a matrix (Shape::D2), but {}", self.to_string()),
+        }
+    }
+    // 一番内側の次元
+    pub(crate) fn cols(&self) -> usize {
+        match self {
+            Self::D2(_, j) | Self::D3(_, _, j) => *j,
+        }
     }
 }
 
@@ -370,12 +652,8 @@ pub struct RawGf32 {
 }
 impl RawGf32 {
     // internal
-    fn _new_empty(shape: Shape, label: Option<&str>) -> Self {
-        let size = if let Shape::D2(i, j) = shape {
-            i * j * 4 // f32 is 4 Byte
-        } else {
-            panic!();
-        };
+    pub(crate) fn _new_empty(shape: Shape, label: Option<&str>) -> Self {
+        let size = shape.size() * 4; // f32 is 4 Byte
 
         let buffer = WgpuServer::create_buffer(size, label);
         
@@ -401,147 +679,241 @@ impl RawGf32 {
         self.shape.size() * 4
     }
 
+    pub fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub(crate) fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    // CPU側に読み出す（row-major）
+    pub fn to_vec(&self) -> Vec<f32> {
+        WgpuServer::get(&self.buffer)
+    }
+
+    // (M, K, N)
+    pub(crate) fn matmul_sizes(&self, other: &Self) -> (usize, usize, usize) {
+        let ((m, k), (k2, n)) = (self.shape.d2(), other.shape.d2());
+        if k != k2 {
+            panic!("incompatible matrix size, self.shape: {}, other.shape: {}", self.shape.to_string(), other.shape.to_string());
+        }
+        (m, k, n)
+    }
+
     pub fn matmul(&self, other: &Self) -> Self {
-        // 行列積の結果のサイズとシェーダのためのサイズ情報
-        let (reuslt_shape, sizes_info) = {
-            if let Shape::D2(i, j) = self.shape {
-                if let Shape::D2(k, l) = other.shape {
-                    if j != k {
-                        panic!("incompatible matrix size");
-                    }
-                    (Shape::D2(i, l), [i as u32, j as u32, l as u32])
-                } else {
-                    panic!()
-                }
-            } else {
-                panic!()
-            }
-        };
-        // サイズ情報のバッファ [u32; 3]
-        let size_info_buffer = DEVICE.with(|w| {
-            let b = w.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("sizes info"),
-                contents: bytemuck::cast_slice(&sizes_info),
-                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-            });
-            b
-        });
+        let (m, k, n) = self.matmul_sizes(other);
+        let result = Self::_new_empty(Shape::D2(m, n), Some("result"));
+        self.gemm_kernel(other, &MatmulParams::new(m, k, n), &Epilogue::default(), &result);
+        result
+    }
+
+    // 結果をoutに書く（outを使い回す）
+    pub fn matmul_into(&self, other: &Self, out: &mut Self) {
+        self.gemm(other, 1.0, 0.0, out);
+    }
+
+    // out = alpha * self * other + beta * out
+    // beta == 0ならoutの中身は読まない
+    pub fn gemm(&self, other: &Self, alpha: f32, beta: f32, out: &mut Self) {
+        self.gemm_fused(other, alpha, beta, &Epilogue::default(), out);
+    }
+
+    // out = epilogue(alpha * self * other + beta * out)。epilogue.scaleは使わずalphaを使う
+    pub fn gemm_fused(&self, other: &Self, alpha: f32, beta: f32, epilogue: &Epilogue, out: &mut Self) {
+        let (m, k, n) = self.matmul_sizes(other);
+        if out.shape != Shape::D2(m, n) {
+            panic!("gemm: out.shape must be {}, but {}", Shape::D2(m, n).to_string(), out.shape.to_string());
+        }
+        epilogue.check(m, n);
+        let params = MatmulParams { alpha, beta, ..MatmulParams::new(m, k, n) };
+        self.gemm_kernel(other, &params, epilogue, out);
+    }
+
+    // out = alpha * op(self) * op(other) + beta * out。op(X)はtransならX^T
+    // 転置したものを作らずに読み方を変える（autogradの逆伝播用）
+    pub fn gemm_trans(&self, trans_self: bool, other: &Self, trans_other: bool, alpha: f32, beta: f32, out: &mut Self) {
+        let ((r1, c1), (r2, c2)) = (self.shape.d2(), other.shape.d2());
+        let (m, k) = if trans_self { (c1, r1) } else { (r1, c1) };
+        let (k2, n) = if trans_other { (c2, r2) } else { (r2, c2) };
+        if k != k2 {
+            panic!("incompatible matrix size, self.shape: {} (trans: {}), other.shape: {} (trans: {})",
+                self.shape.to_string(), trans_self, other.shape.to_string(), trans_other);
+        }
+        if out.shape != Shape::D2(m, n) {
+            panic!("gemm_trans: out.shape must be {}, but {}", Shape::D2(m, n).to_string(), out.shape.to_string());
+        }
+        // strideは保存されている行列の列数
+        let params = MatmulParams { lhs_stride: c1 as u32, rhs_stride: c2 as u32, alpha, beta, ..MatmulParams::new(m, k, n) };
+        let template_params = TemplateParams::new().set("TRANS_LHS", trans_self).set("TRANS_RHS", trans_other);
+        // xが列，yとzが行(matmul_trans.wgslは8x8)
+        let dispatch = Self::split_rows_dispatch(m, n, 8);
+        WgpuServer::execute_matmul(
+            &self.buffer, &other.buffer, &out.buffer, &params,
+            MatmulKernel::new(&MATMUL_TRANS, template_params, dispatch), &Epilogue::default(),
+        );
+    }
+
+    // 大きさを見てカーネルを選ぶ
+    fn gemm_kernel(&self, other: &Self, params: &MatmulParams, epilogue: &Epilogue, out: &Self) {
+        let (m, k, n) = (params.m as usize, params.k as usize, params.n as usize);
+        // どちらかが1の場合はタイル化カーネルではなくGEMVを使う（GEMVはepilogueなし）
+        // (M, K) x (K, 1)
+        if n == 1 && epilogue.is_identity() {
+            WgpuServer::execute_matmul(
+                &self.buffer, &other.buffer, &out.buffer, params,
+                MatmulKernel::new(&GEMV, TemplateParams::new(), Self::gemv_dispatch(m)), epilogue,
+            );
+            return;
+        }
+        // (1, K) x (K, N) = ((K, N)^T x (K, 1))^T
+        if m == 1 && epilogue.is_identity() {
+            let params = MatmulParams { m: params.k, k: params.n, lhs_stride: params.rhs_stride, ..*params };
+            WgpuServer::execute_matmul(
+                &other.buffer, &self.buffer, &out.buffer, &params,
+                MatmulKernel::new(&GEMV_T, TemplateParams::new(), (n.div_ceil(64) as u32, 1, 1)), epilogue,
+            );
+            return;
+        }
+
+        let config = TileConfig::default();
+        if config.fits(m, k, n) {
+            WgpuServer::execute_matmul(
+                &self.buffer, &other.buffer, &out.buffer, params,
+                MatmulKernel::new(&VECTORIZE, config.params(), config.dispatch(m, n)), epilogue,
+            );
+            return;
+        }
+
+        // タイルに収まらない大きさは端を確かめる16x16のカーネルで計算する
+        WgpuServer::execute_matmul(
+            &self.buffer, &other.buffer, &out.buffer, params,
+            MatmulKernel::new(&MATMUL_NAIVE, TemplateParams::new(), Self::split_rows_dispatch(m, n, 16)), epilogue,
+        );
+    }
+
+    // matmul.wgslは16x16。xが列，yが行で，行のworkgroupが1次元の上限を超える分はzに回す
+    fn split_rows_dispatch(m: usize, n: usize, wg: usize) -> (u32, u32, u32) {
+        let max = WgpuServer::limits().max_compute_workgroups_per_dimension as usize;
+        let (col_groups, row_groups) = (n.div_ceil(wg), m.div_ceil(wg).max(1));
+        if col_groups > max {
+            panic!("matmul: N = {} is too large for dispatch (max {} workgroups per dimension)", n, max);
+        }
+        let y = row_groups.min(max);
+        (col_groups as u32, y as u32, row_groups.div_ceil(y) as u32)
+    }
+
+    // (M, K, N)ごとにautotuneした(キャッシュ済みの)タイルで計算する
+    pub fn matmul_tuned(&self, other: &Self) -> Self {
+        let (m, k, n) = self.matmul_sizes(other);
+        let config = autotune::autotune(m, k, n);
+        self.matmul_with(other, &config)
+    }
+
+    // タイルの大きさを指定して計算する。M % BM == 0, N % BN == 0, K % BK == 0であること
+    pub fn matmul_with(&self, other: &Self, config: &TileConfig) -> Self {
+        let (m, k, n) = self.matmul_sizes(other);
+        if !config.fits(m, k, n) {
+            panic!("{:?} does not fit M = {}, K = {}, N = {}", config, m, k, n);
+        }
+
         // 結果のバッファ確保
-        let result = Self::_new_empty(reuslt_shape, Some("result"));
+        let result = Self::_new_empty(Shape::D2(m, n), Some("result"));
 
-        // tile size = BM = BN
-        let tile_size = 32;
-        //println!("tile_size = {}, but sizes_info[0] = {}, sizes_info[2] = {}", tile_size, sizes_info[0], sizes_info[2]);
-        WgpuServer::execute_4(
+        WgpuServer::execute_matmul(
             &self.buffer,
             &other.buffer,
             &result.buffer,
-            &size_info_buffer,
-            //"matmul.wgsl",
-            //include_str!("./matmul.wgsl"),
-            //(sizes_info[0] as u32, sizes_info[2] as u32, 1)
-            "3shared.wgsl",
-            include_str!("./6vectorize.wgsl"),
-            (sizes_info[0] as u32 / tile_size, sizes_info[2] as u32 / tile_size, 1)
+            &MatmulParams::new(m, k, n),
+            MatmulKernel::new(&VECTORIZE, config.params(), config.dispatch(m, n)),
+            &Epilogue::default()
         );
 
-        return  result;
+        result
     }
 
-    pub fn add(&self, other: &Self) -> Self {
-        if self.shape != other.shape {
-            panic!("size unmatch, self.shape: {}, other.shape: {}", self.shape.to_string(), other.shape.to_string());
-        }
-        let out = Self::_new_empty(self.shape.clone(), Some("add out"));
-
-        let tile_size = 16;
-        let (dispatch_x, dispatch_y) = if let Shape::D2(x, y) = self.shape {
-            let dispatch_x = if x < tile_size {
-                1
-            } else {
-                (x / tile_size) as u32
-            };
-            let dispatch_y = if y < tile_size {
-                1
-            } else {
-                (y / tile_size) as u32
-            };
-            (dispatch_x, dispatch_y)
+    // 1ワークグループ = 1行。1次元のdispatch上限を超える分はyに回す
+    fn gemv_dispatch(m: usize) -> (u32, u32, u32) {
+        let max = WgpuServer::limits().max_compute_workgroups_per_dimension as usize;
+        if m <= max {
+            (m as u32, 1, 1)
         } else {
-            unimplemented!()
-        };
-        WgpuServer::execute_3(
+            let y = m.div_ceil(max);
+            if y > max {
+                panic!("gemv: M = {} is too large for dispatch (max {} workgroups per dimension)", m, max);
+            }
+            (max as u32, y as u32, 1)
+        }
+    }
+
+    // y = A x。xはShape::D2(K, 1)でもShape::D2(1, K)でもよい
+    pub fn gemv(&self, x: &Self) -> Self {
+        let (m, k) = self.shape.d2();
+        if x.shape.size() != k {
+            panic!("incompatible matrix size, self.shape: {}, x.shape: {}", self.shape.to_string(), x.shape.to_string());
+        }
+        let result = Self::_new_empty(Shape::D2(m, 1), Some("gemv out"));
+
+        WgpuServer::execute_matmul(
             &self.buffer,
-            &other.buffer,
-            &out.buffer,
-            "add.wgsl",
-            include_str!("./add.wgsl"),
-            (dispatch_x, dispatch_y, 1)
+            &x.buffer,
+            &result.buffer,
+            &MatmulParams::new(m, k, 1),
+            MatmulKernel::new(&GEMV, TemplateParams::new(), Self::gemv_dispatch(m)),
+            &Epilogue::default()
         );
 
-        return out;
+        result
     }
 
-    pub fn sub(&self, other: &Self) -> Self {
-        if self.shape != other.shape {
-            panic!("size unmatch, self.shape: {}, other.shape: {}", self.shape.to_string(), other.shape.to_string());
-        }
-        let out = Self::_new_empty(self.shape.clone(), Some("add out"));
-
-        let tile_size = 16;
-        let (dispatch_x, dispatch_y) = if let Shape::D2(x, y) = self.shape {
-            let dispatch_x = if x < tile_size {
-                1
-            } else {
-                (x / tile_size) as u32
-            };
-            let dispatch_y = if y < tile_size {
-                1
-            } else {
-                (y / tile_size) as u32
-            };
-            (dispatch_x, dispatch_y)
-        } else {
-            unimplemented!()
-        };
-        
-        WgpuServer::execute_3(
+    // y = A^T x。xはShape::D2(M, 1)でもShape::D2(1, M)でもよい。結果はShape::D2(K, 1)
+    pub fn gemv_t(&self, x: &Self) -> Self {
+        let (m, k) = self.shape.d2();
+        if x.shape.size() != m {
+            panic!("incompatible matrix size, self.shape: {}, x.shape: {}", self.shape.to_string(), x.shape.to_string());
+        }
+        let result = Self::_new_empty(Shape::D2(k, 1), Some("gemv_t out"));
+
+        // 1ワークグループ = 出力64要素
+        WgpuServer::execute_matmul(
             &self.buffer,
-            &other.buffer,
-            &out.buffer,
-            "sub.wgsl",
-            include_str!("./sub.wgsl"),
-            (dispatch_x, dispatch_y, 1)
+            &x.buffer,
+            &result.buffer,
+            &MatmulParams::new(m, k, 1),
+            MatmulKernel::new(&GEMV_T, TemplateParams::new(), (k.div_ceil(64) as u32, 1, 1)),
+            &Epilogue::default()
         );
 
-        return out;
+        result
     }
 
-    pub fn print_1(&self) {
-        let result = WgpuServer::get(&self.buffer);
+    // 要素ごと。計算はelementwise.rs
+    pub fn add(&self, other: &Self) -> Self {
+        let mut out = Self::_new_empty(self.shape.clone(), Some("add out"));
+        self.add_into(other, &mut out);
+        out
+    }
 
-        // 出力する
-        println!("shape: {}, body[0]: {:?}", self.shape.to_string(), result[0]);
+    pub fn sub(&self, other: &Self) -> Self {
+        let mut out = Self::_new_empty(self.shape.clone(), Some("sub out"));
+        self.sub_into(other, &mut out);
+        out
+    }
 
-        /*
-        let size = 64;
-        for chunk in result.chunks(size).into_iter() {
-            println!("{:?}", chunk);
-            //break;
-        } */
+    // 大きいものは端だけ出す(display.rs)
+    pub fn print_1(&self) {
+        println!("{}", self);
     }
+    // 省略せずに全部出す
     pub fn print_all(&self) {
-        let result = WgpuServer::get(&self.buffer);
-
-        // 出力する
-        //println!("shape: {}, body[0]: {:?}", self.shape.to_string(), result);
-
-        
-        let size = 32;
-        for chunk in result.chunks(size).into_iter() {
-            println!("{:?}", chunk);
-            //break;
-        } /**/
+        let options = display::print_options();
+        display::set_print_options(PrintOptions { threshold: usize::MAX, ..options });
+        println!("{}", self);
+        display::set_print_options(options);
     }
 }
 
@@ -552,8 +924,8 @@ pub fn run() {
     
     // 計算データをバッファに確保
     let s = std::time::Instant::now();
-    let a = RawGf32::new_init(Shape::D2(2, 2), &vec![1.0; 4], Some("a"));
-    let b = RawGf32::new_init(Shape::D2(2, 2), &vec![2.0; 4], Some("b"));
+    let a = RawGf32::full(Shape::D2(2, 2), 1.0, Some("a"));
+    let b = RawGf32::full(Shape::D2(2, 2), 2.0, Some("b"));
     println!("1, {:?}", s.elapsed());
 
     let add = a.add(&b);
@@ -573,7 +945,7 @@ pub fn run() {
     println!("3, {:?}", s.elapsed());
 
     // 連続した計算
-    let d = RawGf32::new_init(Shape::D2(2, 2), &vec![3.0; 4], Some("d"));
+    let d = RawGf32::full(Shape::D2(2, 2), 3.0, Some("d"));
     // staging bufferを利用してデータを読み出し
     let e = c.matmul(&d);
 
@@ -594,8 +966,8 @@ pub fn run() {
         
         // 1回計算
         
-        let a = RawGf32::new_init(Shape::D2(size, size), &vec![1.0; size*size], Some("a"));
-        let b = RawGf32::new_init(Shape::D2(size, size), &vec![2.0; size*size], Some("b"));
+        let a = RawGf32::full(Shape::D2(size, size), 1.0, Some("a"));
+        let b = RawGf32::full(Shape::D2(size, size), 2.0, Some("b"));
 
         let s = std::time::Instant::now();
         let c = a.matmul(&b);
@@ -615,10 +987,10 @@ pub fn run() {
     /*
     // 2回計算
     let s = std::time::Instant::now();
-    let a = RawGf32::new_init(Shape::D2(size, size), &vec![1.0; size*size], Some("a"));
-    let b = RawGf32::new_init(Shape::D2(size, size), &vec![2.0; size*size], Some("b"));
+    let a = RawGf32::full(Shape::D2(size, size), 1.0, Some("a"));
+    let b = RawGf32::full(Shape::D2(size, size), 2.0, Some("b"));
 
-    let b2 = RawGf32::new_init(Shape::D2(size, size), &vec![2.0; size*size], Some("b"));
+    let b2 = RawGf32::full(Shape::D2(size, size), 2.0, Some("b"));
 
     let c = a.matmul(&b);
     let e = c.matmul(&b2);
@@ -630,4 +1002,215 @@ pub fn run() {
     e.print_1();
     println!("連続２回，{:?}", s.elapsed());
      */
-}
\ No newline at end of file
+}
+
+// GEMVの確認。片方が1のときmatmulがgemv / gemv_tに切り替わる
+pub fn run_gemv() {
+    let (m, k) = (70, 300);
+    let a_values: Vec<f32> = (0..m * k).map(|i| ((i % 13) as f32 - 6.0) * 0.5).collect();
+    let x_values: Vec<f32> = (0..k).map(|i| (i % 7) as f32 - 3.0).collect();
+    let v_values: Vec<f32> = (0..m).map(|i| (i % 5) as f32 - 2.0).collect();
+    let a = RawGf32::new_init(Shape::D2(m, k), &a_values, Some("a"));
+
+    // A x
+    let x = RawGf32::new_init(Shape::D2(k, 1), &x_values, Some("x"));
+    let y = a.matmul(&x);
+    let mut cpu = vec![0.0; m];
+    for i in 0..m {
+        for j in 0..k {
+            cpu[i] += a_values[i * k + j] * x_values[j];
+        }
+    }
+    if y.shape != Shape::D2(m, 1) || y.to_vec() != cpu {
+        panic!("gemv mismatch, gpu: {:?}, cpu: {:?}", y.to_vec(), cpu);
+    }
+
+    // v^T A
+    let v = RawGf32::new_init(Shape::D2(1, m), &v_values, Some("v"));
+    let y = v.matmul(&a);
+    let mut cpu = vec![0.0; k];
+    for i in 0..m {
+        for j in 0..k {
+            cpu[j] += v_values[i] * a_values[i * k + j];
+        }
+    }
+    if y.shape != Shape::D2(1, k) || y.to_vec() != cpu {
+        panic!("gemv_t mismatch, gpu: {:?}, cpu: {:?}", y.to_vec(), cpu);
+    }
+
+    println!("gemv ok");
+}
+
+
+// execute_nの確認。storageの一部(offset, size)とuniformを混ぜる
+pub fn run_execute_n() {
+    let shader = "
+@group(0) @binding(0)
+var<storage, read> input: array<f32>;
+@group(0) @binding(1)
+var<uniform> params: vec4<f32>;
+@group(0) @binding(2)
+var<storage, read_write> output: array<f32>;
+
+@compute @workgroup_size(64, 1, 1)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x < arrayLength(&output)) {
+        output[id.x] = input[id.x] * params.x + params.y;
+    }
+}";
+    // offset_alignment(256Byte)ごとに64個
+    let input: Vec<f32> = (0..192).map(|i| i as f32).collect();
+    let input = WgpuServer::create_buffer_init(&input, Some("input"));
+    let output = WgpuServer::create_buffer(64 * 4, Some("output"));
+    let params = WgpuServer::create_uniform_buffer(16, Some("params"));
+    WgpuServer::write_buffer(&params, 0, bytemuck::cast_slice(&[2.0f32, 1.0, 0.0, 0.0]));
+
+    // inputの64..128だけを見せる
+    let bindings = [
+        Binding::read(&input).range(256, 256),
+        Binding::uniform(&params),
+        Binding::read_write(&output),
+    ];
+    WgpuServer::execute_n("scale and shift", &bindings, &[], "execute_n_check", shader, (1, 1, 1));
+    let result = WgpuServer::get(&output);
+    let expected: Vec<f32> = (64..128).map(|i| i as f32 * 2.0 + 1.0).collect();
+    if result != expected {
+        panic!("execute_n mismatch: {:?}", result);
+    }
+
+    // パイプラインはキャッシュされるのでuniformを書き換えるだけ
+    WgpuServer::write_buffer(&params, 0, bytemuck::cast_slice(&[-1.0f32, 0.0, 0.0, 0.0]));
+    WgpuServer::execute_n("negate", &bindings, &[], "execute_n_check", shader, (1, 1, 1));
+    if WgpuServer::get(&output)[0] != -64.0 {
+        panic!("execute_n with updated uniform mismatch");
+    }
+
+    if WgpuServer::supports_push_constants() {
+        let shader = "
+struct Params {
+    scale: f32,
+}
+var<push_constant> pc: Params;
+@group(0) @binding(0)
+var<storage, read_write> output: array<f32>;
+
+@compute @workgroup_size(64, 1, 1)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    output[id.x] = f32(id.x) * pc.scale;
+}";
+        WgpuServer::execute_n("push constants", &[Binding::read_write(&output)], bytemuck::cast_slice(&[3.0f32]), "push_constant_check", shader, (1, 1, 1));
+        if WgpuServer::get(&output)[2] != 6.0 {
+            panic!("push constants mismatch");
+        }
+    } else {
+        println!("push constants are not supported on {}", WgpuServer::adapter_name());
+    }
+    println!("execute_n ok");
+}
+
+// gemmの確認。カーネルは4種類(gemv, gemv_t, タイル, ナイーブ)
+// 縦長の行列積(K = 2)を1要素ずつ確かめる
+fn check_tall(a_values: &[f32], b_values: &[f32], m: usize, k: usize, n: usize, c: &[f32], label: &str) {
+    for i in 0..m {
+        for j in 0..n {
+            let expected = a_values[i * k] * b_values[j] + a_values[i * k + 1] * b_values[n + j];
+            if c[i * n + j] != expected {
+                panic!("tall {} mismatch at ({}, {}): {} != {}", label, i, j, c[i * n + j], expected);
+            }
+        }
+    }
+}
+
+// gemm_trans(matmul_trans.wgsl)で，workgroupの行数がyの上限を超えるもの
+fn check_tall_gemm_trans() {
+    let (m, k, n) = (65535 * 8 + 40, 2, 3);
+    let a_values: Vec<f32> = (0..m * k).map(|i| (i % 7) as f32 - 3.0).collect();
+    let b_values = vec![1.0, 2.0, 3.0, -1.0, 0.5, 0.0];
+    let a = RawGf32::new_init(Shape::D2(m, k), &a_values, Some("a"));
+    let b = RawGf32::new_init(Shape::D2(k, n), &b_values, Some("b"));
+    let b_t_values = vec![1.0, -1.0, 2.0, 0.5, 3.0, 0.0];
+    let b_t = RawGf32::new_init(Shape::D2(n, k), &b_t_values, Some("b_t"));
+    for (rhs, trans_rhs) in [(&b, false), (&b_t, true)] {
+        let mut c = RawGf32::zeros(Shape::D2(m, n), Some("c"));
+        a.gemm_trans(false, rhs, trans_rhs, 1.0, 0.0, &mut c);
+        check_tall(&a_values, &b_values, m, k, n, &c.to_vec(), "gemm_trans");
+    }
+}
+
+pub fn run_gemm() {
+    for (m, k, n) in [(64, 32, 1), (1, 32, 64), (64, 32, 64), (3, 5, 7)] {
+        let a_values: Vec<f32> = (0..m * k).map(|i| (i % 7) as f32 - 3.0).collect();
+        let b_values: Vec<f32> = (0..k * n).map(|i| (i % 5) as f32 * 0.5).collect();
+        let c_values: Vec<f32> = (0..m * n).map(|i| (i % 3) as f32).collect();
+        let a = RawGf32::new_init(Shape::D2(m, k), &a_values, Some("a"));
+        let b = RawGf32::new_init(Shape::D2(k, n), &b_values, Some("b"));
+
+        let mut ab = vec![0.0; m * n];
+        for i in 0..m {
+            for l in 0..k {
+                for j in 0..n {
+                    ab[i * n + j] += a_values[i * k + l] * b_values[l * n + j];
+                }
+            }
+        }
+
+        if a.matmul(&b).to_vec() != ab {
+            panic!("matmul mismatch for ({}, {}, {})", m, k, n);
+        }
+
+        let (alpha, beta) = (2.0, -1.0);
+        let mut c = RawGf32::new_init(Shape::D2(m, n), &c_values, Some("c"));
+        a.gemm(&b, alpha, beta, &mut c);
+        let expected: Vec<f32> = ab.iter().zip(c_values.iter()).map(|(x, y)| alpha * x + beta * y).collect();
+        if c.to_vec() != expected {
+            panic!("gemm mismatch for ({}, {}, {})", m, k, n);
+        }
+
+        // beta == 0ならoutのNaNは無視される
+        let mut c = RawGf32::full(Shape::D2(m, n), f32::NAN, Some("nan"));
+        a.gemm(&b, 1.0, 0.0, &mut c);
+        if c.to_vec() != ab {
+            panic!("gemm with beta = 0 must not read out ({}, {}, {})", m, k, n);
+        }
+
+        // 転置して保存したものをgemm_transで戻す
+        let transpose = |values: &[f32], rows: usize, cols: usize| -> Vec<f32> {
+            (0..rows * cols).map(|i| values[(i % rows) * cols + i / rows]).collect()
+        };
+        let a_t = RawGf32::new_init(Shape::D2(k, m), &transpose(&a_values, m, k), Some("a_t"));
+        let b_t = RawGf32::new_init(Shape::D2(n, k), &transpose(&b_values, k, n), Some("b_t"));
+        for (lhs, trans_lhs, rhs, trans_rhs) in [(&a, false, &b, false), (&a_t, true, &b, false), (&a, false, &b_t, true), (&a_t, true, &b_t, true)] {
+            let mut c = RawGf32::new_init(Shape::D2(m, n), &c_values, Some("c"));
+            lhs.gemm_trans(trans_lhs, rhs, trans_rhs, alpha, beta, &mut c);
+            if c.to_vec() != expected {
+                panic!("gemm_trans ({}, {}) mismatch for ({}, {}, {})", trans_lhs, trans_rhs, m, k, n);
+            }
+        }
+    }
+
+    // 行のworkgroupが1次元の上限(65535)を超える縦長の行列はzにも分けてdispatchする
+    let (m, k, n) = (65535 * 16 + 40, 2, 3);
+    let a_values: Vec<f32> = (0..m * k).map(|i| (i % 7) as f32 - 3.0).collect();
+    let b_values = vec![1.0, 2.0, 3.0, -1.0, 0.5, 0.0];
+    let a = RawGf32::new_init(Shape::D2(m, k), &a_values, Some("a"));
+    let b = RawGf32::new_init(Shape::D2(k, n), &b_values, Some("b"));
+    check_tall(&a_values, &b_values, m, k, n, &a.matmul(&b).to_vec(), "matmul");
+    // gemm_transはworkgroupが8x8なので65535 * 8行を超えるとzに分ける
+    check_tall_gemm_trans();
+    println!("gemm ok (push constants: {})", WgpuServer::supports_push_constants());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 行のworkgroupが1次元の上限(65535)を超えてもgemm_transが計算できる
+    #[test]
+    fn tall_gemm_trans() {
+        if !adapter_available() {
+            return;
+        }
+        check_tall_gemm_trans();
+    }
+}
@@ -0,0 +1,67 @@
+// 転置つきの行列積 output = alpha * op(lhs) * op(rhs) + beta * output
+// op(X)はTRANS_LHS / TRANS_RHSのときX^T。逆伝播のdA = dC * B^T, dB = A^T * dCに使う
+// op(lhs): Matrix<f32, M, K>。TRANS_LHSなら保存されているのはMatrix<f32, K, M>
+@group(0) @binding(0)
+var<storage, read> lhs: array<f32>;
+// op(rhs): Matrix<f32, K, N>。TRANS_RHSなら保存されているのはMatrix<f32, N, K>
+@group(0) @binding(1)
+var<storage, read> rhs: array<f32>;
+// Matrix<f32, M, N>
+@group(0) @binding(2)
+var<storage, read_write> output: array<f32>;
+// メタデータ。push constantsが使えればvar<push_constant>，使えなければbinding(3)のuniform
+// output = alpha * lhs * rhs + beta * output
+struct MatmulParams {
+    m: u32,
+    k: u32,
+    n: u32,
+    // 保存されている行列の行の間隔（要素数）
+    lhs_stride: u32,
+    rhs_stride: u32,
+    out_stride: u32,
+    alpha: f32,
+    beta: f32,
+}
+{{PARAMS}}
+
+// 転置するかはRust側(RawGf32::gemm_trans)からテンプレートとして埋める
+const TRANS_LHS: bool = {{TRANS_LHS}};
+const TRANS_RHS: bool = {{TRANS_RHS}};
+
+fn lhs_at(row: u32, k: u32) -> f32 {
+    if (TRANS_LHS) {
+        return lhs[k * params.lhs_stride + row];
+    }
+    return lhs[row * params.lhs_stride + k];
+}
+
+fn rhs_at(k: u32, col: u32) -> f32 {
+    if (TRANS_RHS) {
+        return rhs[col * params.rhs_stride + k];
+    }
+    return rhs[k * params.rhs_stride + col];
+}
+
+// xが列，yが行。行のworkgroupが1次元の上限を超えたらzにも分ける(RawGf32::split_rows_dispatch)
+@compute @workgroup_size(8, 8, 1)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>
+) {
+    let col = global_id.x;
+    let row = global_id.y + global_id.z * num_workgroups.y * 8u;
+    if (row >= params.m || col >= params.n) {
+        return;
+    }
+
+    var sum: f32 = 0.0;
+    for (var k: u32 = 0u; k < params.k; k = k + 1u) {
+        sum = sum + lhs_at(row, k) * rhs_at(k, col);
+    }
+    let out_idx = row * params.out_stride + col;
+    var value = params.alpha * sum;
+    if (params.beta != 0.0) {
+        value += params.beta * output[out_idx];
+    }
+    output[out_idx] = value;
+}
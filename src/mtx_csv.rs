@@ -0,0 +1,421 @@
+use std::{fmt::Write as _, io, path::Path};
+use crate::matmul_structured2::{RawGf32, Shape, WgpuServer};
+use crate::sparse::SparseCooGf32;
+
+
+/*
+Matrix Market (.mtx) とCSVの読み書き
+
+Matrix Market (https://math.nist.gov/MatrixMarket/formats.html)
+    %%MatrixMarket matrix <coordinate|array> <real|integer|pattern> <general|symmetric|skew-symmetric>
+    % コメント
+    coordinate: "M N NNZ" の後に "i j value" (1始まり)
+    array:      "M N" の後に値をcolumn-majorで並べる
+CSV
+    1行 = 行列の1行，カンマ区切り，ヘッダなし
+
+壊れた入力はpanicせずに行番号つきのio::ErrorKind::InvalidDataで返す
+*/
+
+fn invalid(line: usize, msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, msg))
+}
+
+// Matrix MarketもCSVも2次元だけ。Shape::D3は保存できない
+fn shape_d2(shape: &Shape) -> io::Result<(usize, usize)> {
+    match *shape {
+        Shape::D2(m, n) => Ok((m, n)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("only Shape::D2 can be saved, but {}", shape.to_string()))),
+    }
+}
+
+// Matrix Marketを読んだ結果。coordinateもarrayもここでは三つ組にそろえる（0始まり）
+pub(crate) struct MtxData {
+    pub rows: usize,
+    pub cols: usize,
+    pub row_idx: Vec<u32>,
+    pub col_idx: Vec<u32>,
+    pub values: Vec<f32>,
+}
+impl MtxData {
+    // max_lenは要素数の上限(GPUのbufferに載る数など)。coordinateのsize lineは大きな値を書けるので確かめる
+    fn to_dense(&self, max_len: usize) -> io::Result<Vec<f32>> {
+        let len = self.rows.checked_mul(self.cols).filter(|&len| len <= max_len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{}x{} is too large for a dense matrix", self.rows, self.cols)))?;
+        let mut dense = vec![0.0; len];
+        for ((&i, &j), &v) in self.row_idx.iter().zip(self.col_idx.iter()).zip(self.values.iter()) {
+            dense[i as usize * self.cols + j as usize] += v;
+        }
+        Ok(dense)
+    }
+}
+
+// 添字はu32で持つ
+fn to_u32(line: usize, index: usize) -> io::Result<u32> {
+    u32::try_from(index).map_err(|_| invalid(line, format!("index {} does not fit in u32", index)))
+}
+
+// arrayで列jの最初に書かれている行。対称なら下三角(i >= j)，歪対称なら対角を除く(i > j)
+fn first_stored_row(symmetry: &Symmetry, j: usize) -> usize {
+    match symmetry {
+        Symmetry::General => 0,
+        Symmetry::Symmetric => j,
+        Symmetry::SkewSymmetric => j + 1,
+    }
+}
+
+#[derive(PartialEq)]
+enum Symmetry {
+    General,
+    Symmetric,
+    SkewSymmetric,
+}
+
+pub(crate) fn parse_mtx(text: &str) -> io::Result<MtxData> {
+    let mut lines = text.lines().enumerate().map(|(i, l)| (i + 1, l));
+
+    // banner
+    let (_, banner) = lines.next().ok_or_else(|| invalid(1, "empty file".to_string()))?;
+    let banner: Vec<String> = banner.split_whitespace().map(|s| s.to_lowercase()).collect();
+    if banner.len() != 5 || banner[0] != "%%matrixmarket" || banner[1] != "matrix" {
+        return Err(invalid(1, format!("bad Matrix Market banner: {:?}", banner)));
+    }
+    let coordinate = match banner[2].as_str() {
+        "coordinate" => true,
+        "array" => false,
+        f => return Err(invalid(1, format!("unsupported format '{}'", f))),
+    };
+    let pattern = match banner[3].as_str() {
+        "real" | "integer" | "double" => false,
+        "pattern" if coordinate => true,
+        f => return Err(invalid(1, format!("unsupported field '{}'", f))),
+    };
+    let symmetry = match banner[4].as_str() {
+        "general" => Symmetry::General,
+        "symmetric" => Symmetry::Symmetric,
+        "skew-symmetric" => Symmetry::SkewSymmetric,
+        s => return Err(invalid(1, format!("unsupported symmetry '{}'", s))),
+    };
+
+    // コメントと空行を飛ばす
+    let mut lines = lines.filter(|(_, l)| !l.trim().is_empty() && !l.trim_start().starts_with('%'));
+
+    // size line
+    let (line_no, size_line) = lines.next().ok_or_else(|| invalid(1, "missing size line".to_string()))?;
+    let sizes = size_line.split_whitespace()
+        .map(|s| s.parse::<usize>().map_err(|_| invalid(line_no, format!("bad size '{}'", s))))
+        .collect::<io::Result<Vec<usize>>>()?;
+    let expected = if coordinate { 3 } else { 2 };
+    if sizes.len() != expected {
+        return Err(invalid(line_no, format!("size line needs {} numbers, got {}", expected, sizes.len())));
+    }
+    let (rows, cols) = (sizes[0], sizes[1]);
+    if symmetry != Symmetry::General && rows != cols {
+        return Err(invalid(line_no, format!("symmetric matrix must be square, got {}x{}", rows, cols)));
+    }
+    // 1つの値には少なくとも1文字と区切りが要るので，ファイルの大きさから書ける個数の上限がわかる
+    // size lineを信用してあふれたり巨大な確保をしたりしないように，ここで弾く
+    let max_entries = text.len().div_ceil(2);
+    let entries = if coordinate {
+        Some(sizes[2])
+    } else {
+        match symmetry {
+            Symmetry::General => rows.checked_mul(cols),
+            Symmetry::Symmetric => rows.checked_add(1).and_then(|r| rows.checked_mul(r)).map(|n| n / 2),
+            Symmetry::SkewSymmetric => rows.checked_mul(rows.saturating_sub(1)).map(|n| n / 2),
+        }
+    };
+    let entries = entries.filter(|&n| n <= max_entries)
+        .ok_or_else(|| invalid(line_no, format!("size {} is too large for a file of {} bytes", size_line.trim(), text.len())))?;
+
+    let mut data = MtxData { rows, cols, row_idx: vec![], col_idx: vec![], values: vec![] };
+    let mut push = |line_no: usize, i: usize, j: usize, v: f32| -> io::Result<()> {
+        let (i, j) = (to_u32(line_no, i)?, to_u32(line_no, j)?);
+        data.row_idx.push(i);
+        data.col_idx.push(j);
+        data.values.push(v);
+        // 対称の場合は下三角だけが書かれている
+        if i != j {
+            match symmetry {
+                Symmetry::General => {}
+                Symmetry::Symmetric => {
+                    data.row_idx.push(j);
+                    data.col_idx.push(i);
+                    data.values.push(v);
+                }
+                Symmetry::SkewSymmetric => {
+                    data.row_idx.push(j);
+                    data.col_idx.push(i);
+                    data.values.push(-v);
+                }
+            }
+        }
+        Ok(())
+    };
+
+    let parse_value = |line_no: usize, s: &str| -> io::Result<f32> {
+        s.parse::<f32>().map_err(|_| invalid(line_no, format!("bad value '{}'", s)))
+    };
+
+    if coordinate {
+        let nnz = entries;
+        let mut count = 0;
+        for (line_no, line) in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let needed = if pattern { 2 } else { 3 };
+            if fields.len() != needed {
+                return Err(invalid(line_no, format!("entry needs {} fields, got {}", needed, fields.len())));
+            }
+            let i = fields[0].parse::<usize>().map_err(|_| invalid(line_no, format!("bad row index '{}'", fields[0])))?;
+            let j = fields[1].parse::<usize>().map_err(|_| invalid(line_no, format!("bad column index '{}'", fields[1])))?;
+            if i == 0 || j == 0 || i > rows || j > cols {
+                return Err(invalid(line_no, format!("index ({}, {}) is out of {}x{} (1-based)", i, j, rows, cols)));
+            }
+            let v = if pattern { 1.0 } else { parse_value(line_no, fields[2])? };
+            push(line_no, i - 1, j - 1, v)?;
+            count += 1;
+        }
+        if count != nnz {
+            return Err(invalid(line_no, format!("expected {} entries, got {}", nnz, count)));
+        }
+    } else {
+        // arrayはcolumn-major。(i, j)は読んだ個数から進める
+        let next_column = |mut i: usize, mut j: usize| {
+            while i >= rows && j < cols {
+                j += 1;
+                i = first_stored_row(&symmetry, j);
+            }
+            (i, j)
+        };
+        let (mut i, mut j) = next_column(first_stored_row(&symmetry, 0), 0);
+        let mut count = 0;
+        for (line_no, line) in lines {
+            for s in line.split_whitespace() {
+                if count == entries {
+                    return Err(invalid(line_no, format!("too many values, expected {}", entries)));
+                }
+                push(line_no, i, j, parse_value(line_no, s)?)?;
+                count += 1;
+                (i, j) = next_column(i + 1, j);
+            }
+        }
+        if count != entries {
+            return Err(invalid(line_no, format!("expected {} values, got {}", entries, count)));
+        }
+    }
+
+    Ok(data)
+}
+
+pub(crate) fn parse_csv(text: &str) -> io::Result<(Shape, Vec<f32>)> {
+    let mut values = vec![];
+    let mut cols = None;
+    let mut rows = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row = line.split(',')
+            .map(|s| s.trim().parse::<f32>().map_err(|_| invalid(line_no, format!("bad value '{}'", s.trim()))))
+            .collect::<io::Result<Vec<f32>>>()?;
+        match cols {
+            None => cols = Some(row.len()),
+            Some(c) if c != row.len() => {
+                return Err(invalid(line_no, format!("expected {} columns, got {}", c, row.len())));
+            }
+            _ => {}
+        }
+        values.extend(row);
+        rows += 1;
+    }
+    let cols = cols.ok_or_else(|| invalid(1, "empty csv".to_string()))?;
+    Ok((Shape::D2(rows, cols), values))
+}
+
+impl RawGf32 {
+    // coordinate形式は密行列に展開する
+    pub fn load_mtx<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let data = parse_mtx(&text)?;
+        // 1つのbufferに載る要素数まで
+        let max_len = (WgpuServer::limits().max_buffer_size / 4) as usize;
+        Ok(RawGf32::new_init(Shape::D2(data.rows, data.cols), &data.to_dense(max_len)?, None))
+    }
+
+    // array real generalで保存する
+    pub fn save_mtx<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let (m, n) = shape_d2(self.shape())?;
+        let values = self.to_vec();
+        let mut text = String::new();
+        writeln!(text, "%%MatrixMarket matrix array real general").unwrap();
+        writeln!(text, "{} {}", m, n).unwrap();
+        for j in 0..n {
+            for i in 0..m {
+                writeln!(text, "{}", values[i * n + j]).unwrap();
+            }
+        }
+        std::fs::write(path, text)
+    }
+
+    pub fn load_csv<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let (shape, values) = parse_csv(&text)?;
+        Ok(RawGf32::new_init(shape, &values, None))
+    }
+
+    pub fn save_csv<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let (_, n) = shape_d2(self.shape())?;
+        let mut text = String::new();
+        for row in self.to_vec().chunks(n) {
+            let row: Vec<String> = row.iter().map(|v| v.to_string()).collect();
+            writeln!(text, "{}", row.join(",")).unwrap();
+        }
+        std::fs::write(path, text)
+    }
+}
+
+impl SparseCooGf32 {
+    pub fn load_mtx<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let data = parse_mtx(&text)?;
+        Ok(SparseCooGf32::new_init(Shape::D2(data.rows, data.cols), &data.row_idx, &data.col_idx, &data.values, None))
+    }
+
+    // coordinate real generalで保存する
+    pub fn save_mtx<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let (m, n) = shape_d2(self.shape())?;
+        let (rows, cols, values) = self.to_host();
+        let mut text = String::new();
+        writeln!(text, "%%MatrixMarket matrix coordinate real general").unwrap();
+        writeln!(text, "{} {} {}", m, n, values.len()).unwrap();
+        for ((i, j), v) in rows.iter().zip(cols.iter()).zip(values.iter()) {
+            writeln!(text, "{} {} {}", i + 1, j + 1, v).unwrap();
+        }
+        std::fs::write(path, text)
+    }
+}
+
+
+
+pub fn run() {
+    let dir = std::env::temp_dir();
+
+    // coordinate, symmetric
+    let text = "%%MatrixMarket matrix coordinate real symmetric
+% 3x3
+3 3 3
+1 1 2.0
+2 1 -1.0
+3 3 4.5
+";
+    let data = parse_mtx(text).unwrap();
+    if data.to_dense(usize::MAX).unwrap() != vec![2.0, -1.0, 0.0, -1.0, 0.0, 0.0, 0.0, 0.0, 4.5] {
+        panic!("mtx symmetric parse failed: {:?}", data.to_dense(usize::MAX).unwrap());
+    }
+
+    // array (column-major)
+    let text = "%%MatrixMarket matrix array real general\n2 3\n1\n4\n2\n5\n3\n6\n";
+    let data = parse_mtx(text).unwrap();
+    if data.to_dense(usize::MAX).unwrap() != vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0] {
+        panic!("mtx array parse failed: {:?}", data.to_dense(usize::MAX).unwrap());
+    }
+
+    // 壊れた入力はErr
+    for broken in [
+        "",
+        "%%MatrixMarket matrix coordinate complex general\n1 1 1\n1 1 1 0\n",
+        "%%MatrixMarket matrix coordinate real general\n2 2 2\n1 1 1.0\n",
+        "%%MatrixMarket matrix coordinate real general\n2 2 1\n3 1 1.0\n",
+        "%%MatrixMarket matrix coordinate real general\n2 2 1\n1 1 abc\n",
+        "%%MatrixMarket matrix array real general\n2 2\n1\n2\n3\n",
+    ] {
+        if parse_mtx(broken).is_ok() {
+            panic!("broken mtx must be an error: {:?}", broken);
+        }
+    }
+    if parse_csv("1,2\n3\n").is_ok() || parse_csv("1,x\n").is_ok() {
+        panic!("broken csv must be an error");
+    }
+
+    // 密行列の往復
+    let values: Vec<f32> = (0..6).map(|i| i as f32 * 1.5 - 2.0).collect();
+    let a = RawGf32::new_init(Shape::D2(2, 3), &values, Some("a"));
+    let path = dir.join("wgpu_matmul_a.mtx");
+    a.save_mtx(&path).unwrap();
+    if RawGf32::load_mtx(&path).unwrap().to_vec() != values {
+        panic!("mtx dense roundtrip failed");
+    }
+    let path = dir.join("wgpu_matmul_a.csv");
+    a.save_csv(&path).unwrap();
+    let b = RawGf32::load_csv(&path).unwrap();
+    if b.shape() != &Shape::D2(2, 3) || b.to_vec() != values {
+        panic!("csv roundtrip failed");
+    }
+
+    // 疎行列の往復
+    let coo = SparseCooGf32::new_init(Shape::D2(3, 4), &[0, 2, 1], &[3, 0, 1], &[1.0, -2.0, 0.5], Some("coo"));
+    let path = dir.join("wgpu_matmul_coo.mtx");
+    coo.save_mtx(&path).unwrap();
+    let loaded = SparseCooGf32::load_mtx(&path).unwrap();
+    if loaded.to_host() != coo.to_host() || loaded.to_dense().to_vec() != coo.to_dense().to_vec() {
+        panic!("mtx sparse roundtrip failed");
+    }
+
+    println!("mtx/csv ok");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matmul_structured2::adapter_available;
+
+    fn dense(text: &str) -> Vec<f32> {
+        parse_mtx(text).unwrap().to_dense(usize::MAX).unwrap()
+    }
+
+    #[test]
+    fn array_symmetric() {
+        // 下三角をcolumn-majorで (0,0), (1,0), (2,0), (1,1), (2,1), (2,2)
+        let text = "%%MatrixMarket matrix array real symmetric\n3 3\n1 2 3\n4 5\n6\n";
+        assert_eq!(dense(text), vec![1.0, 2.0, 3.0, 2.0, 4.0, 5.0, 3.0, 5.0, 6.0]);
+        // 歪対称は対角なし (1,0), (2,0), (2,1)
+        let text = "%%MatrixMarket matrix array real skew-symmetric\n3 3\n1 2 3\n";
+        assert_eq!(dense(text), vec![0.0, -1.0, -2.0, 1.0, 0.0, -3.0, 2.0, 3.0, 0.0]);
+        let text = "%%MatrixMarket matrix array real skew-symmetric\n1 1\n";
+        assert_eq!(dense(text), vec![0.0]);
+    }
+
+    #[test]
+    fn huge_sizes_are_errors() {
+        for broken in [
+            // 積があふれる
+            "%%MatrixMarket matrix array real general\n18446744073709551615 2\n1\n",
+            "%%MatrixMarket matrix array real symmetric\n18446744073709551615 18446744073709551615\n1\n",
+            // ファイルに書ける個数より多い
+            "%%MatrixMarket matrix array real general\n100000 100000\n1\n",
+            "%%MatrixMarket matrix coordinate real general\n2 2 1000000000000\n1 1 1\n",
+            // 添字がu32に入らない
+            "%%MatrixMarket matrix coordinate real general\n4294967297 1 1\n4294967297 1 1.0\n",
+        ] {
+            let err = parse_mtx(broken).err().unwrap_or_else(|| panic!("must be an error: {:?}", broken));
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        // coordinateは大きな行列を書けるが，密行列にはできない
+        let data = parse_mtx("%%MatrixMarket matrix coordinate real general\n4294967296 4294967296 1\n1 1 1.0\n").unwrap();
+        assert_eq!(data.values, vec![1.0]);
+        assert!(data.to_dense(1 << 30).is_err());
+    }
+
+    #[test]
+    fn save_d3_is_error() {
+        if !adapter_available() {
+            return;
+        }
+        let a = RawGf32::zeros(Shape::D3(2, 2, 2), Some("a"));
+        let path = std::env::temp_dir().join("wgpu_matmul_d3.mtx");
+        assert_eq!(a.save_mtx(&path).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(a.save_csv(&path).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::autotune::{TileConfig, VECTORIZE};
+use crate::epilogue::Epilogue;
+use crate::matmul_structured2::{MatmulKernel, MatmulParams, RawGf32, Shape, Wgpu};
+
+
+/*
+複数のadapterで行列積を分担する
+outputの行(M)をBM(TileConfig)の倍数ずつに分けて，各デバイスでタイル化カーネルを回し，最後にCPUで結合する
+
+lhs: Matrix<f32, M, K> -> 行ごとに分割して各デバイスへ
+rhs: Matrix<f32, K, N> -> 全部のデバイスに同じものを送る
+
+ソフトウェアadapter(llvmpipeなど)を2つ並べても動くので，特別なハードウェアなしで確認できる
+*/
+
+pub struct MultiGpu {
+    devices: Vec<Wgpu>,
+    config: TileConfig,
+}
+impl MultiGpu {
+    pub fn new(adapters: &[wgpu::Adapter]) -> Self {
+        if adapters.is_empty() {
+            panic!("MultiGpu needs at least one adapter");
+        }
+        let devices = adapters.iter().map(Wgpu::new).collect();
+        Self { devices, config: TileConfig::default() }
+    }
+
+    // 見えているadapterを全部使う
+    // Backends::all()だと1つのGPUがVulkanとGLの両方から見えて2つに数えられるので，1つのbackendからだけ集める
+    // (PRIMARY(Vulkan, Metal, DX12)を優先して，なければSECONDARY(GL, DX11))
+    pub fn from_all_adapters() -> Self {
+        let instance = wgpu::Instance::default();
+        let mut adapters: Vec<wgpu::Adapter> = instance.enumerate_adapters(wgpu::Backends::PRIMARY).collect();
+        if adapters.is_empty() {
+            adapters = instance.enumerate_adapters(wgpu::Backends::SECONDARY).collect();
+        }
+        Self::new(&adapters)
+    }
+
+    // 同じadapterからn個のdeviceを作る。ソフトウェアadapterでの動作確認用
+    pub fn from_same_adapter(adapter: &wgpu::Adapter, n: usize) -> Self {
+        if n == 0 {
+            panic!("MultiGpu needs at least one device");
+        }
+        let devices = (0..n).map(|_| Wgpu::new(adapter)).collect();
+        Self { devices, config: TileConfig::default() }
+    }
+
+    // 全デバイスで同じタイルを使う。adapterが混在する場合は一番遅いものに合わせて選ぶこと
+    pub fn with_config(mut self, config: TileConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn num_devices(&self) -> usize {
+        self.devices.len()
+    }
+
+    // 行ブロック(BM行単位)をデバイス数で分ける。returnは各デバイスの(開始行, 行数)
+    fn partition_rows(&self, m: usize) -> Vec<(usize, usize)> {
+        let bm = self.config.bm as usize;
+        let num_blocks = m / bm;
+        let n = self.devices.len();
+        let mut parts = vec![];
+        let mut row = 0;
+        for i in 0..n {
+            // 余りは前のデバイスから1ブロックずつ配る
+            let blocks = num_blocks / n + if i < num_blocks % n { 1 } else { 0 };
+            if blocks == 0 {
+                continue;
+            }
+            parts.push((row, blocks * bm));
+            row += blocks * bm;
+        }
+        parts
+    }
+
+    pub fn matmul_host(&self, lhs: &[f32], lhs_shape: &Shape, rhs: &[f32], rhs_shape: &Shape) -> Vec<f32> {
+        let ((m, k), (k2, n)) = (lhs_shape.d2(), rhs_shape.d2());
+        if k != k2 {
+            panic!("incompatible matrix size, lhs: {}, rhs: {}", lhs_shape.to_string(), rhs_shape.to_string());
+        }
+        if lhs.len() != m * k || rhs.len() != k * n {
+            panic!("values length does not match shape, lhs: {}, rhs: {}", lhs_shape.to_string(), rhs_shape.to_string());
+        }
+        // タイル化カーネルは端数を扱えないので，BM, BK, BNの倍数まで0で埋めて計算し，最後に切り取る
+        // (0の行・列は結果に影響しない)
+        let mp = m.next_multiple_of(self.config.bm as usize);
+        let kp = k.next_multiple_of(self.config.bk as usize);
+        let np = n.next_multiple_of(self.config.bn as usize);
+        let lhs = pad(lhs, m, k, mp, kp);
+        let rhs = pad(rhs, k, n, kp, np);
+
+        let parts = self.partition_rows(mp);
+
+        // 1. 全デバイスに投げる（submitは非同期なので各デバイスが並列に動く）
+        let mut outputs = vec![];
+        for (w, &(row, rows)) in self.devices.iter().zip(parts.iter()) {
+            let lhs_part = lhs[row * kp..(row + rows) * kp].to_vec();
+            let lhs_buffer = w.create_buffer_init(&lhs_part, Some("multi_gpu lhs"));
+            let rhs_buffer = w.create_buffer_init(&rhs, Some("multi_gpu rhs"));
+            let out_buffer = w.create_buffer(rows * np * 4, Some("multi_gpu out"));
+            w.execute_matmul(
+                &lhs_buffer,
+                &rhs_buffer,
+                &out_buffer,
+                &MatmulParams::new(rows, kp, np),
+                MatmulKernel::new(&VECTORIZE, self.config.params(), self.config.dispatch(rows, np)),
+                &Epilogue::default()
+            );
+            outputs.push(out_buffer);
+        }
+
+        // 2. 各デバイスから読み出して行方向に結合し，埋めた分を落とす
+        let mut result = Vec::with_capacity(mp * np);
+        for (w, out_buffer) in self.devices.iter().zip(outputs.iter()) {
+            result.extend(w.get(out_buffer));
+        }
+        if (mp, np) == (m, n) {
+            return result;
+        }
+        result.chunks(np).take(m).flat_map(|row| row[..n].iter().copied()).collect()
+    }
+
+    pub fn matmul(&self, lhs: &RawGf32, rhs: &RawGf32) -> RawGf32 {
+        let result_shape = Shape::D2(lhs.shape().d2().0, rhs.shape().d2().1);
+        let result = self.matmul_host(&lhs.to_vec(), lhs.shape(), &rhs.to_vec(), rhs.shape());
+        RawGf32::new_init(result_shape, &result, Some("multi_gpu result"))
+    }
+}
+
+
+// rows x colsの行列を右と下に0を足して rows_p x cols_pにする
+fn pad(values: &[f32], rows: usize, cols: usize, rows_p: usize, cols_p: usize) -> Vec<f32> {
+    if (rows, cols) == (rows_p, cols_p) {
+        return values.to_vec();
+    }
+    let mut padded = vec![0.0; rows_p * cols_p];
+    for i in 0..rows {
+        padded[i * cols_p..i * cols_p + cols].copy_from_slice(&values[i * cols..(i + 1) * cols]);
+    }
+    padded
+}
+
+pub fn run() {
+    // 1つのadapterから2つのdeviceを作る（ソフトウェアadapterでも可）
+    let instance = wgpu::Instance::default();
+    let adapter = pollster::block_on(
+        instance.request_adapter(&wgpu::RequestAdapterOptions::default())
+    ).unwrap();
+    println!("adapter: {:?}", adapter.get_info().name);
+    let multi = MultiGpu::from_same_adapter(&adapter, 2);
+
+    // タイルの倍数と，端数のある大きさ
+    for (m, k, n) in [(96, 64, 64), (100, 37, 50)] {
+        let lhs: Vec<f32> = (0..m * k).map(|i| (i % 7) as f32 - 3.0).collect();
+        let rhs: Vec<f32> = (0..k * n).map(|i| (i % 5) as f32 * 0.5).collect();
+
+        let s = std::time::Instant::now();
+        let result = multi.matmul_host(&lhs, &Shape::D2(m, k), &rhs, &Shape::D2(k, n));
+        println!("multi gpu ({} devices), {:?}", multi.num_devices(), s.elapsed());
+
+        let mut cpu_result = vec![0.0; m * n];
+        for i in 0..m {
+            for j in 0..k {
+                for l in 0..n {
+                    cpu_result[i * n + l] += lhs[i * k + j] * rhs[j * n + l];
+                }
+            }
+        }
+        if result.len() != m * n {
+            panic!("multi gpu result length: {}, expected: {}", result.len(), m * n);
+        }
+        for (c, g) in cpu_result.iter().zip(result.iter()) {
+            if c != g {
+                panic!("cpu: {}, gpu: {}", c, g);
+            }
+        }
+    }
+    println!("multi gpu matmul ok");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::Tolerance;
+    use crate::matmul_structured2::adapter_available;
+
+    // 2つのdeviceに分けた結果が1つのdeviceのmatmulと同じ
+    #[test]
+    fn two_devices_ragged() {
+        if !adapter_available() {
+            return;
+        }
+        let instance = wgpu::Instance::default();
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default())).unwrap();
+        let multi = MultiGpu::from_same_adapter(&adapter, 2);
+        // タイル(32)の倍数でない。整数と0.5刻みなので足す順番によらず厳密
+        for (m, k, n) in [(100, 37, 50), (33, 1, 65)] {
+            let lhs = RawGf32::new_init(Shape::D2(m, k), &(0..m * k).map(|i| (i % 7) as f32 - 3.0).collect::<Vec<_>>(), Some("lhs"));
+            let rhs = RawGf32::new_init(Shape::D2(k, n), &(0..k * n).map(|i| (i % 5) as f32 * 0.5).collect::<Vec<_>>(), Some("rhs"));
+            let result = multi.matmul(&lhs, &rhs);
+            assert_eq!(*result.shape(), Shape::D2(m, n));
+            result.compare(&lhs.matmul(&rhs).to_vec(), &Tolerance::exact()).check("multi gpu", result.shape());
+        }
+        // GLのbackendはadapterのdeviceが全部dropされるとEGLのdisplayをterminateする
+        // displayはプロセスで共有なので，このスレッドのDEVICEや他のテストのdeviceが使えなくなる。最後まで残しておく
+        std::mem::forget(multi);
+        std::mem::forget(adapter);
+        std::mem::forget(instance);
+    }
+}
@@ -0,0 +1,378 @@
+use std::rc::Rc;
+
+use crate::autograd::{Tape, Var};
+use crate::elementwise::{self, Map};
+use crate::epilogue::Activation;
+use crate::matmul_structured2::{Binding, RawGf32, Shape, WgpuServer};
+use crate::random::Rng;
+use crate::softmax::Labels;
+use crate::wgsl_template::{TemplateParams, WgslTemplate};
+
+
+/*
+ニューラルネットの層とoptimizer
+    let model = Sequential::new()
+        .add(Linear::new(2, 32, 1))
+        .add(Activation::Relu)
+        .add(Linear::new(32, 3, 2));
+    let mut optimizer = Adam::new(0.01);
+    loop {
+        let tape = Tape::new();
+        let loss = cross_entropy_loss(model.forward(&tape, tape.leaf(x.clone(), false)), &labels);
+        tape.backward(loss);
+        optimizer.step(&tape, &model.parameters());
+    }
+順伝播も逆伝播もパラメータの更新もGPUの上で行う。CPUに読み出すのはlossだけ
+optimizerはパラメータのbufferをその場で書き換える(optimizer.wgsl)
+*/
+
+pub(crate) const OPTIMIZER: WgslTemplate = WgslTemplate::new("optimizer.wgsl", include_str!("./optimizer.wgsl"));
+
+pub trait Module {
+    fn forward<'t>(&self, tape: &'t Tape, x: Var<'t>) -> Var<'t>;
+
+    // optimizerで更新するもの。順番は毎回同じであること
+    fn parameters(&self) -> Vec<Rc<RawGf32>> {
+        vec![]
+    }
+}
+
+// y = x * weight + bias
+pub struct Linear {
+    // Shape::D2(in, out)
+    pub weight: Rc<RawGf32>,
+    // Shape::D2(1, out)
+    pub bias: Rc<RawGf32>,
+}
+impl Linear {
+    // weightはU(-1/sqrt(in), 1/sqrt(in))，biasは0
+    pub fn new(in_features: usize, out_features: usize, seed: u32) -> Self {
+        let bound = 1.0 / (in_features as f32).sqrt();
+        let weight = Rng::new(seed as u64).uniform(Shape::D2(in_features, out_features), -bound, bound, Some("linear weight"));
+        Self {
+            weight: Rc::new(weight),
+            bias: Rc::new(RawGf32::zeros(Shape::D2(1, out_features), Some("linear bias"))),
+        }
+    }
+}
+impl Module for Linear {
+    fn forward<'t>(&self, tape: &'t Tape, x: Var<'t>) -> Var<'t> {
+        x.linear(tape.leaf(self.weight.clone(), true), tape.leaf(self.bias.clone(), true))
+    }
+
+    fn parameters(&self) -> Vec<Rc<RawGf32>> {
+        vec![self.weight.clone(), self.bias.clone()]
+    }
+}
+
+// 活性化関数はepilogueと同じもの
+impl Module for Activation {
+    fn forward<'t>(&self, _tape: &'t Tape, x: Var<'t>) -> Var<'t> {
+        x.activation(*self)
+    }
+}
+
+#[derive(Default)]
+pub struct Sequential {
+    layers: Vec<Box<dyn Module>>,
+}
+impl Sequential {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add<M: Module + 'static>(mut self, layer: M) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+}
+impl Module for Sequential {
+    fn forward<'t>(&self, tape: &'t Tape, x: Var<'t>) -> Var<'t> {
+        self.layers.iter().fold(x, |x, layer| layer.forward(tape, x))
+    }
+
+    fn parameters(&self) -> Vec<Rc<RawGf32>> {
+        self.layers.iter().flat_map(|layer| layer.parameters()).collect()
+    }
+}
+
+// mean((pred - target)^2)
+pub fn mse_loss<'t>(pred: Var<'t>, target: Var<'t>) -> Var<'t> {
+    let diff = pred.sub(target);
+    diff.mul(diff).mean()
+}
+
+// predはsoftmax前の値(logits)
+pub fn cross_entropy_loss<'t>(logits: Var<'t>, labels: &Rc<Labels>) -> Var<'t> {
+    logits.cross_entropy(labels)
+}
+
+
+pub trait Optimizer {
+    // tape.backward()の後に呼ぶ。勾配がないパラメータ(lossに関係しないもの)は更新しない
+    fn step(&mut self, tape: &Tape, params: &[Rc<RawGf32>]);
+}
+
+// p -= lr * g
+pub struct Sgd {
+    pub lr: f32,
+}
+impl Sgd {
+    pub fn new(lr: f32) -> Self {
+        Self { lr }
+    }
+}
+impl Optimizer for Sgd {
+    fn step(&mut self, tape: &Tape, params: &[Rc<RawGf32>]) {
+        for param in params {
+            if let Some(grad) = tape.grad(param) {
+                elementwise::map(Map::Axpy, &grad, &grad, param, -self.lr);
+            }
+        }
+    }
+}
+
+// v = momentum * v + g, p -= lr * v
+pub struct Momentum {
+    pub lr: f32,
+    pub momentum: f32,
+    velocity: Vec<RawGf32>,
+}
+impl Momentum {
+    pub fn new(lr: f32, momentum: f32) -> Self {
+        Self { lr, momentum, velocity: vec![] }
+    }
+}
+impl Optimizer for Momentum {
+    fn step(&mut self, tape: &Tape, params: &[Rc<RawGf32>]) {
+        init_state(&mut self.velocity, params, 1);
+        let words = OptimizerParams { lr: self.lr, beta1: self.momentum, ..Default::default() };
+        for (param, velocity) in params.iter().zip(&self.velocity) {
+            if let Some(grad) = tape.grad(param) {
+                optimizer_kernel(false, param, &grad, velocity, words);
+            }
+        }
+    }
+}
+
+// Adam (Kingma & Ba)。バイアス補正あり
+pub struct Adam {
+    pub lr: f32,
+    pub beta1: f32,
+    pub beta2: f32,
+    pub eps: f32,
+    t: i32,
+    // (m, v)
+    state: Vec<RawGf32>,
+}
+impl Adam {
+    pub fn new(lr: f32) -> Self {
+        Self { lr, beta1: 0.9, beta2: 0.999, eps: 1e-8, t: 0, state: vec![] }
+    }
+}
+impl Optimizer for Adam {
+    fn step(&mut self, tape: &Tape, params: &[Rc<RawGf32>]) {
+        init_state(&mut self.state, params, 2);
+        self.t += 1;
+        let words = OptimizerParams {
+            lr: self.lr,
+            beta1: self.beta1,
+            beta2: self.beta2,
+            eps: self.eps,
+            correction1: 1.0 - self.beta1.powi(self.t),
+            correction2: 1.0 - self.beta2.powi(self.t),
+        };
+        for (param, state) in params.iter().zip(&self.state) {
+            if let Some(grad) = tape.grad(param) {
+                optimizer_kernel(true, param, &grad, state, words);
+            }
+        }
+    }
+}
+
+// optimizer.wgslのOptimizerParams（lenはkernelで埋める）
+#[derive(Clone, Copy, Default)]
+struct OptimizerParams {
+    lr: f32,
+    beta1: f32,
+    beta2: f32,
+    eps: f32,
+    correction1: f32,
+    correction2: f32,
+}
+
+// パラメータごとにsize * per_param個の0
+fn init_state(state: &mut Vec<RawGf32>, params: &[Rc<RawGf32>], per_param: usize) {
+    if state.is_empty() {
+        // 新しく作ったbufferは0で初期化されている
+        *state = params.iter().map(|p| RawGf32::_new_empty(Shape::D2(per_param, p.shape().size()), Some("optimizer state"))).collect();
+    }
+    if state.len() != params.len() || state.iter().zip(params).any(|(s, p)| s.shape().size() != p.shape().size() * per_param) {
+        panic!("optimizer: parameters changed since the first step");
+    }
+}
+
+thread_local! {
+    // OptimizerParamsとlenのuniform。ステップごとに作らずwrite_bufferで書き換える(softmax.rsのPARAMSと同じ)
+    // uniformは16Byte単位
+    static OPTIMIZER_PARAMS: wgpu::Buffer = WgpuServer::create_uniform_buffer(32, Some("optimizer params"));
+}
+
+fn optimizer_kernel(adam: bool, param: &RawGf32, grad: &RawGf32, state: &RawGf32, p: OptimizerParams) {
+    let len = param.shape().size();
+    let template_params = TemplateParams::new().set("ADAM", adam);
+    let shader_str = OPTIMIZER.instantiate(&template_params).unwrap_or_else(|e| panic!("{}", e));
+    let words = [
+        len as u32,
+        p.lr.to_bits(), p.beta1.to_bits(), p.beta2.to_bits(), p.eps.to_bits(),
+        p.correction1.to_bits(), p.correction2.to_bits(), 0,
+    ];
+    OPTIMIZER_PARAMS.with(|params| {
+        WgpuServer::write_buffer(params, 0, bytemuck::cast_slice(&words));
+        let bindings = [
+            Binding::read_write(param.buffer()),
+            Binding::read(grad.buffer()),
+            Binding::read_write(state.buffer()),
+            Binding::uniform(params),
+        ];
+        let label = if adam { "adam" } else { "momentum" };
+        WgpuServer::execute_n(label, &bindings, &[], &OPTIMIZER.instance_name(&template_params), &shader_str, elementwise::dispatch(len));
+    });
+}
+
+
+
+// optimizerを1ステップずつCPUの計算と比べる
+fn check_optimizers() {
+    let p0 = vec![0.5f32, -1.0, 2.0];
+    let g = vec![0.1f32, -0.2, 0.3];
+    let cpu = |name: &str| -> Vec<f32> {
+        let (lr, mu, b1, b2, eps) = (0.1f32, 0.9f32, 0.9f32, 0.999f32, 1e-8f32);
+        let mut p = p0.clone();
+        let (mut m, mut v) = (vec![0.0f32; 3], vec![0.0f32; 3]);
+        for t in 1..=2 {
+            for i in 0..3 {
+                match name {
+                    "sgd" => p[i] -= lr * g[i],
+                    "momentum" => {
+                        v[i] = mu * v[i] + g[i];
+                        p[i] -= lr * v[i];
+                    }
+                    _ => {
+                        m[i] = b1 * m[i] + (1.0 - b1) * g[i];
+                        v[i] = b2 * v[i] + (1.0 - b2) * g[i] * g[i];
+                        p[i] -= lr * (m[i] / (1.0 - b1.powi(t))) / ((v[i] / (1.0 - b2.powi(t))).sqrt() + eps);
+                    }
+                }
+            }
+        }
+        p
+    };
+    let optimizers: Vec<(&str, Box<dyn Optimizer>)> = vec![
+        ("sgd", Box::new(Sgd::new(0.1))),
+        ("momentum", Box::new(Momentum::new(0.1, 0.9))),
+        ("adam", Box::new(Adam::new(0.1))),
+    ];
+    for (name, mut optimizer) in optimizers {
+        let param = Rc::new(RawGf32::new_init(Shape::D2(1, 3), &p0, Some("param")));
+        let target = Rc::new(RawGf32::new_init(Shape::D2(1, 3), &g, Some("g")));
+        for _ in 0..2 {
+            // loss = sum(param * g)なので勾配はg
+            let tape = Tape::new();
+            let loss = tape.leaf(param.clone(), true).mul(tape.leaf(target.clone(), false)).sum();
+            tape.backward(loss);
+            optimizer.step(&tape, std::slice::from_ref(&param));
+        }
+        let result = param.to_vec();
+        let expected = cpu(name);
+        if result.iter().zip(&expected).any(|(x, y)| (x - y).abs() > 1e-6) {
+            panic!("{} mismatch: {:?} != {:?}", name, result, expected);
+        }
+    }
+}
+
+// 3クラスの渦巻き (x, y) -> class
+fn spiral(points_per_class: usize) -> (Vec<f32>, Vec<u32>) {
+    let mut x = vec![];
+    let mut labels = vec![];
+    for class in 0..3 {
+        for i in 0..points_per_class {
+            let r = i as f32 / points_per_class as f32;
+            let t = class as f32 * 2.094 + r * 4.0 + ((i * 7919) % 13) as f32 * 0.02;
+            x.push(r * t.cos());
+            x.push(r * t.sin());
+            labels.push(class as u32);
+        }
+    }
+    (x, labels)
+}
+
+pub fn run() {
+    check_optimizers();
+
+    // 分類: 2 -> 32 -> 32 -> 3のMLPを交差エントロピーとAdamで学習する
+    let (x, labels) = spiral(100);
+    let n = labels.len();
+    let x = Rc::new(RawGf32::new_init(Shape::D2(n, 2), &x, Some("x")));
+    let labels = Rc::new(Labels::new(&labels, Some("labels")));
+    let model = Sequential::new()
+        .add(Linear::new(2, 32, 1))
+        .add(Activation::Relu)
+        .add(Linear::new(32, 32, 2))
+        .add(Activation::Tanh)
+        .add(Linear::new(32, 3, 3));
+    let mut optimizer = Adam::new(0.02);
+    let epochs = 300;
+    let mut losses = vec![];
+    let time = std::time::Instant::now();
+    for epoch in 0..epochs {
+        let tape = Tape::new();
+        let logits = model.forward(&tape, tape.leaf(x.clone(), false));
+        let loss = cross_entropy_loss(logits, &labels);
+        tape.backward(loss);
+        optimizer.step(&tape, &model.parameters());
+        // CPUに読み出すのはlossだけ
+        let value = WgpuServer::get(loss.value().buffer())[0];
+        if epoch % 50 == 0 || epoch == epochs - 1 {
+            println!("epoch {:>3}: cross entropy = {:.4}", epoch, value);
+        }
+        losses.push(value);
+    }
+    println!("{} epochs, {:?}", epochs, time.elapsed());
+    // ランダムに当てると-ln(1/3) = 1.0986
+    if losses[epochs - 1].is_nan() || losses[epochs - 1] >= 0.5 * losses[0] {
+        panic!("classifier did not learn: {} -> {}", losses[0], losses[epochs - 1]);
+    }
+
+    // 回帰: y = x0 * x1をMSEで学習する。SGDとMomentum
+    let inputs: Vec<f32> = (0..2 * 64).map(|i| ((i * 37) % 64) as f32 / 32.0 - 1.0).collect();
+    let targets: Vec<f32> = inputs.chunks(2).map(|p| p[0] * p[1]).collect();
+    let x = Rc::new(RawGf32::new_init(Shape::D2(64, 2), &inputs, Some("x")));
+    let y = Rc::new(RawGf32::new_init(Shape::D2(64, 1), &targets, Some("y")));
+    let optimizers: Vec<(&str, Box<dyn Optimizer>)> = vec![
+        ("sgd", Box::new(Sgd::new(0.1))),
+        ("momentum", Box::new(Momentum::new(0.05, 0.9))),
+    ];
+    for (name, mut optimizer) in optimizers {
+        let model = Sequential::new()
+            .add(Linear::new(2, 16, 4))
+            .add(Activation::Gelu)
+            .add(Linear::new(16, 1, 5));
+        let mut first = None;
+        let mut last = 0.0;
+        for _ in 0..200 {
+            let tape = Tape::new();
+            let pred = model.forward(&tape, tape.leaf(x.clone(), false));
+            let loss = mse_loss(pred, tape.leaf(y.clone(), false));
+            tape.backward(loss);
+            optimizer.step(&tape, &model.parameters());
+            last = WgpuServer::get(loss.value().buffer())[0];
+            first.get_or_insert(last);
+        }
+        println!("{}: mse {:.5} -> {:.5}", name, first.unwrap(), last);
+        if last.is_nan() || last >= 0.5 * first.unwrap() {
+            panic!("{} did not learn", name);
+        }
+    }
+    println!("nn ok");
+}
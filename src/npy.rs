@@ -0,0 +1,486 @@
+use std::{collections::HashMap, fs::File, io::{self, Read, Write}, path::Path};
+use crate::matmul_structured2::{RawGf32, Shape};
+
+
+/*
+NumPyの.npy/.npzの読み書き
+
+.npy format (https://numpy.org/doc/stable/reference/generated/numpy.lib.format.html)
+    "\x93NUMPY" + major + minor + header_len(v1: u16, v2以降: u32) + header + data
+    header = "{'descr': '<f4', 'fortran_order': False, 'shape': (3, 4), }" を空白と'\n'で64Byteにそろえたもの
+.npz
+    .npyをzipにまとめたもの。名前は"{key}.npy"
+
+読み込みは f32 / f16 / i32 (little, big endian) と C / Fortran order に対応し，GPUへはf32で送る
+書き込みはC orderのlittle endianで，dtypeはsave_npy_as / save_npz_asで選ぶ(既定はf32)
+壊れた入力はpanicせずにio::ErrorKind::InvalidDataで返す
+テスト用のfixtureはtests/fixtures/npy (gen.pyで作る)
+*/
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NpyDtype {
+    F32,
+    F16,
+    I32,
+}
+impl NpyDtype {
+    fn descr(&self) -> &'static str {
+        match self {
+            Self::F32 => "<f4",
+            Self::F16 => "<f2",
+            Self::I32 => "<i4",
+        }
+    }
+    fn byte_size(&self) -> usize {
+        match self {
+            Self::F32 => 4,
+            Self::F16 => 2,
+            Self::I32 => 4,
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// headerの辞書から'key': の後ろの値部分を取り出す
+fn header_value<'a>(header: &'a str, key: &str) -> io::Result<&'a str> {
+    let pattern = format!("'{}':", key);
+    let start = header.find(&pattern)
+        .ok_or_else(|| invalid(format!("npy header has no '{}': {}", key, header)))?;
+    Ok(header[start + pattern.len()..].trim_start())
+}
+
+struct NpyHeader {
+    dtype: NpyDtype,
+    big_endian: bool,
+    fortran_order: bool,
+    shape: Vec<usize>,
+}
+
+fn parse_header(header: &str) -> io::Result<NpyHeader> {
+    // 'descr': '<f4'
+    let descr = header_value(header, "descr")?;
+    let descr = descr.strip_prefix('\'')
+        .and_then(|d| d.split('\'').next())
+        .ok_or_else(|| invalid(format!("npy header has broken descr: {}", header)))?;
+    // 空や先頭がマルチバイト文字でもsplit_atのようにpanicしないようにget(..1)で見る
+    let (big_endian, type_str) = match descr.get(..1) {
+        Some("<") | Some("|") | Some("=") => (false, &descr[1..]),
+        Some(">") => (true, &descr[1..]),
+        _ => (false, descr),
+    };
+    let dtype = match type_str {
+        "f4" => NpyDtype::F32,
+        "f2" => NpyDtype::F16,
+        "i4" => NpyDtype::I32,
+        _ => return Err(invalid(format!("unsupported npy dtype '{}', expected f4, f2 or i4", descr))),
+    };
+
+    // 'fortran_order': False
+    let fortran = header_value(header, "fortran_order")?;
+    let fortran_order = if fortran.starts_with("True") {
+        true
+    } else if fortran.starts_with("False") {
+        false
+    } else {
+        return Err(invalid(format!("npy header has broken fortran_order: {}", header)));
+    };
+
+    // 'shape': (3, 4)
+    let shape_str = header_value(header, "shape")?;
+    let shape_str = shape_str.strip_prefix('(')
+        .and_then(|s| s.split(')').next())
+        .ok_or_else(|| invalid(format!("npy header has broken shape: {}", header)))?;
+    let mut shape = vec![];
+    for dim in shape_str.split(',').map(|d| d.trim()).filter(|d| !d.is_empty()) {
+        let dim = dim.trim_end_matches('L'); // python2のlong
+        shape.push(dim.parse::<usize>().map_err(|_| invalid(format!("npy header has broken shape: {}", header)))?);
+    }
+
+    Ok(NpyHeader { dtype, big_endian, fortran_order, shape })
+}
+
+pub(crate) fn parse_npy(bytes: &[u8]) -> io::Result<(Shape, Vec<f32>)> {
+    if bytes.len() < 10 || &bytes[..6] != MAGIC {
+        return Err(invalid("not a npy file (bad magic)".to_string()));
+    }
+    let major = bytes[6];
+    let (header_len, header_start) = match major {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 => {
+            if bytes.len() < 12 {
+                return Err(invalid("npy file is truncated in header".to_string()));
+            }
+            (u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize, 12)
+        }
+        _ => return Err(invalid(format!("unsupported npy version {}", major))),
+    };
+    let data_start = header_start + header_len;
+    if bytes.len() < data_start {
+        return Err(invalid("npy file is truncated in header".to_string()));
+    }
+    let header = std::str::from_utf8(&bytes[header_start..data_start])
+        .map_err(|_| invalid("npy header is not utf-8".to_string()))?;
+    let header = parse_header(header)?;
+
+    // RawGf32は2次元のみ。0次元は(1, 1)，1次元は行ベクトル(1, n)とする
+    let (rows, cols) = match header.shape.as_slice() {
+        [] => (1, 1),
+        [n] => (1, *n),
+        [m, n] => (*m, *n),
+        s => return Err(invalid(format!("npy shape {:?} is not 2-D", s))),
+    };
+
+    // headerのshapeは信用できないので，掛け算のあふれもErrにする
+    let count = rows.checked_mul(cols)
+        .ok_or_else(|| invalid(format!("npy shape ({}, {}) is too large", rows, cols)))?;
+    let byte_len = count.checked_mul(header.dtype.byte_size())
+        .ok_or_else(|| invalid(format!("npy shape ({}, {}) is too large", rows, cols)))?;
+    let data = &bytes[data_start..];
+    if data.len() < byte_len {
+        return Err(invalid(format!("npy data is truncated, expected {} elements", count)));
+    }
+
+    let values: Vec<f32> = match header.dtype {
+        NpyDtype::F32 => data.chunks_exact(4).take(count).map(|b| {
+            let b = [b[0], b[1], b[2], b[3]];
+            if header.big_endian { f32::from_be_bytes(b) } else { f32::from_le_bytes(b) }
+        }).collect(),
+        NpyDtype::F16 => data.chunks_exact(2).take(count).map(|b| {
+            let b = [b[0], b[1]];
+            let h = if header.big_endian { half::f16::from_be_bytes(b) } else { half::f16::from_le_bytes(b) };
+            h.to_f32()
+        }).collect(),
+        NpyDtype::I32 => data.chunks_exact(4).take(count).map(|b| {
+            let b = [b[0], b[1], b[2], b[3]];
+            (if header.big_endian { i32::from_be_bytes(b) } else { i32::from_le_bytes(b) }) as f32
+        }).collect(),
+    };
+
+    // Fortran orderはcolumn-majorなのでrow-majorに並べ替える
+    let values = if header.fortran_order {
+        let mut c_order = vec![0.0; count];
+        for j in 0..cols {
+            for i in 0..rows {
+                c_order[i * cols + j] = values[j * rows + i];
+            }
+        }
+        c_order
+    } else {
+        values
+    };
+
+    Ok((Shape::D2(rows, cols), values))
+}
+
+// 読み込み(parse_npy)と同じく2次元だけ
+pub(crate) fn encode_npy(shape: &Shape, values: &[f32], dtype: NpyDtype) -> io::Result<Vec<u8>> {
+    let (rows, cols) = match *shape {
+        Shape::D2(m, n) => (m, n),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("only Shape::D2 can be saved as npy, but {}", shape.to_string()))),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': ({}, {}), }}",
+        dtype.descr(), rows, cols
+    );
+    // magic(6) + version(2) + header_len(2) + header + '\n' を64Byteにそろえる
+    let total = 10 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - total % 64) % 64));
+    header.push('\n');
+
+    let mut bytes = Vec::with_capacity(10 + header.len() + values.len() * dtype.byte_size());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&[1, 0]);
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    for &v in values {
+        match dtype {
+            NpyDtype::F32 => bytes.extend_from_slice(&v.to_le_bytes()),
+            NpyDtype::F16 => bytes.extend_from_slice(&half::f16::from_f32(v).to_le_bytes()),
+            NpyDtype::I32 => bytes.extend_from_slice(&(v as i32).to_le_bytes()),
+        }
+    }
+    Ok(bytes)
+}
+
+impl RawGf32 {
+    pub fn load_npy<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let (shape, values) = parse_npy(&bytes)?;
+        Ok(RawGf32::new_init(shape, &values, None))
+    }
+
+    pub fn save_npy<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.save_npy_as(path, NpyDtype::F32)
+    }
+
+    // f16やi32で保存する（f32から変換するので精度は落ちる）
+    pub fn save_npy_as<P: AsRef<Path>>(&self, path: P, dtype: NpyDtype) -> io::Result<()> {
+        let bytes = encode_npy(self.shape(), &self.to_vec(), dtype)?;
+        std::fs::write(path, bytes)
+    }
+
+    // 名前 -> テンソル。キーは"{key}.npy"の{key}部分
+    pub fn load_npz<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, RawGf32>> {
+        let file = File::open(path)?;
+        let mut archive = zip::ZipArchive::new(file)
+            .map_err(|e| invalid(format!("not a npz archive: {}", e)))?;
+        let mut tensors = HashMap::new();
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)
+                .map_err(|e| invalid(format!("broken npz entry {}: {}", i, e)))?;
+            let name = entry.name().trim_end_matches(".npy").to_string();
+            let mut bytes = vec![];
+            entry.read_to_end(&mut bytes)?;
+            let (shape, values) = parse_npy(&bytes)
+                .map_err(|e| invalid(format!("npz entry '{}': {}", name, e)))?;
+            tensors.insert(name, RawGf32::new_init(shape, &values, None));
+        }
+        Ok(tensors)
+    }
+
+    // numpy.savezと同じく無圧縮で保存する
+    pub fn save_npz<P: AsRef<Path>>(path: P, tensors: &[(&str, &RawGf32)]) -> io::Result<()> {
+        Self::save_npz_as(path, tensors, NpyDtype::F32)
+    }
+
+    // 全部のテンソルをdtypeで保存する（save_npy_asと同じく変換で精度は落ちる）
+    pub fn save_npz_as<P: AsRef<Path>>(path: P, tensors: &[(&str, &RawGf32)], dtype: NpyDtype) -> io::Result<()> {
+        let file = File::create(path)?;
+        let mut writer = zip::ZipWriter::new(file);
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        for (name, tensor) in tensors {
+            writer.start_file(format!("{}.npy", name), options)
+                .map_err(io::Error::other)?;
+            writer.write_all(&encode_npy(tensor.shape(), &tensor.to_vec(), dtype)?)?;
+        }
+        writer.finish().map_err(io::Error::other)?;
+        Ok(())
+    }
+}
+
+
+
+pub fn run() {
+    let dir = std::env::temp_dir();
+
+    // f32 C order の往復
+    let values: Vec<f32> = (0..12).map(|i| i as f32 * 0.5).collect();
+    let a = RawGf32::new_init(Shape::D2(3, 4), &values, Some("a"));
+    let path = dir.join("wgpu_matmul_a.npy");
+    a.save_npy(&path).unwrap();
+    let b = RawGf32::load_npy(&path).unwrap();
+    if b.shape() != &Shape::D2(3, 4) || b.to_vec() != values {
+        panic!("npy f32 roundtrip failed: {:?}", b.to_vec());
+    }
+
+    // f16 の往復（0.5刻みはf16で正確に表せる）
+    a.save_npy_as(&path, NpyDtype::F16).unwrap();
+    let b = RawGf32::load_npy(&path).unwrap();
+    if b.to_vec() != values {
+        panic!("npy f16 roundtrip failed: {:?}", b.to_vec());
+    }
+
+    // Fortran order, i32 (numpy.asfortranarray(np.arange(6, dtype=np.int32).reshape(2, 3)))
+    let header = "{'descr': '<i4', 'fortran_order': True, 'shape': (2, 3), }";
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&[1, 0]);
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    for v in [0i32, 3, 1, 4, 2, 5] {
+        bytes.extend_from_slice(&v.to_le_bytes());
+    }
+    let (shape, loaded) = parse_npy(&bytes).unwrap();
+    if shape != Shape::D2(2, 3) || loaded != vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0] {
+        panic!("npy fortran order failed: {:?}", loaded);
+    }
+
+    // 壊れた入力はErr
+    if parse_npy(&bytes[..20]).is_ok() || parse_npy(b"not numpy").is_ok() {
+        panic!("broken npy must be an error");
+    }
+
+    // npz
+    let path = dir.join("wgpu_matmul_ab.npz");
+    let c = RawGf32::new_init(Shape::D2(1, 2), &vec![7.0, 8.0], Some("c"));
+    RawGf32::save_npz(&path, &[("a", &a), ("c", &c)]).unwrap();
+    let tensors = RawGf32::load_npz(&path).unwrap();
+    if tensors["a"].to_vec() != values || tensors["c"].to_vec() != vec![7.0, 8.0] {
+        panic!("npz roundtrip failed");
+    }
+
+    println!("npy/npz ok");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::*;
+    use crate::matmul_structured2::adapter_available;
+
+    fn fixture_path(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/npy").join(name)
+    }
+
+    fn fixture(name: &str) -> Vec<u8> {
+        std::fs::read(fixture_path(name)).unwrap()
+    }
+
+    // テストは並列に走るので名前を分ける
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("wgpu_matmul_{}_{}", std::process::id(), name))
+    }
+
+    // headerを飛ばしたデータ部分
+    fn data_part(bytes: &[u8]) -> &[u8] {
+        let (header_len, header_start) = if bytes[6] == 1 {
+            (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10)
+        } else {
+            (u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize, 12)
+        };
+        &bytes[header_start + header_len..]
+    }
+
+    // np.arange(12).reshape(3, 4) * 0.5
+    fn f4_values() -> Vec<f32> {
+        (0..12).map(|i| i as f32 * 0.5).collect()
+    }
+
+    #[test]
+    fn load_f4_versions_and_endian() {
+        for name in ["f4.npy", "f4_be.npy", "f4_v2.npy", "f4_v3.npy"] {
+            let (shape, values) = parse_npy(&fixture(name)).unwrap();
+            assert_eq!(shape, Shape::D2(3, 4), "{}", name);
+            assert_eq!(values, f4_values(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn load_f2() {
+        let (shape, values) = parse_npy(&fixture("f2.npy")).unwrap();
+        assert_eq!(shape, Shape::D2(2, 3));
+        assert_eq!(values, vec![0.0, 0.5, -1.5, 65504.0, 2f32.powi(-24), -2.0]);
+    }
+
+    #[test]
+    fn load_i4() {
+        let (shape, values) = parse_npy(&fixture("i4.npy")).unwrap();
+        assert_eq!(shape, Shape::D2(2, 2));
+        assert_eq!(values, vec![-3.0, 0.0, 7.0, 100000.0]);
+    }
+
+    #[test]
+    fn load_fortran_order() {
+        let (shape, values) = parse_npy(&fixture("f4_fortran.npy")).unwrap();
+        assert_eq!(shape, Shape::D2(2, 3));
+        assert_eq!(values, vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn load_1d_and_0d() {
+        let (shape, values) = parse_npy(&fixture("f4_1d.npy")).unwrap();
+        assert_eq!(shape, Shape::D2(1, 3));
+        assert_eq!(values, vec![0.0, 1.0, 2.0]);
+        let (shape, values) = parse_npy(&fixture("f4_0d.npy")).unwrap();
+        assert_eq!(shape, Shape::D2(1, 1));
+        assert_eq!(values, vec![2.5]);
+    }
+
+    // encode_npyのデータ部分はnumpyの出力と同じバイト列になり，headerも読み直せる
+    #[test]
+    fn encode_matches_fixture() {
+        for (name, dtype) in [("f4.npy", NpyDtype::F32), ("f2.npy", NpyDtype::F16), ("i4.npy", NpyDtype::I32)] {
+            let bytes = fixture(name);
+            let (shape, values) = parse_npy(&bytes).unwrap();
+            let encoded = encode_npy(&shape, &values, dtype).unwrap();
+            assert_eq!(data_part(&encoded), data_part(&bytes), "{}", name);
+            assert_eq!((encoded.len() - data_part(&encoded).len()) % 64, 0, "{}", name);
+            let (shape2, values2) = parse_npy(&encoded).unwrap();
+            assert_eq!(shape2, shape, "{}", name);
+            assert_eq!(values2, values, "{}", name);
+        }
+    }
+
+    #[test]
+    fn broken_input_is_error() {
+        let bytes = fixture("f4.npy");
+        for broken in [&bytes[..20], &bytes[..bytes.len() - 1], b"not numpy".as_slice()] {
+            let err = parse_npy(broken).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        // 大きすぎるshapeはあふれる前にErr
+        let header = "{'descr': '<f4', 'fortran_order': False, 'shape': (4294967296, 4294967296), }";
+        let mut huge = MAGIC.to_vec();
+        huge.extend_from_slice(&[1, 0]);
+        huge.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        huge.extend_from_slice(header.as_bytes());
+        assert_eq!(parse_npy(&huge).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // 対応していないdtype
+        let f8 = String::from_utf8_lossy(&bytes).replace("<f4", "<f8");
+        assert_eq!(parse_npy(f8.as_bytes()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // descrが空 / 先頭がマルチバイト文字
+        for descr in ["", "\u{3042}f4"] {
+            let header = format!("{{'descr': '{}', 'fortran_order': False, 'shape': (1, 1), }}", descr);
+            let mut broken = MAGIC.to_vec();
+            broken.extend_from_slice(&[1, 0]);
+            broken.extend_from_slice(&(header.len() as u16).to_le_bytes());
+            broken.extend_from_slice(header.as_bytes());
+            broken.extend_from_slice(&[0; 4]);
+            assert_eq!(parse_npy(&broken).unwrap_err().kind(), io::ErrorKind::InvalidData, "descr: {:?}", descr);
+        }
+    }
+
+    #[test]
+    fn encode_d3_is_error() {
+        let err = encode_npy(&Shape::D3(2, 2, 2), &[0.0; 8], NpyDtype::F32).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn npy_roundtrip() {
+        if !adapter_available() {
+            return;
+        }
+        let a = RawGf32::load_npy(fixture_path("f4_fortran.npy")).unwrap();
+        for dtype in [NpyDtype::F32, NpyDtype::F16, NpyDtype::I32] {
+            let path = temp_path(&format!("roundtrip_{}.npy", dtype.descr().trim_start_matches('<')));
+            a.save_npy_as(&path, dtype).unwrap();
+            let b = RawGf32::load_npy(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(b.shape(), &Shape::D2(2, 3));
+            assert_eq!(b.to_vec(), vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0], "{:?}", dtype);
+        }
+    }
+
+    #[test]
+    fn npz_roundtrip() {
+        if !adapter_available() {
+            return;
+        }
+        let tensors = RawGf32::load_npz(fixture_path("ab.npz")).unwrap();
+        assert_eq!(tensors.len(), 2);
+        assert_eq!(tensors["a"].to_vec(), f4_values());
+        assert_eq!(tensors["b"].to_vec(), vec![-3.0, 0.0, 7.0, 100000.0]);
+
+        // aの0.5刻みはi32では落ち，bの100000はf16では表せない
+        for dtype in [NpyDtype::F32, NpyDtype::F16, NpyDtype::I32] {
+            let path = temp_path(&format!("roundtrip_{}.npz", dtype.descr().trim_start_matches('<')));
+            RawGf32::save_npz_as(&path, &[("a", &tensors["a"]), ("b", &tensors["b"])], dtype).unwrap();
+            let loaded = RawGf32::load_npz(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.len(), 2);
+            if dtype != NpyDtype::I32 {
+                assert_eq!(loaded["a"].to_vec(), f4_values(), "{:?}", dtype);
+            }
+            if dtype != NpyDtype::F16 {
+                assert_eq!(loaded["b"].to_vec(), vec![-3.0, 0.0, 7.0, 100000.0], "{:?}", dtype);
+            }
+        }
+    }
+}
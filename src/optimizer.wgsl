@@ -0,0 +1,63 @@
+// パラメータをその場で更新する
+// Matrix<f32, ROWS, COLS>
+@group(0) @binding(0)
+var<storage, read_write> param: array<f32>;
+// Matrix<f32, ROWS, COLS>。backwardの勾配
+@group(0) @binding(1)
+var<storage, read> grad: array<f32>;
+// momentum: v (LEN個)
+// adam: m (LEN個)，v (LEN個)の順
+@group(0) @binding(2)
+var<storage, read_write> state: array<f32>;
+// メタデータ
+struct OptimizerParams {
+    len: u32,
+    lr: f32,
+    // momentumのときはmomentum
+    beta1: f32,
+    beta2: f32,
+    eps: f32,
+    // 1 - beta1^t, 1 - beta2^t（バイアス補正。CPUで計算しておく）
+    correction1: f32,
+    correction2: f32,
+}
+@group(0) @binding(3)
+var<uniform> params: OptimizerParams;
+
+
+/*
+
+ADAMはRust側(nn::Momentum / nn::Adam)からテンプレートとして埋める
+    momentum: v = beta1 * v + g, p -= lr * v
+    adam:     m = beta1 * m + (1 - beta1) * g
+              v = beta2 * v + (1 - beta2) * g^2
+              p -= lr * (m / correction1) / (sqrt(v / correction2) + eps)
+SGDはelementwise(Map::Axpy)で足りるのでここでは扱わない
+
+*/
+
+const ADAM: bool = {{ADAM}};
+
+@compute @workgroup_size(64, 1, 1)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>
+) {
+    // 要素数がdispatchの上限を超えるときはyにも分ける
+    let i = global_id.x + global_id.y * num_workgroups.x * 64u;
+    if (i >= params.len) {
+        return;
+    }
+    let g = grad[i];
+    if (ADAM) {
+        let m = params.beta1 * state[i] + (1.0 - params.beta1) * g;
+        let v = params.beta2 * state[params.len + i] + (1.0 - params.beta2) * g * g;
+        state[i] = m;
+        state[params.len + i] = v;
+        param[i] -= params.lr * (m / params.correction1) / (sqrt(v / params.correction2) + params.eps);
+    } else {
+        let v = params.beta1 * state[i] + g;
+        state[i] = v;
+        param[i] -= params.lr * v;
+    }
+}
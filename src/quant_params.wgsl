@@ -0,0 +1,79 @@
+// チャネルごとのscaleとzero pointを決める(int8量子化)
+// Matrix<f32, ROWS, COLS>
+@group(0) @binding(0)
+var<storage, read> input: array<f32>;
+// チャネルの数だけ
+struct QParams {
+    scale: f32,
+    zero_point: i32,
+}
+@group(0) @binding(1)
+var<storage, read_write> qparams: array<QParams>;
+// メタデータ
+struct QuantParams {
+    rows: u32,
+    cols: u32,
+}
+@group(0) @binding(2)
+var<uniform> params: QuantParams;
+
+
+/*
+
+ROW: trueなら行ごと(チャネル = 行)，falseなら列ごと(チャネル = 列)
+1 workgroup = 1チャネル。WGスレッドで最小値と最大値を求めてshared memoryでまとめる
+0がちょうど表せるように範囲に0を含める
+    scale = (max - min) / 255
+    zero_point = round(-128 - min / scale)   x = scale * (q - zero_point)
+全部0のチャネルはscale = 1
+
+*/
+
+const ROW: bool = {{ROW}};
+const WG: u32 = 64u;
+
+var<workgroup> shared_min: array<f32, 64>;
+var<workgroup> shared_max: array<f32, 64>;
+
+@compute @workgroup_size(64, 1, 1)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>
+) {
+    let channel = workgroup_id.x + workgroup_id.y * num_workgroups.x;
+    let channels = select(params.cols, params.rows, ROW);
+    // workgroupごとに同じなのでbarrierの前に抜けてよい
+    if (channel >= channels) {
+        return;
+    }
+    let len = select(params.rows, params.cols, ROW);
+
+    var lo = 0.0;
+    var hi = 0.0;
+    for (var t = local_id.x; t < len; t += WG) {
+        let i = select(t * params.cols + channel, channel * params.cols + t, ROW);
+        lo = min(lo, input[i]);
+        hi = max(hi, input[i]);
+    }
+    shared_min[local_id.x] = lo;
+    shared_max[local_id.x] = hi;
+    workgroupBarrier();
+
+    for (var stride = WG / 2u; stride > 0u; stride /= 2u) {
+        if (local_id.x < stride) {
+            shared_min[local_id.x] = min(shared_min[local_id.x], shared_min[local_id.x + stride]);
+            shared_max[local_id.x] = max(shared_max[local_id.x], shared_max[local_id.x + stride]);
+        }
+        workgroupBarrier();
+    }
+
+    if (local_id.x == 0u) {
+        var scale = (shared_max[0] - shared_min[0]) / 255.0;
+        if (scale == 0.0) {
+            scale = 1.0;
+        }
+        let zero_point = clamp(round(-128.0 - shared_min[0] / scale), -128.0, 127.0);
+        qparams[channel] = QParams(scale, i32(zero_point));
+    }
+}
@@ -0,0 +1,378 @@
+use crate::autotune::TileConfig;
+use crate::compare::{self, Tolerance};
+use crate::elementwise;
+use crate::matmul_structured2::{Binding, RawGf32, Shape, WgpuServer};
+use crate::random::Rng;
+use crate::wgsl_template::{TemplateParams, WgslTemplate};
+
+
+/*
+int8に量子化したテンソルと行列積(推論用)
+    let x8 = x.quantize(Axis::Row);       // 活性は行ごと
+    let w8 = w.quantize(Axis::Column);    // 重みは出力チャネル(列)ごと
+    let y = x8.matmul(&w8);               // 結果はRawGf32
+    let w32 = w8.dequantize();
+チャネルごとにscaleとzero point(非対称)を持つ。x = scale * (q - zero_point)，qは[-128, 127]
+範囲は0を含めて最小値から最大値まで(quant_params.wgsl)。誤差は要素ごとにscale / 2まで
+bufferはint8を4個ずつu32に詰める。要素iはi / 4番目のu32の(i % 4)番目のbyteで，row-majorの並びのまま
+行列積は5blocking2d.wgslと同じタイル分けで，zero pointを引いてi32で積和し，最後にscaleを掛ける(matmul_i8.wgsl)
+    i32があふれないようにKの32768ごとに部分和をf32に足す
+*/
+
+pub(crate) const QUANT_PARAMS: WgslTemplate = WgslTemplate::new("quant_params.wgsl", include_str!("./quant_params.wgsl"));
+pub(crate) const QUANTIZE: WgslTemplate = WgslTemplate::new("quantize.wgsl", include_str!("./quantize.wgsl"));
+pub(crate) const MATMUL_I8: WgslTemplate = WgslTemplate::new("matmul_i8.wgsl", include_str!("./matmul_i8.wgsl"));
+
+// matmul_i8.wgslのタイル。autotune::search_spaceのどれでも動く(shader_checkで全部検証する)
+const TILE: TileConfig = TileConfig { bm: 32, bn: 32, bk: 8, tm: 4, tn: 4 };
+
+// quant_params.wgslのWG
+const WG: usize = 64;
+
+// scaleとzero pointをどの向きに持つか
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Axis {
+    // 行ごと。行列積の左(活性)
+    Row,
+    // 列ごと。行列積の右(重み)
+    Column,
+}
+impl Axis {
+    fn channels(&self, rows: usize, cols: usize) -> usize {
+        match self {
+            Axis::Row => rows,
+            Axis::Column => cols,
+        }
+    }
+}
+
+// shader_checkで全部検証する
+pub(crate) fn quant_params_template_params() -> Vec<TemplateParams> {
+    [true, false].iter().map(|&row| TemplateParams::new().set("ROW", row)).collect()
+}
+
+pub(crate) fn quantize_template_params() -> Vec<TemplateParams> {
+    let mut params = vec![];
+    for row in [true, false] {
+        for dequantize in [true, false] {
+            params.push(TemplateParams::new().set("ROW", row).set("DEQUANTIZE", dequantize));
+        }
+    }
+    params
+}
+
+pub struct RawGi8 {
+    label: Option<String>,
+    shape: Shape,
+    axis: Axis,
+    // int8が4個ずつ
+    buffer: wgpu::Buffer,
+    // チャネルごとの(scale: f32, zero_point: i32)
+    qparams: wgpu::Buffer,
+}
+impl RawGi8 {
+    pub fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    pub fn axis(&self) -> Axis {
+        self.axis
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    // bufferのバイト数(qparamsは含まない)。同じshapeのRawGf32の約1/4
+    pub fn size(&self) -> usize {
+        self.shape.size().div_ceil(4) * 4
+    }
+
+    // CPU側に読み出す（row-major）
+    pub fn to_vec(&self) -> Vec<i8> {
+        let words: Vec<u32> = WgpuServer::get_as(&self.buffer);
+        let mut values: Vec<i8> = words.iter().flat_map(|w| w.to_le_bytes()).map(|b| b as i8).collect();
+        values.truncate(self.shape.size());
+        values
+    }
+
+    // チャネルごとの(scale, zero_point)
+    pub fn qparams(&self) -> Vec<(f32, i32)> {
+        let words: Vec<u32> = WgpuServer::get_as(&self.qparams);
+        words.chunks(2).map(|p| (f32::from_bits(p[0]), p[1] as i32)).collect()
+    }
+
+    pub fn dequantize(&self) -> RawGf32 {
+        let out = RawGf32::_new_empty(self.shape.clone(), self.label());
+        let len = self.shape.size();
+        let params = quant_params_buffer(&self.shape);
+        let template_params = TemplateParams::new().set("ROW", self.axis == Axis::Row).set("DEQUANTIZE", true);
+        let shader_str = QUANTIZE.instantiate(&template_params).unwrap_or_else(|e| panic!("{}", e));
+        let bindings = [Binding::read(&self.buffer), Binding::read(&self.qparams), Binding::read_write(out.buffer()), Binding::uniform(&params)];
+        WgpuServer::execute_n("dequantize", &bindings, &[], &QUANTIZE.instance_name(&template_params), &shader_str, elementwise::dispatch(len));
+        out
+    }
+
+    // 行ごとに量子化したもの x 列ごとに量子化したもの -> f32
+    pub fn matmul(&self, other: &Self) -> RawGf32 {
+        if self.axis != Axis::Row || other.axis != Axis::Column {
+            panic!("matmul_i8: lhs must be quantized per row and rhs per column, but {:?} and {:?}", self.axis, other.axis);
+        }
+        let (m, k, n) = match (&self.shape, &other.shape) {
+            (Shape::D2(m, k), Shape::D2(k2, n)) if k == k2 => (*m, *k, *n),
+            _ => panic!("incompatible matrix size, self.shape: {}, other.shape: {}", self.shape.to_string(), other.shape.to_string()),
+        };
+        let result = RawGf32::_new_empty(Shape::D2(m, n), Some("result"));
+
+        let dispatch = (n.div_ceil(TILE.bn as usize), m.div_ceil(TILE.bm as usize));
+        let max = WgpuServer::limits().max_compute_workgroups_per_dimension as usize;
+        if dispatch.0 > max || dispatch.1 > max {
+            panic!("matmul_i8: {}x{} is too large for dispatch (max {} workgroups per dimension)", m, n, max);
+        }
+        // uniformは16Byte単位
+        let params = WgpuServer::create_uniform_buffer(16, Some("matmul_i8 params"));
+        WgpuServer::write_buffer(&params, 0, bytemuck::cast_slice(&[m as u32, k as u32, n as u32, 0]));
+        let template_params = TILE.params();
+        let shader_str = MATMUL_I8.instantiate(&template_params).unwrap_or_else(|e| panic!("{}", e));
+        let bindings = [
+            Binding::read(&self.buffer),
+            Binding::read(&self.qparams),
+            Binding::read(&other.buffer),
+            Binding::read(&other.qparams),
+            Binding::read_write(result.buffer()),
+            Binding::uniform(&params),
+        ];
+        WgpuServer::execute_n("matmul_i8", &bindings, &[], &MATMUL_I8.instance_name(&template_params), &shader_str, (dispatch.0 as u32, dispatch.1 as u32, 1));
+        result
+    }
+}
+
+impl RawGf32 {
+    // チャネルごとにscaleとzero pointを決めてint8にする
+    pub fn quantize(&self, axis: Axis) -> RawGi8 {
+        let (rows, cols) = self.shape().d2();
+        let len = rows * cols;
+        if len > u32::MAX as usize {
+            panic!("quantize: too many elements {}", len);
+        }
+        let channels = axis.channels(rows, cols);
+        let qparams = WgpuServer::create_buffer(channels * 8, Some("qparams"));
+        let buffer = WgpuServer::create_buffer(len.div_ceil(4) * 4, self.label());
+        let params = quant_params_buffer(self.shape());
+        let row = axis == Axis::Row;
+
+        // 1 workgroup = 1チャネル
+        let template_params = TemplateParams::new().set("ROW", row);
+        let shader_str = QUANT_PARAMS.instantiate(&template_params).unwrap_or_else(|e| panic!("{}", e));
+        let bindings = [Binding::read(self.buffer()), Binding::read_write(&qparams), Binding::uniform(&params)];
+        let dispatch = elementwise::dispatch(channels * WG);
+        WgpuServer::execute_n("quant_params", &bindings, &[], &QUANT_PARAMS.instance_name(&template_params), &shader_str, dispatch);
+
+        // 1スレッドでu32を1個
+        let template_params = TemplateParams::new().set("ROW", row).set("DEQUANTIZE", false);
+        let shader_str = QUANTIZE.instantiate(&template_params).unwrap_or_else(|e| panic!("{}", e));
+        let bindings = [Binding::read(self.buffer()), Binding::read(&qparams), Binding::read_write(&buffer), Binding::uniform(&params)];
+        let dispatch = elementwise::dispatch(len.div_ceil(4));
+        WgpuServer::execute_n("quantize", &bindings, &[], &QUANTIZE.instance_name(&template_params), &shader_str, dispatch);
+
+        RawGi8 {
+            label: self.label().map(|str| str.to_string()),
+            shape: self.shape().clone(),
+            axis,
+            buffer,
+            qparams,
+        }
+    }
+}
+
+// uniformは16Byte単位
+fn quant_params_buffer(shape: &Shape) -> wgpu::Buffer {
+    let (rows, cols) = shape.d2();
+    let params = WgpuServer::create_uniform_buffer(16, Some("quantize params"));
+    WgpuServer::write_buffer(&params, 0, bytemuck::cast_slice(&[rows as u32, cols as u32, 0, 0]));
+    params
+}
+
+// 量子化した値でCPUで計算する。積和はi64で厳密
+fn matmul_i8_cpu(a: &RawGi8, b: &RawGi8, m: usize, k: usize, n: usize) -> Vec<f32> {
+    let (qa, pa, qb, pb) = (a.to_vec(), a.qparams(), b.to_vec(), b.qparams());
+    let mut c = vec![0.0; m * n];
+    for i in 0..m {
+        for j in 0..n {
+            let acc: i64 = (0..k).map(|l| (qa[i * k + l] as i64 - pa[i].1 as i64) * (qb[l * n + j] as i64 - pb[j].1 as i64)).sum();
+            c[i * n + j] = pa[i].0 * pb[j].0 * acc as f32;
+        }
+    }
+    c
+}
+
+// 量子化と逆量子化を確かめて，逆量子化したものを返す
+fn check_quantize(x: &RawGf32, axis: Axis) -> RawGi8 {
+    let (rows, cols) = x.shape().d2();
+    let values = x.to_vec();
+    let q = x.quantize(axis);
+    let (codes, qparams) = (q.to_vec(), q.qparams());
+    if qparams.len() != axis.channels(rows, cols) {
+        panic!("quantize {:?}: {} qparams", axis, qparams.len());
+    }
+    let channel = |i: usize| match axis {
+        Axis::Row => i / cols,
+        Axis::Column => i % cols,
+    };
+    // CPUで同じ範囲からscaleとzero pointを求める
+    let mut ranges = vec![(0.0f32, 0.0f32); qparams.len()];
+    for (i, &v) in values.iter().enumerate() {
+        let r = &mut ranges[channel(i)];
+        *r = (r.0.min(v), r.1.max(v));
+    }
+    for (c, (&(lo, hi), &(scale, zero_point))) in ranges.iter().zip(&qparams).enumerate() {
+        let expected = if hi > lo { (hi - lo) / 255.0 } else { 1.0 };
+        if (scale - expected).abs() > expected * 1e-6 || !(-128..=127).contains(&zero_point) {
+            panic!("quantize {:?}: channel {}: scale = {}, zero_point = {}, range = [{}, {}]", axis, c, scale, zero_point, lo, hi);
+        }
+    }
+    // 逆量子化はscale * (q - zero_point)そのもの。誤差はscale / 2まで
+    let dequantized = q.dequantize().to_vec();
+    for (i, &v) in values.iter().enumerate() {
+        let (scale, zero_point) = qparams[channel(i)];
+        let expected = scale * (codes[i] as i32 - zero_point) as f32;
+        if dequantized[i] != expected {
+            panic!("dequantize {:?} mismatch at {}: {} != {}", axis, i, dequantized[i], expected);
+        }
+        if (dequantized[i] - v).abs() > scale * 0.5001 {
+            panic!("quantize {:?} error at {}: {} -> {} (scale {})", axis, i, v, dequantized[i], scale);
+        }
+    }
+    q
+}
+
+
+
+// f32の行列積と比べる。差は量子化誤差の分までで，比べたものを返す
+fn check_matmul_i8(rng: &mut Rng, m: usize, k: usize, n: usize) -> compare::Report {
+    // 活性はReLUの後のように非負
+    let a = rng.uniform(Shape::D2(m, k), 0.0, 2.0, Some("a"));
+    let b = rng.normal(Shape::D2(k, n), 0.0, 0.1, Some("b"));
+    let (qa, qb) = (check_quantize(&a, Axis::Row), check_quantize(&b, Axis::Column));
+    let c = qa.matmul(&qb);
+    // 量子化した値に対しては積和が厳密なのでf32の丸めだけ
+    c.compare(&matmul_i8_cpu(&qa, &qb, m, k, n), &Tolerance::new(1e-6, 0.0)).check("matmul_i8", c.shape());
+
+    // f32の行列積との差は量子化誤差の分: sum_k |a| * eb + |b| * ea + ea * eb
+    let (a_values, b_values) = (a.to_vec(), b.to_vec());
+    let (pa, pb) = (qa.qparams(), qb.qparams());
+    let expected = a.matmul(&b).to_vec();
+    let actual = c.to_vec();
+    for i in 0..m {
+        let ea = pa[i].0 as f64 / 2.0;
+        for j in 0..n {
+            let eb = pb[j].0 as f64 / 2.0;
+            let bound: f64 = (0..k).map(|l| a_values[i * k + l].abs() as f64 * eb + b_values[l * n + j].abs() as f64 * ea + ea * eb).sum();
+            let err = (actual[i * n + j] - expected[i * n + j]).abs() as f64;
+            if err > bound * 1.001 + 1e-5 {
+                panic!("matmul_i8 {}x{}x{} at ({}, {}): error {} > bound {}", m, k, n, i, j, err, bound);
+            }
+        }
+    }
+    compare::compare(&actual, &expected, &Tolerance::new(0.05, 0.05))
+}
+
+// 逆量子化したものの行列積と同じ
+fn check_matmul_dequantized(rng: &mut Rng) {
+    let a = rng.uniform(Shape::D2(40, 70), -1.0, 1.0, Some("a"));
+    let b = rng.uniform(Shape::D2(70, 30), -1.0, 1.0, Some("b"));
+    let (qa, qb) = (a.quantize(Axis::Row), b.quantize(Axis::Column));
+    qa.matmul(&qb).compare(&qa.dequantize().matmul(&qb.dequantize()).to_vec(), &Tolerance::new(1e-4, 1e-5)).check("matmul_i8 vs dequantized", &Shape::D2(40, 30));
+}
+
+pub fn run() {
+    let mut rng = Rng::new(8);
+
+    // 0だけのチャネルと負の値がないチャネル
+    let x = RawGf32::new_init(Shape::D2(3, 5), &vec![
+        0.0, 0.0, 0.0, 0.0, 0.0,
+        0.5, 1.0, 2.0, 0.25, 0.0,
+        -1.0, 3.0, -0.5, 0.125, 7.0,
+    ], Some("x"));
+    for axis in [Axis::Row, Axis::Column] {
+        check_quantize(&x, axis);
+    }
+    let q = x.quantize(Axis::Row);
+    if q.size() != 16 || q.to_vec()[..5] != [-128; 5] {
+        panic!("quantize zero row mismatch: {:?}", q.to_vec());
+    }
+
+    // 行列積。タイルの端数も試す
+    for (m, k, n) in [(1, 1, 1), (32, 32, 32), (37, 50, 23), (64, 129, 65), (5, 300, 3)] {
+        let report = check_matmul_i8(&mut rng, m, k, n);
+        println!("matmul_i8 {}x{}x{} vs f32: {}", m, k, n, report);
+    }
+    check_matmul_dequantized(&mut rng);
+
+    // 読むバイト数と速さ
+    let size = 512;
+    let a = rng.uniform(Shape::D2(size, size), -1.0, 1.0, Some("a"));
+    let b = rng.uniform(Shape::D2(size, size), -1.0, 1.0, Some("b"));
+    let (qa, qb) = (a.quantize(Axis::Row), b.quantize(Axis::Column));
+    println!("{}x{}: f32 {} bytes, int8 {} bytes", size, size, a.size(), qa.size());
+    qa.matmul(&qb);
+    WgpuServer::wait();
+    let s = std::time::Instant::now();
+    let repeat = 3;
+    for _ in 0..repeat {
+        qa.matmul(&qb);
+    }
+    WgpuServer::wait();
+    let time = s.elapsed() / repeat;
+    let gops = 2.0 * (size * size * size) as f64 / time.as_secs_f64() / 1e9;
+    println!("matmul_i8 {}x{}x{}: {:?}, {:.2} GOPS", size, size, size, time, gops);
+    println!("quantize ok");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matmul_structured2::adapter_available;
+
+    #[test]
+    fn quantize_channels() {
+        if !adapter_available() {
+            return;
+        }
+        let mut rng = Rng::new(1);
+        let x = rng.normal(Shape::D2(7, 45), 0.0, 3.0, Some("x"));
+        for axis in [Axis::Row, Axis::Column] {
+            check_quantize(&x, axis);
+        }
+    }
+
+    #[test]
+    fn matmul_vs_f32() {
+        if !adapter_available() {
+            return;
+        }
+        let mut rng = Rng::new(2);
+        for (m, k, n) in [(1, 1, 1), (32, 32, 32), (37, 50, 23), (64, 129, 65)] {
+            check_matmul_i8(&mut rng, m, k, n);
+        }
+        check_matmul_dequantized(&mut rng);
+    }
+
+    // i32の積和だけだとK > 33025であふれる
+    #[test]
+    fn matmul_long_k() {
+        if !adapter_available() {
+            return;
+        }
+        let mut rng = Rng::new(3);
+        check_matmul_i8(&mut rng, 3, 70_000, 2);
+        // 全部の項が(q_a - zp_a) * (q_b - zp_b) = 255 * -255になる。i32なら-4.5e9であふれる
+        let k = 70_000;
+        let a = RawGf32::new_init(Shape::D2(1, k), &vec![1.0; k], Some("a"));
+        let b = RawGf32::new_init(Shape::D2(k, 1), &vec![-1.0; k], Some("b"));
+        let (qa, qb) = (a.quantize(Axis::Row), b.quantize(Axis::Column));
+        let c = qa.matmul(&qb);
+        c.compare(&matmul_i8_cpu(&qa, &qb, 1, k, 1), &Tolerance::new(1e-6, 0.0)).check("matmul_i8 long k", c.shape());
+    }
+}
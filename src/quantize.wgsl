@@ -0,0 +1,72 @@
+// int8の量子化 / 逆量子化
+// DEQUANTIZE: false  input: Matrix<f32, ROWS, COLS>, output: int8を4個ずつ詰めたu32
+//             true   input: int8を4個ずつ詰めたu32,   output: Matrix<f32, ROWS, COLS>
+// どちらもu32として宣言してbitcastする
+@group(0) @binding(0)
+var<storage, read> input: array<u32>;
+// チャネルごとのscaleとzero point(quant_params.wgsl)
+struct QParams {
+    scale: f32,
+    zero_point: i32,
+}
+@group(0) @binding(1)
+var<storage, read> qparams: array<QParams>;
+@group(0) @binding(2)
+var<storage, read_write> output: array<u32>;
+// メタデータ
+struct QuantParams {
+    rows: u32,
+    cols: u32,
+}
+@group(0) @binding(3)
+var<uniform> params: QuantParams;
+
+
+/*
+
+要素iはi / 4番目のu32の(i % 4)番目のbyte(下位から)。row-majorの並びのまま詰めるので行の境目をまたぐことがある
+ROW: trueなら行ごとのQParams，falseなら列ごと
+    q = clamp(round(x / scale) + zero_point, -128, 127)
+    x = scale * (q - zero_point)
+量子化は1スレッドでu32を1個，逆量子化は1スレッドでf32を1個書く
+
+*/
+
+const ROW: bool = {{ROW}};
+const DEQUANTIZE: bool = {{DEQUANTIZE}};
+
+fn channel(i: u32) -> u32 {
+    return select(i % params.cols, i / params.cols, ROW);
+}
+
+@compute @workgroup_size(64, 1, 1)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>
+) {
+    let idx = global_id.x + global_id.y * num_workgroups.x * 64u;
+    let len = params.rows * params.cols;
+    if (DEQUANTIZE) {
+        if (idx >= len) {
+            return;
+        }
+        // 符号拡張
+        let q = i32(input[idx / 4u] << (24u - 8u * (idx % 4u))) >> 24u;
+        let p = qparams[channel(idx)];
+        output[idx] = bitcast<u32>(p.scale * f32(q - p.zero_point));
+    } else {
+        if (4u * idx >= len) {
+            return;
+        }
+        var word = 0u;
+        for (var j = 0u; j < 4u; j++) {
+            let i = 4u * idx + j;
+            if (i < len) {
+                let p = qparams[channel(i)];
+                let q = clamp(i32(round(bitcast<f32>(input[i]) / p.scale)) + p.zero_point, -128, 127);
+                word |= (u32(q) & 0xFFu) << (8u * j);
+            }
+        }
+        output[idx] = word;
+    }
+}
@@ -0,0 +1,243 @@
+use crate::elementwise;
+use crate::matmul_structured2::{Binding, RawGf32, Shape, WgpuServer};
+use crate::wgsl_template::{TemplateParams, WgslTemplate};
+
+
+/*
+GPUで乱数のテンソルを作る(Philox4x32-10, counter-based)
+    let mut rng = Rng::new(42);
+    let w = rng.normal(Shape::D2(784, 128), 0.0, 0.05, Some("w"));
+値は(seed, 何個目のテンソルか, 要素の位置)だけで決まるので，実行ごと・バックエンドごとに同じになる
+CPU版(generate_cpu)で確認できる
+    一様乱数(ビット列，[0, 1))とbernoulliはビット単位で一致する
+    normalはlog, cos, sinの精度がバックエンドで違うので誤差の範囲で一致する
+*/
+
+pub(crate) const RANDOM: WgslTemplate = WgslTemplate::new("random.wgsl", include_str!("./random.wgsl"));
+
+const PHILOX_M0: u32 = 0xD2511F53;
+const PHILOX_M1: u32 = 0xCD9E8D57;
+const PHILOX_W0: u32 = 0x9E3779B9;
+const PHILOX_W1: u32 = 0xBB67AE85;
+
+// 2^-24
+const UNIT: f32 = 1.0 / (1u32 << 24) as f32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Distribution {
+    // [alpha, beta)
+    Uniform,
+    // 平均alpha，標準偏差beta
+    Normal,
+    // 確率alphaで1，それ以外0
+    Bernoulli,
+}
+impl Distribution {
+    // shader_checkで全部検証する
+    pub(crate) const ALL: &'static [Distribution] = &[Distribution::Uniform, Distribution::Normal, Distribution::Bernoulli];
+
+    pub(crate) fn template_params(&self) -> TemplateParams {
+        TemplateParams::new().set("DIST", *self as u32)
+    }
+}
+
+// random.wgslのphiloxと同じ
+pub fn philox(counter: [u32; 4], key: [u32; 2]) -> [u32; 4] {
+    let mut c = counter;
+    let mut k = key;
+    for round in 0..10 {
+        if round > 0 {
+            k = [k[0].wrapping_add(PHILOX_W0), k[1].wrapping_add(PHILOX_W1)];
+        }
+        let p0 = PHILOX_M0 as u64 * c[0] as u64;
+        let p1 = PHILOX_M1 as u64 * c[2] as u64;
+        c = [(p1 >> 32) as u32 ^ c[1] ^ k[0], p1 as u32, (p0 >> 32) as u32 ^ c[3] ^ k[1], p0 as u32];
+    }
+    c
+}
+
+fn to_unit(x: u32) -> f32 {
+    (x >> 8) as f32 * UNIT
+}
+
+fn to_unit_nonzero(x: u32) -> f32 {
+    ((x >> 8) + 1) as f32 * UNIT
+}
+
+fn key(seed: u64) -> [u32; 2] {
+    [seed as u32, (seed >> 32) as u32]
+}
+
+// CPUで同じ乱数列を作る（確認用）
+pub(crate) fn generate_cpu(dist: Distribution, seed: u64, stream: u32, len: usize, alpha: f32, beta: f32) -> Vec<f32> {
+    let mut values = Vec::with_capacity(len.next_multiple_of(4));
+    for block in 0..len.div_ceil(4) {
+        let bits = philox([block as u32, stream, 0, 0], key(seed));
+        match dist {
+            Distribution::Uniform => values.extend(bits.iter().map(|&x| alpha + (beta - alpha) * to_unit(x))),
+            Distribution::Normal => {
+                for pair in bits.chunks(2) {
+                    let r = (-2.0 * to_unit_nonzero(pair[0]).ln()).sqrt();
+                    let t = 6.2831855 * to_unit(pair[1]);
+                    values.push(alpha + beta * r * t.cos());
+                    values.push(alpha + beta * r * t.sin());
+                }
+            }
+            Distribution::Bernoulli => values.extend(bits.iter().map(|&x| if to_unit(x) < alpha { 1.0 } else { 0.0 })),
+        }
+    }
+    values.truncate(len);
+    values
+}
+
+pub struct Rng {
+    seed: u64,
+    // 作ったテンソルの数。同じseedでもテンソルごとに違う乱数列になる
+    stream: u32,
+}
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, stream: 0 }
+    }
+
+    // [low, high)
+    pub fn uniform(&mut self, shape: Shape, low: f32, high: f32, label: Option<&str>) -> RawGf32 {
+        self.generate(Distribution::Uniform, shape, low, high, label)
+    }
+
+    pub fn normal(&mut self, shape: Shape, mean: f32, std: f32, label: Option<&str>) -> RawGf32 {
+        if std < 0.0 {
+            panic!("normal: std must not be negative, but {}", std);
+        }
+        self.generate(Distribution::Normal, shape, mean, std, label)
+    }
+
+    // 確率pで1
+    pub fn bernoulli(&mut self, shape: Shape, p: f32, label: Option<&str>) -> RawGf32 {
+        if !(0.0..=1.0).contains(&p) {
+            panic!("bernoulli: p must be in [0, 1], but {}", p);
+        }
+        self.generate(Distribution::Bernoulli, shape, p, 0.0, label)
+    }
+
+    fn generate(&mut self, dist: Distribution, shape: Shape, alpha: f32, beta: f32, label: Option<&str>) -> RawGf32 {
+        let len = shape.size();
+        if len.div_ceil(4) > u32::MAX as usize {
+            panic!("random: too many elements {}", len);
+        }
+        let out = RawGf32::_new_empty(shape, label);
+        let [key0, key1] = key(self.seed);
+        let params = WgpuServer::create_uniform_buffer(32, Some("random params"));
+        let words = [len as u32, key0, key1, self.stream, alpha.to_bits(), beta.to_bits(), 0, 0];
+        WgpuServer::write_buffer(&params, 0, bytemuck::cast_slice(&words));
+
+        let template_params = dist.template_params();
+        let shader_str = RANDOM.instantiate(&template_params).unwrap_or_else(|e| panic!("{}", e));
+        let bindings = [Binding::read_write(out.buffer()), Binding::uniform(&params)];
+        // 1スレッド4要素
+        let dispatch = elementwise::dispatch(len.div_ceil(4));
+        WgpuServer::execute_n("random", &bindings, &[], &RANDOM.instance_name(&template_params), &shader_str, dispatch);
+
+        self.stream = self.stream.checked_add(1).unwrap_or_else(|| panic!("random: stream overflow"));
+        out
+    }
+}
+
+
+
+// Random123のknown answer test
+fn check_known_answers() {
+    if philox([0; 4], [0; 2]) != [0x6627e8d5, 0xe169c58d, 0xbc57ac4c, 0x9b00dbd8] {
+        panic!("philox known answer mismatch: {:x?}", philox([0; 4], [0; 2]));
+    }
+    if philox([u32::MAX; 4], [u32::MAX; 2]) != [0x408f276d, 0x41c83b0e, 0xa20bc7c6, 0x6d5451fd] {
+        panic!("philox known answer mismatch: {:x?}", philox([u32::MAX; 4], [u32::MAX; 2]));
+    }
+}
+
+// GPUの乱数とgenerate_cpuを比べる。normalの(mean, std)を返す
+fn check_gpu_vs_cpu() -> (f64, f64) {
+    // 4で割り切れない要素数
+    let (rows, cols) = (37, 101);
+    let len = rows * cols;
+    let seed = 0x0123_4567_89ab_cdef;
+    let mut rng = Rng::new(seed);
+
+    // [0, 1)はビット単位で一致する
+    let u = rng.uniform(Shape::D2(rows, cols), 0.0, 1.0, Some("uniform")).to_vec();
+    if u != generate_cpu(Distribution::Uniform, seed, 0, len, 0.0, 1.0) {
+        panic!("uniform mismatch");
+    }
+    if u.iter().any(|&x| !(0.0..1.0).contains(&x)) {
+        panic!("uniform out of range");
+    }
+    let mean = u.iter().map(|&x| x as f64).sum::<f64>() / len as f64;
+    if (mean - 0.5).abs() > 0.02 {
+        panic!("uniform mean = {}", mean);
+    }
+    // 2個目のテンソルは別の乱数列
+    // alpha + (beta - alpha) * uはFMAにするバックエンドがあるので誤差を許す
+    let u2 = rng.uniform(Shape::D2(rows, cols), -2.0, 3.0, Some("uniform")).to_vec();
+    let expected = generate_cpu(Distribution::Uniform, seed, 1, len, -2.0, 3.0);
+    if u2.iter().zip(&expected).any(|(x, y)| (x - y).abs() > 1e-6) {
+        panic!("uniform [-2, 3) mismatch");
+    }
+
+    let z = rng.normal(Shape::D2(rows, cols), 1.0, 2.0, Some("normal")).to_vec();
+    let expected = generate_cpu(Distribution::Normal, seed, 2, len, 1.0, 2.0);
+    for i in 0..len {
+        if (z[i] - expected[i]).abs() > 1e-4 * expected[i].abs().max(1.0) {
+            panic!("normal mismatch at {}: {} != {}", i, z[i], expected[i]);
+        }
+    }
+    let mean = z.iter().map(|&x| x as f64).sum::<f64>() / len as f64;
+    let var = z.iter().map(|&x| (x as f64 - mean).powi(2)).sum::<f64>() / len as f64;
+    if (mean - 1.0).abs() > 0.1 || (var.sqrt() - 2.0).abs() > 0.1 {
+        panic!("normal mean = {}, std = {}", mean, var.sqrt());
+    }
+
+    let b = rng.bernoulli(Shape::D2(rows, cols), 0.3, Some("bernoulli")).to_vec();
+    if b != generate_cpu(Distribution::Bernoulli, seed, 3, len, 0.3, 0.0) {
+        panic!("bernoulli mismatch");
+    }
+    let p = b.iter().sum::<f32>() / len as f32;
+    if (p - 0.3).abs() > 0.03 {
+        panic!("bernoulli p = {}", p);
+    }
+
+    // 同じseedなら同じ値，違うseedなら違う値
+    if Rng::new(seed).uniform(Shape::D2(rows, cols), 0.0, 1.0, None).to_vec() != u {
+        panic!("rng is not reproducible");
+    }
+    if Rng::new(seed + 1).uniform(Shape::D2(rows, cols), 0.0, 1.0, None).to_vec() == u {
+        panic!("different seeds give the same values");
+    }
+    (mean, var.sqrt())
+}
+
+pub fn run() {
+    check_known_answers();
+    let (mean, std) = check_gpu_vs_cpu();
+    println!("normal: mean = {:.4}, std = {:.4}", mean, std);
+    println!("random ok");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matmul_structured2::adapter_available;
+
+    #[test]
+    fn philox_known_answers() {
+        check_known_answers();
+    }
+
+    #[test]
+    fn gpu_matches_cpu() {
+        if !adapter_available() {
+            return;
+        }
+        check_gpu_vs_cpu();
+    }
+}
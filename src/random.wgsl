@@ -0,0 +1,117 @@
+// 乱数で埋める
+// Matrix<f32, ROWS, COLS>
+@group(0) @binding(0)
+var<storage, read_write> output: array<f32>;
+// メタデータ
+struct RandomParams {
+    len: u32,
+    // Philoxのkey(seedの下位，上位)
+    key0: u32,
+    key1: u32,
+    // 何個目のテンソルか。counterの2番目に入れる
+    stream: u32,
+    // uniform: [alpha, beta)
+    // normal: 平均alpha，標準偏差beta
+    // bernoulli: 確率alphaで1
+    alpha: f32,
+    beta: f32,
+}
+@group(0) @binding(1)
+var<uniform> params: RandomParams;
+
+
+/*
+
+Philox4x32-10 (Salmon et al., Random123)
+counter = (block, stream, 0, 0)とkeyから4つのu32を作る。1スレッドが4要素(block * 4 ..)を受け持つ
+状態を持たないので，同じseed, streamなら何回・どのGPUで実行しても同じ値になる
+CPU側の実装(random::philox)とビット単位で一致する
+
+DISTはRust側(random::Distribution)からテンプレートとして埋める
+    0: uniform, 1: normal(Box-Muller), 2: bernoulli
+
+*/
+
+const DIST: u32 = {{DIST}}u;
+
+const PHILOX_M0: u32 = 0xD2511F53u;
+const PHILOX_M1: u32 = 0xCD9E8D57u;
+const PHILOX_W0: u32 = 0x9E3779B9u;
+const PHILOX_W1: u32 = 0xBB67AE85u;
+
+// 32bit x 32bitの上位32bit。u64がないので16bitずつに分けて掛ける
+fn mulhi(a: u32, b: u32) -> u32 {
+    let a_lo = a & 0xffffu;
+    let a_hi = a >> 16u;
+    let b_lo = b & 0xffffu;
+    let b_hi = b >> 16u;
+    let lo_lo = a_lo * b_lo;
+    let hi_lo = a_hi * b_lo;
+    let lo_hi = a_lo * b_hi;
+    let hi_hi = a_hi * b_hi;
+    let cross = (lo_lo >> 16u) + (hi_lo & 0xffffu) + lo_hi;
+    return hi_hi + (hi_lo >> 16u) + (cross >> 16u);
+}
+
+fn philox(counter: vec4<u32>, key: vec2<u32>) -> vec4<u32> {
+    var c = counter;
+    var k = key;
+    for (var round = 0u; round < 10u; round += 1u) {
+        if (round > 0u) {
+            k += vec2<u32>(PHILOX_W0, PHILOX_W1);
+        }
+        let hi0 = mulhi(PHILOX_M0, c.x);
+        let lo0 = PHILOX_M0 * c.x;
+        let hi1 = mulhi(PHILOX_M1, c.z);
+        let lo1 = PHILOX_M1 * c.z;
+        c = vec4<u32>(hi1 ^ c.y ^ k.x, lo1, hi0 ^ c.w ^ k.y, lo0);
+    }
+    return c;
+}
+
+// [0, 1)。上位24bitを使うのでf32で正確に表せる
+fn to_unit(x: u32) -> f32 {
+    return f32(x >> 8u) * 5.9604645e-8;
+}
+
+// (0, 1]。Box-Mullerでlog(0)にならないように
+fn to_unit_nonzero(x: u32) -> f32 {
+    return f32((x >> 8u) + 1u) * 5.9604645e-8;
+}
+
+@compute @workgroup_size(64, 1, 1)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>
+) {
+    // 要素数がdispatchの上限を超えるときはyにも分ける
+    let block = global_id.x + global_id.y * num_workgroups.x * 64u;
+    let base = block * 4u;
+    if (base >= params.len) {
+        return;
+    }
+    let bits = philox(vec4<u32>(block, params.stream, 0u, 0u), vec2<u32>(params.key0, params.key1));
+
+    var values: vec4<f32>;
+    if (DIST == 0u) {
+        let u = vec4<f32>(to_unit(bits.x), to_unit(bits.y), to_unit(bits.z), to_unit(bits.w));
+        values = params.alpha + (params.beta - params.alpha) * u;
+    } else if (DIST == 1u) {
+        // 2つの一様乱数から2つの正規乱数
+        let r0 = sqrt(-2.0 * log(to_unit_nonzero(bits.x)));
+        let t0 = 6.2831855 * to_unit(bits.y);
+        let r1 = sqrt(-2.0 * log(to_unit_nonzero(bits.z)));
+        let t1 = 6.2831855 * to_unit(bits.w);
+        let z = vec4<f32>(r0 * cos(t0), r0 * sin(t0), r1 * cos(t1), r1 * sin(t1));
+        values = params.alpha + params.beta * z;
+    } else {
+        let u = vec4<f32>(to_unit(bits.x), to_unit(bits.y), to_unit(bits.z), to_unit(bits.w));
+        values = select(vec4<f32>(0.0), vec4<f32>(1.0), u < vec4<f32>(params.alpha));
+    }
+
+    for (var j = 0u; j < 4u; j += 1u) {
+        if (base + j < params.len) {
+            output[base + j] = values[j];
+        }
+    }
+}
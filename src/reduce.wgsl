@@ -0,0 +1,52 @@
+// 全要素の和 output[0] = alpha * sum(input)
+@group(0) @binding(0)
+var<storage, read> input: array<f32>;
+// Matrix<f32, 1, 1>
+@group(0) @binding(1)
+var<storage, read_write> output: array<f32>;
+// メタデータ
+struct ElementwiseParams {
+    len: u32,
+    alpha: f32,
+    beta: f32,
+}
+@group(0) @binding(2)
+var<uniform> params: ElementwiseParams;
+
+
+/*
+
+1つのworkgroupで全部足す（lossなど小さいものを想定）
+各スレッドがWGおきに足してから，shared memoryで木の形に足す
+mean()はalpha = 1 / lenにする
+
+*/
+
+// 2の累乗（reductionのため）
+const WG: u32 = 256u;
+
+var<workgroup> partial: array<f32, WG>;
+
+@compute @workgroup_size(256, 1, 1)
+fn main(
+    @builtin(local_invocation_id) local_id: vec3<u32>
+) {
+    let tid = local_id.x;
+    var sum = 0.0;
+    for (var i = tid; i < params.len; i += WG) {
+        sum += input[i];
+    }
+    partial[tid] = sum;
+    workgroupBarrier();
+
+    for (var stride = WG / 2u; stride > 0u; stride /= 2u) {
+        if (tid < stride) {
+            partial[tid] += partial[tid + stride];
+        }
+        workgroupBarrier();
+    }
+
+    if (tid == 0u) {
+        output[0] = params.alpha * partial[0];
+    }
+}
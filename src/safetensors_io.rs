@@ -0,0 +1,167 @@
+use std::{collections::HashMap, fs::File, io, path::Path};
+use safetensors::{Dtype, SafeTensors, tensor::TensorView};
+use crate::matmul_structured2::{RawGf32, Shape, WgpuServer};
+
+
+/*
+safetensorsの読み書き (https://github.com/huggingface/safetensors)
+    u64(header_len) + JSON header + data
+
+読み込みはファイルをmmapして，F32ならmmapしたスライスをそのままqueue.write_bufferで送る（中間のVecを作らない）
+F16 / BF16 / I32はf32に変換してから送る
+*/
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn tensor_to_gpu(name: &str, view: &TensorView) -> io::Result<RawGf32> {
+    // RawGf32は2次元か3次元(バッチ)。0次元は(1, 1)，1次元は行ベクトル(1, n)とする
+    let shape = match view.shape() {
+        [] => Shape::D2(1, 1),
+        [n] => Shape::D2(1, *n),
+        [m, n] => Shape::D2(*m, *n),
+        [b, m, n] => Shape::D3(*b, *m, *n),
+        s => return Err(invalid(format!("tensor '{}' has shape {:?}, only up to 3-D is supported", name, s))),
+    };
+    let data = view.data();
+
+    let tensor = match view.dtype() {
+        Dtype::F32 => {
+            // little endianのf32なのでbyte列をそのまま書き込める
+            let tensor = RawGf32::_new_empty(shape, Some(name));
+            WgpuServer::write_buffer(tensor.buffer(), 0, data);
+            tensor
+        }
+        Dtype::F16 => {
+            let values: Vec<f32> = data.chunks_exact(2)
+                .map(|b| half::f16::from_le_bytes([b[0], b[1]]).to_f32())
+                .collect();
+            RawGf32::new_init(shape, &values, Some(name))
+        }
+        Dtype::BF16 => {
+            let values: Vec<f32> = data.chunks_exact(2)
+                .map(|b| half::bf16::from_le_bytes([b[0], b[1]]).to_f32())
+                .collect();
+            RawGf32::new_init(shape, &values, Some(name))
+        }
+        Dtype::I32 => {
+            let values: Vec<f32> = data.chunks_exact(4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32)
+                .collect();
+            RawGf32::new_init(shape, &values, Some(name))
+        }
+        dtype => return Err(invalid(format!("tensor '{}' has unsupported dtype {:?}", name, dtype))),
+    };
+    Ok(tensor)
+}
+
+impl RawGf32 {
+    pub fn load_safetensors<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, RawGf32>> {
+        let file = File::open(path)?;
+        // SAFETY: 読み込み中にファイルが書き換えられないことを前提にする
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        let tensors = SafeTensors::deserialize(&mmap)
+            .map_err(|e| invalid(format!("broken safetensors file: {:?}", e)))?;
+
+        let mut result = HashMap::new();
+        for (name, view) in tensors.tensors() {
+            let tensor = tensor_to_gpu(&name, &view)?;
+            result.insert(name, tensor);
+        }
+        Ok(result)
+    }
+
+    // 全部F32で保存する
+    pub fn save_safetensors<P: AsRef<Path>>(path: P, tensors: &[(&str, &RawGf32)]) -> io::Result<()> {
+        let values: Vec<(String, Vec<usize>, Vec<f32>)> = tensors.iter().map(|(name, tensor)| {
+            let shape = match *tensor.shape() {
+                Shape::D2(m, n) => vec![m, n],
+                Shape::D3(b, m, n) => vec![b, m, n],
+            };
+            (name.to_string(), shape, tensor.to_vec())
+        }).collect();
+
+        let mut views = vec![];
+        for (name, shape, data) in values.iter() {
+            let view = TensorView::new(Dtype::F32, shape.clone(), bytemuck::cast_slice(data))
+                .map_err(|e| invalid(format!("tensor '{}': {:?}", name, e)))?;
+            views.push((name.clone(), view));
+        }
+        safetensors::serialize_to_file(views, &None, path.as_ref())
+            .map_err(|e| io::Error::other(format!("failed to write safetensors: {:?}", e)))
+    }
+}
+
+
+
+pub fn run() {
+    let path = std::env::temp_dir().join("wgpu_matmul_weights.safetensors");
+
+    let w_values: Vec<f32> = (0..32 * 16).map(|i| (i % 11) as f32 - 5.0).collect();
+    let b_values: Vec<f32> = (0..16).map(|i| i as f32 * 0.25).collect();
+    let w = RawGf32::new_init(Shape::D2(32, 16), &w_values, Some("w"));
+    let b = RawGf32::new_init(Shape::D2(1, 16), &b_values, Some("b"));
+
+    RawGf32::save_safetensors(&path, &[("linear.weight", &w), ("linear.bias", &b)]).unwrap();
+    let loaded = RawGf32::load_safetensors(&path).unwrap();
+
+    if loaded["linear.weight"].shape() != &Shape::D2(32, 16) || loaded["linear.weight"].to_vec() != w_values {
+        panic!("safetensors weight roundtrip failed");
+    }
+    if loaded["linear.bias"].to_vec() != b_values {
+        panic!("safetensors bias roundtrip failed");
+    }
+
+    // 壊れたファイルはErr
+    std::fs::write(&path, b"broken").unwrap();
+    if RawGf32::load_safetensors(&path).is_ok() {
+        panic!("broken safetensors must be an error");
+    }
+
+    println!("safetensors ok");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matmul_structured2::adapter_available;
+
+    // テストは並列に走るので名前を分ける
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("wgpu_matmul_{}_{}", std::process::id(), name))
+    }
+
+    // F32はmmapしたスライスをwrite_bufferで送る経路を通る。2つ目以降のtensorはファイルの途中から読む
+    #[test]
+    fn roundtrip_f32() {
+        if !adapter_available() {
+            return;
+        }
+        let path = temp_path("roundtrip.safetensors");
+        let mut specials = vec![-0.0, f32::NAN, f32::INFINITY, f32::MIN_POSITIVE / 2.0];
+        specials.extend((0..2).map(|i| i as f32 * 0.1));
+        let tensors = [
+            ("d2", Shape::D2(3, 5), (0..15).map(|i| i as f32 * 0.37 - 2.0).collect::<Vec<f32>>()),
+            ("d3", Shape::D3(2, 3, 4), (0..24).map(|i| (i as f32).sin()).collect()),
+            ("one", Shape::D2(1, 1), vec![1.5]),
+            ("specials", Shape::D2(2, 3), specials),
+        ];
+        let gpu: Vec<RawGf32> = tensors.iter()
+            .map(|(name, shape, values)| RawGf32::new_init(shape.clone(), values, Some(name)))
+            .collect();
+        let named: Vec<(&str, &RawGf32)> = tensors.iter().zip(gpu.iter()).map(|((name, _, _), t)| (*name, t)).collect();
+        RawGf32::save_safetensors(&path, &named).unwrap();
+        let loaded = RawGf32::load_safetensors(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), tensors.len());
+        for (name, shape, values) in tensors.iter() {
+            let tensor = &loaded[*name];
+            assert_eq!(tensor.shape(), shape, "{}", name);
+            let bits = |v: &[f32]| v.iter().map(|x| x.to_bits()).collect::<Vec<u32>>();
+            assert_eq!(bits(&tensor.to_vec()), bits(values), "{}", name);
+        }
+    }
+}
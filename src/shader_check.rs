@@ -0,0 +1,391 @@
+use crate::autotune::{self, TileConfig, BLOCKING1D, BLOCKING2D, VEC4, VECTORIZE};
+use crate::df64;
+use crate::epilogue::{self, Activation, Epilogue};
+use crate::elementwise::{self, Map};
+use crate::f16;
+use crate::matmul_structured2::{matmul_params_decl, matmul_template_params, GEMV, GEMV_T, MATMUL_NAIVE, MATMUL_TRANS};
+use crate::nn;
+use crate::quantize;
+use crate::random::{self, Distribution};
+use crate::softmax;
+use crate::transpose;
+use crate::wgsl_template::{self, TemplateError, TemplateParams, WgslTemplate};
+
+
+/*
+GPUなしで全部の.wgslをnagaで検証する
+    1. parse + validate
+    2. entry point "main"がcomputeであること
+    3. group(0)のbindingがexecute_4 / execute_6 / execute_matmulで渡すもの(read / read_write / uniform)と一致すること
+   epilogueありの行列積はbias, residualがMatmulParamsの後に続く
+    4. workgroupのメモリとスレッド数がLimitsに収まること
+テンプレートはautotuneの探索空間の全部の組み合わせで埋めて検証する
+行列積カーネルはuniform版とpush constant版の両方を検証する
+*/
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Access {
+    Read,
+    ReadWrite,
+    Uniform,
+}
+
+// binding(i)のアクセス
+// execute_4: 使っていない行列積カーネル。binding(3)はsizes
+pub const EXECUTE_4: &[Access] = &[Access::Read, Access::Read, Access::ReadWrite, Access::Read];
+// execute_6: CSR。binding(4)が出力，binding(5)がsizes
+pub const EXECUTE_6: &[Access] = &[Access::Read, Access::Read, Access::Read, Access::Read, Access::ReadWrite, Access::Read];
+// execute_matmul: binding(3)はMatmulParamsのuniform。push constantsが使えるときはbinding(3)なし
+pub const MATMUL: &[Access] = &[Access::Read, Access::Read, Access::ReadWrite, Access::Uniform];
+pub const MATMUL_PUSH: &[Access] = &[Access::Read, Access::Read, Access::ReadWrite];
+// epilogueのbias, residualはMATMUL / MATMUL_PUSHの後に続く。[push_constants][bufferの数]
+pub const MATMUL_EPILOGUE: [[&[Access]; 3]; 2] = [
+    [
+        MATMUL,
+        &[Access::Read, Access::Read, Access::ReadWrite, Access::Uniform, Access::Read],
+        &[Access::Read, Access::Read, Access::ReadWrite, Access::Uniform, Access::Read, Access::Read],
+    ],
+    [
+        MATMUL_PUSH,
+        &[Access::Read, Access::Read, Access::ReadWrite, Access::Read],
+        &[Access::Read, Access::Read, Access::ReadWrite, Access::Read, Access::Read],
+    ],
+];
+// softmax.rs: (input, output, params) / (logits, labels, loss, params)
+pub const SOFTMAX: &[Access] = &[Access::Read, Access::ReadWrite, Access::Uniform];
+pub const CROSS_ENTROPY: &[Access] = &[Access::Read, Access::Read, Access::ReadWrite, Access::Uniform];
+pub const CROSS_ENTROPY_GRAD: &[Access] = &[Access::Read, Access::Read, Access::Read, Access::ReadWrite, Access::Uniform];
+// elementwise.rs: (a, b, output, params) / (input, output, params)
+pub const ELEMENTWISE: &[Access] = &[Access::Read, Access::Read, Access::ReadWrite, Access::Uniform];
+pub const REDUCE: &[Access] = &[Access::Read, Access::ReadWrite, Access::Uniform];
+// nn.rs: (param, grad, state, params)
+pub const OPTIMIZER: &[Access] = &[Access::ReadWrite, Access::Read, Access::ReadWrite, Access::Uniform];
+// transpose.rs: (input, output, params)
+pub const TRANSPOSE: &[Access] = &[Access::Read, Access::ReadWrite, Access::Uniform];
+// f16.rs: (input, output, params) / (lhs, rhs, output, params)
+pub const F16_CONVERT: &[Access] = &[Access::Read, Access::ReadWrite, Access::Uniform];
+pub const MATMUL_F16: &[Access] = &[Access::Read, Access::Read, Access::ReadWrite, Access::Uniform];
+// df64.rs: (lhs, rhs, output, params)
+pub const MATMUL_DF64: &[Access] = &[Access::Read, Access::Read, Access::ReadWrite, Access::Uniform];
+// quantize.rs: (input, qparams, params) / (input, qparams, output, params) / (lhs, lhs_qparams, rhs, rhs_qparams, output, params)
+pub const QUANT_PARAMS: &[Access] = &[Access::Read, Access::ReadWrite, Access::Uniform];
+pub const QUANTIZE: &[Access] = &[Access::Read, Access::Read, Access::ReadWrite, Access::Uniform];
+pub const MATMUL_I8: &[Access] = &[Access::Read, Access::Read, Access::Read, Access::Read, Access::ReadWrite, Access::Uniform];
+// random.rs: (output, params)
+pub const RANDOM: &[Access] = &[Access::ReadWrite, Access::Uniform];
+// collatz.rsは自前でbindingを1つだけ作る
+pub const COLLATZ: &[Access] = &[Access::ReadWrite];
+
+// (ファイル名, ソース, layout)
+pub const SHADERS: &[(&str, &str, &[Access])] = &[
+    ("1naive.wgsl", include_str!("./1naive.wgsl"), EXECUTE_4),
+    ("2GMcoalescing.wgsl", include_str!("./2GMcoalescing.wgsl"), EXECUTE_4),
+    ("3shared.wgsl", include_str!("./3shared.wgsl"), EXECUTE_4),
+    ("collatz.wgsl", include_str!("./collatz.wgsl"), COLLATZ),
+    (softmax::CROSS_ENTROPY.0, softmax::CROSS_ENTROPY.1, CROSS_ENTROPY),
+    (elementwise::REDUCE.0, elementwise::REDUCE.1, REDUCE),
+    (softmax::CROSS_ENTROPY_GRAD.0, softmax::CROSS_ENTROPY_GRAD.1, CROSS_ENTROPY_GRAD),
+    (transpose::TRANSPOSE.0, transpose::TRANSPOSE.1, TRANSPOSE),
+    (f16::MATMUL_F16.0, f16::MATMUL_F16.1, MATMUL_F16),
+    ("matmul2.wgsl", include_str!("./matmul2.wgsl"), EXECUTE_4),
+    ("spmm.wgsl", include_str!("./spmm.wgsl"), EXECUTE_6),
+    ("spmv.wgsl", include_str!("./spmv.wgsl"), EXECUTE_6),
+];
+
+// テンプレートと，それを埋めるパラメータの集合
+pub fn templates(limits: &wgpu::Limits) -> Vec<(WgslTemplate, Vec<TemplateParams>, &'static [Access])> {
+    let tiles = autotune::search_space(limits);
+    // 4blocking1dはTN = 1
+    let mut tiles_1d = vec![];
+    for &bm in &[32, 64, 128] {
+        for &bn in &[32, 64, 128] {
+            for &bk in &[4, 8, 16] {
+                for &tm in &[4, 8, 16] {
+                    let config = TileConfig { bm, bn, bk, tm, tn: 1 };
+                    if config.is_valid(limits) {
+                        tiles_1d.push(config);
+                    }
+                }
+            }
+        }
+    }
+    // vec4版はTM, TNが4の倍数
+    let tiles_vec4: Vec<TileConfig> = tiles.iter().copied().filter(|c| c.tm % 4 == 0 && c.tn % 4 == 0).collect();
+    let params = |configs: &[TileConfig]| configs.iter().map(|c| c.params()).collect::<Vec<_>>();
+
+    let mut templates = vec![
+        (BLOCKING1D, params(&tiles_1d), EXECUTE_4),
+        (BLOCKING2D, params(&tiles), EXECUTE_4),
+        (VEC4, params(&tiles_vec4), EXECUTE_4),
+        (softmax::SOFTMAX, vec![TemplateParams::new().set("LOG", false), TemplateParams::new().set("LOG", true)], SOFTMAX),
+        (elementwise::ELEMENTWISE, Map::ALL.iter().map(|m| m.template_params()).collect(), ELEMENTWISE),
+        (nn::OPTIMIZER, vec![TemplateParams::new().set("ADAM", false), TemplateParams::new().set("ADAM", true)], OPTIMIZER),
+        (random::RANDOM, Distribution::ALL.iter().map(|d| d.template_params()).collect(), RANDOM),
+        (f16::CONVERT, f16::convert_template_params(), F16_CONVERT),
+        (quantize::QUANT_PARAMS, quantize::quant_params_template_params(), QUANT_PARAMS),
+        (quantize::QUANTIZE, quantize::quantize_template_params(), QUANTIZE),
+        (quantize::MATMUL_I8, params(&tiles), MATMUL_I8),
+        (df64::MATMUL_DF64, df64::template_params(), MATMUL_DF64),
+    ];
+    // execute_matmulで使うもの
+    let none = Epilogue::default();
+    for (push_constants, layout) in [(false, MATMUL), (true, MATMUL_PUSH)] {
+        let vectorize = tiles.iter().map(|c| matmul_template_params(c.params(), push_constants, &none)).collect();
+        templates.push((VECTORIZE, vectorize, layout));
+        for template in [MATMUL_NAIVE, GEMV, GEMV_T] {
+            templates.push((template, vec![matmul_template_params(TemplateParams::new(), push_constants, &none)], layout));
+        }
+        let trans = [(false, false), (true, false), (false, true), (true, true)].iter()
+            .map(|&(l, r)| {
+                let params = TemplateParams::new().set("TRANS_LHS", l).set("TRANS_RHS", r);
+                matmul_template_params(params, push_constants, &none)
+            })
+            .collect();
+        templates.push((MATMUL_TRANS, trans, layout));
+    }
+    // epilogueあり(bias, activation, residual)
+    let epilogues = [
+        (false, Activation::Relu, false),
+        (true, Activation::Gelu, false),
+        (false, Activation::Tanh, true),
+        (true, Activation::Gelu, true),
+    ];
+    for push_constants in [false, true] {
+        for &(bias, activation, residual) in &epilogues {
+            let layout = MATMUL_EPILOGUE[push_constants as usize][bias as usize + residual as usize];
+            let params = |p: TemplateParams| {
+                epilogue::template_params(p, push_constants, bias, activation, residual).set("PARAMS", matmul_params_decl(push_constants))
+            };
+            templates.push((VECTORIZE, vec![params(TileConfig::default().params())], layout));
+            templates.push((MATMUL_NAIVE, vec![params(TemplateParams::new())], layout));
+        }
+    }
+    templates
+}
+
+// 書きかけで検証が通らないことが分かっているもの
+// 直したらここから外す（通るようになったのに残っているとcargo testが失敗する）
+pub const KNOWN_BROKEN: &[&str] = &["6_2vec4.wgsl"];
+
+#[derive(Debug)]
+pub enum CheckError {
+    Naga(TemplateError),
+    Check { name: String, message: String },
+}
+impl std::fmt::Display for CheckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckError::Naga(e) => write!(f, "{}", e),
+            CheckError::Check { name, message } => write!(f, "{}: {}", name, message),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShaderReport {
+    pub workgroup_size: [u32; 3],
+    // Byte
+    pub workgroup_memory: u32,
+}
+
+// 検証済みのModuleについて2. 3. 4.を調べる
+pub fn check_module(
+    name: &str,
+    module: &naga::Module,
+    layout: &[Access],
+    limits: &wgpu::Limits,
+) -> Result<ShaderReport, CheckError> {
+    let error = |message: String| CheckError::Check { name: name.to_string(), message };
+
+    let Some(entry) = module.entry_points.iter().find(|e| e.name == "main") else {
+        return Err(error("entry point 'main' not found".to_string()));
+    };
+    if entry.stage != naga::ShaderStage::Compute {
+        return Err(error(format!("entry point 'main' is {:?}, not compute", entry.stage)));
+    }
+
+    // binding
+    let mut bound = vec![None; layout.len()];
+    let mut workgroup_memory = 0;
+    let mut layouter = naga::proc::Layouter::default();
+    layouter.update(module.to_ctx()).map_err(|e| error(format!("layout error: {}", e)))?;
+    for (_, var) in module.global_variables.iter() {
+        let var_name = var.name.clone().unwrap_or_default();
+        match (var.space, &var.binding) {
+            (naga::AddressSpace::WorkGroup, _) => {
+                workgroup_memory += layouter[var.ty].size;
+            }
+            (naga::AddressSpace::Storage { .. } | naga::AddressSpace::Uniform, Some(binding)) => {
+                if binding.group != 0 {
+                    return Err(error(format!("'{}' is in group({}), execute_* only binds group(0)", var_name, binding.group)));
+                }
+                let i = binding.binding as usize;
+                if i >= layout.len() {
+                    return Err(error(format!("'{}' is binding({}), but only {} buffers are bound", var_name, i, layout.len())));
+                }
+                let actual = match var.space {
+                    naga::AddressSpace::Storage { access } if access.contains(naga::StorageAccess::STORE) => Access::ReadWrite,
+                    naga::AddressSpace::Storage { .. } => Access::Read,
+                    _ => Access::Uniform,
+                };
+                if actual != layout[i] {
+                    return Err(error(format!("'{}' (binding({})) is {:?}, expected {:?}", var_name, i, actual, layout[i])));
+                }
+                bound[i] = Some(var_name);
+            }
+            (space, Some(binding)) => {
+                return Err(error(format!("'{}' (binding({})) is {:?}, execute_* only binds buffers", var_name, binding.binding, space)));
+            }
+            _ => {}
+        }
+    }
+    if let Some(i) = bound.iter().position(|b| b.is_none()) {
+        return Err(error(format!("binding({}) is bound by execute_* but not declared", i)));
+    }
+
+    // limits
+    let size = entry.workgroup_size;
+    let max_size = [limits.max_compute_workgroup_size_x, limits.max_compute_workgroup_size_y, limits.max_compute_workgroup_size_z];
+    if (0..3).any(|i| size[i] > max_size[i]) {
+        return Err(error(format!("workgroup_size {:?} exceeds {:?}", size, max_size)));
+    }
+    let invocations = size[0] * size[1] * size[2];
+    if invocations > limits.max_compute_invocations_per_workgroup {
+        return Err(error(format!("{} invocations per workgroup exceeds {}", invocations, limits.max_compute_invocations_per_workgroup)));
+    }
+    if workgroup_memory > limits.max_compute_workgroup_storage_size {
+        return Err(error(format!("{} bytes of workgroup memory exceeds {}", workgroup_memory, limits.max_compute_workgroup_storage_size)));
+    }
+
+    Ok(ShaderReport { workgroup_size: size, workgroup_memory })
+}
+
+pub fn check_shader(name: &str, source: &str, layout: &[Access], limits: &wgpu::Limits) -> Result<ShaderReport, CheckError> {
+    let (module, _) = wgsl_template::validate(name, source).map_err(CheckError::Naga)?;
+    check_module(name, &module, layout, limits)
+}
+
+// (名前, 結果)を全部返す。テンプレートはインスタンスごと
+pub fn check_all(limits: &wgpu::Limits) -> Vec<(String, Result<ShaderReport, CheckError>)> {
+    let mut results = check_shaders(limits);
+    results.extend(check_templates(limits));
+    results
+}
+
+pub fn check_shaders(limits: &wgpu::Limits) -> Vec<(String, Result<ShaderReport, CheckError>)> {
+    SHADERS.iter()
+        .map(|(name, source, layout)| (name.to_string(), check_shader(name, source, layout, limits)))
+        .collect()
+}
+
+pub fn check_templates(limits: &wgpu::Limits) -> Vec<(String, Result<ShaderReport, CheckError>)> {
+    let mut results = vec![];
+    for (template, configs, layout) in templates(limits) {
+        for params in configs {
+            let name = template.instance_name(&params);
+            let result = template.validate(&params)
+                .map_err(CheckError::Naga)
+                .and_then(|(module, _)| check_module(&name, &module, layout, limits));
+            results.push((name, result));
+        }
+    }
+    results
+}
+
+// 失敗したもののメッセージ。KNOWN_BROKENは失敗してよいが，通るようになったら失敗として返す
+pub fn failures(results: &[(String, Result<ShaderReport, CheckError>)]) -> Vec<String> {
+    let mut failures = vec![];
+    for (name, result) in results {
+        let file_name = name.split('[').next().unwrap();
+        match (result, KNOWN_BROKEN.contains(&file_name)) {
+            (Ok(_), true) => failures.push(format!("{} passes validation, remove it from KNOWN_BROKEN", name)),
+            (Ok(_), false) | (Err(_), true) => {}
+            (Err(e), false) => failures.push(e.to_string()),
+        }
+    }
+    failures
+}
+
+
+
+// 検証はcargo testで行う(tests)。ここでは数を表示するだけ
+pub fn run() {
+    for (limits_name, limits) in [("default", wgpu::Limits::default()), ("downlevel", wgpu::Limits::downlevel_defaults())] {
+        let results = check_all(&limits);
+        let failures = failures(&results);
+        for failure in &failures {
+            println!("{}\n", failure);
+        }
+        println!("shader_check ({}): {} shaders, {} failed", limits_name, results.len(), failures.len());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_no_failures(results: Vec<(String, Result<ShaderReport, CheckError>)>) {
+        let failures = failures(&results);
+        assert!(failures.is_empty(), "{} of {} shaders failed:\n{}", failures.len(), results.len(), failures.join("\n\n"));
+    }
+
+    // ソースディレクトリの.wgslが全部登録されているか
+    #[test]
+    fn all_shaders_registered() {
+        let src = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let registered: Vec<&str> = SHADERS.iter().map(|(name, _, _)| *name)
+            .chain(templates(&wgpu::Limits::default()).iter().map(|(t, _, _)| t.name))
+            .collect();
+        for entry in std::fs::read_dir(&src).unwrap() {
+            let file_name = entry.unwrap().file_name().into_string().unwrap();
+            if file_name.ends_with(".wgsl") {
+                assert!(registered.contains(&file_name.as_str()), "{} is not registered in shader_check::SHADERS", file_name);
+            }
+        }
+    }
+
+    #[test]
+    fn shaders_default_limits() {
+        assert_no_failures(check_shaders(&wgpu::Limits::default()));
+    }
+
+    #[test]
+    fn shaders_downlevel_limits() {
+        assert_no_failures(check_shaders(&wgpu::Limits::downlevel_defaults()));
+    }
+
+    #[test]
+    fn templates_default_limits() {
+        assert_no_failures(check_templates(&wgpu::Limits::default()));
+    }
+
+    #[test]
+    fn templates_downlevel_limits() {
+        assert_no_failures(check_templates(&wgpu::Limits::downlevel_defaults()));
+    }
+
+    // limitsを小さくするとworkgroupメモリで落ちる
+    #[test]
+    fn workgroup_memory_limit() {
+        let small = wgpu::Limits { max_compute_workgroup_storage_size: 1024, ..wgpu::Limits::default() };
+        let config = TileConfig { bm: 64, bn: 64, bk: 8, tm: 8, tn: 8 };
+        let params = matmul_template_params(config.params(), false, &Epilogue::default());
+        let (module, _) = VECTORIZE.validate(&params).unwrap();
+        assert!(check_module("6vectorize.wgsl", &module, MATMUL, &small).is_err());
+    }
+
+    // layoutが違うのも検出する
+    #[test]
+    fn binding_mismatch() {
+        let (name, source, _) = SHADERS.iter().find(|(name, _, _)| *name == "collatz.wgsl").unwrap();
+        assert!(check_shader(name, source, EXECUTE_4, &wgpu::Limits::default()).is_err());
+    }
+
+    // 壊れたシェーダは失敗として数える
+    #[test]
+    fn broken_shader_fails() {
+        let results = vec![("broken.wgsl".to_string(), check_shader("broken.wgsl", "fn main( {", EXECUTE_4, &wgpu::Limits::default()))];
+        assert_eq!(failures(&results).len(), 1);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::elementwise;
+use crate::matmul_structured2::{Binding, RawGf32, Shape, WgpuServer};
+use crate::wgsl_template::{TemplateParams, WgslTemplate};
+
+
+/*
+行ごとのsoftmax / log-softmaxと交差エントロピー
+    softmax(x)_j = exp(x_j - max(x)) / sum_k exp(x_k - max(x))
+    log_softmax(x)_j = x_j - max(x) - log(sum_k exp(x_k - max(x)))
+    cross_entropy(x, label) = mean_i(-log_softmax(x_i)[label_i])
+最大値を引くので大きな値(1000など)でもinfにならない
+*/
+
+pub(crate) const SOFTMAX: WgslTemplate = WgslTemplate::new("softmax.wgsl", include_str!("./softmax.wgsl"));
+pub(crate) const CROSS_ENTROPY: (&str, &str) = ("cross_entropy.wgsl", include_str!("./cross_entropy.wgsl"));
+pub(crate) const CROSS_ENTROPY_GRAD: (&str, &str) = ("cross_entropy_grad.wgsl", include_str!("./cross_entropy_grad.wgsl"));
+
+// 正解のクラス。GPUに置いておくので学習のループで毎回送らなくてよい
+pub struct Labels {
+    buffer: wgpu::Buffer,
+    len: usize,
+    // cross_entropyでクラス数を超えていないか確認する
+    max_label: u32,
+}
+impl Labels {
+    pub fn new(labels: &[u32], label: Option<&str>) -> Self {
+        if labels.is_empty() {
+            panic!("labels must not be empty");
+        }
+        Self {
+            buffer: WgpuServer::create_buffer_init(labels, label),
+            len: labels.len(),
+            max_label: *labels.iter().max().unwrap(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+}
+
+thread_local! {
+    // (rows, cols)のuniform。DEVICEと同じくスレッドごとに1つを使い回す
+    // write_bufferは次のsubmitの前に実行されるので，前のdispatchが読み終わる前に上書きされることはない(matmul_paramsと同じ)
+    // uniformは16Byte単位
+    static PARAMS: wgpu::Buffer = WgpuServer::create_uniform_buffer(16, Some("softmax params"));
+}
+
+// (rows, cols)を書き込んだuniformでfを呼ぶ
+fn with_params<R>(rows: usize, cols: usize, f: impl FnOnce(&wgpu::Buffer) -> R) -> R {
+    PARAMS.with(|params| {
+        WgpuServer::write_buffer(params, 0, bytemuck::cast_slice(&[rows as u32, cols as u32, 0, 0]));
+        f(params)
+    })
+}
+
+// 1行1workgroup。dispatchの上限を超える行数はyにも分ける
+fn row_dispatch(rows: usize) -> (u32, u32, u32) {
+    let max = WgpuServer::limits().max_compute_workgroups_per_dimension as usize;
+    let x = rows.min(max);
+    (x as u32, rows.div_ceil(x) as u32, 1)
+}
+
+impl RawGf32 {
+    fn rows_cols(&self) -> (usize, usize) {
+        let (rows, cols) = self.shape().d2();
+        if rows == 0 || cols == 0 {
+            panic!("softmax of empty matrix: {}", self.shape().to_string());
+        }
+        (rows, cols)
+    }
+
+    fn softmax_kernel(&self, log: bool, label: &str, out: &Self) {
+        let (rows, cols) = self.rows_cols();
+        if out.shape() != self.shape() {
+            panic!("{}: out.shape must be {}, but {}", label, self.shape().to_string(), out.shape().to_string());
+        }
+        let template_params = TemplateParams::new().set("LOG", log);
+        let shader_str = SOFTMAX.instantiate(&template_params).unwrap_or_else(|e| panic!("{}", e));
+        with_params(rows, cols, |params| {
+            let bindings = [Binding::read(self.buffer()), Binding::read_write(out.buffer()), Binding::uniform(params)];
+            WgpuServer::execute_n(label, &bindings, &[], &SOFTMAX.instance_name(&template_params), &shader_str, row_dispatch(rows));
+        });
+    }
+
+    // 行ごと
+    pub fn softmax(&self) -> Self {
+        let mut out = Self::_new_empty(self.shape().clone(), Some("softmax"));
+        self.softmax_into(&mut out);
+        out
+    }
+
+    // 行ごと。softmaxしてからlogを取るより精度がよい（小さい確率が0にならない）
+    pub fn log_softmax(&self) -> Self {
+        let mut out = Self::_new_empty(self.shape().clone(), Some("log_softmax"));
+        self.log_softmax_into(&mut out);
+        out
+    }
+
+    pub fn softmax_into(&self, out: &mut Self) {
+        self.softmax_kernel(false, "softmax", out);
+    }
+
+    pub fn log_softmax_into(&self, out: &mut Self) {
+        self.softmax_kernel(true, "log_softmax", out);
+    }
+
+    // selfはsoftmax前の値(logits)。Shape::D2(1, 1)で行の平均を返す
+    // 1行1workgroupで行ごとのlossを出してから，meanでもう1回まとめる
+    pub fn cross_entropy(&self, labels: &Labels) -> Self {
+        let (rows, cols) = self.rows_cols();
+        if labels.len() != rows {
+            panic!("cross_entropy: {} labels for {}", labels.len(), self.shape().to_string());
+        }
+        if labels.max_label as usize >= cols {
+            panic!("cross_entropy: label {} is out of range for {} classes", labels.max_label, cols);
+        }
+        let losses = Self::_new_empty(Shape::D2(rows, 1), Some("cross_entropy rows"));
+        with_params(rows, cols, |params| {
+            let bindings = [
+                Binding::read(self.buffer()),
+                Binding::read(labels.buffer()),
+                Binding::read_write(losses.buffer()),
+                Binding::uniform(params),
+            ];
+            let (shader_name, shader_str) = CROSS_ENTROPY;
+            WgpuServer::execute_n("cross_entropy", &bindings, &[], shader_name, shader_str, row_dispatch(rows));
+        });
+        losses.mean()
+    }
+
+    // cross_entropyの逆伝播。grad += dloss * (softmax(self) - onehot(labels)) / rows
+    // dlossはShape::D2(1, 1)
+    pub(crate) fn cross_entropy_backward(&self, labels: &Labels, dloss: &RawGf32, grad: &RawGf32) {
+        let (rows, cols) = self.rows_cols();
+        with_params(rows, cols, |params| {
+            let bindings = [
+                Binding::read(self.buffer()),
+                Binding::read(labels.buffer()),
+                Binding::read(dloss.buffer()),
+                Binding::read_write(grad.buffer()),
+                Binding::uniform(params),
+            ];
+            let (shader_name, shader_str) = CROSS_ENTROPY_GRAD;
+            WgpuServer::execute_n("cross_entropy backward", &bindings, &[], shader_name, shader_str, elementwise::dispatch(rows));
+        });
+    }
+}
+
+
+
+// CPUでの確認用(f64)
+fn log_softmax_cpu(values: &[f32], cols: usize) -> Vec<f64> {
+    values.chunks(cols).flat_map(|row| {
+        let m = row.iter().fold(f64::NEG_INFINITY, |m, &x| m.max(x as f64));
+        let log_sum = row.iter().map(|&x| (x as f64 - m).exp()).sum::<f64>().ln();
+        row.iter().map(move |&x| x as f64 - m - log_sum)
+    }).collect()
+}
+
+pub fn run() {
+    // 列数がworkgroup_size(64)より小さい / 大きい / 割り切れない
+    for (rows, cols) in [(5, 10), (3, 64), (7, 200), (1, 1)] {
+        // 大きな値でもexpがオーバーフローしないこと
+        let values: Vec<f32> = (0..rows * cols).map(|i| ((i * 37) % 23) as f32 * 50.0 - 500.0).collect();
+        let x = RawGf32::new_init(Shape::D2(rows, cols), &values, Some("x"));
+        let expected = log_softmax_cpu(&values, cols);
+
+        let log_result = x.log_softmax().to_vec();
+        let result = x.softmax().to_vec();
+        for i in 0..rows * cols {
+            if (log_result[i] as f64 - expected[i]).abs() > 1e-4 * expected[i].abs().max(1.0) {
+                panic!("log_softmax ({}, {}) mismatch at {}: {} != {}", rows, cols, i, log_result[i], expected[i]);
+            }
+            if (result[i] as f64 - expected[i].exp()).abs() > 1e-6 {
+                panic!("softmax ({}, {}) mismatch at {}: {} != {}", rows, cols, i, result[i], expected[i].exp());
+            }
+        }
+        for row in result.chunks(cols) {
+            let sum: f32 = row.iter().sum();
+            if (sum - 1.0).abs() > 1e-5 {
+                panic!("softmax row sum = {}", sum);
+            }
+        }
+    }
+
+    // -infはexp(-inf) = 0になる
+    let x = RawGf32::new_init(Shape::D2(1, 3), &vec![f32::NEG_INFINITY, 0.0, 0.0], Some("x"));
+    if x.softmax().to_vec() != vec![0.0, 0.5, 0.5] {
+        panic!("softmax with -inf mismatch");
+    }
+
+    // 交差エントロピー。バッチ(行)がworkgroup_sizeより大きい / クラス数がworkgroup_sizeより大きい
+    for (rows, cols) in [(130, 10), (3, 1000)] {
+        let values: Vec<f32> = (0..rows * cols).map(|i| ((i * 13) % 17) as f32 * 3.0 - 20.0).collect();
+        let labels: Vec<u32> = (0..rows).map(|i| (i * 7 % cols) as u32).collect();
+        let x = RawGf32::new_init(Shape::D2(rows, cols), &values, Some("logits"));
+        let loss = x.cross_entropy(&Labels::new(&labels, Some("labels"))).to_vec();
+        let log_probs = log_softmax_cpu(&values, cols);
+        let expected = -(0..rows).map(|i| log_probs[i * cols + labels[i] as usize]).sum::<f64>() / rows as f64;
+        if (loss[0] as f64 - expected).abs() > 1e-4 * expected.abs() {
+            panic!("cross_entropy ({}, {}) mismatch: {} != {}", rows, cols, loss[0], expected);
+        }
+        println!("cross_entropy ({}, {}) = {}", rows, cols, loss[0]);
+    }
+    println!("softmax ok");
+}
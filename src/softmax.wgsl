@@ -0,0 +1,93 @@
+// 行ごとのsoftmax / log-softmax
+// Matrix<f32, ROWS, COLS>
+@group(0) @binding(0)
+var<storage, read> input: array<f32>;
+// Matrix<f32, ROWS, COLS>
+@group(0) @binding(1)
+var<storage, read_write> output: array<f32>;
+// メタデータ
+struct SoftmaxParams {
+    rows: u32,
+    cols: u32,
+}
+@group(0) @binding(2)
+var<uniform> params: SoftmaxParams;
+
+
+/*
+
+1つのworkgroupで1行を計算する
+    1. 各スレッドが担当する列の(最大値, 最大値を引いたexpの和)を1回の読み出しで求める（online softmax）
+    2. workgroup内で(max, sum)を合わせる
+        (m1, s1), (m2, s2) -> m = max(m1, m2), s = s1 * exp(m1 - m) + s2 * exp(m2 - m)
+    3. softmax: exp(x - max) / sum
+       log-softmax: x - max - log(sum)
+最大値を引いてからexpするのでオーバーフローしない
+初期値を-infにするとexp(-inf - -inf)がNaNになるのでf32の最小値にする
+
+LOGはRust側(softmax.rs)からテンプレートとして埋める
+
+*/
+
+const LOG: bool = {{LOG}};
+// 2の累乗（reductionのため）
+const WG: u32 = 64u;
+const LOWEST: f32 = -3.402823e38;
+
+var<workgroup> shared_max: array<f32, WG>;
+var<workgroup> shared_sum: array<f32, WG>;
+
+
+@compute @workgroup_size(64, 1, 1)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>
+) {
+    // 行数がdispatchの上限を超えるときはyにも分ける
+    let row = workgroup_id.x + workgroup_id.y * num_workgroups.x;
+    // workgroupBarrierの前にreturnしないように，範囲外の行もbarrierまでは進めて読み書きだけしない
+    let in_range = row < params.rows;
+    let cols = params.cols;
+    let shift = row * cols;
+    let tid = local_id.x;
+
+    var m = LOWEST;
+    var s = 0.0;
+    if (in_range) {
+        for (var col = tid; col < cols; col += WG) {
+            let x = input[shift + col];
+            let new_m = max(m, x);
+            s = s * exp(m - new_m) + exp(x - new_m);
+            m = new_m;
+        }
+    }
+    shared_max[tid] = m;
+    shared_sum[tid] = s;
+    workgroupBarrier();
+
+    for (var stride = WG / 2u; stride > 0u; stride /= 2u) {
+        if (tid < stride) {
+            let m1 = shared_max[tid];
+            let m2 = shared_max[tid + stride];
+            let new_m = max(m1, m2);
+            shared_sum[tid] = shared_sum[tid] * exp(m1 - new_m) + shared_sum[tid + stride] * exp(m2 - new_m);
+            shared_max[tid] = new_m;
+        }
+        workgroupBarrier();
+    }
+
+    let row_max = shared_max[0];
+    let row_sum = shared_sum[0];
+    if (in_range) {
+        let log_sum = log(row_sum);
+        for (var col = tid; col < cols; col += WG) {
+            let x = input[shift + col] - row_max;
+            if (LOG) {
+                output[shift + col] = x - log_sum;
+            } else {
+                output[shift + col] = exp(x) / row_sum;
+            }
+        }
+    }
+}
@@ -0,0 +1,425 @@
+use crate::elementwise;
+use crate::matmul_structured2::{RawGf32, Shape, WgpuServer};
+
+
+/*
+疎行列
+COO: (row, col, value)の三つ組をそのまま3本のbufferに持つ。Matrix Marketのcoordinate形式と同じ
+CSR: row_ptr[M + 1], col_idx[nnz], values[nnz]。i行目の要素は row_ptr[i]..row_ptr[i + 1]
+indexは0始まりのu32
+
+グラフ系は99%が0なので密行列のmatmulより疎行列 × 密のほうが速い
+*/
+
+pub struct SparseCooGf32 {
+    label: Option<String>,
+    shape: Shape,
+    nnz: usize,
+    row_idx: wgpu::Buffer,
+    col_idx: wgpu::Buffer,
+    values: wgpu::Buffer,
+}
+impl SparseCooGf32 {
+    pub fn new_init(shape: Shape, row_idx: &[u32], col_idx: &[u32], values: &[f32], label: Option<&str>) -> Self {
+        if row_idx.len() != values.len() || col_idx.len() != values.len() {
+            panic!("row_idx, col_idx and values must have the same length, row_idx: {}, col_idx: {}, values: {}", row_idx.len(), col_idx.len(), values.len());
+        }
+        let (m, n) = shape.d2();
+        for (&i, &j) in row_idx.iter().zip(col_idx.iter()) {
+            if i as usize >= m || j as usize >= n {
+                panic!("index ({}, {}) is out of {}", i, j, shape.to_string());
+            }
+        }
+
+        Self {
+            label: label.map(|str| str.to_string()),
+            shape,
+            nnz: values.len(),
+            row_idx: WgpuServer::create_buffer_init(row_idx, label),
+            col_idx: WgpuServer::create_buffer_init(col_idx, label),
+            values: WgpuServer::create_buffer_init(values, label),
+        }
+    }
+
+    pub fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.nnz
+    }
+
+    // CPU側に読み出す (row_idx, col_idx, values)
+    pub fn to_host(&self) -> (Vec<u32>, Vec<u32>, Vec<f32>) {
+        if self.nnz == 0 {
+            return (vec![], vec![], vec![]);
+        }
+        (
+            WgpuServer::get_as(&self.row_idx),
+            WgpuServer::get_as(&self.col_idx),
+            WgpuServer::get(&self.values),
+        )
+    }
+
+    // 同じ位置の重複は足し合わせる（Matrix Marketの慣習）
+    pub fn to_dense(&self) -> RawGf32 {
+        let (m, n) = self.shape.d2();
+        let (rows, cols, values) = self.to_host();
+        let mut dense = vec![0.0; m * n];
+        for ((&i, &j), &v) in rows.iter().zip(cols.iter()).zip(values.iter()) {
+            dense[i as usize * n + j as usize] += v;
+        }
+        RawGf32::new_init(self.shape.clone(), &dense, self.label.as_deref())
+    }
+}
+
+
+pub struct SparseCsrGf32 {
+    label: Option<String>,
+    shape: Shape,
+    nnz: usize,
+    row_ptr: wgpu::Buffer,
+    col_idx: wgpu::Buffer,
+    values: wgpu::Buffer,
+}
+impl SparseCsrGf32 {
+    pub fn new_init(shape: Shape, row_ptr: &[u32], col_idx: &[u32], values: &[f32], label: Option<&str>) -> Self {
+        let (m, n) = shape.d2();
+        if row_ptr.len() != m + 1 {
+            panic!("row_ptr must have M + 1 = {} elements, got {}", m + 1, row_ptr.len());
+        }
+        if col_idx.len() != values.len() || row_ptr[m] as usize != values.len() {
+            panic!("col_idx, values and row_ptr[M] must match, col_idx: {}, values: {}, row_ptr[M]: {}", col_idx.len(), values.len(), row_ptr[m]);
+        }
+        if row_ptr.windows(2).any(|w| w[0] > w[1]) {
+            panic!("row_ptr must be non-decreasing");
+        }
+        if let Some(&j) = col_idx.iter().find(|&&j| j as usize >= n) {
+            panic!("column index {} is out of {}", j, shape.to_string());
+        }
+
+        // nnz == 0 でも長さ0のbufferはbindできないので1要素入れておく
+        let nnz = values.len();
+        let (col_idx, values): (&[u32], &[f32]) = if nnz == 0 {
+            (&[0], &[0.0])
+        } else {
+            (col_idx, values)
+        };
+
+        Self {
+            label: label.map(|str| str.to_string()),
+            shape,
+            nnz,
+            row_ptr: WgpuServer::create_buffer_init(row_ptr, label),
+            col_idx: WgpuServer::create_buffer_init(col_idx, label),
+            values: WgpuServer::create_buffer_init(values, label),
+        }
+    }
+
+    // 行，列の順に並べ替えて重複は足し合わせる
+    pub fn from_coo(coo: &SparseCooGf32) -> Self {
+        let (m, _) = coo.shape().d2();
+        let (rows, cols, values) = coo.to_host();
+        let mut entries: Vec<(u32, u32, f32)> = rows.into_iter().zip(cols).zip(values)
+            .map(|((i, j), v)| (i, j, v))
+            .collect();
+        entries.sort_by_key(|&(i, j, _)| (i, j));
+
+        let mut row_ptr = vec![0u32; m + 1];
+        let mut col_idx: Vec<u32> = vec![];
+        let mut csr_values: Vec<f32> = vec![];
+        let mut last = None;
+        for (i, j, v) in entries {
+            if last == Some((i, j)) {
+                *csr_values.last_mut().unwrap() += v;
+                continue;
+            }
+            last = Some((i, j));
+            row_ptr[i as usize + 1] += 1;
+            col_idx.push(j);
+            csr_values.push(v);
+        }
+        for i in 0..m {
+            row_ptr[i + 1] += row_ptr[i];
+        }
+        Self::new_init(coo.shape().clone(), &row_ptr, &col_idx, &csr_values, coo.label.as_deref())
+    }
+
+    // 0でない要素だけを拾う
+    pub fn from_dense(dense: &RawGf32) -> Self {
+        let (_, n) = dense.shape().d2();
+        let mut row_ptr = vec![0u32];
+        let mut col_idx = vec![];
+        let mut values = vec![];
+        for row in dense.to_vec().chunks(n) {
+            for (j, &v) in row.iter().enumerate() {
+                if v != 0.0 {
+                    col_idx.push(j as u32);
+                    values.push(v);
+                }
+            }
+            row_ptr.push(values.len() as u32);
+        }
+        Self::new_init(dense.shape().clone(), &row_ptr, &col_idx, &values, None)
+    }
+
+    pub fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.nnz
+    }
+
+    // CPU側に読み出す (row_ptr, col_idx, values)
+    pub fn to_host(&self) -> (Vec<u32>, Vec<u32>, Vec<f32>) {
+        let row_ptr = WgpuServer::get_as(&self.row_ptr);
+        if self.nnz == 0 {
+            return (row_ptr, vec![], vec![]);
+        }
+        (row_ptr, WgpuServer::get_as(&self.col_idx), WgpuServer::get(&self.values))
+    }
+
+    pub fn to_dense(&self) -> RawGf32 {
+        let (_, n) = self.shape.d2();
+        let (row_ptr, col_idx, values) = self.to_host();
+        let mut dense = vec![0.0; self.shape.size()];
+        for i in 0..row_ptr.len() - 1 {
+            for k in row_ptr[i] as usize..row_ptr[i + 1] as usize {
+                dense[i * n + col_idx[k] as usize] = values[k];
+            }
+        }
+        RawGf32::new_init(self.shape.clone(), &dense, self.label.as_deref())
+    }
+
+    // 疎行列 × 密ベクトル。xはShape::D2(K, 1)
+    pub fn spmv(&self, x: &RawGf32) -> RawGf32 {
+        let (m, k) = if let (Shape::D2(m, k), Shape::D2(k2, 1)) = (&self.shape, x.shape()) {
+            if k != k2 {
+                panic!("incompatible matrix size, self.shape: {}, x.shape: {}", self.shape.to_string(), x.shape().to_string());
+            }
+            (*m, *k)
+        } else {
+            panic!("spmv needs x of Shape::D2(K, 1), but x.shape: {}", x.shape().to_string());
+        };
+        // 長さ0のbufferはbindできない
+        if m == 0 || k == 0 {
+            panic!("spmv of empty matrix: {}", self.shape.to_string());
+        }
+        let sizes_info = vec![m as u32, k as u32, 1];
+        let size_info_buffer = WgpuServer::create_buffer_init(&sizes_info, Some("sizes info"));
+        let out = RawGf32::_new_empty(Shape::D2(m, 1), Some("spmv out"));
+
+        WgpuServer::execute_6(
+            [&self.row_ptr, &self.col_idx, &self.values, x.buffer(), out.buffer(), &size_info_buffer],
+            "spmv.wgsl",
+            include_str!("./spmv.wgsl"),
+            // 1スレッド = 1行。65535 * 64行を超えたらyにも分ける
+            elementwise::dispatch(m)
+        );
+        out
+    }
+
+    // 疎行列 × 密行列
+    pub fn spmm(&self, rhs: &RawGf32) -> RawGf32 {
+        let ((m, k), (k2, n)) = (self.shape.d2(), rhs.shape().d2());
+        if k != k2 {
+            panic!("incompatible matrix size, self.shape: {}, rhs.shape: {}", self.shape.to_string(), rhs.shape().to_string());
+        }
+        if m == 0 || k == 0 || n == 0 {
+            panic!("spmm of empty matrix, self.shape: {}, rhs.shape: {}", self.shape.to_string(), rhs.shape().to_string());
+        }
+        let sizes_info = vec![m as u32, k as u32, n as u32];
+        let size_info_buffer = WgpuServer::create_buffer_init(&sizes_info, Some("sizes info"));
+        let out = RawGf32::_new_empty(Shape::D2(m, n), Some("spmm out"));
+
+        // xが列，yが行。行のworkgroupが1次元の上限を超えたらzにも分ける
+        let max = WgpuServer::limits().max_compute_workgroups_per_dimension as usize;
+        let (col_groups, row_groups) = (n.div_ceil(16), m.div_ceil(16).max(1));
+        if col_groups > max {
+            panic!("spmm: N = {} is too large for dispatch (max {} workgroups per dimension)", n, max);
+        }
+        let y = row_groups.min(max);
+        let dispatch = (col_groups as u32, y as u32, row_groups.div_ceil(y) as u32);
+
+        WgpuServer::execute_6(
+            [&self.row_ptr, &self.col_idx, &self.values, rhs.buffer(), out.buffer(), &size_info_buffer],
+            "spmm.wgsl",
+            include_str!("./spmm.wgsl"),
+            dispatch
+        );
+        out
+    }
+
+    // 右辺が1列ならspmv，それ以外はspmm
+    pub fn matmul(&self, rhs: &RawGf32) -> RawGf32 {
+        if let Shape::D2(_, 1) = rhs.shape() {
+            self.spmv(rhs)
+        } else {
+            self.spmm(rhs)
+        }
+    }
+}
+
+
+
+// 行数が1次元のdispatch上限(65535 workgroup)を超える場合。i行目は(i, 0)に i % 7
+fn check_tall() {
+    let m = 65535 * 64 + 100;
+    let row_ptr: Vec<u32> = (0..=m as u32).collect();
+    let values: Vec<f32> = (0..m).map(|i| (i % 7) as f32).collect();
+    let tall = SparseCsrGf32::new_init(Shape::D2(m, 1), &row_ptr, &vec![0; m], &values, Some("tall"));
+    let dense = tall.to_dense();
+    let x = RawGf32::new_init(Shape::D2(1, 1), &vec![2.0], None);
+    let y = tall.spmv(&x).to_vec();
+    if let Some(i) = (0..m).find(|&i| y[i] != 2.0 * values[i]) {
+        panic!("tall spmv mismatch at row {}: {}", i, y[i]);
+    }
+    if y != dense.matmul(&x).to_vec() {
+        panic!("tall spmv does not match dense matmul");
+    }
+    let b = RawGf32::new_init(Shape::D2(1, 2), &vec![1.0, -1.0], None);
+    let c = tall.spmm(&b).to_vec();
+    if let Some(i) = (0..m).find(|&i| c[i * 2] != values[i] || c[i * 2 + 1] != -values[i]) {
+        panic!("tall spmm mismatch at row {}: {:?}", i, &c[i * 2..i * 2 + 2]);
+    }
+    if c != dense.matmul(&b).to_vec() {
+        panic!("tall spmm does not match dense matmul");
+    }
+}
+
+pub fn run() {
+    // 5x4, 対角 + 少し
+    let coo = SparseCooGf32::new_init(
+        Shape::D2(5, 4),
+        &[0, 1, 2, 3, 4, 0, 2, 2],
+        &[0, 1, 2, 3, 0, 3, 0, 0],
+        &[1.0, 2.0, 3.0, 4.0, 5.0, -1.0, 0.5, 0.5],
+        Some("coo"),
+    );
+    let csr = SparseCsrGf32::from_coo(&coo);
+    // (2, 0)の重複は1つにまとまる
+    if csr.nnz() != 7 {
+        panic!("from_coo did not merge duplicates, nnz = {}", csr.nnz());
+    }
+    let dense = coo.to_dense();
+    if csr.to_dense().to_vec() != dense.to_vec() {
+        panic!("csr to_dense mismatch");
+    }
+    let from_dense = SparseCsrGf32::from_dense(&dense);
+    if from_dense.to_host() != csr.to_host() {
+        panic!("from_dense mismatch");
+    }
+
+    let dense_values = dense.to_vec();
+    let cpu_matmul = |rhs: &Vec<f32>, n: usize| -> Vec<f32> {
+        let mut out = vec![0.0; 5 * n];
+        for i in 0..5 {
+            for k in 0..4 {
+                for j in 0..n {
+                    out[i * n + j] += dense_values[i * 4 + k] * rhs[k * n + j];
+                }
+            }
+        }
+        out
+    };
+
+    // spmv
+    let x_values = vec![1.0, 2.0, 3.0, 4.0];
+    let x = RawGf32::new_init(Shape::D2(4, 1), &x_values, Some("x"));
+    let y = csr.matmul(&x);
+    if y.to_vec() != cpu_matmul(&x_values, 1) {
+        panic!("spmv mismatch, gpu: {:?}, cpu: {:?}", y.to_vec(), cpu_matmul(&x_values, 1));
+    }
+
+    // spmm
+    let b_values: Vec<f32> = (0..4 * 20).map(|i| (i % 9) as f32 - 4.0).collect();
+    let b = RawGf32::new_init(Shape::D2(4, 20), &b_values, Some("b"));
+    let c = csr.matmul(&b);
+    if c.to_vec() != cpu_matmul(&b_values, 20) {
+        panic!("spmm mismatch");
+    }
+
+    // 全部0
+    let empty = SparseCsrGf32::from_dense(&RawGf32::new_init(Shape::D2(5, 4), &vec![0.0; 20], None));
+    if empty.spmv(&x).to_vec() != vec![0.0; 5] {
+        panic!("empty spmv mismatch");
+    }
+
+    check_tall();
+
+    println!("sparse ok");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matmul_structured2::adapter_available;
+
+    // 値は小さい整数なので足す順番が違っても密行列のmatmulと一致する
+    fn check_against_dense(csr: &SparseCsrGf32, n: usize) {
+        let (_, k) = csr.shape().d2();
+        let dense = csr.to_dense();
+        let b_values: Vec<f32> = (0..k * n).map(|i| (i % 5) as f32 - 2.0).collect();
+        let b = RawGf32::new_init(Shape::D2(k, n), &b_values, Some("b"));
+        assert_eq!(csr.matmul(&b).to_vec(), dense.matmul(&b).to_vec(), "n = {}", n);
+    }
+
+    #[test]
+    fn empty_rows() {
+        if !adapter_available() {
+            return;
+        }
+        // 0, 2, 5行目が空。最初と最後の行も空にする
+        let coo = SparseCooGf32::new_init(
+            Shape::D2(6, 3),
+            &[1, 1, 3, 4, 4, 4],
+            &[0, 2, 1, 0, 1, 2],
+            &[1.0, -2.0, 3.0, 4.0, 5.0, -6.0],
+            Some("coo"),
+        );
+        let csr = SparseCsrGf32::from_coo(&coo);
+        assert_eq!(csr.to_host().0, vec![0, 0, 2, 2, 3, 6, 6]);
+        for n in [1, 7, 20] {
+            check_against_dense(&csr, n);
+        }
+    }
+
+    #[test]
+    fn all_empty() {
+        if !adapter_available() {
+            return;
+        }
+        let csr = SparseCsrGf32::new_init(Shape::D2(5, 4), &[0; 6], &[], &[], Some("empty"));
+        assert_eq!(csr.nnz(), 0);
+        for n in [1, 20] {
+            check_against_dense(&csr, n);
+        }
+    }
+
+    #[test]
+    fn tall_split_dispatch() {
+        if !adapter_available() {
+            return;
+        }
+        check_tall();
+    }
+
+    // 長さ0のoutをbindする前に弾く
+    #[test]
+    fn zero_rows_is_rejected() {
+        if !adapter_available() {
+            return;
+        }
+        let csr = SparseCsrGf32::new_init(Shape::D2(0, 4), &[0], &[], &[], None);
+        let x = RawGf32::new_init(Shape::D2(4, 1), &vec![1.0; 4], None);
+        let b = RawGf32::new_init(Shape::D2(4, 2), &vec![1.0; 8], None);
+        let rejected = |result: std::thread::Result<RawGf32>| match result {
+            Err(e) => e.downcast_ref::<String>().is_some_and(|m| m.contains("empty matrix")),
+            Ok(_) => false,
+        };
+        assert!(rejected(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| csr.spmv(&x)))));
+        assert!(rejected(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| csr.spmm(&b)))));
+    }
+}
@@ -0,0 +1,40 @@
+// 疎行列(CSR) × 密行列
+// Matrix<f32, M, K> (CSR)
+@group(0) @binding(0)
+var<storage, read> row_ptr: array<u32>;
+@group(0) @binding(1)
+var<storage, read> col_idx: array<u32>;
+@group(0) @binding(2)
+var<storage, read> values: array<f32>;
+// Matrix<f32, K, N>
+@group(0) @binding(3)
+var<storage, read> rhs: array<f32>;
+// Matrix<f32, M, N>
+@group(0) @binding(4)
+var<storage, read_write> output: array<f32>;
+// メタデータ
+// vec![M, K, N]
+@group(0) @binding(5)
+var<storage, read> sizes: vec3<u32>;
+
+// xが列(N)，yが行(M)。同じ行の隣り合うスレッドはrhsの同じ行を読むのでcoalescingが効く
+// 行が多いときはzにも分けてdispatchする
+@compute @workgroup_size(16, 16, 1)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+) {
+    let M: u32 = sizes[0];
+    let N: u32 = sizes[2];
+    let col = global_id.x;
+    let row = global_id.y + global_id.z * num_workgroups.y * 16u;
+    if (row >= M || col >= N) {
+        return;
+    }
+
+    var sum: f32 = 0.0;
+    for (var i = row_ptr[row]; i < row_ptr[row + 1u]; i += 1u) {
+        sum += values[i] * rhs[col_idx[i] * N + col];
+    }
+    output[row * N + col] = sum;
+}
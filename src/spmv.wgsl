@@ -0,0 +1,37 @@
+// 疎行列(CSR) × 密ベクトル
+// Matrix<f32, M, K> (CSR)
+@group(0) @binding(0)
+var<storage, read> row_ptr: array<u32>;
+@group(0) @binding(1)
+var<storage, read> col_idx: array<u32>;
+@group(0) @binding(2)
+var<storage, read> values: array<f32>;
+// Vector<f32, K>
+@group(0) @binding(3)
+var<storage, read> x: array<f32>;
+// Vector<f32, M>
+@group(0) @binding(4)
+var<storage, read_write> y: array<f32>;
+// メタデータ
+// vec![M, K, N(=1)]
+@group(0) @binding(5)
+var<storage, read> sizes: vec3<u32>;
+
+// 1スレッド = 1行。行が多いときはyにも分けてdispatchする(elementwise::dispatch)
+@compute @workgroup_size(64, 1, 1)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+) {
+    let M: u32 = sizes[0];
+    let row = global_id.x + global_id.y * num_workgroups.x * 64u;
+    if (row >= M) {
+        return;
+    }
+
+    var sum: f32 = 0.0;
+    for (var i = row_ptr[row]; i < row_ptr[row + 1u]; i += 1u) {
+        sum += values[i] * x[col_idx[i]];
+    }
+    y[row] = sum;
+}
@@ -1,3 +1,4 @@
+use crate::compare::Tolerance;
 use crate::matmul_structured2::{RawGf32, Shape};
 
 
@@ -7,10 +8,10 @@ struct Strassen4 {
 impl Strassen4 {
     fn new_fill_with(size: usize, fill_with: f32) -> Self {
         let bodys = [
-            RawGf32::new_init(Shape::D2(size, size), &vec![fill_with; size*size], None),
-            RawGf32::new_init(Shape::D2(size, size), &vec![fill_with; size*size], None),
-            RawGf32::new_init(Shape::D2(size, size), &vec![fill_with; size*size], None),
-            RawGf32::new_init(Shape::D2(size, size), &vec![fill_with; size*size], None),
+            RawGf32::full(Shape::D2(size, size), fill_with, None),
+            RawGf32::full(Shape::D2(size, size), fill_with, None),
+            RawGf32::full(Shape::D2(size, size), fill_with, None),
+            RawGf32::full(Shape::D2(size, size), fill_with, None),
         ];
         Self { bodys }
     }
@@ -47,9 +48,13 @@ impl Strassen4 {
         Strassen4::new_from([c11, c12, c21, c22])
     }
 
-    fn print_1(&self) {
-        for body in &self.bodys {
-            body.print_1();
+    // 全部の要素がexpectedか
+    fn check(&self, expected: f32) {
+        for (name, body) in ["c11", "c12", "c21", "c22"].iter().zip(&self.bodys) {
+            let (rows, cols) = body.shape().d2();
+            let report = body.compare(&vec![expected; rows * cols], &Tolerance::default());
+            report.check(name, body.shape());
+            println!("{}: {}", name, report);
         }
     }
 }
@@ -68,8 +73,8 @@ pub fn run() {
         let s = std::time::Instant::now();
         let c = a.matmul(&b);
 
-        println!("result of c is: ");
-        c.print_1();
+        // (2 * size)個の1 * 2の和
+        c.check(4.0 * size as f32);
         let time = s.elapsed();
         results.push(time);
         println!("連続１回, size = {}, time = {:?}", size, time);
@@ -0,0 +1,136 @@
+use crate::compare::Tolerance;
+use crate::matmul_structured2::{Binding, RawGf32, Shape, WgpuServer};
+
+
+/*
+転置。結果は新しい(連続した)bufferに作る
+    Shape::D2(rows, cols)        -> Shape::D2(cols, rows)
+    Shape::D3(batch, rows, cols) -> Shape::D3(batch, cols, rows)  行列ごとに転置する
+32x32のタイルをshared memoryに置いて読みも書きも連続させる(transpose.wgsl)
+行列積で転置して使うだけならgemm_transで読み方を変える方がコピーがなくてよい
+*/
+
+pub(crate) const TRANSPOSE: (&str, &str) = ("transpose.wgsl", include_str!("./transpose.wgsl"));
+
+// transpose.wgslのTILE
+const TILE: usize = 32;
+
+impl RawGf32 {
+    pub fn transpose(&self) -> Self {
+        let (batch, rows, cols, shape) = match *self.shape() {
+            Shape::D2(rows, cols) => (1, rows, cols, Shape::D2(cols, rows)),
+            Shape::D3(batch, rows, cols) => (batch, rows, cols, Shape::D3(batch, cols, rows)),
+        };
+        let out = Self::_new_empty(shape, Some("transpose"));
+
+        // x, yに入りきらないタイルはbatchと一緒にzに折り込む（(1, 3_000_000)などの細長い行列）
+        let max = WgpuServer::limits().max_compute_workgroups_per_dimension as usize;
+        let (col_tiles, row_tiles) = (cols.div_ceil(TILE), rows.div_ceil(TILE));
+        // chunkの大きさを揃えて，範囲外のタイルを減らす
+        let (col_chunks, row_chunks) = (col_tiles.div_ceil(max), row_tiles.div_ceil(max));
+        let (x, y) = (col_tiles.div_ceil(col_chunks), row_tiles.div_ceil(row_chunks));
+        let z = batch * col_chunks * row_chunks;
+        if z > max {
+            panic!("transpose: {} is too large for dispatch (max {} workgroups per dimension)", self.shape().to_string(), max);
+        }
+        // uniformは16Byte単位
+        let params = WgpuServer::create_uniform_buffer(16, Some("transpose params"));
+        WgpuServer::write_buffer(&params, 0, bytemuck::cast_slice(&[rows as u32, cols as u32, col_chunks as u32, row_chunks as u32]));
+        let bindings = [Binding::read(self.buffer()), Binding::read_write(out.buffer()), Binding::uniform(&params)];
+        let (shader_name, shader_str) = TRANSPOSE;
+        WgpuServer::execute_n("transpose", &bindings, &[], shader_name, shader_str, (x as u32, y as u32, z as u32));
+        out
+    }
+}
+
+
+
+fn transpose_cpu(values: &[f32], batch: usize, rows: usize, cols: usize) -> Vec<f32> {
+    (0..batch * rows * cols).map(|i| {
+        // 出力の(b, col, row)
+        let (b, col, row) = (i / (rows * cols), i / rows % cols, i % rows);
+        values[(b * rows + row) * cols + col]
+    }).collect()
+}
+
+fn check_transpose(shapes: &[Shape]) {
+    for shape in shapes.iter().cloned() {
+        let (batch, rows, cols) = match shape {
+            Shape::D2(rows, cols) => (1, rows, cols),
+            Shape::D3(batch, rows, cols) => (batch, rows, cols),
+        };
+        let values: Vec<f32> = (0..shape.size()).map(|i| i as f32).collect();
+        let a = RawGf32::new_init(shape.clone(), &values, Some("a"));
+        let t = a.transpose();
+        let expected_shape = match shape {
+            Shape::D2(rows, cols) => Shape::D2(cols, rows),
+            Shape::D3(batch, rows, cols) => Shape::D3(batch, cols, rows),
+        };
+        if *t.shape() != expected_shape {
+            panic!("transpose shape mismatch: {}", t.shape().to_string());
+        }
+        t.compare(&transpose_cpu(&values, batch, rows, cols), &Tolerance::exact()).check("transpose", t.shape());
+        // 2回で元に戻る
+        t.transpose().compare(&values, &Tolerance::exact()).check("transpose twice", &shape);
+    }
+}
+
+pub fn run() {
+    // タイルより小さい / ちょうど / 端数 / 細長い
+    check_transpose(&[
+        Shape::D2(1, 1), Shape::D2(5, 7), Shape::D2(32, 32), Shape::D2(33, 65), Shape::D2(100, 3), Shape::D2(1, 200),
+        Shape::D3(3, 45, 70), Shape::D3(4, 32, 1), Shape::D3(1, 64, 96),
+    ]);
+    // タイルの数がdispatchの上限(65535)を超える。2回転置するのでx, yの両方とbatchを通る
+    // llvmpipeでは数分かかる
+    check_transpose(&[Shape::D3(2, 3, 2_100_000)]);
+
+    // 転置したものとgemm_transは同じ結果
+    let (m, k, n) = (37, 50, 23);
+    let a = RawGf32::new_init(Shape::D2(k, m), &(0..k * m).map(|i| ((i * 7) % 13) as f32 - 6.0).collect(), Some("a"));
+    let b = RawGf32::new_init(Shape::D2(k, n), &(0..k * n).map(|i| ((i * 5) % 11) as f32 - 5.0).collect(), Some("b"));
+    let mut expected = RawGf32::zeros(Shape::D2(m, n), Some("expected"));
+    a.gemm_trans(true, &b, false, 1.0, 0.0, &mut expected);
+    a.transpose().matmul(&b).compare(&expected.to_vec(), &Tolerance::exact()).check("transpose + matmul", &Shape::D2(m, n));
+
+    // 速さ
+    let size = 1024;
+    let a = RawGf32::zeros(Shape::D2(size, size), Some("a"));
+    a.transpose();
+    WgpuServer::wait();
+    let s = std::time::Instant::now();
+    let repeat = 3;
+    for _ in 0..repeat {
+        a.transpose();
+    }
+    WgpuServer::wait();
+    let time = s.elapsed() / repeat;
+    // 読みと書きで2回
+    let gbps = (size * size * 4 * 2) as f64 / time.as_secs_f64() / 1e9;
+    println!("transpose {}x{}: {:?}, {:.2} GB/s", size, size, time, gbps);
+    println!("transpose ok");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matmul_structured2::adapter_available;
+
+    #[test]
+    fn small_shapes() {
+        if !adapter_available() {
+            return;
+        }
+        check_transpose(&[Shape::D2(1, 1), Shape::D2(33, 65), Shape::D2(100, 3), Shape::D3(3, 45, 70), Shape::D3(4, 32, 1)]);
+    }
+
+    #[test]
+    fn more_tiles_than_dispatch_limit() {
+        if !adapter_available() {
+            return;
+        }
+        // 65625タイル。2回転置するので(2_100_000, 1)も通る
+        check_transpose(&[Shape::D2(1, 2_100_000)]);
+    }
+}
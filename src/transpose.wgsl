@@ -0,0 +1,73 @@
+// 転置 output[b][j][i] = input[b][i][j]
+// Matrix<f32, ROWS, COLS>がBATCH個
+@group(0) @binding(0)
+var<storage, read> input: array<f32>;
+// Matrix<f32, COLS, ROWS>がBATCH個
+@group(0) @binding(1)
+var<storage, read_write> output: array<f32>;
+// メタデータ
+struct TransposeParams {
+    rows: u32,
+    cols: u32,
+    // x, yのdispatchの上限を超えるタイルをzに何回分折り込んだか
+    col_chunks: u32,
+    row_chunks: u32,
+}
+@group(0) @binding(2)
+var<uniform> params: TransposeParams;
+
+
+/*
+
+TILE x TILEのタイルをshared memoryに置いて，読みも書きも行方向に連続させる(coalescing)
+1 workgroup = 1タイル。workgroup_sizeは(TILE, ROWS_PER_PASS)で，1スレッドがTILE / ROWS_PER_PASS行を受け持つ
+書き出しではshared memoryを列方向に読むので，行の長さをTILE + 1にしてbankをずらす
+(TILEだと同じ列の要素が全部同じbankになる)
+端数のタイルは範囲外を読み書きしない
+workgroup_id.zはbatchと，x, yに入りきらなかったタイルの番号をまとめたもの
+    z = (b * row_chunks + row_chunk) * col_chunks + col_chunk
+    タイルの列 = workgroup_id.x + col_chunk * num_workgroups.x (行も同じ)
+    最後のchunkは範囲外のタイルになることがあるが，範囲外は読み書きしないのでそのままでよい
+
+*/
+
+const TILE: u32 = 32u;
+const ROWS_PER_PASS: u32 = 8u;
+const STRIDE: u32 = 33u;
+
+var<workgroup> tile: array<f32, 1056>;
+
+@compute @workgroup_size(32, 8, 1)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>
+) {
+    let rows = params.rows;
+    let cols = params.cols;
+    let col_chunk = workgroup_id.z % params.col_chunks;
+    let row_chunk = workgroup_id.z / params.col_chunks % params.row_chunks;
+    let b = workgroup_id.z / (params.col_chunks * params.row_chunks);
+    let shift = b * rows * cols;
+    let row0 = (workgroup_id.y + row_chunk * num_workgroups.y) * TILE;
+    let col0 = (workgroup_id.x + col_chunk * num_workgroups.x) * TILE;
+
+    for (var j = 0u; j < TILE; j += ROWS_PER_PASS) {
+        let row = row0 + local_id.y + j;
+        let col = col0 + local_id.x;
+        if (row < rows && col < cols) {
+            tile[(local_id.y + j) * STRIDE + local_id.x] = input[shift + row * cols + col];
+        }
+    }
+
+    workgroupBarrier();
+
+    // 出力の行はinputの列
+    for (var j = 0u; j < TILE; j += ROWS_PER_PASS) {
+        let out_row = col0 + local_id.y + j;
+        let out_col = row0 + local_id.x;
+        if (out_row < cols && out_col < rows) {
+            output[shift + out_row * rows + out_col] = tile[local_id.x * STRIDE + local_id.y + j];
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use std::{collections::HashMap, fmt, sync::Mutex};
+
+
+/*
+WGSLのテンプレート
+    const BM: u32 = {{BM}}u;
+のように{{NAME}}をRustから渡した値で置き換える
+
+wgpu 0.17ではパイプラインのoverride constants(ComputePipelineDescriptor::constants)がないので文字列で埋める
+生成したソースはパイプラインを作る前にnagaでparse + validateする
+置き換えは行の中だけで行うので，生成後の行番号はテンプレートの行番号と同じになる
+エラーはテンプレートの行と生成後の行を両方表示する
+*/
+
+// wgpuに渡すラベル。instance_nameはパラメータが多いと数百文字になり，
+// GLではglObjectLabelKHRの上限(GL_MAX_LABEL_LENGTH)を超えてGL_INVALID_VALUEになるので短くする
+// "6vectorize.wgsl[BM=64,...]" -> "6vectorize.wgsl#1f2e3d4c5b6a7988"
+// テンプレート名 + パラメータ部分のハッシュ(FNV-1a)。実行ごとに変わらないようにstdのHasherは使わない
+// テンプレートでない名前はそのまま
+pub fn short_label(instance_name: &str) -> String {
+    let Some(start) = instance_name.find('[') else {
+        return instance_name.to_string();
+    };
+    let hash = instance_name[start..].bytes()
+        .fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+    format!("{}#{:016x}", &instance_name[..start], hash)
+}
+
+lazy_static::lazy_static! {
+    // instance_name -> 検証済みのソース
+    static ref INSTANCES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct TemplateParams(Vec<(String, String)>);
+impl TemplateParams {
+    pub fn new() -> Self {
+        Self(vec![])
+    }
+
+    // 同じ名前は後から設定した方が優先
+    pub fn set<T: fmt::Display>(mut self, name: &str, value: T) -> Self {
+        let value = value.to_string();
+        match self.0.iter_mut().find(|(n, _)| n == name) {
+            Some(entry) => entry.1 = value,
+            None => self.0.push((name.to_string(), value)),
+        }
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    fn names(&self) -> Vec<&str> {
+        self.0.iter().map(|(n, _)| n.as_str()).collect()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TemplateError {
+    pub name: String,
+    // 1始まり
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    pub template_line: String,
+    // naga のエラーのときだけ
+    pub rendered_line: Option<String>,
+}
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}:{}:{}: {}", self.name, self.line, self.column, self.message)?;
+        let width = self.line.to_string().len();
+        write!(f, "{:>width$} | {}", self.line, self.template_line, width = width)?;
+        if let Some(rendered) = &self.rendered_line {
+            if rendered != &self.template_line {
+                write!(f, "\n{:>width$} | {}  (rendered)", "", rendered, width = width)?;
+            }
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for TemplateError {}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WgslTemplate {
+    pub name: &'static str,
+    pub source: &'static str,
+}
+impl WgslTemplate {
+    pub const fn new(name: &'static str, source: &'static str) -> Self {
+        Self { name, source }
+    }
+
+    // テンプレートに出てくる{{NAME}}。出てきた順で重複なし
+    pub fn placeholders(&self) -> Vec<String> {
+        let mut names: Vec<String> = vec![];
+        for line in self.source.lines() {
+            let mut rest = line;
+            while let Some(start) = rest.find("{{") {
+                let Some(end) = rest[start..].find("}}") else { break };
+                let name = rest[start + 2..start + end].trim().to_string();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+                rest = &rest[start + end + 2..];
+            }
+        }
+        names
+    }
+
+    // パラメータを埋めたときの名前。execute_*のshader_nameに使う
+    pub fn instance_name(&self, params: &TemplateParams) -> String {
+        let values: Vec<String> = self.placeholders().iter()
+            .map(|n| format!("{}={}", n, params.get(n).unwrap_or("?")))
+            .collect();
+        format!("{}[{}]", self.name, values.join(","))
+    }
+
+    // {{NAME}}を置き換えるだけ。使われないパラメータは無視する（TileConfigのように複数のカーネルで共有するため）
+    pub fn render(&self, params: &TemplateParams) -> Result<String, TemplateError> {
+        let mut out = String::with_capacity(self.source.len());
+        for (i, line) in self.source.split('\n').enumerate() {
+            if i > 0 {
+                out.push('\n');
+            }
+            let error = |column: usize, message: String| TemplateError {
+                name: self.name.to_string(),
+                line: i + 1,
+                column: column + 1,
+                message,
+                template_line: line.trim_end().to_string(),
+                rendered_line: None,
+            };
+
+            let mut pos = 0;
+            while let Some(start) = line[pos..].find("{{").map(|s| s + pos) {
+                out.push_str(&line[pos..start]);
+                let Some(end) = line[start..].find("}}").map(|e| e + start) else {
+                    return Err(error(start, "unclosed '{{'".to_string()));
+                };
+                let name = line[start + 2..end].trim();
+                if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    return Err(error(start, format!("invalid template parameter name '{}'", name)));
+                }
+                let Some(value) = params.get(name) else {
+                    return Err(error(start, format!(
+                        "template parameter '{}' is not set (given: {})", name, params.names().join(", ")
+                    )));
+                };
+                if value.contains('\n') {
+                    // 行番号がずれるので禁止
+                    return Err(error(start, format!("value of '{}' must not contain a newline", name)));
+                }
+                out.push_str(value);
+                pos = end + 2;
+            }
+            out.push_str(&line[pos..]);
+        }
+        Ok(out)
+    }
+
+    // render + naga検証。結果はinstance_nameごとにキャッシュする
+    pub fn instantiate(&self, params: &TemplateParams) -> Result<String, TemplateError> {
+        let key = self.instance_name(params);
+        if let Some(source) = INSTANCES.lock().unwrap().get(&key) {
+            return Ok(source.clone());
+        }
+        let source = self.render(params)?;
+        validate_rendered(&key, self.source, &source)?;
+        INSTANCES.lock().unwrap().insert(key, source.clone());
+        Ok(source)
+    }
+
+    // render + naga検証してnagaのModuleを返す（shader_checkでbindingなどを調べる用）
+    pub fn validate(&self, params: &TemplateParams) -> Result<(naga::Module, naga::valid::ModuleInfo), TemplateError> {
+        let source = self.render(params)?;
+        validate_rendered(&self.instance_name(params), self.source, &source)
+    }
+}
+
+fn naga_error(name: &str, template: &str, rendered: &str, message: String, location: Option<naga::SourceLocation>) -> TemplateError {
+    let (line, column) = match location {
+        Some(loc) => (loc.line_number as usize, loc.line_position as usize),
+        None => (0, 0),
+    };
+    let nth = |source: &str| source.lines().nth(line.saturating_sub(1)).unwrap_or("").trim_end().to_string();
+    TemplateError {
+        name: name.to_string(),
+        line,
+        column,
+        message,
+        template_line: nth(template),
+        rendered_line: Some(nth(rendered)),
+    }
+}
+
+fn validate_rendered(name: &str, template: &str, rendered: &str) -> Result<(naga::Module, naga::valid::ModuleInfo), TemplateError> {
+    let module = naga::front::wgsl::parse_str(rendered).map_err(|e| {
+        naga_error(name, template, rendered, e.message().to_string(), e.location(rendered))
+    })?;
+    // push constantsはデバイスが対応しているときだけ使う（execute_n側で確認する）
+    let info = naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::PUSH_CONSTANT)
+        .validate(&module)
+        .map_err(|e| {
+            // 原因まで辿って1行にする
+            let mut message = e.as_inner().to_string();
+            let mut source: Option<&dyn std::error::Error> = std::error::Error::source(e.as_inner());
+            while let Some(s) = source {
+                message += &format!(": {}", s);
+                source = s.source();
+            }
+            naga_error(name, template, rendered, message, e.location(rendered))
+        })?;
+    Ok((module, info))
+}
+
+// テンプレートでない.wgslの検証
+pub fn validate(name: &str, source: &str) -> Result<(naga::Module, naga::valid::ModuleInfo), TemplateError> {
+    validate_rendered(name, source, source)
+}
+
+
+
+pub fn run() {
+    let template = WgslTemplate::new("test.wgsl", "\
+@group(0) @binding(0)
+var<storage, read_write> output: array<f32>;
+var<workgroup> shared_mem: array<f32, {{SIZE}}>;
+@compute @workgroup_size({{WG}}, 1, 1)
+fn main(@builtin(local_invocation_id) local_id: vec3<u32>) {
+    shared_mem[local_id.x] = {{VALUE}};
+    workgroupBarrier();
+    output[local_id.x] = shared_mem[local_id.x];
+}");
+    if template.placeholders() != vec!["SIZE", "WG", "VALUE"] {
+        panic!("placeholders mismatch: {:?}", template.placeholders());
+    }
+
+    let params = TemplateParams::new().set("SIZE", 64).set("WG", 64).set("VALUE", "1.0");
+    let source = template.instantiate(&params).unwrap();
+    if !source.contains("array<f32, 64>") || source.lines().count() != template.source.lines().count() {
+        panic!("render failed:\n{}", source);
+    }
+
+    // パラメータが足りない
+    let err = template.render(&TemplateParams::new().set("SIZE", 64)).unwrap_err();
+    println!("{}\n", err);
+    if err.line != 4 {
+        panic!("missing parameter must point at line 4");
+    }
+
+    // 型が合わないのはnagaで検出して，テンプレートの行を指す
+    let err = template.instantiate(&params.clone().set("VALUE", "1u")).unwrap_err();
+    println!("{}\n", err);
+    if err.line != 6 || err.template_line.trim() != "shared_mem[local_id.x] = {{VALUE}};" {
+        panic!("naga error must point at line 6: {:?}", err);
+    }
+
+    // タイル化カーネルは全部同じパラメータで埋められる
+    // 6_2vec4.wgslは書きかけなのでここでは除く
+    use crate::autotune::{TileConfig, BLOCKING1D, BLOCKING2D, VECTORIZE};
+    let config = TileConfig { bm: 64, bn: 64, bk: 8, tm: 8, tn: 8 };
+    let blocking1d = TileConfig { bm: 64, bn: 64, bk: 8, tm: 16, tn: 1 };
+    for (template, config) in [(VECTORIZE, config), (BLOCKING1D, blocking1d), (BLOCKING2D, config)] {
+        let params = crate::matmul_structured2::matmul_template_params(config.params(), false, &Default::default());
+        if let Err(e) = template.instantiate(&params) {
+            panic!("{}", e);
+        }
+    }
+    println!("wgsl_template ok");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_label() {
+        let template = WgslTemplate::new("t.wgsl", "const A: u32 = {{A}}u;\nconst B: u32 = {{B}}u;");
+        let long = template.instance_name(&TemplateParams::new().set("A", 1).set("B", "x".repeat(300)));
+        let label = super::short_label(&long);
+        assert!(label.starts_with("t.wgsl#") && label.len() == "t.wgsl#".len() + 16, "{}", label);
+        // 同じ名前は同じラベル，パラメータが違えば別のラベル
+        assert_eq!(label, super::short_label(&long));
+        let other = template.instance_name(&TemplateParams::new().set("A", 2).set("B", "x".repeat(300)));
+        assert_ne!(label, super::short_label(&other));
+        assert_eq!(super::short_label("collatz.wgsl"), "collatz.wgsl");
+    }
+}
@@ -0,0 +1,57 @@
+# npy.rsのテスト用fixtureを作る
+# numpyがない環境でも作れるように，numpy.lib.format.write_array / numpy.savez と同じバイト列を標準ライブラリだけで書く
+# (numpyがあれば np.save(path, arr) / np.lib.format.write_array(f, arr, version=(2, 0)) でも同じものになる)
+import struct
+import zipfile
+
+ARRAY_ALIGN = 64
+GROWTH_AXIS_MAX_DIGITS = 21
+
+
+def npy(descr, shape, data, fortran_order=False, version=(1, 0)):
+    # numpy.lib.format._write_array_header
+    d = {'descr': descr, 'fortran_order': fortran_order, 'shape': shape}
+    header = "{" + "".join("'%s': %s, " % (k, repr(v)) for k, v in sorted(d.items())) + "}"
+    if len(shape) > 0:
+        header += " " * (GROWTH_AXIS_MAX_DIGITS - len(repr(shape[-1 if fortran_order else 0])))
+    # numpy.lib.format._wrap_header
+    fmt, encoding = {(1, 0): ('<H', 'latin1'), (2, 0): ('<I', 'latin1'), (3, 0): ('<I', 'utf8')}[version]
+    header = header.encode(encoding)
+    hlen = len(header) + 1
+    padlen = ARRAY_ALIGN - ((6 + 2 + struct.calcsize(fmt) + hlen) % ARRAY_ALIGN)
+    prefix = b'\x93NUMPY' + bytes(version) + struct.pack(fmt, hlen + padlen)
+    return prefix + header + b' ' * padlen + b'\n' + data
+
+
+def pack(fmt, values):
+    return struct.pack(fmt[0] + fmt[1:] * len(values), *values)
+
+
+f4 = [i * 0.5 for i in range(12)]
+# np.arange(12, dtype='<f4').reshape(3, 4) * 0.5
+f4_c = npy('<f4', (3, 4), pack('<f', f4))
+files = {
+    'f4.npy': f4_c,
+    # .astype('>f4')
+    'f4_be.npy': npy('>f4', (3, 4), pack('>f', f4)),
+    # np.array([[0, 0.5, -1.5], [65504, 2**-24, -2]], dtype='<f2')
+    'f2.npy': npy('<f2', (2, 3), pack('<e', [0.0, 0.5, -1.5, 65504.0, 2.0 ** -24, -2.0])),
+    # np.array([[-3, 0], [7, 100000]], dtype='<i4')
+    'i4.npy': npy('<i4', (2, 2), pack('<i', [-3, 0, 7, 100000])),
+    # np.asfortranarray(np.arange(6, dtype='<f4').reshape(2, 3))
+    'f4_fortran.npy': npy('<f4', (2, 3), pack('<f', [0, 3, 1, 4, 2, 5]), fortran_order=True),
+    # np.arange(3, dtype='<f4') と np.float32(2.5)
+    'f4_1d.npy': npy('<f4', (3,), pack('<f', [0, 1, 2])),
+    'f4_0d.npy': npy('<f4', (), pack('<f', [2.5])),
+    # np.lib.format.write_array(f, arr, version=(2, 0)) / (3, 0)
+    'f4_v2.npy': npy('<f4', (3, 4), pack('<f', f4), version=(2, 0)),
+    'f4_v3.npy': npy('<f4', (3, 4), pack('<f', f4), version=(3, 0)),
+}
+for name, data in files.items():
+    with open(name, 'wb') as f:
+        f.write(data)
+
+# np.savez('ab.npz', a=f4, b=i4) (無圧縮のzip)
+with zipfile.ZipFile('ab.npz', 'w', compression=zipfile.ZIP_STORED) as z:
+    z.writestr('a.npy', files['f4.npy'])
+    z.writestr('b.npy', files['i4.npy'])